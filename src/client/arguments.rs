@@ -1,6 +1,12 @@
+use crate::magnet::MagnetLink;
+use crate::peer::choker::ChokerSettings;
+use crate::peer::tracker::tiers::TrackerStatus;
+use crate::peer::tracker::ScrapeStats;
+use crate::types::{FileStatus, InfoHash, SwarmHealth, TorrentResponse};
 use clap::{ArgMatches, Command, Parser, ValueEnum};
 use futures::channel::oneshot;
 use libp2p::core::Multiaddr;
+use libp2p::request_response::ResponseChannel;
 use libp2p::PeerId;
 use std::error::Error;
 use std::net::SocketAddr;
@@ -36,6 +42,31 @@ pub struct WSSettings {
     pub address: Multiaddr,
     pub socket_workers: usize,
 }
+#[derive(Debug, Clone)]
+pub struct TrackerSettings {
+    pub socket_addr: SocketAddr,
+    pub interval: u64,
+    pub min_interval: u64,
+    pub peer_expiry: u64,
+    pub whitelist: Option<Vec<InfoHash>>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct QueueSettings {
+    pub max_active_downloads: usize,
+    pub max_active_seeds: usize,
+    /// Limit on downloads and seeds together.
+    pub max_active: usize,
+}
+
+#[derive(Debug, Clone)]
+pub struct DhtSettings {
+    pub enabled: bool,
+    pub socket_addr: SocketAddr,
+    /// `host:port` of well known nodes used to join the DHT.
+    pub bootstrap: Vec<String>,
+}
+
 #[derive(Debug, Clone)]
 pub struct MetricsSettings {
     pub socket_addr: SocketAddr,
@@ -70,6 +101,42 @@ impl Default for MetricsSettings {
     }
 }
 
+impl Default for TrackerSettings {
+    fn default() -> Self {
+        Self {
+            socket_addr: ("127.0.0.1:6969".parse::<SocketAddr>().unwrap()),
+            interval: 1800,
+            min_interval: 900,
+            peer_expiry: 3600,
+            whitelist: None,
+        }
+    }
+}
+
+impl Default for QueueSettings {
+    fn default() -> Self {
+        Self {
+            max_active_downloads: 3,
+            max_active_seeds: 5,
+            max_active: 8,
+        }
+    }
+}
+
+impl Default for DhtSettings {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            socket_addr: "0.0.0.0:6881".parse::<SocketAddr>().unwrap(),
+            bootstrap: vec![
+                "router.bittorrent.com:6881".to_string(),
+                "dht.transmissionbt.com:6881".to_string(),
+                "router.utorrent.com:6881".to_string(),
+            ],
+        }
+    }
+}
+
 impl Default for IPFSSettings {
     fn default() -> Self {
         Self {
@@ -81,14 +148,24 @@ impl Default for IPFSSettings {
     }
 }
 
+const DEFAULT_DB_DIR: &str = "~/.jubjub";
+
 #[derive(Clone, Debug)]
 pub struct Settings {
     pub tcp: TcpSettings,
     pub ws: WSSettings,
     pub metrics: MetricsSettings,
     pub ipfs: IPFSSettings,
+    pub tracker: TrackerSettings,
+    pub queue: QueueSettings,
+    pub dht: DhtSettings,
+    pub mode: Mode,
     pub max_peers: usize,
+    /// Peers we upload to at once.
+    pub upload_slots: usize,
     pub download_dir: PathBuf,
+    /// Where the torrent library and resume data are kept.
+    pub db_dir: PathBuf,
     secret_key: Option<u8>,
 }
 
@@ -99,8 +176,14 @@ impl Default for Settings {
             ws: WSSettings::default(),
             metrics: MetricsSettings::default(),
             ipfs: IPFSSettings::default(),
+            tracker: TrackerSettings::default(),
+            queue: QueueSettings::default(),
+            dht: DhtSettings::default(),
+            mode: Mode::ClientMode,
             max_peers: 10,
+            upload_slots: ChokerSettings::default().upload_slots,
             download_dir: "~/Downloads".parse::<PathBuf>().unwrap(),
+            db_dir: PathBuf::from(DEFAULT_DB_DIR),
             secret_key: None,
         }
     }
@@ -116,9 +199,14 @@ impl Settings {
             Ok(file) => Some(file),
             Err(_) => panic!("\x1b[31mErr:\x1b[0m Error opening config file at {}", path),
         };
+        let mode = matches.get_one::<Mode>("mode").copied();
         if let Some(file) = file {
             tracing::info!("Using config file at {}", path);
-            return Settings::create_from_file(file).await;
+            let mut settings = Settings::create_from_file(file).await;
+            if let Some(mode) = mode {
+                settings.mode = mode;
+            }
+            return settings;
         }
 
         tracing::info!("using command line args for settings  ");
@@ -152,6 +240,19 @@ impl Settings {
             .expect("Invalid download_dir field")
             .parse::<PathBuf>()
             .unwrap();
+        let db_dir = jubjub_table
+            .get("db_dir")
+            .map(|db_dir| PathBuf::from(db_dir.as_str().expect("Invalid db_dir field")))
+            .unwrap_or_else(|| PathBuf::from(DEFAULT_DB_DIR));
+        let upload_slots = jubjub_table
+            .get("upload_slots")
+            .map(|slots| {
+                slots
+                    .as_integer()
+                    .and_then(|slots| usize::try_from(slots).ok())
+                    .expect("Invalid upload_slots field")
+            })
+            .unwrap_or(ChokerSettings::default().upload_slots);
         let tcp_table = parsed
             .get("tcp")
             .expect("Missing tcp field")
@@ -269,13 +370,126 @@ impl Settings {
             .expect("Missing max_peers field")
             .as_integer()
             .expect("Invalid max_peers field") as usize;
+        let mode = match jubjub_table.get("mode") {
+            Some(mode) => Mode::from_str(mode.as_str().expect("Invalid mode field"), true)
+                .expect("Invalid mode field"),
+            None => Mode::ClientMode,
+        };
+        // the tracker table is optional, only TrackingMode needs it
+        let tracker = match parsed.get("tracker") {
+            Some(tracker_table) => {
+                let defaults = TrackerSettings::default();
+                let integer = |key: &str, default: u64| {
+                    tracker_table
+                        .get(key)
+                        .map(|value| value.as_integer().expect("Invalid tracker field") as u64)
+                        .unwrap_or(default)
+                };
+                TrackerSettings {
+                    socket_addr: tracker_table
+                        .get("address")
+                        .map(|address| {
+                            address
+                                .as_str()
+                                .expect("Invalid address field")
+                                .parse::<SocketAddr>()
+                                .expect("Invalid address field")
+                        })
+                        .unwrap_or(defaults.socket_addr),
+                    interval: integer("interval", defaults.interval),
+                    min_interval: integer("min_interval", defaults.min_interval),
+                    peer_expiry: integer("peer_expiry", defaults.peer_expiry),
+                    whitelist: tracker_table.get("whitelist").map(|whitelist| {
+                        whitelist
+                            .as_array()
+                            .expect("Invalid whitelist field")
+                            .iter()
+                            .map(|info_hash| {
+                                info_hash
+                                    .as_str()
+                                    .expect("Invalid whitelist entry")
+                                    .parse::<InfoHash>()
+                                    .expect("Invalid whitelist entry")
+                            })
+                            .collect()
+                    }),
+                }
+            }
+            None => TrackerSettings::default(),
+        };
+        let queue = match parsed.get("queue") {
+            Some(queue_table) => {
+                let defaults = QueueSettings::default();
+                let limit = |key: &str, default: usize| {
+                    queue_table
+                        .get(key)
+                        .map(|value| {
+                            value
+                                .as_integer()
+                                .and_then(|value| usize::try_from(value).ok())
+                                .expect("Invalid queue field")
+                        })
+                        .unwrap_or(default)
+                };
+                QueueSettings {
+                    max_active_downloads: limit(
+                        "max_active_downloads",
+                        defaults.max_active_downloads,
+                    ),
+                    max_active_seeds: limit("max_active_seeds", defaults.max_active_seeds),
+                    max_active: limit("max_active", defaults.max_active),
+                }
+            }
+            None => QueueSettings::default(),
+        };
+        let dht = match parsed.get("dht") {
+            Some(dht_table) => {
+                let defaults = DhtSettings::default();
+                DhtSettings {
+                    enabled: dht_table
+                        .get("enabled")
+                        .map(|enabled| enabled.as_bool().expect("Invalid enabled field"))
+                        .unwrap_or(defaults.enabled),
+                    socket_addr: dht_table
+                        .get("address")
+                        .map(|address| {
+                            address
+                                .as_str()
+                                .expect("Invalid address field")
+                                .parse::<SocketAddr>()
+                                .expect("Invalid address field")
+                        })
+                        .unwrap_or(defaults.socket_addr),
+                    bootstrap: dht_table
+                        .get("bootstrap")
+                        .map(|bootstrap| {
+                            bootstrap
+                                .as_array()
+                                .expect("Invalid bootstrap field")
+                                .iter()
+                                .map(|node| {
+                                    node.as_str().expect("Invalid bootstrap entry").to_string()
+                                })
+                                .collect()
+                        })
+                        .unwrap_or(defaults.bootstrap),
+                }
+            }
+            None => DhtSettings::default(),
+        };
         Settings {
             tcp,
             ws,
             metrics,
             ipfs,
+            tracker,
+            queue,
+            dht,
+            mode,
             max_peers,
+            upload_slots,
             download_dir,
+            db_dir,
             secret_key: Some(secret_key),
         }
     }
@@ -374,25 +588,42 @@ impl Settings {
             .expect("Invalid download dir")
             .parse::<PathBuf>()
             .expect("Invalid download dir");
+        let upload_slots = matches
+            .get_one::<String>("upload_slots")
+            .expect("Invalid upload_slots")
+            .parse::<usize>()
+            .expect("Invalid upload_slots");
+        let db_dir = PathBuf::from(DEFAULT_DB_DIR);
         let secret_key = matches
             .get_one::<String>("key>")
             .expect("Invalid secret key")
             .parse::<u8>()
             .expect("Invalid secret key");
 
+        let mode = matches
+            .get_one::<Mode>("mode")
+            .copied()
+            .unwrap_or(Mode::ClientMode);
+
         Settings {
             tcp,
             ws,
             ipfs,
             metrics,
+            tracker: TrackerSettings::default(),
+            queue: QueueSettings::default(),
+            dht: DhtSettings::default(),
+            mode,
             max_peers,
+            upload_slots,
             download_dir,
+            db_dir,
             secret_key: Some(secret_key),
         }
     }
 }
 
-#[derive(Debug, Clone, Copy, ValueEnum)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
 pub enum Mode {
     ClientMode,
     DiscoveryMode,
@@ -434,6 +665,43 @@ pub enum ClientCommand {
         peer: PeerId,
         tx: oneshot::Sender<Result<Vec<u8>, Box<dyn Error + Send>>>,
     },
+    /// Answers an inbound request of `peer` for `torrent`.
+    RespondFile {
+        peer: PeerId,
+        torrent: String,
+        file: Vec<u8>,
+        channel: ResponseChannel<TorrentResponse>,
+    },
+    AddMagnet {
+        magnet: MagnetLink,
+        tx: oneshot::Sender<InfoHash>,
+    },
+    /// Adds a torrent from its metainfo, downloading into `save_path`.
+    AddTorrent {
+        file: Vec<u8>,
+        save_path: PathBuf,
+        tx: oneshot::Sender<InfoHash>,
+    },
+    /// `(tracker url, info-hash)` for every tracker of every torrent.
+    TrackerList {
+        tx: oneshot::Sender<Vec<(String, InfoHash)>>,
+    },
+    UpdateScrape {
+        stats: hashbrown::HashMap<InfoHash, ScrapeStats>,
+        tx: oneshot::Sender<Vec<SwarmHealth>>,
+    },
+    TrackerStatus {
+        info_hash: InfoHash,
+        tx: oneshot::Sender<Option<Vec<TrackerStatus>>>,
+    },
+    /// New queue states; paused and queued torrents stop announcing.
+    SetStates {
+        states: Vec<(InfoHash, FileStatus)>,
+    },
+    /// Saves resume data and stops the session.
+    Shutdown {
+        tx: oneshot::Sender<()>,
+    },
 }
 
 pub fn execute_cmd(_tx: serde_json::Value) -> Result<(), Box<dyn Error>> {
@@ -456,6 +724,21 @@ pub fn get_cmds() -> clap::Command {
                 .default_value("10")
                 .conflicts_with("config"),
         )
+        .arg(
+            Arg::new("upload_slots")
+                .long("upload-slots")
+                .num_args(1)
+                .help("Number of peers to upload to at once")
+                .default_value("4")
+                .conflicts_with("config"),
+        )
+        .arg(
+            Arg::new("mode")
+                .long("mode")
+                .num_args(1)
+                .value_parser(clap::value_parser!(Mode))
+                .help("Mode to run jubjub in"),
+        )
         .arg(
             Arg::new("key")
                 .short('k')
@@ -0,0 +1,286 @@
+use crate::metainfo::{FileEntry, Info, Metainfo, MetainfoError, MAX_PIECE_LENGTH};
+use serde_bytes::ByteBuf;
+use sha1::{Digest, Sha1};
+use std::fs::File;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+
+const MIN_PIECE_LENGTH: u64 = 16 * 1024;
+const MAX_AUTO_PIECE_LENGTH: u64 = 16 * 1024 * 1024;
+// aim for roughly this many pieces when picking the piece length
+const TARGET_PIECE_COUNT: u64 = 1500;
+
+#[derive(Debug, Clone, Default)]
+pub struct CreateOptions {
+    /// Picked from the total size when `None`, otherwise a power of two
+    /// from 16 KiB to 64 MiB.
+    pub piece_length: Option<u64>,
+    pub trackers: Vec<Vec<String>>,
+    pub comment: Option<String>,
+    pub created_by: Option<String>,
+    pub private: bool,
+    pub web_seeds: Vec<String>,
+}
+
+/// Power of two piece length that keeps the piece count near `TARGET_PIECE_COUNT`.
+pub fn auto_piece_length(total_length: u64) -> u64 {
+    (total_length / TARGET_PIECE_COUNT)
+        .next_power_of_two()
+        .clamp(MIN_PIECE_LENGTH, MAX_AUTO_PIECE_LENGTH)
+}
+
+/// Builds the metainfo for a single file or a directory tree. Blocking: hashes
+/// every byte under `path`.
+pub fn create_torrent(path: &Path, options: &CreateOptions) -> Result<Metainfo, MetainfoError> {
+    let name = path
+        .file_name()
+        .and_then(|name| name.to_str())
+        .ok_or_else(|| MetainfoError::Invalid(format!("invalid path {}", path.display())))?
+        .to_string();
+    let files = if path.is_dir() {
+        let mut files = Vec::new();
+        walk(path, &mut files)?;
+        if files.is_empty() {
+            return Err(MetainfoError::Invalid(format!(
+                "{} contains no files",
+                path.display()
+            )));
+        }
+        files
+    } else {
+        vec![path.to_path_buf()]
+    };
+    let lengths = files
+        .iter()
+        .map(|file| Ok(std::fs::metadata(file)?.len()))
+        .collect::<Result<Vec<u64>, MetainfoError>>()?;
+    let total_length: u64 = lengths.iter().sum();
+    let piece_length = options
+        .piece_length
+        .unwrap_or_else(|| auto_piece_length(total_length));
+    let pieces = hash_pieces(&files, piece_length)?;
+
+    let (length, entries) = if path.is_dir() {
+        let entries = files
+            .iter()
+            .zip(&lengths)
+            .map(|(file, length)| FileEntry {
+                length: *length,
+                path: file
+                    .strip_prefix(path)
+                    .expect("walked files are under the root")
+                    .components()
+                    .map(|component| component.as_os_str().to_string_lossy().into_owned())
+                    .collect(),
+            })
+            .collect();
+        (None, Some(entries))
+    } else {
+        (Some(total_length), None)
+    };
+    let info = Info {
+        name,
+        piece_length,
+        pieces: ByteBuf::from(pieces),
+        length,
+        files: entries,
+        private: options.private.then_some(1),
+    };
+    let tiers: Vec<Vec<String>> = options
+        .trackers
+        .iter()
+        .filter(|tier| !tier.is_empty())
+        .cloned()
+        .collect();
+    let announce = tiers.first().and_then(|tier| tier.first()).cloned();
+    // a lone tracker does not need an announce-list
+    let announce_list = (tiers.iter().flatten().count() > 1).then_some(tiers);
+    Ok(Metainfo {
+        announce,
+        announce_list,
+        creation_date: Some(chrono::Utc::now().timestamp()),
+        comment: options.comment.clone(),
+        created_by: Some(
+            options
+                .created_by
+                .clone()
+                .unwrap_or_else(|| format!("jubjub/{}", crate::config::VERSION_STR)),
+        ),
+        encoding: Some("UTF-8".to_string()),
+        url_list: options.web_seeds.clone(),
+        info,
+    })
+}
+
+/// Creates the metainfo for `path` and writes it to `output`, returning the
+/// bencoded bytes that were written.
+pub fn write_torrent(
+    path: &Path,
+    output: &Path,
+    options: &CreateOptions,
+) -> Result<Vec<u8>, MetainfoError> {
+    let metainfo = create_torrent(path, options)?;
+    let bytes = metainfo.to_bytes()?;
+    std::fs::write(output, &bytes)?;
+    Ok(bytes)
+}
+
+// files sorted by path so the layout is reproducible across platforms
+fn walk(dir: &Path, files: &mut Vec<PathBuf>) -> Result<(), MetainfoError> {
+    let mut entries = std::fs::read_dir(dir)?
+        .map(|entry| entry.map(|entry| entry.path()))
+        .collect::<Result<Vec<_>, _>>()?;
+    entries.sort();
+    for entry in entries {
+        let file_type = std::fs::symlink_metadata(&entry)?.file_type();
+        if file_type.is_dir() {
+            walk(&entry, files)?;
+        } else if file_type.is_file() {
+            files.push(entry);
+        }
+    }
+    Ok(())
+}
+
+fn hash_pieces(files: &[PathBuf], piece_length: u64) -> Result<Vec<u8>, MetainfoError> {
+    if !piece_length.is_power_of_two()
+        || !(MIN_PIECE_LENGTH..=MAX_PIECE_LENGTH).contains(&piece_length)
+    {
+        return Err(MetainfoError::Invalid(format!(
+            "piece length {} is not a power of two from {} to {}",
+            piece_length, MIN_PIECE_LENGTH, MAX_PIECE_LENGTH
+        )));
+    }
+    let mut pieces = Vec::new();
+    let mut piece = Vec::with_capacity(piece_length as usize);
+    for path in files {
+        let mut file = File::open(path)?;
+        loop {
+            let wanted = piece_length as usize - piece.len();
+            let read = (&mut file).take(wanted as u64).read_to_end(&mut piece)?;
+            if piece.len() == piece_length as usize {
+                pieces.extend_from_slice(&Sha1::digest(&piece));
+                piece.clear();
+            }
+            if read == 0 {
+                break;
+            }
+        }
+    }
+    if !piece.is_empty() {
+        pieces.extend_from_slice(&Sha1::digest(&piece));
+    }
+    Ok(pieces)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::Torrent;
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("jubjub_create_{}", name));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn test_auto_piece_length() {
+        assert_eq!(auto_piece_length(0), MIN_PIECE_LENGTH);
+        assert_eq!(auto_piece_length(1500 * 1024 * 1024), 1024 * 1024);
+        assert_eq!(auto_piece_length(u64::MAX / 2), MAX_AUTO_PIECE_LENGTH);
+    }
+
+    #[test]
+    fn test_create_single_file() {
+        let dir = temp_dir("single");
+        let file = dir.join("data.bin");
+        let data: Vec<u8> = (0..3 * MIN_PIECE_LENGTH + 100).map(|i| i as u8).collect();
+        std::fs::write(&file, &data).unwrap();
+        let options = CreateOptions {
+            piece_length: Some(MIN_PIECE_LENGTH),
+            trackers: vec![vec!["http://tracker.example/announce".to_string()]],
+            comment: Some("build".to_string()),
+            private: true,
+            web_seeds: vec!["http://seed.example/".to_string()],
+            ..Default::default()
+        };
+        let output = dir.join("data.torrent");
+        let bytes = write_torrent(&file, &output, &options).unwrap();
+        assert_eq!(std::fs::read(&output).unwrap(), bytes);
+
+        let torrent = Torrent::from_bytes(&bytes).unwrap();
+        let metainfo = torrent.metainfo.unwrap();
+        assert_eq!(
+            metainfo.announce.as_deref(),
+            Some("http://tracker.example/announce")
+        );
+        assert_eq!(metainfo.announce_list, None);
+        assert_eq!(metainfo.url_list, vec!["http://seed.example/".to_string()]);
+        assert!(metainfo.info.is_private());
+        assert_eq!(metainfo.info.piece_count(), 4);
+        assert_eq!(
+            metainfo.info.piece_hash(3).unwrap(),
+            &Sha1::digest(&data[3 * MIN_PIECE_LENGTH as usize..])[..]
+        );
+        // the info-hash of a created torrent is over canonical bencode
+        let info = serde_bencode::to_bytes(&metainfo.info).unwrap();
+        assert_eq!(
+            torrent.info_hash,
+            crate::types::InfoHash::from_info_bytes(&info)
+        );
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_create_directory() {
+        let dir = temp_dir("dir");
+        let root = dir.join("pack");
+        std::fs::create_dir_all(root.join("sub")).unwrap();
+        std::fs::write(root.join("b.txt"), [1u8; 20 * 1024]).unwrap();
+        std::fs::write(root.join("sub").join("a.txt"), [2u8; 30 * 1024]).unwrap();
+        let options = CreateOptions {
+            piece_length: Some(MIN_PIECE_LENGTH),
+            trackers: vec![
+                vec!["http://a.example/announce".to_string()],
+                vec!["udp://b.example:80".to_string()],
+            ],
+            ..Default::default()
+        };
+        let metainfo = create_torrent(&root, &options).unwrap();
+        assert_eq!(metainfo.info.name, "pack");
+        assert_eq!(metainfo.info.total_length(), 50 * 1024);
+        assert_eq!(metainfo.info.piece_count(), 4);
+        assert_eq!(metainfo.trackers().len(), 2);
+        let files = metainfo.info.files.as_ref().unwrap();
+        assert_eq!(files[0].path, vec!["b.txt".to_string()]);
+        assert_eq!(files[1].path, vec!["sub".to_string(), "a.txt".to_string()]);
+        // second piece spans both files
+        let mut piece = vec![1u8; 4 * 1024];
+        piece.extend_from_slice(&[2u8; 12 * 1024]);
+        assert_eq!(
+            metainfo.info.piece_hash(1).unwrap(),
+            &Sha1::digest(&piece)[..]
+        );
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_invalid_piece_length() {
+        let dir = temp_dir("piece_length");
+        let file = dir.join("data.bin");
+        std::fs::write(&file, [0u8; 10]).unwrap();
+        for piece_length in [0, 1000, MIN_PIECE_LENGTH / 2, 2 * MAX_PIECE_LENGTH] {
+            let options = CreateOptions {
+                piece_length: Some(piece_length),
+                ..Default::default()
+            };
+            assert!(matches!(
+                create_torrent(&file, &options),
+                Err(MetainfoError::Invalid(_))
+            ));
+        }
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
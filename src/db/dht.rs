@@ -0,0 +1,71 @@
+use super::DbError;
+use crate::dht::{NodeId, NodeInfo};
+
+const NODE_ID_KEY: &[u8] = b"id";
+const NODES_KEY: &[u8] = b"nodes";
+
+/// Our DHT node id and the nodes we last knew, so a restart keeps its place
+/// in the id space and rejoins without the bootstrap routers.
+#[derive(Debug, Clone)]
+pub struct NodeCache {
+    tree: sled::Tree,
+}
+
+impl NodeCache {
+    pub fn new(db: &sled::Db) -> Result<Self, DbError> {
+        Ok(Self {
+            tree: db.open_tree("dht")?,
+        })
+    }
+
+    /// The saved node id, or a new random one that is saved for next time.
+    pub fn node_id(&self) -> Result<NodeId, DbError> {
+        if let Some(bytes) = self.tree.get(NODE_ID_KEY)? {
+            if let Ok(id) = NodeId::from_bytes(&bytes) {
+                return Ok(id);
+            }
+        }
+        let id = NodeId::random();
+        self.tree.insert(NODE_ID_KEY, id.as_bytes())?;
+        Ok(id)
+    }
+
+    pub fn save_nodes(&self, nodes: &[NodeInfo]) -> Result<(), DbError> {
+        self.tree.insert(NODES_KEY, bincode::serialize(nodes)?)?;
+        Ok(())
+    }
+
+    pub fn load_nodes(&self) -> Result<Vec<NodeInfo>, DbError> {
+        match self.tree.get(NODES_KEY)? {
+            Some(bytes) => Ok(bincode::deserialize(&bytes)?),
+            None => Ok(Vec::new()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_node_cache() {
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        let cache = NodeCache::new(&db).unwrap();
+        let id = cache.node_id().unwrap();
+        assert_eq!(cache.node_id().unwrap(), id);
+        assert!(cache.load_nodes().unwrap().is_empty());
+
+        let nodes = vec![
+            NodeInfo {
+                id: NodeId([1; 20]),
+                addr: "10.0.0.1:6881".parse().unwrap(),
+            },
+            NodeInfo {
+                id: NodeId([2; 20]),
+                addr: "[::1]:6881".parse().unwrap(),
+            },
+        ];
+        cache.save_nodes(&nodes).unwrap();
+        assert_eq!(NodeCache::new(&db).unwrap().load_nodes().unwrap(), nodes);
+    }
+}
@@ -0,0 +1,255 @@
+use super::{unix_time, DbError};
+use crate::magnet::MagnetLink;
+use crate::metainfo::{Metainfo, MetainfoError};
+use crate::types::{FileStatus, InfoHash};
+use serde::{Deserialize, Serialize};
+use std::cmp::Reverse;
+use std::path::PathBuf;
+use std::time::SystemTime;
+
+/// A torrent the client knows about, whether running or not.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct TorrentRecord {
+    pub info_hash: InfoHash,
+    pub name: String,
+    /// The bencoded .torrent file, `None` for a magnet link whose metadata
+    /// hasn't arrived yet.
+    pub metainfo: Option<Vec<u8>>,
+    pub magnet: Option<String>,
+    pub save_path: PathBuf,
+    pub labels: Vec<String>,
+    /// Higher runs first.
+    pub priority: u32,
+    pub state: FileStatus,
+    /// Runs regardless of the queue limits.
+    pub forced: bool,
+    /// Unix seconds.
+    pub added: u64,
+    pub updated: u64,
+    pub completed: Option<u64>,
+}
+
+impl TorrentRecord {
+    fn new(info_hash: InfoHash, name: String, save_path: PathBuf) -> Self {
+        let now = unix_time(SystemTime::now());
+        Self {
+            info_hash,
+            name,
+            metainfo: None,
+            magnet: None,
+            save_path,
+            labels: Vec::new(),
+            priority: 0,
+            state: FileStatus::DownloadQueued,
+            forced: false,
+            added: now,
+            updated: now,
+            completed: None,
+        }
+    }
+
+    pub fn from_metainfo(bytes: &[u8], save_path: PathBuf) -> Result<Self, MetainfoError> {
+        let metainfo = Metainfo::from_bytes(bytes)?;
+        let info_hash = InfoHash::from_info_bytes(Metainfo::raw_info(bytes)?);
+        let mut record = Self::new(info_hash, metainfo.info.name, save_path);
+        record.metainfo = Some(bytes.to_vec());
+        Ok(record)
+    }
+
+    pub fn from_magnet(magnet: &MagnetLink, save_path: PathBuf) -> Self {
+        let info_hash = magnet.swarm_hash();
+        let name = magnet
+            .display_name
+            .clone()
+            .unwrap_or_else(|| info_hash.to_hex());
+        let mut record = Self::new(info_hash, name, save_path);
+        record.magnet = Some(magnet.to_uri());
+        record
+    }
+
+    pub fn metainfo(&self) -> Option<Result<Metainfo, MetainfoError>> {
+        self.metainfo.as_deref().map(Metainfo::from_bytes)
+    }
+
+    pub fn has_label(&self, label: &str) -> bool {
+        self.labels.iter().any(|own| own == label)
+    }
+}
+
+/// Every torrent added to the client, persisted in sled under its info-hash.
+#[derive(Debug, Clone)]
+pub struct Library {
+    tree: sled::Tree,
+}
+
+impl Library {
+    pub fn new(db: &sled::Db) -> Result<Self, DbError> {
+        Ok(Self {
+            tree: db.open_tree("library")?,
+        })
+    }
+
+    fn put(&self, record: &TorrentRecord) -> Result<(), DbError> {
+        self.tree
+            .insert(record.info_hash.as_bytes(), bincode::serialize(record)?)?;
+        Ok(())
+    }
+
+    /// Adds a torrent. Returns false and leaves the library alone if it is
+    /// already there.
+    pub fn add(&self, record: &TorrentRecord) -> Result<bool, DbError> {
+        if self.tree.contains_key(record.info_hash.as_bytes())? {
+            return Ok(false);
+        }
+        self.put(record)?;
+        Ok(true)
+    }
+
+    pub fn get(&self, info_hash: &InfoHash) -> Result<Option<TorrentRecord>, DbError> {
+        match self.tree.get(info_hash.as_bytes())? {
+            Some(bytes) => Ok(Some(bincode::deserialize(&bytes)?)),
+            None => Ok(None),
+        }
+    }
+
+    pub fn contains(&self, info_hash: &InfoHash) -> Result<bool, DbError> {
+        Ok(self.tree.contains_key(info_hash.as_bytes())?)
+    }
+
+    /// Changes a stored torrent and bumps its `updated` time. Returns the
+    /// new record, or `None` if the torrent isn't in the library.
+    pub fn update(
+        &self,
+        info_hash: &InfoHash,
+        change: impl FnOnce(&mut TorrentRecord),
+    ) -> Result<Option<TorrentRecord>, DbError> {
+        let Some(mut record) = self.get(info_hash)? else {
+            return Ok(None);
+        };
+        change(&mut record);
+        record.updated = unix_time(SystemTime::now()).max(record.updated);
+        self.put(&record)?;
+        Ok(Some(record))
+    }
+
+    pub fn set_state(
+        &self,
+        info_hash: &InfoHash,
+        state: FileStatus,
+    ) -> Result<Option<TorrentRecord>, DbError> {
+        self.update(info_hash, |record| {
+            if state == FileStatus::Seeding && record.completed.is_none() {
+                record.completed = Some(unix_time(SystemTime::now()));
+            }
+            record.state = state;
+        })
+    }
+
+    pub fn remove(&self, info_hash: &InfoHash) -> Result<Option<TorrentRecord>, DbError> {
+        match self.tree.remove(info_hash.as_bytes())? {
+            Some(bytes) => Ok(Some(bincode::deserialize(&bytes)?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Every torrent, highest priority first and oldest first within a
+    /// priority. Unreadable entries are skipped.
+    pub fn torrents(&self) -> Result<Vec<TorrentRecord>, DbError> {
+        let mut torrents = Vec::new();
+        for entry in self.tree.iter() {
+            let (key, bytes) = entry?;
+            match bincode::deserialize::<TorrentRecord>(&bytes) {
+                Ok(record) => torrents.push(record),
+                Err(e) => tracing::warn!("Skipping library entry {:?}: {}", key, e),
+            }
+        }
+        torrents.sort_by_key(|record| (Reverse(record.priority), record.added));
+        Ok(torrents)
+    }
+
+    pub fn with_label(&self, label: &str) -> Result<Vec<TorrentRecord>, DbError> {
+        Ok(self
+            .torrents()?
+            .into_iter()
+            .filter(|record| record.has_label(label))
+            .collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::create::{create_torrent, CreateOptions};
+
+    #[test]
+    fn test_library_lifecycle() {
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        let library = Library::new(&db).unwrap();
+        let magnet = MagnetLink::parse(
+            "magnet:?xt=urn:btih:0123456789abcdef0123456789abcdef01234567&dn=first",
+        )
+        .unwrap();
+        let first = TorrentRecord::from_magnet(&magnet, PathBuf::from("/downloads"));
+        assert_eq!(first.name, "first");
+        assert!(first.metainfo().is_none());
+        assert!(library.add(&first).unwrap());
+        assert!(!library.add(&first).unwrap());
+
+        let dir = std::env::temp_dir().join("jubjub_library");
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("data.bin"), [1u8; 100]).unwrap();
+        let bytes = create_torrent(&dir.join("data.bin"), &CreateOptions::default())
+            .unwrap()
+            .to_bytes()
+            .unwrap();
+        let second = TorrentRecord::from_metainfo(&bytes, PathBuf::from("/downloads")).unwrap();
+        assert_eq!(second.name, "data.bin");
+        assert_eq!(second.metainfo().unwrap().unwrap().info.name, "data.bin");
+        library.add(&second).unwrap();
+        let _ = std::fs::remove_dir_all(&dir);
+
+        // equal priority keeps the order they were added in
+        let mut older = second.clone();
+        older.info_hash = InfoHash::new([9; 20]);
+        older.added = first.added.saturating_sub(10);
+        library.add(&older).unwrap();
+        let order = |library: &Library| -> Vec<InfoHash> {
+            library
+                .torrents()
+                .unwrap()
+                .iter()
+                .map(|record| record.info_hash)
+                .collect()
+        };
+        assert_eq!(order(&library)[0], older.info_hash);
+
+        let updated = library
+            .update(&second.info_hash, |record| {
+                record.priority = 5;
+                record.labels.push("linux".to_string());
+                record.forced = true;
+            })
+            .unwrap()
+            .unwrap();
+        assert!(updated.updated >= second.updated);
+        assert!(library.get(&second.info_hash).unwrap().unwrap().forced);
+        assert_eq!(order(&library)[0], second.info_hash);
+        assert_eq!(library.with_label("linux").unwrap(), vec![updated]);
+
+        let seeding = library
+            .set_state(&first.info_hash, FileStatus::Seeding)
+            .unwrap()
+            .unwrap();
+        assert!(seeding.completed.is_some());
+        assert_eq!(library.get(&first.info_hash).unwrap(), Some(seeding));
+
+        assert!(library.remove(&first.info_hash).unwrap().is_some());
+        assert!(!library.contains(&first.info_hash).unwrap());
+        assert!(library
+            .update(&first.info_hash, |record| record.priority = 1)
+            .unwrap()
+            .is_none());
+        assert_eq!(library.torrents().unwrap().len(), 2);
+    }
+}
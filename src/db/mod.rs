@@ -1 +1,34 @@
+pub mod dht;
+pub mod library;
 pub mod peers;
+pub mod resume;
+
+use crate::client::arguments::Settings;
+use crate::storage::layout::expand_home;
+use std::time::{SystemTime, UNIX_EPOCH};
+use thiserror::Error;
+
+pub use dht::NodeCache;
+pub use library::{Library, TorrentRecord};
+pub use resume::{ResumeData, ResumeStore};
+
+#[derive(Debug, Error)]
+pub enum DbError {
+    #[error("Database error: {0}")]
+    Db(#[from] sled::Error),
+    #[error("Corrupt database entry: {0}")]
+    Decode(#[from] bincode::Error),
+    #[error("Invalid torrent: {0}")]
+    Metainfo(#[from] crate::metainfo::MetainfoError),
+}
+
+/// Opens the database under `settings.db_dir`.
+pub fn open(settings: &Settings) -> Result<sled::Db, DbError> {
+    Ok(sled::open(expand_home(&settings.db_dir))?)
+}
+
+fn unix_time(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_secs())
+        .unwrap_or(0)
+}
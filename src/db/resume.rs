@@ -0,0 +1,208 @@
+use super::{unix_time, DbError};
+use crate::download::Priority;
+use crate::storage::FileLayout;
+use crate::types::{Bitfield, InfoHash};
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+/// Size and modification time of a file when resume data was saved.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FileStamp {
+    pub length: u64,
+    pub modified: Option<u64>,
+}
+
+impl FileStamp {
+    /// `None` if the file doesn't exist.
+    pub fn read(path: &Path) -> Option<Self> {
+        let metadata = std::fs::metadata(path).ok()?;
+        Some(Self {
+            length: metadata.len(),
+            modified: metadata.modified().ok().map(unix_time),
+        })
+    }
+}
+
+/// Everything needed to pick a torrent up again after a restart without
+/// hashing its data.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ResumeData {
+    pub info_hash: InfoHash,
+    pub piece_count: usize,
+    /// Bitfield of the verified pieces.
+    pub have: Vec<u8>,
+    pub file_priorities: Vec<Priority>,
+    pub uploaded: u64,
+    pub downloaded: u64,
+    /// Tracker urls per tier, in the order they were last tried.
+    pub trackers: Vec<Vec<String>>,
+    /// `(url, tracker id)` pairs to send back after a restart.
+    pub tracker_ids: Vec<(String, String)>,
+    pub save_path: PathBuf,
+    /// Unix seconds.
+    pub added: u64,
+    pub completed: Option<u64>,
+    pub files: Vec<Option<FileStamp>>,
+}
+
+impl ResumeData {
+    pub fn new(info_hash: InfoHash, layout: &FileLayout) -> Self {
+        Self {
+            info_hash,
+            piece_count: layout.piece_count(),
+            have: Bitfield::new(layout.piece_count()).as_bytes().to_vec(),
+            file_priorities: Vec::new(),
+            uploaded: 0,
+            downloaded: 0,
+            trackers: Vec::new(),
+            tracker_ids: Vec::new(),
+            save_path: layout.root.clone(),
+            added: unix_time(SystemTime::now()),
+            completed: None,
+            files: Vec::new(),
+        }
+    }
+
+    pub fn have(&self) -> Option<Bitfield> {
+        Bitfield::from_bytes(&self.have, self.piece_count).ok()
+    }
+
+    /// Records the verified pieces and stamps the files as they are now, so
+    /// call it after flushing the storage.
+    pub fn update(&mut self, have: &Bitfield, layout: &FileLayout) {
+        self.piece_count = have.len();
+        self.have = have.as_bytes().to_vec();
+        if have.is_complete() && self.completed.is_none() {
+            self.completed = Some(unix_time(SystemTime::now()));
+        }
+        self.files = layout
+            .files
+            .iter()
+            .map(|file| FileStamp::read(&file.path))
+            .collect();
+    }
+
+    /// The saved pieces if the files still look the way they did when the
+    /// resume data was saved. `None` means a full recheck is needed.
+    pub fn verified_have(&self, layout: &FileLayout) -> Option<Bitfield> {
+        if self.piece_count != layout.piece_count() || self.files.len() != layout.files.len() {
+            return None;
+        }
+        let unchanged = layout
+            .files
+            .iter()
+            .zip(&self.files)
+            .all(|(file, stamp)| FileStamp::read(&file.path) == *stamp);
+        if !unchanged {
+            return None;
+        }
+        self.have()
+    }
+}
+
+/// Resume data of every torrent, keyed by info-hash.
+#[derive(Debug, Clone)]
+pub struct ResumeStore {
+    tree: sled::Tree,
+}
+
+impl ResumeStore {
+    pub fn new(db: &sled::Db) -> Result<Self, DbError> {
+        Ok(Self {
+            tree: db.open_tree("resume")?,
+        })
+    }
+
+    pub fn save(&self, data: &ResumeData) -> Result<(), DbError> {
+        self.tree
+            .insert(data.info_hash.as_bytes(), bincode::serialize(data)?)?;
+        Ok(())
+    }
+
+    /// Makes saved data durable, e.g. before shutting down.
+    pub async fn flush(&self) -> Result<(), DbError> {
+        self.tree.flush_async().await?;
+        Ok(())
+    }
+
+    pub fn load(&self, info_hash: &InfoHash) -> Result<Option<ResumeData>, DbError> {
+        match self.tree.get(info_hash.as_bytes())? {
+            Some(bytes) => Ok(Some(bincode::deserialize(&bytes)?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Every saved torrent, for startup. Unreadable entries are skipped.
+    pub fn load_all(&self) -> Result<Vec<ResumeData>, DbError> {
+        let mut all = Vec::new();
+        for entry in self.tree.iter() {
+            let (key, bytes) = entry?;
+            match bincode::deserialize(&bytes) {
+                Ok(data) => all.push(data),
+                Err(e) => tracing::warn!("Skipping resume data for {:?}: {}", key, e),
+            }
+        }
+        Ok(all)
+    }
+
+    pub fn remove(&self, info_hash: &InfoHash) -> Result<(), DbError> {
+        self.tree.remove(info_hash.as_bytes())?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::metainfo::Info;
+    use serde_bytes::ByteBuf;
+
+    #[test]
+    fn test_resume_roundtrip() {
+        let dir = std::env::temp_dir().join("jubjub_resume");
+        let _ = std::fs::remove_dir_all(&dir);
+        let info = Info {
+            name: "file".to_string(),
+            piece_length: 4,
+            pieces: ByteBuf::from(vec![0u8; 60]),
+            length: Some(10),
+            files: None,
+            private: None,
+        };
+        let layout = FileLayout::new(&info, &dir).unwrap();
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("file"), [0u8; 10]).unwrap();
+
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        let store = ResumeStore::new(&db).unwrap();
+        let info_hash = InfoHash::new([3; 20]);
+        assert_eq!(store.load(&info_hash).unwrap(), None);
+
+        let mut data = ResumeData::new(info_hash, &layout);
+        let mut have = Bitfield::new(3);
+        have.set(1);
+        data.update(&have, &layout);
+        data.file_priorities = vec![Priority::High];
+        data.trackers = vec![vec!["udp://tracker.example:80".to_string()]];
+        data.tracker_ids = vec![("udp://tracker.example:80".to_string(), "id".to_string())];
+        data.uploaded = 100;
+        store.save(&data).unwrap();
+
+        let loaded = store.load(&info_hash).unwrap().unwrap();
+        assert_eq!(loaded, data);
+        assert_eq!(store.load_all().unwrap(), vec![data.clone()]);
+        assert_eq!(loaded.verified_have(&layout), Some(have.clone()));
+        assert_eq!(loaded.completed, None);
+
+        // a file that changed size needs a recheck
+        std::fs::write(dir.join("file"), [0u8; 4]).unwrap();
+        assert_eq!(loaded.verified_have(&layout), None);
+
+        data.update(&Bitfield::full(3), &layout);
+        assert!(data.completed.is_some());
+        store.remove(&info_hash).unwrap();
+        assert!(store.load_all().unwrap().is_empty());
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+}
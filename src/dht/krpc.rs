@@ -0,0 +1,370 @@
+use super::routing::{NodeId, NodeInfo};
+use super::DhtError;
+use crate::peer::tracker::{encode_compact_peer, parse_compact_peers, parse_compact_peers6};
+use crate::types::InfoHash;
+use serde::{Deserialize, Serialize};
+use serde_bytes::ByteBuf;
+use std::net::SocketAddr;
+
+/// The KRPC protocol error, which we send for bad tokens.
+pub const ERROR_PROTOCOL: i64 = 203;
+/// Sent in reply to queries with a method we don't know.
+pub const ERROR_METHOD: i64 = 204;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Query {
+    Ping,
+    FindNode {
+        target: NodeId,
+    },
+    GetPeers {
+        info_hash: InfoHash,
+    },
+    AnnouncePeer {
+        info_hash: InfoHash,
+        port: u16,
+        /// Use the port the query came from, for peers behind NAT.
+        implied_port: bool,
+        token: Vec<u8>,
+    },
+}
+
+impl Query {
+    pub fn method(&self) -> &'static str {
+        match self {
+            Query::Ping => "ping",
+            Query::FindNode { .. } => "find_node",
+            Query::GetPeers { .. } => "get_peers",
+            Query::AnnouncePeer { .. } => "announce_peer",
+        }
+    }
+}
+
+/// The `r` dictionary of a response. Which fields are set depends on the
+/// query it answers.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Response {
+    pub id: NodeId,
+    pub nodes: Vec<NodeInfo>,
+    /// Peers of the torrent, answering `get_peers`.
+    pub values: Vec<SocketAddr>,
+    pub token: Option<Vec<u8>>,
+}
+
+impl Response {
+    pub fn new(id: NodeId) -> Self {
+        Self {
+            id,
+            nodes: Vec::new(),
+            values: Vec::new(),
+            token: None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Body {
+    Query { id: NodeId, query: Query },
+    Response(Response),
+    Error { code: i64, message: String },
+}
+
+/// A KRPC message, the bencoded dictionaries BEP 5 nodes exchange over UDP.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Message {
+    /// Echoed back in the response to match it to the query.
+    pub transaction: Vec<u8>,
+    pub body: Body,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct RawArguments {
+    id: ByteBuf,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    target: Option<ByteBuf>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    info_hash: Option<ByteBuf>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    port: Option<u16>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    implied_port: Option<u8>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    token: Option<ByteBuf>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    nodes: Option<ByteBuf>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    nodes6: Option<ByteBuf>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    values: Option<Vec<ByteBuf>>,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct RawMessage {
+    t: ByteBuf,
+    y: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    q: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    a: Option<RawArguments>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    r: Option<RawArguments>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    e: Option<(i64, ByteBuf)>,
+}
+
+fn missing(key: &str) -> DhtError {
+    DhtError::Decode(format!("missing {}", key))
+}
+
+fn info_hash(bytes: Option<ByteBuf>) -> Result<InfoHash, DhtError> {
+    let bytes = bytes.ok_or_else(|| missing("info_hash"))?;
+    Ok(InfoHash::new(NodeId::from_bytes(&bytes)?.0))
+}
+
+impl Message {
+    pub fn query(transaction: Vec<u8>, id: NodeId, query: Query) -> Self {
+        Self {
+            transaction,
+            body: Body::Query { id, query },
+        }
+    }
+
+    pub fn response(transaction: Vec<u8>, response: Response) -> Self {
+        Self {
+            transaction,
+            body: Body::Response(response),
+        }
+    }
+
+    pub fn error(transaction: Vec<u8>, code: i64, message: &str) -> Self {
+        Self {
+            transaction,
+            body: Body::Error {
+                code,
+                message: message.to_string(),
+            },
+        }
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, DhtError> {
+        let raw: RawMessage =
+            serde_bencode::from_bytes(bytes).map_err(|e| DhtError::Decode(e.to_string()))?;
+        let body = match raw.y.as_str() {
+            "q" => {
+                let method = raw.q.ok_or_else(|| missing("q"))?;
+                let args = raw.a.ok_or_else(|| missing("a"))?;
+                let id = NodeId::from_bytes(&args.id)?;
+                let query = match method.as_str() {
+                    "ping" => Query::Ping,
+                    "find_node" => {
+                        let target = args.target.ok_or_else(|| missing("target"))?;
+                        Query::FindNode {
+                            target: NodeId::from_bytes(&target)?,
+                        }
+                    }
+                    "get_peers" => Query::GetPeers {
+                        info_hash: info_hash(args.info_hash)?,
+                    },
+                    "announce_peer" => Query::AnnouncePeer {
+                        info_hash: info_hash(args.info_hash)?,
+                        port: args.port.ok_or_else(|| missing("port"))?,
+                        implied_port: args.implied_port.unwrap_or(0) != 0,
+                        token: args.token.ok_or_else(|| missing("token"))?.into_vec(),
+                    },
+                    _ => {
+                        return Err(DhtError::UnknownMethod {
+                            transaction: raw.t.into_vec(),
+                            method,
+                        })
+                    }
+                };
+                Body::Query { id, query }
+            }
+            "r" => {
+                let args = raw.r.ok_or_else(|| missing("r"))?;
+                let mut response = Response::new(NodeId::from_bytes(&args.id)?);
+                if let Some(nodes) = args.nodes {
+                    response.nodes = NodeInfo::parse_compact(&nodes, false)?;
+                }
+                if let Some(nodes6) = args.nodes6 {
+                    response
+                        .nodes
+                        .extend(NodeInfo::parse_compact(&nodes6, true)?);
+                }
+                for value in args.values.unwrap_or_default() {
+                    let peers = if value.len() == 18 {
+                        parse_compact_peers6(&value)
+                    } else {
+                        parse_compact_peers(&value)
+                    };
+                    response
+                        .values
+                        .extend(peers.map_err(|e| DhtError::Decode(e.to_string()))?);
+                }
+                response.token = args.token.map(ByteBuf::into_vec);
+                Body::Response(response)
+            }
+            "e" => {
+                let (code, message) = raw.e.ok_or_else(|| missing("e"))?;
+                Body::Error {
+                    code,
+                    message: String::from_utf8_lossy(&message).into_owned(),
+                }
+            }
+            other => return Err(DhtError::Decode(format!("message type {:?}", other))),
+        };
+        Ok(Self {
+            transaction: raw.t.into_vec(),
+            body,
+        })
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut raw = RawMessage {
+            t: ByteBuf::from(self.transaction.clone()),
+            ..Default::default()
+        };
+        match &self.body {
+            Body::Query { id, query } => {
+                raw.y = "q".to_string();
+                raw.q = Some(query.method().to_string());
+                let mut args = RawArguments {
+                    id: ByteBuf::from(id.0.to_vec()),
+                    ..Default::default()
+                };
+                match query {
+                    Query::Ping => {}
+                    Query::FindNode { target } => {
+                        args.target = Some(ByteBuf::from(target.0.to_vec()));
+                    }
+                    Query::GetPeers { info_hash } => {
+                        args.info_hash = Some(ByteBuf::from(info_hash.as_bytes().to_vec()));
+                    }
+                    Query::AnnouncePeer {
+                        info_hash,
+                        port,
+                        implied_port,
+                        token,
+                    } => {
+                        args.info_hash = Some(ByteBuf::from(info_hash.as_bytes().to_vec()));
+                        args.port = Some(*port);
+                        args.implied_port = Some(*implied_port as u8);
+                        args.token = Some(ByteBuf::from(token.clone()));
+                    }
+                }
+                raw.a = Some(args);
+            }
+            Body::Response(response) => {
+                raw.y = "r".to_string();
+                let mut args = RawArguments {
+                    id: ByteBuf::from(response.id.0.to_vec()),
+                    token: response.token.clone().map(ByteBuf::from),
+                    ..Default::default()
+                };
+                let (v4, v6): (Vec<&NodeInfo>, Vec<&NodeInfo>) =
+                    response.nodes.iter().partition(|node| node.addr.is_ipv4());
+                if !v4.is_empty() {
+                    args.nodes = Some(ByteBuf::from(
+                        v4.iter()
+                            .flat_map(|node| node.to_compact())
+                            .collect::<Vec<u8>>(),
+                    ));
+                }
+                if !v6.is_empty() {
+                    args.nodes6 = Some(ByteBuf::from(
+                        v6.iter()
+                            .flat_map(|node| node.to_compact())
+                            .collect::<Vec<u8>>(),
+                    ));
+                }
+                if !response.values.is_empty() {
+                    args.values = Some(
+                        response
+                            .values
+                            .iter()
+                            .map(|peer| ByteBuf::from(encode_compact_peer(peer)))
+                            .collect(),
+                    );
+                }
+                raw.r = Some(args);
+            }
+            Body::Error { code, message } => {
+                raw.y = "e".to_string();
+                raw.e = Some((*code, ByteBuf::from(message.as_bytes().to_vec())));
+            }
+        }
+        serde_bencode::to_bytes(&raw).expect("KRPC messages always encode")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_bep5_examples() {
+        let ping = b"d1:ad2:id20:abcdefghij0123456789e1:q4:ping1:t2:aa1:y1:qe";
+        let message = Message::from_bytes(ping).unwrap();
+        let id = NodeId(*b"abcdefghij0123456789");
+        assert_eq!(message, Message::query(b"aa".to_vec(), id, Query::Ping));
+        assert_eq!(message.to_bytes(), ping.to_vec());
+
+        let announce = b"d1:ad2:id20:abcdefghij01234567899:info_hash20:mnopqrstuvwxyz1234564:porti6881e5:token8:aoeusnthe1:q13:announce_peer1:t2:aa1:y1:qe";
+        let message = Message::from_bytes(announce).unwrap();
+        assert_eq!(
+            message.body,
+            Body::Query {
+                id,
+                query: Query::AnnouncePeer {
+                    info_hash: InfoHash::new(*b"mnopqrstuvwxyz123456"),
+                    port: 6881,
+                    implied_port: false,
+                    token: b"aoeusnth".to_vec(),
+                }
+            }
+        );
+
+        let peers = b"d1:rd2:id20:abcdefghij01234567895:token8:aoeusnth6:valuesl6:axje.u6:idhtnmee1:t2:aa1:y1:re";
+        let message = Message::from_bytes(peers).unwrap();
+        let Body::Response(response) = &message.body else {
+            panic!("expected a response");
+        };
+        assert_eq!(response.token.as_deref(), Some(&b"aoeusnth"[..]));
+        assert_eq!(response.values.len(), 2);
+        assert_eq!(response.values[0], "97.120.106.101:11893".parse().unwrap());
+        assert_eq!(message.to_bytes(), peers.to_vec());
+
+        let error = b"d1:eli201e23:A Generic Error Ocurrede1:t2:aa1:y1:ee";
+        let message = Message::from_bytes(error).unwrap();
+        assert_eq!(
+            message,
+            Message::error(b"aa".to_vec(), 201, "A Generic Error Ocurred")
+        );
+        assert_eq!(message.to_bytes(), error.to_vec());
+    }
+
+    #[test]
+    fn test_nodes_roundtrip() {
+        let mut response = Response::new(NodeId([1; 20]));
+        response.nodes = vec![
+            NodeInfo {
+                id: NodeId([2; 20]),
+                addr: "10.0.0.1:6881".parse().unwrap(),
+            },
+            NodeInfo {
+                id: NodeId([3; 20]),
+                addr: "[2001:db8::1]:6881".parse().unwrap(),
+            },
+        ];
+        let message = Message::response(vec![0, 1], response);
+        assert_eq!(Message::from_bytes(&message.to_bytes()).unwrap(), message);
+
+        let unknown = b"d1:ad2:id20:abcdefghij0123456789e1:q4:vote1:t2:aa1:y1:qe";
+        assert!(matches!(
+            Message::from_bytes(unknown),
+            Err(DhtError::UnknownMethod { transaction, method })
+                if transaction == b"aa" && method == "vote"
+        ));
+        assert!(Message::from_bytes(b"d1:t2:aa1:y1:qe").is_err());
+    }
+}
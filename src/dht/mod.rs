@@ -0,0 +1,73 @@
+pub mod krpc;
+pub mod node;
+pub mod routing;
+
+use crate::db::NodeCache;
+use std::net::SocketAddr;
+use std::time::Duration;
+use thiserror::Error;
+
+pub use node::DhtNode;
+pub use routing::{NodeId, NodeInfo, RoutingTable};
+
+/// How often questionable nodes are pinged and stale peers dropped.
+pub const MAINTENANCE_INTERVAL: Duration = Duration::from_secs(60);
+/// How often the routing table is written to the node cache.
+pub const SAVE_INTERVAL: Duration = Duration::from_secs(10 * 60);
+
+#[derive(Debug, Error)]
+pub enum DhtError {
+    #[error("DHT socket error: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("Malformed KRPC message: {0}")]
+    Decode(String),
+    #[error("Unknown KRPC method {method}")]
+    UnknownMethod {
+        transaction: Vec<u8>,
+        method: String,
+    },
+    #[error("Query to {0} timed out")]
+    Timeout(SocketAddr),
+    #[error("Node replied with error {code}: {message}")]
+    Remote { code: i64, message: String },
+}
+
+/// Joins the DHT through the cached nodes and the bootstrap routers, then
+/// keeps the routing table healthy and saved for the next start.
+pub async fn run(node: DhtNode, cache: NodeCache, bootstrap: Vec<String>) {
+    let mut addrs: Vec<SocketAddr> = match cache.load_nodes() {
+        Ok(nodes) => nodes.into_iter().map(|node| node.addr).collect(),
+        Err(e) => {
+            tracing::warn!("Could not load the DHT node cache: {}", e);
+            Vec::new()
+        }
+    };
+    for host in &bootstrap {
+        match tokio::net::lookup_host(host.as_str()).await {
+            Ok(resolved) => addrs.extend(resolved),
+            Err(e) => tracing::debug!("Could not resolve DHT router {}: {}", host, e),
+        }
+    }
+    let count = node.bootstrap(&addrs).await;
+    tracing::info!("Joined the DHT with {} nodes", count);
+
+    let mut maintenance = tokio::time::interval(MAINTENANCE_INTERVAL);
+    let mut save = tokio::time::interval(SAVE_INTERVAL);
+    loop {
+        tokio::select! {
+            _ = maintenance.tick() => {
+                // lost every node, e.g. after being offline
+                if node.node_count() == 0 {
+                    node.bootstrap(&addrs).await;
+                } else {
+                    node.maintain().await;
+                }
+            }
+            _ = save.tick() => {
+                if let Err(e) = cache.save_nodes(&node.nodes()) {
+                    tracing::warn!("Could not save the DHT node cache: {}", e);
+                }
+            }
+        }
+    }
+}
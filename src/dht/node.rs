@@ -0,0 +1,663 @@
+use super::krpc::{Body, Message, Query, Response, ERROR_METHOD, ERROR_PROTOCOL};
+use super::routing::{NodeId, NodeInfo, RoutingTable, K};
+use super::DhtError;
+use crate::types::InfoHash;
+use futures::future::join_all;
+use hashbrown::{HashMap, HashSet};
+use rand::seq::IteratorRandom;
+use rand::Rng;
+use sha1::{Digest, Sha1};
+use std::net::{IpAddr, SocketAddr};
+use std::sync::{Arc, Mutex, MutexGuard, Weak};
+use std::time::{Duration, Instant};
+use tokio::net::UdpSocket;
+use tokio::sync::oneshot;
+use tokio::task::JoinHandle;
+
+pub const QUERY_TIMEOUT: Duration = Duration::from_secs(5);
+/// Queries a lookup keeps in flight at once.
+pub const ALPHA: usize = 3;
+/// Tokens handed out stay valid for one to two rotations.
+pub const TOKEN_ROTATION: Duration = Duration::from_secs(5 * 60);
+/// Announced peers are forgotten unless they announce again.
+pub const PEER_EXPIRY: Duration = Duration::from_secs(30 * 60);
+/// Most peers returned by one `get_peers`, to keep the reply in a datagram.
+pub const MAX_VALUES: usize = 50;
+pub const MAX_PEERS_PER_TORRENT: usize = 1000;
+/// Torrents we store announced peers for, the one announced to least
+/// recently makes room for a new one.
+pub const MAX_TORRENTS: usize = 2000;
+/// Announced peers stored over all torrents, the oldest makes room.
+pub const MAX_PEERS: usize = 20_000;
+const MAX_PACKET: usize = 4096;
+
+struct Pending {
+    addr: SocketAddr,
+    tx: oneshot::Sender<Result<Response, DhtError>>,
+}
+
+struct State {
+    table: RoutingTable,
+    /// Peers announced to us, with when they last announced.
+    peers: HashMap<InfoHash, HashMap<SocketAddr, Instant>>,
+    secret: [u8; 20],
+    previous_secret: [u8; 20],
+    rotated: Instant,
+    pending: HashMap<Vec<u8>, Pending>,
+    next_transaction: u16,
+}
+
+impl State {
+    fn rotate_secret(&mut self, now: Instant) {
+        if now.duration_since(self.rotated) >= TOKEN_ROTATION {
+            self.previous_secret = self.secret;
+            self.secret = rand::thread_rng().gen();
+            self.rotated = now;
+        }
+    }
+
+    fn valid_token(&self, token: &[u8], ip: IpAddr) -> bool {
+        token == make_token(&self.secret, ip) || token == make_token(&self.previous_secret, ip)
+    }
+
+    fn add_peer(&mut self, info_hash: InfoHash, addr: SocketAddr, now: Instant) {
+        match self.peers.get_mut(&info_hash) {
+            Some(peers) if peers.contains_key(&addr) => {
+                peers.insert(addr, now);
+                return;
+            }
+            Some(peers) if peers.len() >= MAX_PEERS_PER_TORRENT => return,
+            Some(_) => {}
+            None => {
+                if self.peers.len() >= MAX_TORRENTS {
+                    self.evict_torrent();
+                }
+            }
+        }
+        if self.peers.values().map(HashMap::len).sum::<usize>() >= MAX_PEERS {
+            self.evict_peer();
+        }
+        self.peers.entry(info_hash).or_default().insert(addr, now);
+    }
+
+    fn evict_torrent(&mut self) {
+        let oldest = self
+            .peers
+            .iter()
+            .min_by_key(|(_, peers)| peers.values().max().copied())
+            .map(|(info_hash, _)| *info_hash);
+        if let Some(info_hash) = oldest {
+            self.peers.remove(&info_hash);
+        }
+    }
+
+    fn evict_peer(&mut self) {
+        let oldest = self
+            .peers
+            .iter()
+            .flat_map(|(info_hash, peers)| {
+                peers
+                    .iter()
+                    .map(move |(addr, announced)| (*announced, *info_hash, *addr))
+            })
+            .min_by_key(|(announced, _, _)| *announced);
+        if let Some((_, info_hash, addr)) = oldest {
+            let peers = self.peers.get_mut(&info_hash).expect("peer was found");
+            peers.remove(&addr);
+            if peers.is_empty() {
+                self.peers.remove(&info_hash);
+            }
+        }
+    }
+
+    fn peers(&self, info_hash: &InfoHash, now: Instant) -> Vec<SocketAddr> {
+        let Some(peers) = self.peers.get(info_hash) else {
+            return Vec::new();
+        };
+        peers
+            .iter()
+            .filter(|(_, announced)| now.duration_since(**announced) < PEER_EXPIRY)
+            .map(|(addr, _)| *addr)
+            .choose_multiple(&mut rand::thread_rng(), MAX_VALUES)
+    }
+
+    fn expire_peers(&mut self, now: Instant) {
+        for peers in self.peers.values_mut() {
+            peers.retain(|_, announced| now.duration_since(*announced) < PEER_EXPIRY);
+        }
+        self.peers.retain(|_, peers| !peers.is_empty());
+    }
+}
+
+// a token proves the announcing node received our get_peers reply at its ip
+fn make_token(secret: &[u8; 20], ip: IpAddr) -> Vec<u8> {
+    let mut hasher = Sha1::new();
+    match ip {
+        IpAddr::V4(ip) => hasher.update(ip.octets()),
+        IpAddr::V6(ip) => hasher.update(ip.octets()),
+    }
+    hasher.update(secret);
+    hasher.finalize()[..8].to_vec()
+}
+
+struct Inner {
+    id: NodeId,
+    socket: Arc<UdpSocket>,
+    state: Mutex<State>,
+    receiver: Mutex<Option<JoinHandle<()>>>,
+}
+
+impl Drop for Inner {
+    fn drop(&mut self) {
+        if let Some(receiver) = self.receiver.lock().unwrap().take() {
+            receiver.abort();
+        }
+    }
+}
+
+/// The nodes closest to the target that answered, with the tokens they
+/// handed out, and any peers they knew.
+struct Lookup {
+    nodes: Vec<(NodeInfo, Option<Vec<u8>>)>,
+    peers: Vec<SocketAddr>,
+}
+
+/// A mainline DHT (BEP 5) node on one UDP socket. It answers other nodes'
+/// queries in the background for as long as a handle to it is alive.
+#[derive(Clone)]
+pub struct DhtNode {
+    inner: Arc<Inner>,
+}
+
+impl DhtNode {
+    pub async fn bind(addr: SocketAddr, id: NodeId) -> Result<Self, DhtError> {
+        let socket = Arc::new(UdpSocket::bind(addr).await?);
+        let now = Instant::now();
+        let mut rng = rand::thread_rng();
+        let inner = Arc::new(Inner {
+            id,
+            socket: socket.clone(),
+            state: Mutex::new(State {
+                table: RoutingTable::new(id),
+                peers: HashMap::new(),
+                secret: rng.gen(),
+                previous_secret: rng.gen(),
+                rotated: now,
+                pending: HashMap::new(),
+                next_transaction: rng.gen(),
+            }),
+            receiver: Mutex::new(None),
+        });
+        let receiver = tokio::spawn(receive(Arc::downgrade(&inner), socket));
+        *inner.receiver.lock().unwrap() = Some(receiver);
+        Ok(Self { inner })
+    }
+
+    pub fn id(&self) -> NodeId {
+        self.inner.id
+    }
+
+    pub fn local_addr(&self) -> Result<SocketAddr, DhtError> {
+        Ok(self.inner.socket.local_addr()?)
+    }
+
+    fn state(&self) -> MutexGuard<'_, State> {
+        self.inner.state.lock().unwrap()
+    }
+
+    pub fn node_count(&self) -> usize {
+        self.state().table.len()
+    }
+
+    /// Good nodes of the routing table, for the node cache.
+    pub fn nodes(&self) -> Vec<NodeInfo> {
+        self.state().table.nodes()
+    }
+
+    /// Pings a node, e.g. a cached one or one a peer told us about with a
+    /// `port` message. Nodes that answer join the routing table.
+    pub async fn ping(&self, addr: SocketAddr) -> Result<NodeId, DhtError> {
+        Ok(self.query(addr, Query::Ping).await?.id)
+    }
+
+    /// Pings the DHT node a peer announced with a `port` message
+    /// (`PeerEvent::Port`) in the background, so it can join the routing
+    /// table.
+    pub fn port_message(&self, ip: IpAddr, port: u16) {
+        if port == 0 {
+            return;
+        }
+        let node = self.clone();
+        let addr = SocketAddr::new(ip, port);
+        tokio::spawn(async move {
+            if let Err(e) = node.ping(addr).await {
+                tracing::trace!("Peer's DHT node {} did not answer: {}", addr, e);
+            }
+        });
+    }
+
+    /// Pings the given nodes and looks up our own id to fill the buckets
+    /// near us. Returns the size of the routing table.
+    pub async fn bootstrap(&self, addrs: &[SocketAddr]) -> usize {
+        join_all(addrs.iter().map(|addr| self.ping(*addr))).await;
+        self.find_node(self.inner.id).await;
+        self.node_count()
+    }
+
+    /// The nodes closest to `target` that answered, closest first.
+    pub async fn find_node(&self, target: NodeId) -> Vec<NodeInfo> {
+        self.lookup(target, None)
+            .await
+            .nodes
+            .into_iter()
+            .map(|(node, _)| node)
+            .collect()
+    }
+
+    pub async fn get_peers(&self, info_hash: InfoHash) -> Vec<SocketAddr> {
+        self.lookup(info_hash.into(), Some(info_hash)).await.peers
+    }
+
+    /// Looks up the torrent's peers and tells the closest nodes that we have
+    /// it too, listening on `port` or, if `None`, on the DHT socket's port.
+    /// Returns the peers found.
+    pub async fn announce(&self, info_hash: InfoHash, port: Option<u16>) -> Vec<SocketAddr> {
+        let lookup = self.lookup(info_hash.into(), Some(info_hash)).await;
+        let announces = lookup.nodes.iter().filter_map(|(node, token)| {
+            let query = Query::AnnouncePeer {
+                info_hash,
+                port: port.unwrap_or(0),
+                implied_port: port.is_none(),
+                token: token.clone()?,
+            };
+            Some(self.query(node.addr, query))
+        });
+        let accepted = join_all(announces)
+            .await
+            .iter()
+            .filter(|result| result.is_ok())
+            .count();
+        tracing::debug!("Announced {} to {} DHT nodes", info_hash, accepted);
+        lookup.peers
+    }
+
+    /// Pings questionable nodes so dead ones can be replaced, and drops
+    /// peers that stopped announcing.
+    pub async fn maintain(&self) {
+        let now = Instant::now();
+        let questionable = {
+            let mut state = self.state();
+            state.rotate_secret(now);
+            state.expire_peers(now);
+            state.table.questionable(now)
+        };
+        join_all(questionable.iter().map(|node| self.ping(node.addr))).await;
+    }
+
+    /// Sends a query and waits for the answer. Unanswered queries count
+    /// against the node in the routing table.
+    pub async fn query(&self, addr: SocketAddr, query: Query) -> Result<Response, DhtError> {
+        let (tx, rx) = oneshot::channel();
+        let transaction = {
+            let mut state = self.state();
+            let transaction = state.next_transaction.to_be_bytes().to_vec();
+            state.next_transaction = state.next_transaction.wrapping_add(1);
+            state
+                .pending
+                .insert(transaction.clone(), Pending { addr, tx });
+            transaction
+        };
+        let message = Message::query(transaction.clone(), self.inner.id, query);
+        if let Err(e) = self.inner.socket.send_to(&message.to_bytes(), addr).await {
+            self.state().pending.remove(&transaction);
+            return Err(e.into());
+        }
+        match tokio::time::timeout(QUERY_TIMEOUT, rx).await {
+            Ok(Ok(result)) => result,
+            _ => {
+                let mut state = self.state();
+                state.pending.remove(&transaction);
+                state.table.failed(&addr);
+                Err(DhtError::Timeout(addr))
+            }
+        }
+    }
+
+    // iterative Kademlia lookup: keep asking the closest nodes we know of
+    // that haven't been asked yet until the K closest have all answered
+    async fn lookup(&self, target: NodeId, info_hash: Option<InfoHash>) -> Lookup {
+        let query = match info_hash {
+            Some(info_hash) => Query::GetPeers { info_hash },
+            None => Query::FindNode { target },
+        };
+        let mut candidates = self.state().table.closest(&target, K);
+        let mut queried = HashSet::new();
+        let mut nodes = Vec::new();
+        let mut peers = Vec::new();
+        loop {
+            candidates.sort_by_key(|node| node.id.distance(&target));
+            let batch: Vec<NodeInfo> = candidates
+                .iter()
+                .take(K)
+                .filter(|node| !queried.contains(&node.addr))
+                .take(ALPHA)
+                .copied()
+                .collect();
+            if batch.is_empty() {
+                break;
+            }
+            queried.extend(batch.iter().map(|node| node.addr));
+            let results = join_all(
+                batch
+                    .iter()
+                    .map(|node| self.query(node.addr, query.clone())),
+            );
+            for (node, result) in batch.iter().zip(results.await) {
+                let response = match result {
+                    Ok(response) => response,
+                    Err(e) => {
+                        tracing::trace!("DHT lookup query to {} failed: {}", node.addr, e);
+                        candidates.retain(|candidate| candidate.addr != node.addr);
+                        continue;
+                    }
+                };
+                for found in response.nodes {
+                    let known = queried.contains(&found.addr)
+                        || candidates
+                            .iter()
+                            .any(|candidate| candidate.addr == found.addr);
+                    if found.id != self.inner.id && !known {
+                        candidates.push(found);
+                    }
+                }
+                for peer in response.values {
+                    if !peers.contains(&peer) {
+                        peers.push(peer);
+                    }
+                }
+                let answered = NodeInfo {
+                    id: response.id,
+                    addr: node.addr,
+                };
+                nodes.push((answered, response.token));
+            }
+        }
+        nodes.sort_by_key(|(node, _)| node.id.distance(&target));
+        nodes.truncate(K);
+        Lookup { nodes, peers }
+    }
+
+    async fn handle(&self, bytes: &[u8], from: SocketAddr) {
+        let message = match Message::from_bytes(bytes) {
+            Ok(message) => message,
+            Err(DhtError::UnknownMethod {
+                transaction,
+                method,
+            }) => {
+                tracing::trace!("Unknown DHT method {:?} from {}", method, from);
+                let reply = Message::error(transaction, ERROR_METHOD, "Method Unknown");
+                if let Err(e) = self.inner.socket.send_to(&reply.to_bytes(), from).await {
+                    tracing::debug!("Could not answer DHT node {}: {}", from, e);
+                }
+                return;
+            }
+            Err(e) => {
+                tracing::trace!("Dropping DHT message from {}: {}", from, e);
+                return;
+            }
+        };
+        match message.body {
+            Body::Query { id, query } => {
+                let reply = self.answer(message.transaction, id, query, from);
+                if let Err(e) = self.inner.socket.send_to(&reply.to_bytes(), from).await {
+                    tracing::debug!("Could not answer DHT node {}: {}", from, e);
+                }
+            }
+            Body::Response(response) => self.resolve(&message.transaction, from, Ok(response)),
+            Body::Error {
+                code,
+                message: text,
+            } => self.resolve(
+                &message.transaction,
+                from,
+                Err(DhtError::Remote {
+                    code,
+                    message: text,
+                }),
+            ),
+        }
+    }
+
+    fn answer(&self, transaction: Vec<u8>, id: NodeId, query: Query, from: SocketAddr) -> Message {
+        let now = Instant::now();
+        let mut state = self.state();
+        state.table.insert(NodeInfo { id, addr: from }, now);
+        state.rotate_secret(now);
+        let mut response = Response::new(self.inner.id);
+        match query {
+            Query::Ping => {}
+            Query::FindNode { target } => response.nodes = state.table.closest(&target, K),
+            Query::GetPeers { info_hash } => {
+                response.token = Some(make_token(&state.secret, from.ip()));
+                response.values = state.peers(&info_hash, now);
+                if response.values.is_empty() {
+                    response.nodes = state.table.closest(&info_hash.into(), K);
+                }
+            }
+            Query::AnnouncePeer {
+                info_hash,
+                port,
+                implied_port,
+                token,
+            } => {
+                if !state.valid_token(&token, from.ip()) {
+                    return Message::error(transaction, ERROR_PROTOCOL, "Bad token");
+                }
+                let port = if implied_port { from.port() } else { port };
+                state.add_peer(info_hash, SocketAddr::new(from.ip(), port), now);
+            }
+        }
+        Message::response(transaction, response)
+    }
+
+    fn resolve(&self, transaction: &[u8], from: SocketAddr, result: Result<Response, DhtError>) {
+        let mut state = self.state();
+        // only the node we asked can answer
+        if state
+            .pending
+            .get(transaction)
+            .is_none_or(|pending| pending.addr != from)
+        {
+            return;
+        }
+        let pending = state.pending.remove(transaction).unwrap();
+        if let Ok(response) = &result {
+            let node = NodeInfo {
+                id: response.id,
+                addr: from,
+            };
+            state.table.insert(node, Instant::now());
+        }
+        let _ = pending.tx.send(result);
+    }
+}
+
+// holds the node weakly so dropping the last handle closes the node
+async fn receive(inner: Weak<Inner>, socket: Arc<UdpSocket>) {
+    let mut buf = vec![0; MAX_PACKET];
+    loop {
+        let (len, from) = match socket.recv_from(&mut buf).await {
+            Ok(received) => received,
+            Err(e) => {
+                tracing::debug!("DHT receive failed: {}", e);
+                continue;
+            }
+        };
+        let Some(inner) = inner.upgrade() else {
+            break;
+        };
+        DhtNode { inner }.handle(&buf[..len], from).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn swarm(count: usize) -> Vec<DhtNode> {
+        let mut nodes = Vec::new();
+        for _ in 0..count {
+            let node = DhtNode::bind("127.0.0.1:0".parse().unwrap(), NodeId::random())
+                .await
+                .unwrap();
+            nodes.push(node);
+        }
+        let first = nodes[0].local_addr().unwrap();
+        for node in &nodes[1..] {
+            assert!(node.bootstrap(&[first]).await > 0);
+        }
+        nodes
+    }
+
+    #[tokio::test]
+    async fn test_find_node() {
+        let nodes = swarm(6).await;
+        // the first node heard from everyone that bootstrapped through it
+        assert_eq!(nodes[0].node_count(), 5);
+        let target = nodes[5].id();
+        let found = nodes[1].find_node(target).await;
+        assert_eq!(found[0].id, target);
+        assert_eq!(found[0].addr, nodes[5].local_addr().unwrap());
+        assert_eq!(
+            nodes[2].ping(nodes[3].local_addr().unwrap()).await.unwrap(),
+            nodes[3].id()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_announce_and_get_peers() {
+        let nodes = swarm(5).await;
+        let info_hash = InfoHash::new([7; 20]);
+        assert!(nodes[4].get_peers(info_hash).await.is_empty());
+
+        assert!(nodes[1].announce(info_hash, Some(6881)).await.is_empty());
+        let peers = nodes[3].get_peers(info_hash).await;
+        assert_eq!(peers, vec!["127.0.0.1:6881".parse().unwrap()]);
+
+        // implied port announces the DHT socket's own port
+        let found = nodes[2].announce(info_hash, None).await;
+        assert_eq!(found, peers);
+        let mut peers = nodes[4].get_peers(info_hash).await;
+        peers.sort();
+        let mut expected = vec![
+            "127.0.0.1:6881".parse().unwrap(),
+            nodes[2].local_addr().unwrap(),
+        ];
+        expected.sort();
+        assert_eq!(peers, expected);
+    }
+
+    #[tokio::test]
+    async fn test_tokens() {
+        let nodes = swarm(2).await;
+        let addr = nodes[0].local_addr().unwrap();
+        let info_hash = InfoHash::new([1; 20]);
+        let announce = |token: Vec<u8>| Query::AnnouncePeer {
+            info_hash,
+            port: 1,
+            implied_port: false,
+            token,
+        };
+        let forged = nodes[1].query(addr, announce(vec![0; 8])).await;
+        assert!(matches!(
+            forged,
+            Err(DhtError::Remote { code, .. }) if code == ERROR_PROTOCOL
+        ));
+
+        let token = nodes[1]
+            .query(addr, Query::GetPeers { info_hash })
+            .await
+            .unwrap()
+            .token
+            .unwrap();
+        // still good after one rotation, not after two
+        let now = Instant::now();
+        let mut state = nodes[0].state();
+        let ip: IpAddr = "127.0.0.1".parse().unwrap();
+        state.rotate_secret(now + TOKEN_ROTATION);
+        assert!(state.valid_token(&token, ip));
+        assert!(!state.valid_token(&token, "127.0.0.2".parse().unwrap()));
+        state.rotate_secret(now + TOKEN_ROTATION * 2);
+        assert!(!state.valid_token(&token, ip));
+    }
+
+    #[tokio::test]
+    async fn test_unknown_method() {
+        let nodes = swarm(2).await;
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let query = b"d1:ad2:id20:abcdefghij0123456789e1:q4:vote1:t2:xy1:y1:qe";
+        socket
+            .send_to(query, nodes[0].local_addr().unwrap())
+            .await
+            .unwrap();
+        let mut buf = vec![0; MAX_PACKET];
+        let (len, _) = tokio::time::timeout(QUERY_TIMEOUT, socket.recv_from(&mut buf))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(
+            Message::from_bytes(&buf[..len]).unwrap(),
+            Message::error(b"xy".to_vec(), ERROR_METHOD, "Method Unknown")
+        );
+
+        // a peer's port message brings its node into the routing table
+        let lone = DhtNode::bind("127.0.0.1:0".parse().unwrap(), NodeId::random())
+            .await
+            .unwrap();
+        let addr = nodes[1].local_addr().unwrap();
+        lone.port_message(addr.ip(), addr.port());
+        tokio::time::timeout(QUERY_TIMEOUT, async {
+            while lone.node_count() == 0 {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .unwrap();
+    }
+
+    #[test]
+    fn test_stored_peers_are_bounded() {
+        let now = Instant::now();
+        let mut state = State {
+            table: RoutingTable::new(NodeId::random()),
+            peers: HashMap::new(),
+            secret: [0; 20],
+            previous_secret: [0; 20],
+            rotated: now,
+            pending: HashMap::new(),
+            next_transaction: 0,
+        };
+        let addr = |i: usize| SocketAddr::from(((i as u32).to_be_bytes(), 6881));
+        let info_hash = |i: usize| {
+            let mut hash = [0; 20];
+            hash[..8].copy_from_slice(&(i as u64).to_be_bytes());
+            InfoHash::new(hash)
+        };
+        for i in 0..=MAX_TORRENTS {
+            state.add_peer(info_hash(i), addr(i), now + Duration::from_secs(i as u64));
+        }
+        assert_eq!(state.peers.len(), MAX_TORRENTS);
+        assert!(!state.peers.contains_key(&info_hash(0)));
+
+        let later = now + Duration::from_secs(MAX_TORRENTS as u64 + 1);
+        for i in 0..MAX_PEERS {
+            let torrent = MAX_TORRENTS - i % 20;
+            state.add_peer(info_hash(torrent), addr(MAX_TORRENTS + i), later);
+        }
+        let total: usize = state.peers.values().map(HashMap::len).sum();
+        assert_eq!(total, MAX_PEERS);
+        // the oldest announces went first
+        assert!(!state.peers.contains_key(&info_hash(1)));
+        assert!(state.peers[&info_hash(MAX_TORRENTS)].len() > 1);
+    }
+}
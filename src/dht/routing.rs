@@ -0,0 +1,339 @@
+use super::DhtError;
+use crate::types::InfoHash;
+use data_encoding::HEXLOWER;
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::time::{Duration, Instant};
+
+pub const ID_LEN: usize = 20;
+/// Nodes per bucket.
+pub const K: usize = 8;
+/// A node that failed this many queries in a row can be replaced.
+pub const MAX_FAILURES: u32 = 2;
+/// Nodes not heard from for this long are pinged before being trusted again.
+pub const QUESTIONABLE_AFTER: Duration = Duration::from_secs(15 * 60);
+
+/// 160-bit node id, compared by XOR distance.
+#[derive(Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize, Deserialize)]
+pub struct NodeId(pub [u8; ID_LEN]);
+
+impl NodeId {
+    pub fn random() -> Self {
+        NodeId(rand::thread_rng().gen())
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, DhtError> {
+        let id: [u8; ID_LEN] = bytes
+            .try_into()
+            .map_err(|_| DhtError::Decode(format!("node id of {} bytes", bytes.len())))?;
+        Ok(NodeId(id))
+    }
+
+    pub fn as_bytes(&self) -> &[u8; ID_LEN] {
+        &self.0
+    }
+
+    /// Compares lexicographically as a 160-bit number.
+    pub fn distance(&self, other: &NodeId) -> [u8; ID_LEN] {
+        let mut distance = [0; ID_LEN];
+        for (byte, (a, b)) in distance.iter_mut().zip(self.0.iter().zip(&other.0)) {
+            *byte = a ^ b;
+        }
+        distance
+    }
+
+    /// Number of leading bits shared with `other`, `None` for the same id.
+    pub fn common_prefix(&self, other: &NodeId) -> Option<usize> {
+        let distance = self.distance(other);
+        let byte = distance.iter().position(|byte| *byte != 0)?;
+        Some(byte * 8 + distance[byte].leading_zeros() as usize)
+    }
+}
+
+impl From<InfoHash> for NodeId {
+    fn from(info_hash: InfoHash) -> Self {
+        NodeId(*info_hash.as_bytes())
+    }
+}
+
+impl std::fmt::Debug for NodeId {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "NodeId({})", HEXLOWER.encode(&self.0))
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct NodeInfo {
+    pub id: NodeId,
+    pub addr: SocketAddr,
+}
+
+impl NodeInfo {
+    /// Parses the `nodes` (26 bytes each) or `nodes6` (38 bytes each) string
+    /// of a response.
+    pub fn parse_compact(bytes: &[u8], ipv6: bool) -> Result<Vec<NodeInfo>, DhtError> {
+        let ip_len = if ipv6 { 16 } else { 4 };
+        let len = ID_LEN + ip_len + 2;
+        if !bytes.len().is_multiple_of(len) {
+            return Err(DhtError::Decode(format!(
+                "compact nodes length {} is not a multiple of {}",
+                bytes.len(),
+                len
+            )));
+        }
+        Ok(bytes
+            .chunks_exact(len)
+            .map(|chunk| {
+                let (id, addr) = chunk.split_at(ID_LEN);
+                let (ip, port) = addr.split_at(ip_len);
+                let ip = if ipv6 {
+                    let ip: [u8; 16] = ip.try_into().unwrap();
+                    IpAddr::V6(Ipv6Addr::from(ip))
+                } else {
+                    let ip: [u8; 4] = ip.try_into().unwrap();
+                    IpAddr::V4(Ipv4Addr::from(ip))
+                };
+                NodeInfo {
+                    id: NodeId(id.try_into().unwrap()),
+                    addr: SocketAddr::new(ip, u16::from_be_bytes([port[0], port[1]])),
+                }
+            })
+            .collect())
+    }
+
+    /// The id followed by the compact address.
+    pub fn to_compact(&self) -> Vec<u8> {
+        let mut bytes = self.id.0.to_vec();
+        bytes.extend(crate::peer::tracker::encode_compact_peer(&self.addr));
+        bytes
+    }
+}
+
+#[derive(Debug, Clone)]
+struct Entry {
+    node: NodeInfo,
+    last_seen: Instant,
+    failures: u32,
+}
+
+impl Entry {
+    fn is_bad(&self) -> bool {
+        self.failures >= MAX_FAILURES
+    }
+}
+
+/// Kademlia routing table. Bucket `i` holds up to `K` nodes sharing exactly
+/// `i` leading bits with our id, so we know many nodes close to us and a
+/// few from every other part of the id space.
+#[derive(Debug, Clone)]
+pub struct RoutingTable {
+    own_id: NodeId,
+    buckets: Vec<Vec<Entry>>,
+}
+
+impl RoutingTable {
+    pub fn new(own_id: NodeId) -> Self {
+        Self {
+            own_id,
+            buckets: vec![Vec::new(); ID_LEN * 8],
+        }
+    }
+
+    pub fn own_id(&self) -> NodeId {
+        self.own_id
+    }
+
+    pub fn len(&self) -> usize {
+        self.buckets.iter().map(Vec::len).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.buckets.iter().all(Vec::is_empty)
+    }
+
+    /// Records that we heard from a node. A full bucket only takes the node
+    /// in place of a bad one, since long lived nodes are the most likely to
+    /// stay. Returns false if the node was dropped.
+    pub fn insert(&mut self, node: NodeInfo, now: Instant) -> bool {
+        let Some(index) = self.own_id.common_prefix(&node.id) else {
+            return false;
+        };
+        let bucket = &mut self.buckets[index];
+        if let Some(position) = bucket.iter().position(|entry| entry.node.id == node.id) {
+            // anyone can claim a known id, so a node only moves once the old
+            // address stopped answering
+            if bucket[position].node.addr != node.addr && !bucket[position].is_bad() {
+                return false;
+            }
+            let mut entry = bucket.remove(position);
+            entry.node.addr = node.addr;
+            entry.last_seen = now;
+            entry.failures = 0;
+            bucket.push(entry);
+            return true;
+        }
+        let entry = Entry {
+            node,
+            last_seen: now,
+            failures: 0,
+        };
+        if bucket.len() < K {
+            bucket.push(entry);
+            return true;
+        }
+        match bucket.iter().position(Entry::is_bad) {
+            Some(position) => {
+                bucket.remove(position);
+                bucket.push(entry);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// A query to `addr` went unanswered.
+    pub fn failed(&mut self, addr: &SocketAddr) {
+        for entry in self.buckets.iter_mut().flatten() {
+            if entry.node.addr == *addr {
+                entry.failures += 1;
+            }
+        }
+    }
+
+    pub fn remove(&mut self, id: &NodeId) -> bool {
+        let Some(index) = self.own_id.common_prefix(id) else {
+            return false;
+        };
+        let bucket = &mut self.buckets[index];
+        let before = bucket.len();
+        bucket.retain(|entry| entry.node.id != *id);
+        bucket.len() != before
+    }
+
+    pub fn contains(&self, id: &NodeId) -> bool {
+        self.own_id
+            .common_prefix(id)
+            .is_some_and(|index| self.buckets[index].iter().any(|e| e.node.id == *id))
+    }
+
+    /// Up to `count` good nodes closest to `target`, closest first.
+    pub fn closest(&self, target: &NodeId, count: usize) -> Vec<NodeInfo> {
+        let mut nodes: Vec<NodeInfo> = self
+            .buckets
+            .iter()
+            .flatten()
+            .filter(|entry| !entry.is_bad())
+            .map(|entry| entry.node)
+            .collect();
+        nodes.sort_by_key(|node| node.id.distance(target));
+        nodes.truncate(count);
+        nodes
+    }
+
+    /// Nodes to ping, either silent for a while or failing.
+    pub fn questionable(&self, now: Instant) -> Vec<NodeInfo> {
+        self.buckets
+            .iter()
+            .flatten()
+            .filter(|entry| {
+                entry.failures > 0 || now.duration_since(entry.last_seen) >= QUESTIONABLE_AFTER
+            })
+            .map(|entry| entry.node)
+            .collect()
+    }
+
+    /// Every good node, for the node cache.
+    pub fn nodes(&self) -> Vec<NodeInfo> {
+        self.buckets
+            .iter()
+            .flatten()
+            .filter(|entry| !entry.is_bad())
+            .map(|entry| entry.node)
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn node(first: u8, last: u8, port: u16) -> NodeInfo {
+        let mut id = [0; ID_LEN];
+        id[0] = first;
+        id[ID_LEN - 1] = last;
+        NodeInfo {
+            id: NodeId(id),
+            addr: SocketAddr::from(([127, 0, 0, 1], port)),
+        }
+    }
+
+    #[test]
+    fn test_compact_nodes() {
+        let v4 = node(1, 2, 6881);
+        let bytes = v4.to_compact();
+        assert_eq!(bytes.len(), 26);
+        assert_eq!(NodeInfo::parse_compact(&bytes, false).unwrap(), vec![v4]);
+        let v6 = NodeInfo {
+            id: NodeId([7; ID_LEN]),
+            addr: "[::1]:6881".parse().unwrap(),
+        };
+        let bytes = v6.to_compact();
+        assert_eq!(NodeInfo::parse_compact(&bytes, true).unwrap(), vec![v6]);
+        assert!(NodeInfo::parse_compact(&bytes[1..], true).is_err());
+    }
+
+    #[test]
+    fn test_buckets() {
+        let now = Instant::now();
+        let mut table = RoutingTable::new(NodeId([0; ID_LEN]));
+        assert!(!table.insert(node(0, 0, 1), now));
+        // ids starting with 0x80 all land in bucket 0
+        for port in 0..K as u16 {
+            assert!(table.insert(node(0x80, port as u8, port), now));
+        }
+        assert!(!table.insert(node(0x80, 100, 100), now));
+        assert_eq!(table.len(), K);
+        // a bad node makes room
+        let bad = node(0x80, 3, 3);
+        table.failed(&bad.addr);
+        table.failed(&bad.addr);
+        assert!(table.insert(node(0x80, 100, 100), now));
+        assert!(!table.contains(&bad.id));
+
+        let close = node(0, 1, 200);
+        table.insert(close, now);
+        let closest = table.closest(&NodeId([0; ID_LEN]), 2);
+        assert_eq!(closest[0], close);
+        assert_eq!(closest.len(), 2);
+        assert_eq!(closest[1].id.0[0], 0x80);
+
+        assert!(table.questionable(now).is_empty());
+        assert_eq!(
+            table.questionable(now + QUESTIONABLE_AFTER).len(),
+            table.len()
+        );
+        assert!(table.remove(&close.id));
+        assert_eq!(table.nodes().len(), K);
+    }
+
+    #[test]
+    fn test_spoofed_id() {
+        let now = Instant::now();
+        let mut table = RoutingTable::new(NodeId([0; ID_LEN]));
+        let honest = node(0x80, 1, 1);
+        table.insert(honest, now);
+        table.failed(&honest.addr);
+        let spoofed = NodeInfo {
+            addr: SocketAddr::from(([10, 0, 0, 1], 1)),
+            ..honest
+        };
+        assert!(!table.insert(spoofed, now));
+        assert_eq!(table.questionable(now), vec![honest]);
+
+        // once the old address is bad the node may move
+        table.failed(&honest.addr);
+        assert!(table.insert(spoofed, now));
+        assert_eq!(table.nodes(), vec![spoofed]);
+    }
+}
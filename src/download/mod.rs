@@ -0,0 +1,12 @@
+pub mod picker;
+pub mod pipeline;
+pub mod scheduler;
+pub mod verify;
+
+pub use picker::{PickMode, PiecePicker, Priority};
+pub use pipeline::RequestQueue;
+pub use scheduler::{BlockOutcome, BlockScheduler};
+pub use verify::{PieceVerifier, Verdict};
+
+/// Size of the blocks pieces are requested in.
+pub const BLOCK_LEN: u32 = 16 * 1024;
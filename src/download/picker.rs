@@ -0,0 +1,333 @@
+use crate::storage::FileLayout;
+use crate::types::Bitfield;
+use rand::seq::SliceRandom;
+use serde::{Deserialize, Serialize};
+
+#[derive(
+    Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize,
+)]
+pub enum Priority {
+    /// Don't download.
+    Skip,
+    Low,
+    #[default]
+    Normal,
+    High,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum PickMode {
+    #[default]
+    RarestFirst,
+    /// In piece order, for streaming.
+    Sequential,
+}
+
+/// Decides which piece to request next from availability counts and
+/// priorities, independent of any connection.
+#[derive(Debug, Clone)]
+pub struct PiecePicker {
+    availability: Vec<u32>,
+    have: Bitfield,
+    in_progress: Bitfield,
+    priorities: Vec<Priority>,
+    file_priorities: Vec<Priority>,
+    mode: PickMode,
+}
+
+impl PiecePicker {
+    pub fn new(piece_count: usize) -> Self {
+        Self {
+            availability: vec![0; piece_count],
+            have: Bitfield::new(piece_count),
+            in_progress: Bitfield::new(piece_count),
+            priorities: vec![Priority::Normal; piece_count],
+            file_priorities: Vec::new(),
+            mode: PickMode::RarestFirst,
+        }
+    }
+
+    /// Starts from the pieces we already have, e.g. after resuming.
+    pub fn with_have(have: Bitfield) -> Self {
+        let mut picker = Self::new(have.len());
+        picker.have = have;
+        picker
+    }
+
+    pub fn piece_count(&self) -> usize {
+        self.availability.len()
+    }
+
+    pub fn mode(&self) -> PickMode {
+        self.mode
+    }
+
+    pub fn set_mode(&mut self, mode: PickMode) {
+        self.mode = mode;
+    }
+
+    pub fn have(&self) -> &Bitfield {
+        &self.have
+    }
+
+    pub fn availability(&self, index: usize) -> u32 {
+        self.availability.get(index).copied().unwrap_or(0)
+    }
+
+    pub fn priority(&self, index: usize) -> Priority {
+        self.priorities
+            .get(index)
+            .copied()
+            .unwrap_or(Priority::Skip)
+    }
+
+    pub fn add_bitfield(&mut self, bitfield: &Bitfield) {
+        for index in bitfield.iter_set() {
+            self.add_have(index);
+        }
+    }
+
+    /// Forgets a disconnected peer's pieces.
+    pub fn remove_bitfield(&mut self, bitfield: &Bitfield) {
+        for index in bitfield.iter_set() {
+            if let Some(count) = self.availability.get_mut(index) {
+                *count = count.saturating_sub(1);
+            }
+        }
+    }
+
+    pub fn add_have(&mut self, index: usize) {
+        if let Some(count) = self.availability.get_mut(index) {
+            *count += 1;
+        }
+    }
+
+    /// Per-file priorities, empty until one was set.
+    pub fn file_priorities(&self) -> &[Priority] {
+        &self.file_priorities
+    }
+
+    pub fn set_piece_priority(&mut self, index: usize, priority: Priority) {
+        if let Some(current) = self.priorities.get_mut(index) {
+            *current = priority;
+        }
+    }
+
+    /// Sets the priority of a file. A piece shared by several files gets the
+    /// highest priority among them.
+    pub fn set_file_priority(
+        &mut self,
+        layout: &FileLayout,
+        file_index: usize,
+        priority: Priority,
+    ) {
+        if self.file_priorities.len() != layout.files.len() {
+            self.file_priorities = vec![Priority::Normal; layout.files.len()];
+        }
+        if file_index >= self.file_priorities.len() {
+            return;
+        }
+        self.file_priorities[file_index] = priority;
+        let mut priorities = vec![Priority::Skip; self.piece_count()];
+        for (file_index, priority) in self.file_priorities.iter().enumerate() {
+            for index in layout.file_pieces(file_index).unwrap_or(0..0) {
+                if let Some(current) = priorities.get_mut(index) {
+                    *current = (*current).max(*priority);
+                }
+            }
+        }
+        self.priorities = priorities;
+    }
+
+    fn wanted(&self, index: usize) -> bool {
+        !self.have.has(index) && self.priorities[index] != Priority::Skip
+    }
+
+    /// Next piece to download from a peer with `peer` pieces, which is then
+    /// marked in progress. Pieces already in progress are finished first so
+    /// partial pieces don't pile up.
+    pub fn pick(&mut self, peer: &Bitfield) -> Option<usize> {
+        if let Some(index) = self.best(&self.candidates(peer, true)) {
+            return Some(index);
+        }
+        self.pick_new(peer)
+    }
+
+    /// Like [`PiecePicker::pick`] but only starts pieces nobody works on yet.
+    pub fn pick_new(&mut self, peer: &Bitfield) -> Option<usize> {
+        let index = self.best(&self.candidates(peer, false))?;
+        self.in_progress.set(index);
+        Some(index)
+    }
+
+    fn candidates(&self, peer: &Bitfield, in_progress: bool) -> Vec<usize> {
+        (0..self.piece_count())
+            .filter(|index| {
+                self.wanted(*index)
+                    && peer.has(*index)
+                    && self.in_progress.has(*index) == in_progress
+            })
+            .collect()
+    }
+
+    fn best(&self, candidates: &[usize]) -> Option<usize> {
+        let priority = candidates
+            .iter()
+            .map(|index| self.priorities[*index])
+            .max()?;
+        let candidates: Vec<usize> = candidates
+            .iter()
+            .copied()
+            .filter(|index| self.priorities[*index] == priority)
+            .collect();
+        match self.mode {
+            PickMode::Sequential => candidates.first().copied(),
+            PickMode::RarestFirst => {
+                let rarest = candidates
+                    .iter()
+                    .map(|index| self.availability[*index])
+                    .min()?;
+                let rarest: Vec<usize> = candidates
+                    .into_iter()
+                    .filter(|index| self.availability[*index] == rarest)
+                    .collect();
+                // random tie-breaking spreads peers over different pieces
+                rarest.choose(&mut rand::thread_rng()).copied()
+            }
+        }
+    }
+
+    pub fn is_in_progress(&self, index: usize) -> bool {
+        self.in_progress.has(index)
+    }
+
+    /// Puts a piece back, e.g. after it failed verification or every peer
+    /// working on it went away.
+    pub fn abort(&mut self, index: usize) {
+        self.in_progress.unset(index);
+    }
+
+    /// Records a downloaded and verified piece.
+    pub fn mark_have(&mut self, index: usize) {
+        self.in_progress.unset(index);
+        self.have.set(index);
+    }
+
+    /// Whether a peer has anything we still want.
+    pub fn is_interesting(&self, peer: &Bitfield) -> bool {
+        peer.iter_set().any(|index| self.wanted(index))
+    }
+
+    /// Whether some wanted piece hasn't been started yet.
+    pub fn has_unstarted(&self) -> bool {
+        (0..self.piece_count()).any(|index| self.wanted(index) && !self.in_progress.has(index))
+    }
+
+    /// All wanted pieces are downloaded, skipped pieces aside.
+    pub fn is_complete(&self) -> bool {
+        (0..self.piece_count()).all(|index| !self.wanted(index))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::metainfo::{FileEntry, Info};
+    use serde_bytes::ByteBuf;
+
+    fn bitfield(len: usize, pieces: &[usize]) -> Bitfield {
+        let mut bitfield = Bitfield::new(len);
+        for index in pieces {
+            bitfield.set(*index);
+        }
+        bitfield
+    }
+
+    #[test]
+    fn test_rarest_first() {
+        let mut picker = PiecePicker::new(4);
+        picker.add_bitfield(&Bitfield::full(4));
+        picker.add_bitfield(&bitfield(4, &[0, 1, 3]));
+        picker.add_have(0);
+        // availability is 3, 2, 1, 2
+        let seed = Bitfield::full(4);
+        assert_eq!(picker.pick(&seed), Some(2));
+        // the started piece is finished before rarer new ones
+        assert_eq!(picker.pick(&seed), Some(2));
+        picker.mark_have(2);
+        let tie = picker.pick(&seed).unwrap();
+        assert!(tie == 1 || tie == 3);
+        picker.mark_have(tie);
+        assert_eq!(picker.pick(&seed), Some(4 - tie));
+        picker.abort(4 - tie);
+        assert!(!picker.is_in_progress(4 - tie));
+
+        picker.remove_bitfield(&Bitfield::full(4));
+        assert_eq!(picker.availability(0), 2);
+        // a peer with nothing we want
+        assert_eq!(picker.pick(&bitfield(4, &[2])), None);
+        assert!(!picker.is_interesting(&bitfield(4, &[2])));
+    }
+
+    #[test]
+    fn test_random_tie_breaking() {
+        let mut seen = std::collections::HashSet::new();
+        for _ in 0..200 {
+            let mut picker = PiecePicker::new(8);
+            picker.add_bitfield(&Bitfield::full(8));
+            seen.insert(picker.pick(&Bitfield::full(8)).unwrap());
+        }
+        assert!(seen.len() > 1);
+    }
+
+    #[test]
+    fn test_sequential_and_priorities() {
+        let mut picker = PiecePicker::with_have(bitfield(5, &[0]));
+        picker.set_mode(PickMode::Sequential);
+        picker.add_bitfield(&Bitfield::full(5));
+        picker.add_have(4);
+        let seed = Bitfield::full(5);
+        assert_eq!(picker.pick(&seed), Some(1));
+        picker.mark_have(1);
+        picker.set_piece_priority(4, Priority::High);
+        picker.set_piece_priority(2, Priority::Skip);
+        assert_eq!(picker.pick(&seed), Some(4));
+        picker.mark_have(4);
+        assert_eq!(picker.pick(&seed), Some(3));
+        picker.mark_have(3);
+        assert!(picker.is_complete());
+        assert_eq!(picker.pick(&seed), None);
+    }
+
+    #[test]
+    fn test_file_priorities() {
+        let info = Info {
+            name: "pack".to_string(),
+            piece_length: 10,
+            pieces: ByteBuf::from(vec![0; 20 * 4]),
+            length: None,
+            files: Some(vec![
+                FileEntry {
+                    length: 15,
+                    path: vec!["a".to_string()],
+                },
+                FileEntry {
+                    length: 25,
+                    path: vec!["b".to_string()],
+                },
+            ]),
+            private: None,
+        };
+        let layout = FileLayout::new(&info, std::path::Path::new("/tmp")).unwrap();
+        let mut picker = PiecePicker::new(4);
+        picker.set_file_priority(&layout, 0, Priority::Skip);
+        assert_eq!(picker.priority(0), Priority::Skip);
+        // piece 1 is shared with the second file
+        assert_eq!(picker.priority(1), Priority::Normal);
+        picker.set_file_priority(&layout, 1, Priority::Skip);
+        assert!(picker.is_complete());
+        picker.set_file_priority(&layout, 0, Priority::High);
+        assert_eq!(picker.priority(1), Priority::High);
+        assert_eq!(picker.priority(2), Priority::Skip);
+    }
+}
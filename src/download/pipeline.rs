@@ -0,0 +1,172 @@
+use super::BLOCK_LEN;
+use crate::peer::wire::BlockInfo;
+use hashbrown::HashMap;
+use std::time::{Duration, Instant};
+
+pub const MIN_DEPTH: usize = 2;
+pub const MAX_DEPTH: usize = 250;
+const INITIAL_DEPTH: usize = 4;
+
+// weight of the newest sample in the throughput average
+const SMOOTHING: f64 = 0.25;
+
+/// Blocks requested from one peer and not yet received. The queue depth
+/// follows the bandwidth-delay product of the connection so the pipe stays
+/// full without piling requests onto a slow peer.
+#[derive(Debug, Clone)]
+pub struct RequestQueue {
+    outstanding: HashMap<BlockInfo, Instant>,
+    depth: usize,
+    // bytes per second
+    rate: Option<f64>,
+    // lowest request to block time seen, i.e. latency without queueing
+    round_trip: Option<Duration>,
+    last_block: Option<Instant>,
+}
+
+impl Default for RequestQueue {
+    fn default() -> Self {
+        Self {
+            outstanding: HashMap::new(),
+            depth: INITIAL_DEPTH,
+            rate: None,
+            round_trip: None,
+            last_block: None,
+        }
+    }
+}
+
+impl RequestQueue {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn depth(&self) -> usize {
+        self.depth
+    }
+
+    pub fn len(&self) -> usize {
+        self.outstanding.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.outstanding.is_empty()
+    }
+
+    /// How many more blocks can be requested right now.
+    pub fn free_slots(&self) -> usize {
+        self.depth.saturating_sub(self.outstanding.len())
+    }
+
+    pub fn contains(&self, block: &BlockInfo) -> bool {
+        self.outstanding.contains_key(block)
+    }
+
+    /// Estimated download rate in bytes per second.
+    pub fn rate(&self) -> Option<f64> {
+        self.rate
+    }
+
+    pub fn round_trip(&self) -> Option<Duration> {
+        self.round_trip
+    }
+
+    pub fn push(&mut self, block: BlockInfo, now: Instant) {
+        self.outstanding.insert(block, now);
+    }
+
+    /// Drops a request without a measurement, e.g. after a cancel or timeout.
+    pub fn remove(&mut self, block: &BlockInfo) -> bool {
+        self.outstanding.remove(block).is_some()
+    }
+
+    /// Forgets every outstanding request, e.g. when the peer chokes us.
+    pub fn clear(&mut self) -> Vec<BlockInfo> {
+        self.outstanding.drain().map(|(block, _)| block).collect()
+    }
+
+    /// Records an arrived block and adapts the depth. Returns false for a
+    /// block that wasn't outstanding.
+    pub fn complete(&mut self, block: &BlockInfo, now: Instant) -> bool {
+        let Some(requested) = self.outstanding.remove(block) else {
+            return false;
+        };
+        let round_trip = now.saturating_duration_since(requested);
+        self.round_trip = Some(match self.round_trip {
+            Some(current) => current.min(round_trip),
+            None => round_trip,
+        });
+        // with a full pipe blocks arrive back to back, so measure from the
+        // previous block rather than from the request
+        let since = match self.last_block {
+            Some(last) if last > requested => last,
+            _ => requested,
+        };
+        let elapsed = now
+            .saturating_duration_since(since)
+            .max(Duration::from_millis(1));
+        let sample = block.length as f64 / elapsed.as_secs_f64();
+        self.rate = Some(match self.rate {
+            Some(rate) => rate + SMOOTHING * (sample - rate),
+            None => sample,
+        });
+        self.last_block = Some(now);
+        self.update_depth();
+        true
+    }
+
+    fn update_depth(&mut self) {
+        let (Some(rate), Some(round_trip)) = (self.rate, self.round_trip) else {
+            return;
+        };
+        let in_flight = rate * round_trip.as_secs_f64() / BLOCK_LEN as f64;
+        // twice the bandwidth-delay product leaves room for the rate to grow
+        self.depth = ((2.0 * in_flight).ceil() as usize).clamp(MIN_DEPTH, MAX_DEPTH);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn block(begin: u32) -> BlockInfo {
+        BlockInfo {
+            index: 0,
+            begin,
+            length: BLOCK_LEN,
+        }
+    }
+
+    #[test]
+    fn test_depth_follows_bandwidth_delay() {
+        let start = Instant::now();
+        let mut queue = RequestQueue::new();
+        assert_eq!(queue.free_slots(), INITIAL_DEPTH);
+        assert!(!queue.complete(&block(0), start));
+
+        // 100 ms round trip, one block every 10 ms: 10 blocks in flight
+        for i in 0..100 {
+            let requested = start + Duration::from_millis(10 * i);
+            queue.push(block(i as u32), requested);
+            assert!(queue.complete(&block(i as u32), requested + Duration::from_millis(100)));
+        }
+        assert_eq!(queue.round_trip(), Some(Duration::from_millis(100)));
+        assert!((19..=21).contains(&queue.depth()));
+
+        // the same peer slowed to a block per second
+        let slow = start + Duration::from_secs(10);
+        for i in 0..20 {
+            let requested = slow + Duration::from_secs(i);
+            queue.push(block(i as u32), requested);
+            queue.complete(&block(i as u32), requested + Duration::from_secs(1));
+        }
+        assert_eq!(queue.depth(), MIN_DEPTH);
+
+        queue.push(block(1), slow);
+        queue.push(block(2), slow);
+        assert_eq!(queue.free_slots(), 0);
+        assert!(queue.remove(&block(1)));
+        assert_eq!(queue.clear(), vec![block(2)]);
+        assert!(queue.is_empty());
+    }
+}
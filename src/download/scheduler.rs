@@ -0,0 +1,439 @@
+use super::picker::PiecePicker;
+use super::pipeline::RequestQueue;
+use super::BLOCK_LEN;
+use crate::peer::connection::PeerKey;
+use crate::peer::wire::BlockInfo;
+use crate::types::Bitfield;
+use hashbrown::HashMap;
+use std::collections::BTreeMap;
+use std::time::Instant;
+
+/// The 16 KiB blocks of a piece, the last one possibly shorter.
+pub fn piece_blocks(index: u32, piece_len: u64) -> Vec<BlockInfo> {
+    (0..piece_len)
+        .step_by(BLOCK_LEN as usize)
+        .map(|begin| BlockInfo {
+            index,
+            begin: begin as u32,
+            length: (piece_len - begin).min(BLOCK_LEN as u64) as u32,
+        })
+        .collect()
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum BlockState {
+    Missing,
+    /// Requested from these peers, more than one only in endgame.
+    Requested(Vec<PeerKey>),
+    /// Received from this peer.
+    Received(PeerKey),
+}
+
+#[derive(Debug)]
+struct PartialPiece {
+    blocks: Vec<BlockInfo>,
+    states: Vec<BlockState>,
+}
+
+impl PartialPiece {
+    fn new(index: u32, piece_len: u64) -> Self {
+        let blocks = piece_blocks(index, piece_len);
+        let states = vec![BlockState::Missing; blocks.len()];
+        Self { blocks, states }
+    }
+
+    fn position(&self, block: &BlockInfo) -> Option<usize> {
+        let position = (block.begin / BLOCK_LEN) as usize;
+        (self.blocks.get(position)? == block).then_some(position)
+    }
+
+    fn is_complete(&self) -> bool {
+        self.states
+            .iter()
+            .all(|state| matches!(state, BlockState::Received(_)))
+    }
+
+    fn contributors(&self) -> Vec<PeerKey> {
+        let mut peers: Vec<PeerKey> = self
+            .states
+            .iter()
+            .filter_map(|state| match state {
+                BlockState::Received(peer) => Some(*peer),
+                _ => None,
+            })
+            .collect();
+        peers.sort_unstable();
+        peers.dedup();
+        peers
+    }
+}
+
+#[derive(Debug)]
+struct PeerDownload {
+    bitfield: Bitfield,
+    queue: RequestQueue,
+    choked: bool,
+}
+
+/// What became of an arrived block.
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct BlockOutcome {
+    /// Endgame duplicates of the block still requested from other peers.
+    pub cancels: Vec<(PeerKey, BlockInfo)>,
+    /// Every block of this piece has arrived and it can be verified.
+    pub completed: Option<u32>,
+}
+
+/// Splits pieces into blocks and decides which blocks to request from which
+/// peer. Once every missing block is requested the scheduler enters endgame
+/// and hands out the remaining blocks to several peers at once, so the last
+/// pieces don't wait on the slowest peer.
+#[derive(Debug)]
+pub struct BlockScheduler {
+    picker: PiecePicker,
+    piece_length: u64,
+    total_length: u64,
+    pieces: BTreeMap<u32, PartialPiece>,
+    peers: HashMap<PeerKey, PeerDownload>,
+}
+
+impl BlockScheduler {
+    pub fn new(picker: PiecePicker, piece_length: u64, total_length: u64) -> Self {
+        Self {
+            picker,
+            piece_length,
+            total_length,
+            pieces: BTreeMap::new(),
+            peers: HashMap::new(),
+        }
+    }
+
+    pub fn picker(&self) -> &PiecePicker {
+        &self.picker
+    }
+
+    /// For changing priorities and the pick mode.
+    pub fn picker_mut(&mut self) -> &mut PiecePicker {
+        &mut self.picker
+    }
+
+    fn piece_len(&self, index: u32) -> u64 {
+        let start = index as u64 * self.piece_length;
+        (start + self.piece_length).min(self.total_length) - start
+    }
+
+    pub fn add_peer(&mut self, peer: PeerKey, bitfield: Bitfield) {
+        self.remove_peer(&peer);
+        self.picker.add_bitfield(&bitfield);
+        self.peers.insert(
+            peer,
+            PeerDownload {
+                bitfield,
+                queue: RequestQueue::new(),
+                choked: true,
+            },
+        );
+    }
+
+    /// Releases the peer's requests so other peers can pick them up.
+    pub fn remove_peer(&mut self, peer: &PeerKey) {
+        if let Some(mut download) = self.peers.remove(peer) {
+            self.picker.remove_bitfield(&download.bitfield);
+            for block in download.queue.clear() {
+                self.release(peer, &block);
+            }
+        }
+    }
+
+    pub fn peer_have(&mut self, peer: &PeerKey, index: u32) {
+        if let Some(download) = self.peers.get_mut(peer) {
+            if !download.bitfield.has(index as usize) && download.bitfield.set(index as usize) {
+                self.picker.add_have(index as usize);
+            }
+        }
+    }
+
+    pub fn peer_unchoked(&mut self, peer: &PeerKey) {
+        if let Some(download) = self.peers.get_mut(peer) {
+            download.choked = false;
+        }
+    }
+
+    /// A choke discards the peer's pending requests.
+    pub fn peer_choked(&mut self, peer: &PeerKey) {
+        let Some(download) = self.peers.get_mut(peer) else {
+            return;
+        };
+        download.choked = true;
+        for block in download.queue.clear() {
+            self.release(peer, &block);
+        }
+    }
+
+    pub fn request_timed_out(&mut self, peer: &PeerKey, block: &BlockInfo) {
+        if let Some(download) = self.peers.get_mut(peer) {
+            if download.queue.remove(block) {
+                self.release(peer, block);
+            }
+        }
+    }
+
+    fn release(&mut self, peer: &PeerKey, block: &BlockInfo) {
+        let Some(piece) = self.pieces.get_mut(&block.index) else {
+            return;
+        };
+        let Some(position) = piece.position(block) else {
+            return;
+        };
+        if let BlockState::Requested(peers) = &mut piece.states[position] {
+            peers.retain(|requester| requester != peer);
+            if peers.is_empty() {
+                piece.states[position] = BlockState::Missing;
+            }
+        }
+    }
+
+    pub fn is_interesting(&self, peer: &PeerKey) -> bool {
+        self.peers
+            .get(peer)
+            .is_some_and(|download| self.picker.is_interesting(&download.bitfield))
+    }
+
+    /// Every wanted block is either received or requested.
+    pub fn is_endgame(&self) -> bool {
+        !self.picker.has_unstarted()
+            && self
+                .pieces
+                .values()
+                .flat_map(|piece| piece.states.iter())
+                .all(|state| *state != BlockState::Missing)
+    }
+
+    /// Blocks to request from `peer` now, filling its queue up to the
+    /// current depth. Blocks of started pieces come first.
+    pub fn requests(&mut self, peer: &PeerKey, now: Instant) -> Vec<BlockInfo> {
+        let Some(download) = self.peers.get(peer) else {
+            return Vec::new();
+        };
+        if download.choked {
+            return Vec::new();
+        }
+        let mut slots = download.queue.free_slots();
+        let bitfield = download.bitfield.clone();
+        let mut picked = Vec::new();
+
+        for (index, piece) in self.pieces.iter_mut() {
+            if slots == 0 {
+                break;
+            }
+            if !bitfield.has(*index as usize) {
+                continue;
+            }
+            take_blocks(piece, peer, &mut slots, &mut picked, |state| {
+                *state == BlockState::Missing
+            });
+        }
+        while slots > 0 {
+            let Some(index) = self.picker.pick_new(&bitfield) else {
+                break;
+            };
+            let index = index as u32;
+            let mut piece = PartialPiece::new(index, self.piece_len(index));
+            take_blocks(&mut piece, peer, &mut slots, &mut picked, |state| {
+                *state == BlockState::Missing
+            });
+            self.pieces.insert(index, piece);
+        }
+        if slots > 0 && self.is_endgame() {
+            for (index, piece) in self.pieces.iter_mut() {
+                if slots == 0 {
+                    break;
+                }
+                if !bitfield.has(*index as usize) {
+                    continue;
+                }
+                take_blocks(
+                    piece,
+                    peer,
+                    &mut slots,
+                    &mut picked,
+                    |state| matches!(state, BlockState::Requested(peers) if !peers.contains(peer)),
+                );
+            }
+        }
+
+        let download = self.peers.get_mut(peer).expect("peer checked above");
+        for block in &picked {
+            download.queue.push(*block, now);
+        }
+        picked
+    }
+
+    /// Records a block from `peer`. Returns `None` for blocks that aren't
+    /// needed, such as the slower copy of an endgame request.
+    pub fn block_received(
+        &mut self,
+        peer: &PeerKey,
+        block: &BlockInfo,
+        now: Instant,
+    ) -> Option<BlockOutcome> {
+        if let Some(download) = self.peers.get_mut(peer) {
+            download.queue.complete(block, now);
+        }
+        let piece = self.pieces.get_mut(&block.index)?;
+        let position = piece.position(block)?;
+        let requesters = match &piece.states[position] {
+            BlockState::Received(_) => return None,
+            BlockState::Missing => Vec::new(),
+            BlockState::Requested(peers) => peers.clone(),
+        };
+        piece.states[position] = BlockState::Received(*peer);
+        let mut outcome = BlockOutcome::default();
+        if piece.is_complete() {
+            outcome.completed = Some(block.index);
+        }
+        for other in requesters.into_iter().filter(|other| other != peer) {
+            if let Some(download) = self.peers.get_mut(&other) {
+                download.queue.remove(block);
+            }
+            outcome.cancels.push((other, *block));
+        }
+        Some(outcome)
+    }
+
+    /// The completed piece passed its hash check. Returns the peers that
+    /// sent its blocks.
+    pub fn piece_verified(&mut self, index: u32) -> Vec<PeerKey> {
+        self.picker.mark_have(index as usize);
+        self.pieces
+            .remove(&index)
+            .map(|piece| piece.contributors())
+            .unwrap_or_default()
+    }
+
+    /// The completed piece failed its hash check and is downloaded again.
+    /// Returns the peers that sent its blocks, one of them sent bad data.
+    pub fn piece_failed(&mut self, index: u32) -> Vec<PeerKey> {
+        let Some(piece) = self.pieces.get_mut(&index) else {
+            return Vec::new();
+        };
+        let contributors = piece.contributors();
+        piece.states.fill(BlockState::Missing);
+        contributors
+    }
+}
+
+fn take_blocks(
+    piece: &mut PartialPiece,
+    peer: &PeerKey,
+    slots: &mut usize,
+    picked: &mut Vec<BlockInfo>,
+    wanted: impl Fn(&BlockState) -> bool,
+) {
+    for (block, state) in piece.blocks.iter().zip(piece.states.iter_mut()) {
+        if *slots == 0 {
+            return;
+        }
+        if !wanted(state) {
+            continue;
+        }
+        match state {
+            BlockState::Requested(peers) => peers.push(*peer),
+            _ => *state = BlockState::Requested(vec![*peer]),
+        }
+        picked.push(*block);
+        *slots -= 1;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::download::PickMode;
+
+    const A: PeerKey = [1; 20];
+    const B: PeerKey = [2; 20];
+
+    // three pieces of two blocks, the last piece a single short block
+    fn scheduler() -> BlockScheduler {
+        let mut picker = PiecePicker::new(3);
+        picker.set_mode(PickMode::Sequential);
+        BlockScheduler::new(picker, 2 * BLOCK_LEN as u64, 4 * BLOCK_LEN as u64 + 100)
+    }
+
+    #[test]
+    fn test_piece_blocks() {
+        let blocks = piece_blocks(3, 2 * BLOCK_LEN as u64 + 10);
+        assert_eq!(blocks.len(), 3);
+        assert_eq!(blocks[1].begin, BLOCK_LEN);
+        assert_eq!(blocks[2].length, 10);
+        assert!(piece_blocks(0, 0).is_empty());
+    }
+
+    #[test]
+    fn test_requests_and_choke() {
+        let now = Instant::now();
+        let mut scheduler = scheduler();
+        scheduler.add_peer(A, Bitfield::full(3));
+        assert!(scheduler.is_interesting(&A));
+        assert!(scheduler.requests(&A, now).is_empty());
+        scheduler.peer_unchoked(&A);
+        // the initial depth of 4 spans two pieces
+        let requested = scheduler.requests(&A, now);
+        assert_eq!(requested.len(), 4);
+        assert_eq!(requested[0], piece_blocks(0, 2 * BLOCK_LEN as u64)[0]);
+        assert_eq!(requested[3].index, 1);
+        assert!(scheduler.requests(&A, now).is_empty());
+
+        // a choke hands the blocks to the next peer
+        scheduler.peer_choked(&A);
+        scheduler.add_peer(B, Bitfield::full(3));
+        scheduler.peer_unchoked(&B);
+        assert_eq!(scheduler.requests(&B, now), requested);
+
+        scheduler.peer_have(&A, 2);
+        scheduler.remove_peer(&A);
+        assert_eq!(scheduler.picker().availability(0), 1);
+        assert_eq!(scheduler.requests(&A, now), vec![]);
+    }
+
+    #[test]
+    fn test_endgame_cancels() {
+        let now = Instant::now();
+        let mut scheduler = scheduler();
+        scheduler.add_peer(A, Bitfield::full(3));
+        scheduler.peer_unchoked(&A);
+        let first = scheduler.requests(&A, now);
+        assert!(!scheduler.is_endgame());
+        for block in &first {
+            let outcome = scheduler.block_received(&A, block, now).unwrap();
+            assert!(outcome.cancels.is_empty());
+            if let Some(index) = outcome.completed {
+                assert_eq!(scheduler.piece_verified(index), vec![A]);
+            }
+        }
+        assert!(scheduler.picker().have().has(1));
+
+        // A takes the short last block, B duplicates it in endgame
+        let last = scheduler.requests(&A, now);
+        assert_eq!(last.len(), 1);
+        assert_eq!(last[0].length, 100);
+        assert!(scheduler.is_endgame());
+        scheduler.add_peer(B, Bitfield::full(3));
+        scheduler.peer_unchoked(&B);
+        assert_eq!(scheduler.requests(&B, now), last);
+        assert!(scheduler.requests(&B, now).is_empty());
+
+        let outcome = scheduler.block_received(&B, &last[0], now).unwrap();
+        assert_eq!(outcome.cancels, vec![(A, last[0])]);
+        assert_eq!(outcome.completed, Some(2));
+        // A's copy arrives anyway and is not needed
+        assert_eq!(scheduler.block_received(&A, &last[0], now), None);
+
+        // a bad piece is downloaded again
+        assert_eq!(scheduler.piece_failed(2), vec![B]);
+        assert_eq!(scheduler.requests(&A, now), last);
+        scheduler.request_timed_out(&A, &last[0]);
+        assert_eq!(scheduler.requests(&B, now), last);
+    }
+}
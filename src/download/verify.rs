@@ -0,0 +1,270 @@
+use crate::metainfo::{Info, MAX_PIECE_LENGTH};
+use crate::peer::connection::PeerKey;
+use hashbrown::{HashMap, HashSet};
+use sha1::{Digest, Sha1};
+
+/// Bad pieces a peer may be proven to have sent before it is banned.
+pub const MAX_STRIKES: u32 = 3;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Verdict {
+    /// The piece matches its hash and can be written to disk. `banned` are
+    /// the peers it proved to have sent bad blocks of it before.
+    Valid { data: Vec<u8>, banned: Vec<PeerKey> },
+    /// The piece was discarded. `banned` are the peers banned because of it.
+    Corrupt { banned: Vec<PeerKey> },
+}
+
+#[derive(Debug, Clone)]
+struct Block {
+    begin: usize,
+    len: usize,
+    peer: PeerKey,
+}
+
+/// Buffers the blocks of pieces in memory until they can be checked against
+/// the torrent's SHA-1 hashes, so unverified data never reaches the disk, and
+/// keeps score of the peers that sent bad data.
+///
+/// A failed piece only proves a peer bad if it sent the whole piece. Pieces
+/// from several peers are blamed once the piece passes: the blocks of the
+/// failed attempt are kept as hashes and compared with the good data, so
+/// only the peers whose blocks differ are struck.
+#[derive(Debug)]
+pub struct PieceVerifier {
+    info: Info,
+    buffers: HashMap<u32, Vec<u8>>,
+    blocks: HashMap<u32, Vec<Block>>,
+    // blocks of failed attempts with the hash of the data sent
+    suspects: HashMap<u32, Vec<(Block, [u8; 20])>>,
+    strikes: HashMap<PeerKey, u32>,
+    banned: HashSet<PeerKey>,
+}
+
+impl PieceVerifier {
+    pub fn new(info: Info) -> Self {
+        Self {
+            info,
+            buffers: HashMap::new(),
+            blocks: HashMap::new(),
+            suspects: HashMap::new(),
+            strikes: HashMap::new(),
+            banned: HashSet::new(),
+        }
+    }
+
+    /// Copies a block `peer` sent into its piece's buffer. Returns false if
+    /// the block doesn't fit the piece or the piece is too large to buffer.
+    pub fn add_block(&mut self, peer: PeerKey, index: u32, begin: u32, data: &[u8]) -> bool {
+        let Some(len) = self.info.piece_size(index as usize) else {
+            return false;
+        };
+        if len > MAX_PIECE_LENGTH {
+            return false;
+        }
+        let begin = begin as usize;
+        let end = begin + data.len();
+        if end as u64 > len {
+            return false;
+        }
+        let buffer = self
+            .buffers
+            .entry(index)
+            .or_insert_with(|| vec![0; len as usize]);
+        buffer[begin..end].copy_from_slice(data);
+        let blocks = self.blocks.entry(index).or_default();
+        blocks.retain(|block| block.begin != begin);
+        blocks.push(Block {
+            begin,
+            len: data.len(),
+            peer,
+        });
+        true
+    }
+
+    /// Checks a completed piece. A peer proven to have sent bad data gets a
+    /// strike and is banned after `MAX_STRIKES`, so one flipped bit doesn't
+    /// cost a peer for good. A good piece takes a strike away again.
+    pub fn verify(&mut self, index: u32) -> Verdict {
+        let data = self.buffers.remove(&index).unwrap_or_default();
+        let blocks = self.blocks.remove(&index).unwrap_or_default();
+        let valid = match self.info.piece_hash(index as usize) {
+            Some(expected) => {
+                self.info.piece_size(index as usize) == Some(data.len() as u64)
+                    && Sha1::digest(&data)[..] == *expected
+            }
+            None => false,
+        };
+        let mut contributors: Vec<PeerKey> = blocks.iter().map(|block| block.peer).collect();
+        contributors.sort_unstable();
+        contributors.dedup();
+
+        if valid {
+            let mut banned = Vec::new();
+            let mut liars: Vec<PeerKey> = self
+                .suspects
+                .remove(&index)
+                .unwrap_or_default()
+                .into_iter()
+                .filter(|(block, hash)| {
+                    data.get(block.begin..block.begin + block.len)
+                        .is_none_or(|good| Sha1::digest(good)[..] != hash[..])
+                })
+                .map(|(block, _)| block.peer)
+                .collect();
+            liars.sort_unstable();
+            liars.dedup();
+            for peer in &liars {
+                if self.strike(*peer) {
+                    banned.push(*peer);
+                }
+            }
+            for peer in contributors {
+                if let Some(strikes) = self.strikes.get_mut(&peer) {
+                    *strikes = strikes.saturating_sub(1);
+                }
+            }
+            return Verdict::Valid { data, banned };
+        }
+
+        tracing::warn!(
+            "Piece {} failed its hash check, sent by {} peer(s)",
+            index,
+            contributors.len()
+        );
+        let mut banned = Vec::new();
+        match contributors[..] {
+            [peer] => {
+                if self.strike(peer) {
+                    banned.push(peer);
+                }
+            }
+            // blamed once a good copy of the piece shows who lied
+            _ => {
+                let suspects = self.suspects.entry(index).or_default();
+                for block in blocks {
+                    let Some(sent) = data.get(block.begin..block.begin + block.len) else {
+                        continue;
+                    };
+                    let hash = Sha1::digest(sent).into();
+                    suspects.push((block, hash));
+                }
+            }
+        }
+        Verdict::Corrupt { banned }
+    }
+
+    // returns true if the peer got banned just now
+    fn strike(&mut self, peer: PeerKey) -> bool {
+        let strikes = self.strikes.entry(peer).or_insert(0);
+        *strikes += 1;
+        *strikes >= MAX_STRIKES && self.banned.insert(peer)
+    }
+
+    /// Drops a partly downloaded piece, e.g. when it is given up on.
+    pub fn discard(&mut self, index: u32) {
+        self.buffers.remove(&index);
+        self.blocks.remove(&index);
+    }
+
+    pub fn strikes(&self, peer: &PeerKey) -> u32 {
+        self.strikes.get(peer).copied().unwrap_or(0)
+    }
+
+    /// Banned peers should be disconnected and not connected to again.
+    pub fn is_banned(&self, peer: &PeerKey) -> bool {
+        self.banned.contains(peer)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_bytes::ByteBuf;
+
+    const A: PeerKey = [1; 20];
+    const B: PeerKey = [2; 20];
+    const C: PeerKey = [3; 20];
+
+    fn verifier(data: &[u8]) -> PieceVerifier {
+        let mut pieces = Sha1::digest(&data[..20]).to_vec();
+        pieces.extend_from_slice(&Sha1::digest(&data[20..]));
+        PieceVerifier::new(Info {
+            name: "test".to_string(),
+            piece_length: 20,
+            pieces: ByteBuf::from(pieces),
+            length: Some(30),
+            files: None,
+            private: None,
+        })
+    }
+
+    #[test]
+    fn test_verify_and_ban() {
+        let data: Vec<u8> = (0..30).collect();
+        let mut verifier = verifier(&data);
+        assert!(!verifier.add_block(A, 1, 5, &data[20..]));
+        assert!(!verifier.add_block(A, 2, 0, &data[..1]));
+
+        assert!(verifier.add_block(A, 0, 0, &data[..20]));
+        assert_eq!(
+            verifier.verify(0),
+            Verdict::Valid {
+                data: data[..20].to_vec(),
+                banned: vec![],
+            }
+        );
+
+        // B keeps sending bad pieces on its own
+        for _ in 1..MAX_STRIKES {
+            assert!(verifier.add_block(B, 1, 0, &[1; 10]));
+            assert_eq!(verifier.verify(1), Verdict::Corrupt { banned: vec![] });
+        }
+        assert!(!verifier.is_banned(&B));
+        assert!(verifier.add_block(B, 1, 0, &[1; 10]));
+        assert_eq!(verifier.verify(1), Verdict::Corrupt { banned: vec![B] });
+        assert!(verifier.is_banned(&B) && !verifier.is_banned(&A));
+        assert!(verifier.add_block(B, 1, 0, &[1; 10]));
+        assert_eq!(verifier.verify(1), Verdict::Corrupt { banned: vec![] });
+    }
+
+    #[test]
+    fn test_smart_ban() {
+        let data: Vec<u8> = (0..30).collect();
+        let mut verifier = verifier(&data);
+        // two peers, one of them lying: nobody is blamed yet
+        for _ in 0..MAX_STRIKES {
+            assert!(verifier.add_block(A, 0, 0, &data[..10]));
+            assert!(verifier.add_block(B, 0, 10, &[0; 10]));
+            assert_eq!(verifier.verify(0), Verdict::Corrupt { banned: vec![] });
+        }
+        assert_eq!(verifier.strikes(&A), 0);
+        assert_eq!(verifier.strikes(&B), 0);
+
+        // the good copy proves B sent the bad block, A is cleared
+        assert!(verifier.add_block(A, 0, 0, &data[..10]));
+        assert!(verifier.add_block(C, 0, 10, &data[10..20]));
+        assert_eq!(
+            verifier.verify(0),
+            Verdict::Valid {
+                data: data[..20].to_vec(),
+                banned: vec![],
+            }
+        );
+        assert_eq!(verifier.strikes(&A), 0);
+        assert_eq!(verifier.strikes(&B), 1);
+    }
+
+    #[test]
+    fn test_oversized_piece_is_not_buffered() {
+        let mut verifier = PieceVerifier::new(Info {
+            name: "test".to_string(),
+            piece_length: 2 * MAX_PIECE_LENGTH,
+            pieces: ByteBuf::from(vec![0; 20]),
+            length: Some(2 * MAX_PIECE_LENGTH),
+            files: None,
+            private: None,
+        });
+        assert!(!verifier.add_block(A, 0, 0, &[0; 10]));
+    }
+}
@@ -0,0 +1,200 @@
+use crate::types::{InfoHash, InfoHashError};
+use data_encoding::HEXLOWER_PERMISSIVE;
+use std::str::FromStr;
+use thiserror::Error;
+use url::Url;
+
+const BTIH_PREFIX: &str = "urn:btih:";
+const BTMH_PREFIX: &str = "urn:btmh:";
+// multihash header for a 32 byte sha2-256 digest
+const SHA256_MULTIHASH: &str = "1220";
+
+#[derive(Debug, Error, PartialEq, Eq)]
+pub enum MagnetError {
+    #[error("Not a magnet URI: {0}")]
+    InvalidUri(String),
+    #[error("Magnet URI has no urn:btih or urn:btmh exact topic")]
+    MissingInfoHash,
+    #[error("Invalid btih info-hash: {0}")]
+    InvalidInfoHash(#[from] InfoHashError),
+    #[error("Invalid btmh multihash: {0}")]
+    InvalidMultihash(String),
+}
+
+/// A parsed `magnet:?xt=urn:btih:...` link (BEP 9).
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct MagnetLink {
+    pub info_hash: Option<InfoHash>,
+    /// BitTorrent v2 sha2-256 info-hash from `xt=urn:btmh`.
+    pub info_hash_v2: Option<[u8; 32]>,
+    pub display_name: Option<String>,
+    pub trackers: Vec<String>,
+    pub web_seeds: Vec<String>,
+    pub peers: Vec<String>,
+}
+
+impl MagnetLink {
+    pub fn parse(uri: &str) -> Result<Self, MagnetError> {
+        let url = Url::parse(uri.trim()).map_err(|e| MagnetError::InvalidUri(e.to_string()))?;
+        if url.scheme() != "magnet" {
+            return Err(MagnetError::InvalidUri(format!(
+                "unexpected scheme {}",
+                url.scheme()
+            )));
+        }
+        let mut link = MagnetLink::default();
+        for (key, value) in url.query_pairs() {
+            match key.as_ref() {
+                "xt" => {
+                    if let Some(hash) = value.strip_prefix(BTIH_PREFIX) {
+                        link.info_hash = Some(hash.parse()?);
+                    } else if let Some(multihash) = value.strip_prefix(BTMH_PREFIX) {
+                        link.info_hash_v2 = Some(parse_multihash(multihash)?);
+                    }
+                }
+                "dn" => link.display_name = Some(value.into_owned()),
+                "tr" => link.trackers.push(value.into_owned()),
+                "ws" => link.web_seeds.push(value.into_owned()),
+                "x.pe" => link.peers.push(value.into_owned()),
+                _ => {}
+            }
+        }
+        if link.info_hash.is_none() && link.info_hash_v2.is_none() {
+            return Err(MagnetError::MissingInfoHash);
+        }
+        Ok(link)
+    }
+
+    /// The 20 byte hash used to identify the swarm on trackers and the DHT.
+    /// v2-only links use the truncated sha2-256 hash as BEP 52 specifies.
+    pub fn swarm_hash(&self) -> InfoHash {
+        match (&self.info_hash, &self.info_hash_v2) {
+            (Some(hash), _) => *hash,
+            (None, Some(hash_v2)) => {
+                let mut truncated = [0u8; 20];
+                truncated.copy_from_slice(&hash_v2[..20]);
+                InfoHash::new(truncated)
+            }
+            (None, None) => unreachable!("magnet links are parsed with at least one hash"),
+        }
+    }
+
+    pub fn to_uri(&self) -> String {
+        let mut url = Url::parse("magnet:").expect("valid magnet base");
+        {
+            let mut query = url.query_pairs_mut();
+            if let Some(hash) = &self.info_hash {
+                query.append_pair("xt", &format!("{}{}", BTIH_PREFIX, hash.to_hex()));
+            }
+            if let Some(hash_v2) = &self.info_hash_v2 {
+                query.append_pair(
+                    "xt",
+                    &format!(
+                        "{}{}{}",
+                        BTMH_PREFIX,
+                        SHA256_MULTIHASH,
+                        HEXLOWER_PERMISSIVE.encode(hash_v2)
+                    ),
+                );
+            }
+            if let Some(name) = &self.display_name {
+                query.append_pair("dn", name);
+            }
+            for tracker in &self.trackers {
+                query.append_pair("tr", tracker);
+            }
+            for web_seed in &self.web_seeds {
+                query.append_pair("ws", web_seed);
+            }
+            for peer in &self.peers {
+                query.append_pair("x.pe", peer);
+            }
+        }
+        url.to_string()
+    }
+}
+
+fn parse_multihash(multihash: &str) -> Result<[u8; 32], MagnetError> {
+    let digest = multihash
+        .strip_prefix(SHA256_MULTIHASH)
+        .ok_or_else(|| MagnetError::InvalidMultihash("not a sha2-256 multihash".to_string()))?;
+    let bytes = HEXLOWER_PERMISSIVE
+        .decode(digest.as_bytes())
+        .map_err(|e| MagnetError::InvalidMultihash(e.to_string()))?;
+    bytes
+        .try_into()
+        .map_err(|_| MagnetError::InvalidMultihash("digest is not 32 bytes".to_string()))
+}
+
+impl FromStr for MagnetLink {
+    type Err = MagnetError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        MagnetLink::parse(s)
+    }
+}
+
+impl std::fmt::Display for MagnetLink {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.to_uri())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const HEX: &str = "a9993e364706816aba3e25717850c26c9cd0d89d";
+    const BASE32: &str = "VGMT4NSHA2AWVOR6EVYXQUGCNSONBWE5";
+
+    #[test]
+    fn test_parse_magnet() {
+        let uri = format!(
+            "magnet:?xt=urn:btih:{}&dn=Some+File%201&tr=udp%3A%2F%2Ftracker.example%3A80&tr=http://b.example/announce&ws=http%3A%2F%2Fseed.example%2Ff&x.pe=10.0.0.1:6881",
+            HEX
+        );
+        let link: MagnetLink = uri.parse().unwrap();
+        assert_eq!(link.info_hash.unwrap().to_hex(), HEX);
+        assert_eq!(link.display_name.as_deref(), Some("Some File 1"));
+        assert_eq!(
+            link.trackers,
+            vec!["udp://tracker.example:80", "http://b.example/announce"]
+        );
+        assert_eq!(link.web_seeds, vec!["http://seed.example/f"]);
+        assert_eq!(link.peers, vec!["10.0.0.1:6881"]);
+        assert_eq!(MagnetLink::parse(&link.to_uri()).unwrap(), link);
+    }
+
+    #[test]
+    fn test_parse_base32_and_v2() {
+        let link = MagnetLink::parse(&format!("magnet:?xt=urn:btih:{}", BASE32)).unwrap();
+        assert_eq!(link.info_hash.unwrap().to_hex(), HEX);
+
+        let digest = "ab".repeat(32);
+        let link = MagnetLink::parse(&format!("magnet:?xt=urn:btmh:1220{}", digest)).unwrap();
+        assert_eq!(link.info_hash, None);
+        assert_eq!(link.info_hash_v2, Some([0xab; 32]));
+        assert_eq!(link.swarm_hash(), InfoHash::new([0xab; 20]));
+        assert_eq!(MagnetLink::parse(&link.to_uri()).unwrap(), link);
+    }
+
+    #[test]
+    fn test_invalid_magnet() {
+        assert_eq!(
+            MagnetLink::parse("magnet:?dn=nothing"),
+            Err(MagnetError::MissingInfoHash)
+        );
+        assert!(matches!(
+            MagnetLink::parse("http://example.com/?xt=urn:btih:abc"),
+            Err(MagnetError::InvalidUri(_))
+        ));
+        assert!(matches!(
+            MagnetLink::parse("magnet:?xt=urn:btih:abc"),
+            Err(MagnetError::InvalidInfoHash(_))
+        ));
+        assert!(matches!(
+            MagnetLink::parse("magnet:?xt=urn:btmh:1114abcd"),
+            Err(MagnetError::InvalidMultihash(_))
+        ));
+    }
+}
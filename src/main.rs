@@ -1,56 +1,312 @@
 pub mod client;
 pub mod config;
+pub mod create;
 pub mod db;
+pub mod dht;
+pub mod download;
+pub mod magnet;
+pub mod metainfo;
 pub mod metrics;
 pub mod network;
 pub mod parser;
 pub mod peer;
+pub mod queue;
+pub mod storage;
 pub mod types;
 
-use crate::client::arguments::{get_cmds, Settings};
+use crate::client::arguments::{get_cmds, Mode, Settings};
 use eframe::egui;
+use futures::StreamExt;
 use libp2p::metrics::Registry;
+use magnet::MagnetLink;
 use metrics::{setup_tracing, MetricServer};
 use network::Session;
-use peer::client::ClientMode;
+use peer::client::{Client, ClientMode};
+use queue::{QueueManager, QueueMove};
 use std::error::Error;
 use std::sync::{Arc, RwLock};
 
 pub struct App {
-    torrents: Vec<String>,
+    library: Option<db::Library>,
+    torrents: Vec<db::TorrentRecord>,
+    queue: QueueManager,
     config: Arc<RwLock<Settings>>,
     torrent_files: Vec<egui::DroppedFile>,
     torrent_file_path: Option<String>,
     session_id: u32,
     session: Option<Session>,
+    client: Option<Client>,
+    magnet_uri: String,
+    magnet_error: Option<String>,
+    swarm_health: Arc<RwLock<Vec<types::SwarmHealth>>>,
+    /// Torrents the session finished, waiting to move on to seeding.
+    completed: Arc<RwLock<Vec<types::InfoHash>>>,
 }
 
 impl Default for App {
     fn default() -> Self {
         App {
-            torrents: vec!["example_torrent.torrent".to_string()],
+            library: None,
+            torrents: vec![],
+            queue: QueueManager::new(Default::default()),
             torrent_files: vec![],
             torrent_file_path: None,
             config: Arc::new(RwLock::new(Settings::default())),
             session_id: 0,
             session: None,
+            client: None,
+            magnet_uri: String::new(),
+            magnet_error: None,
+            swarm_health: Default::default(),
+            completed: Default::default(),
+        }
+    }
+}
+
+impl App {
+    pub fn new(
+        config: Arc<RwLock<Settings>>,
+        client: Client,
+        library: db::Library,
+        completed: Arc<RwLock<Vec<types::InfoHash>>>,
+    ) -> Self {
+        let queue_settings = config.read().unwrap().queue;
+        let mut app = App {
+            config,
+            client: Some(client),
+            library: Some(library),
+            completed,
+            ..Default::default()
+        };
+        app.refresh_torrents();
+        app.queue = QueueManager::with_torrents(queue_settings, &app.torrents);
+        app.apply_queue();
+        app
+    }
+
+    /// Rebalances the queue and stores the new states in the library.
+    fn apply_queue(&mut self) {
+        let changes = self.queue.update();
+        if let Some(library) = &self.library {
+            for (info_hash, state) in &changes {
+                if let Err(e) = library.set_state(info_hash, *state) {
+                    tracing::warn!("Failed to update {}: {}", info_hash, e);
+                }
+            }
+        }
+        if let (Some(mut client), false) = (self.client.clone(), changes.is_empty()) {
+            tokio::spawn(async move { client.set_states(changes).await });
+        }
+        self.refresh_torrents();
+    }
+
+    fn apply_completed(&mut self) {
+        let completed = std::mem::take(&mut *self.completed.write().unwrap());
+        if completed.is_empty() {
+            return;
+        }
+        for info_hash in completed {
+            self.queue.completed(&info_hash);
+        }
+        self.apply_queue();
+    }
+
+    fn move_torrent(&mut self, info_hash: types::InfoHash, to: QueueMove) {
+        if !self.queue.move_to(&info_hash, to) {
+            return;
+        }
+        if let Some(library) = &self.library {
+            for (info_hash, priority) in self.queue.priorities() {
+                if let Err(e) = library.update(&info_hash, |record| record.priority = priority) {
+                    tracing::warn!("Failed to update {}: {}", info_hash, e);
+                }
+            }
+        }
+        self.apply_queue();
+    }
+
+    fn refresh_torrents(&mut self) {
+        if let Some(library) = &self.library {
+            match library.torrents() {
+                Ok(torrents) => self.torrents = torrents,
+                Err(e) => tracing::warn!("Failed to read the torrent library: {}", e),
+            }
+        }
+    }
+
+    fn add_to_library(&mut self, record: db::TorrentRecord) {
+        if let Some(library) = &self.library {
+            match library.add(&record) {
+                Ok(true) => {
+                    tracing::info!("Added {} to the library", record.name);
+                    self.queue.add(record.info_hash, record.completed.is_some());
+                }
+                Ok(false) => tracing::info!("{} is already in the library", record.name),
+                Err(e) => tracing::warn!("Failed to add {} to the library: {}", record.name, e),
+            }
+        }
+        self.apply_queue();
+    }
+
+    fn add_torrent_file(&mut self, path: &std::path::Path) {
+        let download_dir = self.config.read().unwrap().download_dir.clone();
+        let record = std::fs::read(path)
+            .map_err(|e| e.to_string())
+            .and_then(|bytes| {
+                db::TorrentRecord::from_metainfo(&bytes, download_dir).map_err(|e| e.to_string())
+            });
+        let record = match record {
+            Ok(record) => record,
+            Err(e) => {
+                tracing::warn!("Failed to add {}: {}", path.display(), e);
+                return;
+            }
+        };
+        let save_path = record.save_path.clone();
+        self.add_to_library(record);
+        if let Some(client) = self.client.clone() {
+            let request = serde_json::json!({
+                "method": "add_torrent",
+                "params": { "file": path, "save_path": save_path },
+            });
+            tokio::spawn(async move {
+                match client.execute_command(request).await {
+                    Ok(res) => tracing::info!("Added torrent: {}", res),
+                    Err(e) => tracing::warn!("Failed to add torrent: {}", e),
+                }
+            });
+        }
+    }
+
+    fn add_magnet(&mut self) {
+        let magnet = match MagnetLink::parse(&self.magnet_uri) {
+            Ok(magnet) => magnet,
+            Err(e) => {
+                self.magnet_error = Some(e.to_string());
+                return;
+            }
+        };
+        self.magnet_error = None;
+        let download_dir = self.config.read().unwrap().download_dir.clone();
+        self.add_to_library(db::TorrentRecord::from_magnet(&magnet, download_dir));
+        if let Some(client) = self.client.clone() {
+            let request = serde_json::json!({
+                "method": "add_magnet",
+                "params": { "uri": self.magnet_uri },
+            });
+            tokio::spawn(async move {
+                match client.execute_command(request).await {
+                    Ok(res) => tracing::info!("Added magnet: {}", res),
+                    Err(e) => tracing::warn!("Failed to add magnet: {}", e),
+                }
+            });
+        }
+        self.magnet_uri.clear();
+    }
+
+    fn scrape(&self) {
+        if let Some(mut client) = self.client.clone() {
+            let swarm_health = self.swarm_health.clone();
+            tokio::spawn(async move {
+                match client.scrape().await {
+                    Ok(health) => *swarm_health.write().unwrap() = health,
+                    Err(e) => tracing::warn!("Failed to scrape trackers: {}", e),
+                }
+            });
         }
     }
 }
 
 impl eframe::App for App {
     fn update(&mut self, ctx: &egui::Context, frame: &mut eframe::Frame) {
+        self.apply_completed();
         let download_dir = {
             let config_guard = self.config.read().unwrap();
             config_guard.download_dir.clone()
         };
         egui::CentralPanel::default().show(ctx, |ui| {
             ui.label("Torrents");
+            let mut moved = None;
+            let mut toggled = None;
+            for torrent in &self.torrents {
+                let info_hash = torrent.info_hash;
+                let forced = self.queue.is_forced(&info_hash);
+                ui.horizontal(|ui| {
+                    ui.monospace(&torrent.name);
+                    ui.label(format!("{:?}", torrent.state));
+                    for (label, to) in [
+                        ("⏫", QueueMove::Top),
+                        ("⬆", QueueMove::Up),
+                        ("⬇", QueueMove::Down),
+                        ("⏬", QueueMove::Bottom),
+                    ] {
+                        if ui.small_button(label).clicked() {
+                            moved = Some((info_hash, to));
+                        }
+                    }
+                    let paused = torrent.state == types::FileStatus::Paused;
+                    if ui
+                        .small_button(if paused { "Resume" } else { "Pause" })
+                        .clicked()
+                    {
+                        toggled = Some((info_hash, !paused, forced));
+                    }
+                    let mut force = forced;
+                    if ui.checkbox(&mut force, "Force").changed() {
+                        toggled = Some((info_hash, false, force));
+                    }
+                });
+            }
+            if let Some((info_hash, to)) = moved {
+                self.move_torrent(info_hash, to);
+            }
+            if let Some((info_hash, pause, force)) = toggled {
+                if pause {
+                    self.queue.pause(&info_hash);
+                } else {
+                    self.queue.resume(&info_hash);
+                    self.queue.set_forced(&info_hash, force);
+                }
+                if let Some(library) = &self.library {
+                    let forced = self.queue.is_forced(&info_hash);
+                    if let Err(e) = library.update(&info_hash, |record| record.forced = forced) {
+                        tracing::warn!("Failed to update {}: {}", info_hash, e);
+                    }
+                }
+                self.apply_queue();
+            }
+            for health in self.swarm_health.read().unwrap().iter() {
+                let stats = match health.stats {
+                    Some(stats) => format!(
+                        "{} seeders, {} leechers, {} completed",
+                        stats.seeders, stats.leechers, stats.completed
+                    ),
+                    None => "not scraped".to_string(),
+                };
+                ui.horizontal(|ui| {
+                    ui.monospace(&health.name);
+                    ui.label(stats);
+                });
+            }
+            if ui.button("Scrape trackers").clicked() {
+                self.scrape();
+            }
             if ui.button("Add torrent").clicked() {
                 if let Some(path) = rfd::FileDialog::new().pick_file() {
+                    self.add_torrent_file(&path);
                     self.torrent_file_path = Some(path.display().to_string());
                 }
             }
+            ui.horizontal(|ui| {
+                ui.label("Magnet link:");
+                ui.text_edit_singleline(&mut self.magnet_uri);
+                if ui.button("Add magnet").clicked() {
+                    self.add_magnet();
+                }
+            });
+            if let Some(error) = &self.magnet_error {
+                ui.colored_label(egui::Color32::RED, error);
+            }
             if let Some(torrent_path) = &self.torrent_file_path {
                 ui.horizontal(|ui| {
                     ui.label("Torrent file:");
@@ -132,13 +388,21 @@ async fn main() -> Result<(), Box<dyn Error>> {
     let metrics_registry = Registry::default();
     let registry_rwlock = Arc::new(RwLock::new(metrics_registry));
     let metrics = MetricServer::new(registry_rwlock.clone(), config_rwlock.clone());
-    let (tcp_listen_address) = {
+    let (tcp_listen_address, mode, tracker_settings) = {
         let config_guard = config_rwlock.read().unwrap();
-        config_guard.tcp.address.clone()
+        (
+            config_guard.tcp.address.clone(),
+            config_guard.mode,
+            config_guard.tracker.clone(),
+        )
     };
     dotenv::dotenv().ok();
     let key = dotenv::var("SECRET_KEY").unwrap();
-    let (mut network_client, mut network_events, network_event_loop) = network::new(
+    // before the session and the DHT start logging
+    setup_tracing();
+    let db = db::open(&config_rwlock.read().unwrap())?;
+    let library = db::Library::new(&db)?;
+    let (mut network_client, mut network_events, mut network_event_loop) = network::new(
         config_rwlock.clone(),
         metrics.clone(),
         ClientMode::Download,
@@ -146,14 +410,69 @@ async fn main() -> Result<(), Box<dyn Error>> {
     )
     .await
     .unwrap();
+    network_event_loop.restore(library.torrents()?, db::ResumeStore::new(&db)?);
+    let dht_settings = config_rwlock.read().unwrap().dht.clone();
+    if dht_settings.enabled {
+        let cache = db::NodeCache::new(&db)?;
+        match dht::DhtNode::bind(dht_settings.socket_addr, cache.node_id()?).await {
+            Ok(node) => {
+                network_event_loop.set_dht(node.clone());
+                tokio::spawn(dht::run(node, cache, dht_settings.bootstrap));
+            }
+            Err(e) => tracing::warn!("DHT disabled: {}", e),
+        }
+    }
     tokio::spawn(network_event_loop.run());
     network_client
         .start_listening(tcp_listen_address)
         .await
         .expect("Failed to  start listening");
-    //moved to network
-    setup_tracing();
+    if mode == Mode::TrackingMode {
+        let metrics = metrics.clone();
+        tokio::spawn(async move {
+            if let Err(e) = peer::tracker::server::serve(tracker_settings, Some(metrics)).await {
+                tracing::error!("Tracker server failed: {}", e);
+            }
+        });
+    }
     tokio::spawn(metrics::metrics_server(metrics));
+    let completed: Arc<RwLock<Vec<types::InfoHash>>> = Default::default();
+    {
+        let completed = completed.clone();
+        let library = library.clone();
+        let mut client = network_client.clone();
+        tokio::spawn(async move {
+            while let Some(event) = network_events.next().await {
+                match event {
+                    types::Event::Completed(info_hash) => {
+                        completed.write().unwrap().push(info_hash);
+                    }
+                    // peers ask for the metainfo of the torrents we provide,
+                    // unknown ones are refused by dropping the channel
+                    types::Event::InboundRequest {
+                        peer,
+                        request,
+                        channel,
+                    } => {
+                        let metainfo = request
+                            .parse::<types::InfoHash>()
+                            .ok()
+                            .and_then(|info_hash| library.get(&info_hash).ok().flatten())
+                            .and_then(|record| record.metainfo);
+                        if let Some(file) = metainfo {
+                            client.respond_file(peer, request, file, channel).await;
+                        }
+                    }
+                }
+            }
+        });
+    }
+    let app = App::new(
+        config_rwlock.clone(),
+        network_client.clone(),
+        library,
+        completed,
+    );
     let options = eframe::NativeOptions {
         viewport: egui::ViewportBuilder::default()
             .with_inner_size([860.0, 720.0])
@@ -163,10 +482,11 @@ async fn main() -> Result<(), Box<dyn Error>> {
     eframe::run_native(
         "jubjub_torrent",
         options,
-        Box::new(|cc| {
+        Box::new(move |cc| {
             // egui_extras
-            Box::<App>::default()
+            Box::new(app)
         }),
     );
+    network_client.shutdown().await;
     Ok(())
 }
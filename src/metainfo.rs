@@ -0,0 +1,522 @@
+use crate::parser::{self, BencodeError};
+use serde::{Deserialize, Deserializer, Serialize};
+use serde_bytes::ByteBuf;
+use std::path::{Component, Path, PathBuf};
+use thiserror::Error;
+
+pub const PIECE_HASH_LEN: usize = 20;
+/// Largest piece length we accept, since whole pieces are held in memory.
+pub const MAX_PIECE_LENGTH: u64 = 64 * 1024 * 1024;
+
+#[derive(Debug, Error)]
+pub enum MetainfoError {
+    #[error("Failed to read torrent file: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("Failed to decode bencoded metainfo: {0}")]
+    Bencode(#[from] serde_bencode::Error),
+    #[error("Malformed bencode: {0}")]
+    Parse(#[from] BencodeError),
+    #[error("Invalid metainfo: {0}")]
+    Invalid(String),
+}
+
+/// Top level dictionary of a .torrent file (BEP 3).
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Metainfo {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub announce: Option<String>,
+    #[serde(
+        default,
+        rename = "announce-list",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub announce_list: Option<Vec<Vec<String>>>,
+    #[serde(
+        default,
+        rename = "creation date",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub creation_date: Option<i64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub comment: Option<String>,
+    #[serde(
+        default,
+        rename = "created by",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub created_by: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub encoding: Option<String>,
+    /// Web seeds (BEP 19), either a single url or a list in the wild.
+    #[serde(
+        default,
+        rename = "url-list",
+        deserialize_with = "deserialize_url_list",
+        skip_serializing_if = "Vec::is_empty"
+    )]
+    pub url_list: Vec<String>,
+    pub info: Info,
+}
+
+fn deserialize_url_list<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Vec<String>, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum UrlList {
+        One(String),
+        Many(Vec<String>),
+    }
+    let urls = match UrlList::deserialize(deserializer)? {
+        UrlList::One(url) => vec![url],
+        UrlList::Many(urls) => urls,
+    };
+    Ok(urls.into_iter().filter(|url| !url.is_empty()).collect())
+}
+
+/// The `info` dictionary, which is what the info-hash is computed over.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Info {
+    pub name: String,
+    #[serde(rename = "piece length")]
+    pub piece_length: u64,
+    pub pieces: ByteBuf,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub length: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub files: Option<Vec<FileEntry>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub private: Option<u8>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FileEntry {
+    pub length: u64,
+    pub path: Vec<String>,
+}
+
+impl Metainfo {
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, MetainfoError> {
+        let metainfo: Metainfo = serde_bencode::from_bytes(bytes)?;
+        metainfo.validate()?;
+        Ok(metainfo)
+    }
+
+    pub fn to_bytes(&self) -> Result<Vec<u8>, MetainfoError> {
+        Ok(serde_bencode::to_bytes(self)?)
+    }
+
+    fn validate(&self) -> Result<(), MetainfoError> {
+        self.info.validate()
+    }
+
+    /// A .torrent file around an info dictionary fetched from peers. The
+    /// info bytes are kept as they are so the info-hash doesn't change.
+    pub fn from_raw_info(
+        info: &[u8],
+        trackers: &[Vec<String>],
+    ) -> Result<(Self, Vec<u8>), MetainfoError> {
+        #[derive(Serialize)]
+        struct Announce<'a> {
+            #[serde(skip_serializing_if = "Option::is_none")]
+            announce: Option<&'a String>,
+            #[serde(rename = "announce-list", skip_serializing_if = "Vec::is_empty")]
+            announce_list: Vec<Vec<String>>,
+        }
+        let mut bytes = serde_bencode::to_bytes(&Announce {
+            announce: trackers.iter().flatten().next(),
+            announce_list: trackers.to_vec(),
+        })?;
+        // `info` sorts after both announce keys, so it goes last
+        bytes.pop();
+        bytes.extend_from_slice(b"4:info");
+        bytes.extend_from_slice(info);
+        bytes.push(b'e');
+        let metainfo = Metainfo::from_bytes(&bytes)?;
+        Ok((metainfo, bytes))
+    }
+
+    /// Raw bytes of the `info` dictionary exactly as they appear in `bytes`,
+    /// which is what the info-hash has to be computed over.
+    pub fn raw_info(bytes: &[u8]) -> Result<&[u8], MetainfoError> {
+        let span = parser::dict_value_span(bytes, b"info")?;
+        Ok(&bytes[span])
+    }
+
+    /// Trackers grouped in tiers, falling back to `announce` when there is no
+    /// `announce-list`.
+    pub fn trackers(&self) -> Vec<Vec<String>> {
+        match &self.announce_list {
+            Some(tiers) if tiers.iter().any(|tier| !tier.is_empty()) => tiers
+                .iter()
+                .filter(|tier| !tier.is_empty())
+                .cloned()
+                .collect(),
+            _ => self
+                .announce
+                .iter()
+                .map(|announce| vec![announce.clone()])
+                .collect(),
+        }
+    }
+}
+
+/// A single plain path component, so joining it can't leave the directory
+/// it is joined onto.
+fn is_safe_segment(segment: &str) -> bool {
+    let mut components = Path::new(segment).components();
+    !segment.contains(['/', '\\'])
+        && matches!(components.next(), Some(Component::Normal(_)))
+        && components.next().is_none()
+}
+
+impl Info {
+    pub fn validate(&self) -> Result<(), MetainfoError> {
+        if self.name.is_empty() {
+            return Err(MetainfoError::Invalid("empty name".to_string()));
+        }
+        // the name becomes the file or directory created in the download dir
+        if !is_safe_segment(&self.name) {
+            return Err(MetainfoError::Invalid(format!(
+                "invalid name {:?}",
+                self.name
+            )));
+        }
+        if self.piece_length == 0 {
+            return Err(MetainfoError::Invalid("piece length is zero".to_string()));
+        }
+        if self.piece_length > MAX_PIECE_LENGTH {
+            return Err(MetainfoError::Invalid(format!(
+                "piece length {} is over {}",
+                self.piece_length, MAX_PIECE_LENGTH
+            )));
+        }
+        if !self.pieces.len().is_multiple_of(PIECE_HASH_LEN) {
+            return Err(MetainfoError::Invalid(format!(
+                "pieces length {} is not a multiple of {}",
+                self.pieces.len(),
+                PIECE_HASH_LEN
+            )));
+        }
+        match (&self.length, &self.files) {
+            (Some(_), None) => {}
+            (None, Some(files)) => {
+                if files.is_empty() {
+                    return Err(MetainfoError::Invalid("empty files list".to_string()));
+                }
+                for file in files {
+                    if file.path.is_empty() {
+                        return Err(MetainfoError::Invalid("empty file path".to_string()));
+                    }
+                    for segment in &file.path {
+                        if !is_safe_segment(segment) {
+                            return Err(MetainfoError::Invalid(format!(
+                                "invalid path segment {:?}",
+                                segment
+                            )));
+                        }
+                    }
+                }
+            }
+            (Some(_), Some(_)) => {
+                return Err(MetainfoError::Invalid(
+                    "both length and files are present".to_string(),
+                ))
+            }
+            (None, None) => {
+                return Err(MetainfoError::Invalid(
+                    "neither length nor files are present".to_string(),
+                ))
+            }
+        }
+        let total_length = self
+            .checked_total_length()
+            .ok_or_else(|| MetainfoError::Invalid("total length overflows".to_string()))?;
+        let expected = total_length.div_ceil(self.piece_length);
+        if expected != self.piece_count() as u64 {
+            return Err(MetainfoError::Invalid(format!(
+                "expected {} piece hashes, found {}",
+                expected,
+                self.piece_count()
+            )));
+        }
+        Ok(())
+    }
+
+    pub fn is_multi_file(&self) -> bool {
+        self.files.is_some()
+    }
+
+    pub fn is_private(&self) -> bool {
+        self.private == Some(1)
+    }
+
+    /// Saturates for file lengths that overflow, which `validate` rejects.
+    pub fn total_length(&self) -> u64 {
+        self.checked_total_length().unwrap_or(u64::MAX)
+    }
+
+    pub fn checked_total_length(&self) -> Option<u64> {
+        match (&self.length, &self.files) {
+            (Some(length), _) => Some(*length),
+            (None, Some(files)) => files
+                .iter()
+                .try_fold(0u64, |total, file| total.checked_add(file.length)),
+            (None, None) => Some(0),
+        }
+    }
+
+    pub fn piece_count(&self) -> usize {
+        self.pieces.len() / PIECE_HASH_LEN
+    }
+
+    pub fn piece_hash(&self, index: usize) -> Option<&[u8]> {
+        let start = index.checked_mul(PIECE_HASH_LEN)?;
+        self.pieces.get(start..start + PIECE_HASH_LEN)
+    }
+
+    /// Length of the piece at `index`; only the last piece may be shorter.
+    pub fn piece_size(&self, index: usize) -> Option<u64> {
+        if index >= self.piece_count() {
+            return None;
+        }
+        let start = index as u64 * self.piece_length;
+        Some(self.piece_length.min(self.total_length() - start))
+    }
+
+    /// Files as relative paths with their lengths, in torrent order.
+    pub fn file_paths(&self) -> Vec<(PathBuf, u64)> {
+        match &self.files {
+            Some(files) => files
+                .iter()
+                .map(|file| {
+                    let mut path = PathBuf::from(&self.name);
+                    path.extend(&file.path);
+                    (path, file.length)
+                })
+                .collect(),
+            None => vec![(PathBuf::from(&self.name), self.total_length())],
+        }
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    pub(crate) fn bstr(s: &str) -> String {
+        format!("{}:{}", s.len(), s)
+    }
+
+    pub(crate) fn single_file_bytes() -> Vec<u8> {
+        let mut bytes = format!(
+            "d{}{}{}{}{}{}{}i1700000000e{}d{}i20e{}{}{}i16e{}40:",
+            bstr("announce"),
+            bstr("http://tracker.example/announce"),
+            bstr("comment"),
+            bstr("test"),
+            bstr("created by"),
+            bstr("jubjub"),
+            bstr("creation date"),
+            bstr("info"),
+            bstr("length"),
+            bstr("name"),
+            bstr("file.txt"),
+            bstr("piece length"),
+            bstr("pieces"),
+        )
+        .into_bytes();
+        bytes.extend_from_slice(&[0xab; 40]);
+        bytes.extend_from_slice(b"ee");
+        bytes
+    }
+
+    pub(crate) fn multi_file_bytes() -> Vec<u8> {
+        let mut bytes = format!(
+            "d{}ll{}el{}{}ee{}d{}ld{}i10e{}l{}{}eed{}i30e{}l{}eee{}{}{}i16e{}60:",
+            bstr("announce-list"),
+            bstr("http://a.example/ann"),
+            bstr("http://b.example/ann"),
+            bstr("http://c.example/ann"),
+            bstr("info"),
+            bstr("files"),
+            bstr("length"),
+            bstr("path"),
+            bstr("dir"),
+            bstr("a.txt"),
+            bstr("length"),
+            bstr("path"),
+            bstr("b.bin"),
+            bstr("name"),
+            bstr("pack"),
+            bstr("piece length"),
+            bstr("pieces"),
+        )
+        .into_bytes();
+        bytes.extend_from_slice(&[0xcd; 60]);
+        bytes.extend_from_slice(format!("{}i1eee", bstr("private")).as_bytes());
+        bytes
+    }
+
+    #[test]
+    fn test_single_file_metainfo() {
+        let metainfo = Metainfo::from_bytes(&single_file_bytes()).unwrap();
+        assert_eq!(
+            metainfo.announce.as_deref(),
+            Some("http://tracker.example/announce")
+        );
+        assert_eq!(metainfo.comment.as_deref(), Some("test"));
+        assert_eq!(metainfo.created_by.as_deref(), Some("jubjub"));
+        assert_eq!(metainfo.creation_date, Some(1700000000));
+        assert_eq!(metainfo.info.name, "file.txt");
+        assert_eq!(metainfo.info.total_length(), 20);
+        assert_eq!(metainfo.info.piece_count(), 2);
+        assert_eq!(metainfo.info.piece_size(1), Some(4));
+        assert_eq!(metainfo.info.piece_hash(1), Some(&[0xab; 20][..]));
+        assert!(!metainfo.info.is_multi_file());
+        assert!(!metainfo.info.is_private());
+        assert_eq!(
+            metainfo.trackers(),
+            vec![vec!["http://tracker.example/announce".to_string()]]
+        );
+    }
+
+    #[test]
+    fn test_multi_file_metainfo() {
+        let metainfo = Metainfo::from_bytes(&multi_file_bytes()).unwrap();
+        assert!(metainfo.announce.is_none());
+        assert_eq!(metainfo.trackers().len(), 2);
+        assert!(metainfo.info.is_multi_file());
+        assert!(metainfo.info.is_private());
+        assert_eq!(metainfo.info.total_length(), 40);
+        assert_eq!(
+            metainfo.info.file_paths(),
+            vec![
+                (PathBuf::from("pack/dir/a.txt"), 10),
+                (PathBuf::from("pack/b.bin"), 30)
+            ]
+        );
+    }
+
+    #[test]
+    fn test_metainfo_roundtrip() {
+        let metainfo = Metainfo::from_bytes(&multi_file_bytes()).unwrap();
+        let bytes = metainfo.to_bytes().unwrap();
+        assert_eq!(Metainfo::from_bytes(&bytes).unwrap(), metainfo);
+    }
+
+    #[test]
+    fn test_from_raw_info() {
+        let original = multi_file_bytes();
+        let info = Metainfo::raw_info(&original).unwrap();
+        let trackers = vec![vec!["http://a.example/ann".to_string()]];
+        let (metainfo, bytes) = Metainfo::from_raw_info(info, &trackers).unwrap();
+        assert_eq!(Metainfo::raw_info(&bytes).unwrap(), info);
+        assert_eq!(metainfo.announce.as_deref(), Some("http://a.example/ann"));
+        assert_eq!(metainfo.trackers(), trackers);
+        assert_eq!(metainfo.info.name, "pack");
+
+        let (metainfo, bytes) = Metainfo::from_raw_info(info, &[]).unwrap();
+        assert!(metainfo.trackers().is_empty());
+        assert!(bytes.starts_with(b"d4:info"));
+        assert!(Metainfo::from_raw_info(b"d4:name1:ae", &[]).is_err());
+    }
+
+    #[test]
+    fn test_url_list() {
+        let mut single = single_file_bytes();
+        single.pop();
+        single
+            .extend_from_slice(format!("{}{}e", bstr("url-list"), bstr("http://seed/")).as_bytes());
+        let metainfo = Metainfo::from_bytes(&single).unwrap();
+        assert_eq!(metainfo.url_list, vec!["http://seed/".to_string()]);
+
+        let mut many = single_file_bytes();
+        many.pop();
+        many.extend_from_slice(
+            format!(
+                "{}l{}{}ee",
+                bstr("url-list"),
+                bstr("http://a/"),
+                bstr("http://b/")
+            )
+            .as_bytes(),
+        );
+        let metainfo = Metainfo::from_bytes(&many).unwrap();
+        assert_eq!(metainfo.url_list.len(), 2);
+        let roundtrip = Metainfo::from_bytes(&metainfo.to_bytes().unwrap()).unwrap();
+        assert_eq!(roundtrip, metainfo);
+    }
+
+    #[test]
+    fn test_invalid_metainfo() {
+        // 20 bytes at 16 bytes per piece needs two hashes, only one is given
+        let mut bytes = Vec::new();
+        bytes.extend_from_slice(b"d4:infod6:lengthi20e4:name1:a12:piece lengthi16e6:pieces20:");
+        bytes.extend_from_slice(&[0u8; 20]);
+        bytes.extend_from_slice(b"ee");
+        assert!(matches!(
+            Metainfo::from_bytes(&bytes),
+            Err(MetainfoError::Invalid(_))
+        ));
+        assert!(matches!(
+            Metainfo::from_bytes(b"d4:infoi1ee"),
+            Err(MetainfoError::Bencode(_))
+        ));
+    }
+
+    fn single_file_with(name: &str, piece_length: u64) -> Vec<u8> {
+        let mut bytes = format!(
+            "d4:infod6:lengthi20e{}{}{}i{}e6:pieces20:",
+            bstr("name"),
+            bstr(name),
+            bstr("piece length"),
+            piece_length
+        )
+        .into_bytes();
+        bytes.extend_from_slice(&[0u8; 20]);
+        bytes.extend_from_slice(b"ee");
+        bytes
+    }
+
+    #[test]
+    fn test_unsafe_name() {
+        assert!(Metainfo::from_bytes(&single_file_with("ok.txt", 32)).is_ok());
+        for name in ["../../.bashrc", "/etc/x", "..", ".", "a/b", "a\\b"] {
+            assert!(
+                matches!(
+                    Metainfo::from_bytes(&single_file_with(name, 32)),
+                    Err(MetainfoError::Invalid(_))
+                ),
+                "{:?} accepted",
+                name
+            );
+        }
+    }
+
+    #[test]
+    fn test_piece_length_bounds() {
+        // BEP 3 doesn't require a power of two
+        assert!(Metainfo::from_bytes(&single_file_with("a", 24)).is_ok());
+        assert!(Metainfo::from_bytes(&single_file_with("a", 0)).is_err());
+        assert!(Metainfo::from_bytes(&single_file_with("a", MAX_PIECE_LENGTH * 2)).is_err());
+    }
+
+    #[test]
+    fn test_total_length_overflow() {
+        let mut bytes = format!(
+            "d4:infod5:filesld6:lengthi{0}e4:pathl1:aeed6:lengthi{0}e4:pathl1:beed6:lengthi{0}e4:pathl1:ceee{1}{2}{3}i32e6:pieces20:",
+            i64::MAX,
+            bstr("name"),
+            bstr("pack"),
+            bstr("piece length"),
+        )
+        .into_bytes();
+        bytes.extend_from_slice(&[0u8; 20]);
+        bytes.extend_from_slice(b"ee");
+        assert!(matches!(
+            Metainfo::from_bytes(&bytes),
+            Err(MetainfoError::Invalid(_))
+        ));
+    }
+}
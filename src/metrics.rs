@@ -25,7 +25,7 @@ impl MetricServer {
     pub fn new(registry: Arc<RwLock<Registry>>, config: Arc<RwLock<Settings>>) -> Self {
         MetricServer { registry, config }
     }
-    fn get_registry(&self) -> Arc<RwLock<Registry>> {
+    pub(crate) fn get_registry(&self) -> Arc<RwLock<Registry>> {
         Arc::clone(&self.registry)
     }
 }
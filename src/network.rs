@@ -5,8 +5,16 @@ use libp2p::Multiaddr;
 
 use crate::client::arguments::ClientCommand;
 use crate::client::arguments::Settings;
+use crate::db::{ResumeData, ResumeStore, TorrentRecord};
+use crate::dht::DhtNode;
+use crate::magnet::MagnetLink;
 use crate::metrics::MetricServer;
-use crate::peer::client::ClientMode;
+use crate::peer::choker::{Choker, ChokerSettings};
+use crate::peer::client::{generate_peer_id, ClientMode};
+use crate::peer::connection::PeerKey;
+use crate::peer::tracker::tiers::TrackerTiers;
+use crate::peer::tracker::{AnnounceResponse, TrackerError};
+use crate::storage::{Storage, StorageError, StorageSettings};
 use crate::types;
 use crate::types::Event;
 use crate::{
@@ -23,10 +31,12 @@ use libp2p::{
     tcp, PeerId, SwarmBuilder,
 };
 use prometheus_client::registry::Registry;
+use sha1::{Digest, Sha1};
 use std::error::Error;
+use std::net::SocketAddr;
 use std::sync::Arc;
 use std::sync::RwLock;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 use thiserror::Error;
 use tracing::info;
 const BOOTNODES: [&str; 4] = [
@@ -36,6 +46,14 @@ const BOOTNODES: [&str; 4] = [
     "QmcZf59bWwK5XFi76CZX8cbJ4BhTzzA3gU1ZjYZcYW3dwt",
 ];
 const IPFS_PROTO_NAME: StreamProtocol = StreamProtocol::new("/ipfs/kad/1.0.0");
+// how often torrents are checked for due announces
+const TICK_INTERVAL: Duration = Duration::from_secs(1);
+// how often written pieces are flushed and resume data saved
+const FLUSH_INTERVAL: Duration = Duration::from_secs(60);
+// how often torrents look up their peers on the DHT
+const DHT_INTERVAL: Duration = Duration::from_secs(15 * 60);
+// how long shutdown waits for the trackers to hear we stopped
+const STOP_TIMEOUT: Duration = Duration::from_secs(5);
 
 #[derive(Debug, Error)]
 pub enum NetworkError {
@@ -70,12 +88,14 @@ pub(crate) async fn new(
         tcp_addr,
         workers,
         download_dir,
+        upload_slots,
     ) = {
         let config_guard = config.read().unwrap();
         (
             config_guard.tcp.address.clone(),
             config_guard.tcp.socket_workers,
             config_guard.download_dir.clone(),
+            config_guard.upload_slots,
         )
     };
     info!("Peer id: {:?}. Public key: {:?}", peer_id, keys.public());
@@ -117,7 +137,16 @@ pub(crate) async fn new(
             mode,
         },
         event_rx,
-        Session::new(swarm, metrics, command_rx, event_tx),
+        Session::new(
+            swarm,
+            metrics,
+            command_rx,
+            event_tx,
+            ChokerSettings {
+                upload_slots,
+                ..Default::default()
+            },
+        ),
     ))
 }
 
@@ -137,7 +166,45 @@ pub(crate) struct Session {
     provider_query_tx_map: HashMap<kad::QueryId, oneshot::Sender<()>>,
     request_file_map:
         HashMap<OutboundRequestId, oneshot::Sender<Result<Vec<u8>, Box<dyn Error + Send>>>>,
+    // the torrent each file request downloads for, counted once answered
+    request_torrent_map: HashMap<OutboundRequestId, types::InfoHash>,
     query_peer_map: HashMap<kad::QueryId, oneshot::Sender<std::collections::HashSet<PeerId>>>,
+    torrents: HashMap<types::InfoHash, types::Torrent>,
+    peer_id: [u8; 20],
+    // port of our BitTorrent listener, the libp2p one doesn't speak the
+    // wire protocol, so there is none to publish yet
+    bt_port: Option<u16>,
+    // results of jobs spawned by the session, e.g. tracker announces
+    updates_tx: mpsc::Sender<SessionUpdate>,
+    updates_rx: mpsc::Receiver<SessionUpdate>,
+    resume: Option<ResumeStore>,
+    last_flush: Instant,
+    // upload slots for the peers requesting files from us
+    choker: Choker,
+    dht: Option<DhtNode>,
+    last_dht: Option<Instant>,
+    // latest queue state of each torrent, for torrents added after it
+    states: HashMap<types::InfoHash, types::FileStatus>,
+}
+
+enum SessionUpdate {
+    Announced {
+        info_hash: types::InfoHash,
+        tiers: TrackerTiers,
+        result: Result<AnnounceResponse, TrackerError>,
+    },
+    Checked {
+        info_hash: types::InfoHash,
+        result: Result<types::Bitfield, StorageError>,
+    },
+    Saved {
+        info_hash: types::InfoHash,
+        data: ResumeData,
+    },
+    DhtPeers {
+        info_hash: types::InfoHash,
+        peers: Vec<SocketAddr>,
+    },
 }
 
 impl Session {
@@ -146,7 +213,9 @@ impl Session {
         metrics: MetricServer,
         command_rx: mpsc::Receiver<ClientCommand>,
         event_tx: mpsc::Sender<types::Event>,
+        choker: ChokerSettings,
     ) -> Self {
+        let (updates_tx, updates_rx) = mpsc::channel(32);
         Self {
             swarm,
             metrics,
@@ -156,17 +225,291 @@ impl Session {
             request_cmd_map: Default::default(),
             provider_query_tx_map: Default::default(),
             request_file_map: Default::default(),
+            request_torrent_map: Default::default(),
             query_peer_map: Default::default(),
+            torrents: Default::default(),
+            peer_id: generate_peer_id(),
+            bt_port: None,
+            updates_tx,
+            updates_rx,
+            resume: None,
+            last_flush: Instant::now(),
+            choker: Choker::new(choker),
+            dht: None,
+            last_dht: None,
+            states: Default::default(),
+        }
+    }
+
+    /// Torrents find peers on the DHT through `node` from now on.
+    pub fn set_dht(&mut self, node: DhtNode) {
+        self.dht = Some(node);
+    }
+
+    // private torrents (BEP 27) stay off the DHT
+    fn discover(&mut self, now: Instant) {
+        let Some(node) = self.dht.clone() else {
+            return;
+        };
+        let due = self
+            .last_dht
+            .is_none_or(|last| now.duration_since(last) >= DHT_INTERVAL);
+        // nothing to ask before the node has joined
+        if !due || node.node_count() == 0 {
+            return;
+        }
+        self.last_dht = Some(now);
+        let port = self.bt_port;
+        for torrent in self
+            .torrents
+            .values()
+            .filter(|torrent| torrent.is_active() && !torrent.is_private())
+        {
+            let info_hash = torrent.info_hash;
+            let node = node.clone();
+            let mut updates = self.updates_tx.clone();
+            // announce_peer would publish a port nobody can connect to
+            tokio::spawn(async move {
+                let peers = match port {
+                    Some(port) => node.announce(info_hash, Some(port)).await,
+                    None => node.get_peers(info_hash).await,
+                };
+                let _ = updates
+                    .send(SessionUpdate::DhtPeers { info_hash, peers })
+                    .await;
+            });
+        }
+    }
+
+    /// Adds the torrents of the library. Their pieces come from the resume
+    /// data when the files are unchanged, otherwise they are rechecked.
+    pub fn restore(&mut self, records: Vec<TorrentRecord>, resume: ResumeStore) {
+        for record in records {
+            let torrent = match (&record.metainfo, &record.magnet) {
+                (Some(bytes), _) => types::Torrent::from_bytes(bytes).map_err(|e| e.to_string()),
+                (None, Some(uri)) => MagnetLink::parse(uri)
+                    .map(types::Torrent::from_magnet)
+                    .map_err(|e| e.to_string()),
+                (None, None) => Err("no metainfo or magnet".to_string()),
+            };
+            let mut torrent = match torrent {
+                Ok(torrent) => torrent,
+                Err(e) => {
+                    tracing::warn!("Not restoring {}: {}", record.name, e);
+                    continue;
+                }
+            };
+            let info_hash = torrent.info_hash;
+            torrent.set_active(record.state.is_active());
+            self.states.insert(info_hash, record.state);
+            if torrent
+                .attach_storage(&record.save_path, StorageSettings::default())
+                .is_some()
+            {
+                let have = match resume.load(&info_hash) {
+                    Ok(Some(data)) => torrent.restore(data),
+                    Ok(None) => None,
+                    Err(e) => {
+                        tracing::warn!("Unreadable resume data for {}: {}", info_hash, e);
+                        None
+                    }
+                };
+                match have {
+                    Some(have) => {
+                        if torrent.set_have(have) && record.completed.is_none() {
+                            self.completed(info_hash);
+                        }
+                    }
+                    None => self.recheck(&torrent),
+                }
+            }
+            self.torrents.insert(info_hash, torrent);
+        }
+        self.resume = Some(resume);
+    }
+
+    fn completed(&mut self, info_hash: types::InfoHash) {
+        if let Err(e) = self.event_tx.try_send(Event::Completed(info_hash)) {
+            tracing::warn!("Completion of {} not reported: {}", info_hash, e);
+        }
+    }
+
+    fn recheck(&self, torrent: &types::Torrent) {
+        let (Some(storage), Some(metainfo)) = (torrent.storage.clone(), &torrent.metainfo) else {
+            return;
+        };
+        let info_hash = torrent.info_hash;
+        let info = metainfo.info.clone();
+        let mut updates = self.updates_tx.clone();
+        tokio::spawn(async move {
+            let result = storage.recheck(&info).await;
+            let _ = updates
+                .send(SessionUpdate::Checked { info_hash, result })
+                .await;
+        });
+    }
+
+    /// Flushes every torrent's writes and saves its resume data in the
+    /// background.
+    fn save_resume_data(&mut self) {
+        let Some(store) = self.resume.clone() else {
+            return;
+        };
+        for (info_hash, torrent) in self.torrents.iter_mut() {
+            let (Some(storage), Some(have)) = (torrent.storage.clone(), torrent.have().cloned())
+            else {
+                continue;
+            };
+            let Some(data) = torrent.resume_data() else {
+                continue;
+            };
+            let info_hash = *info_hash;
+            let store = store.clone();
+            let mut updates = self.updates_tx.clone();
+            tokio::spawn(async move {
+                if let Some(data) = save_resume(&storage, &have, data, &store).await {
+                    let _ = updates.send(SessionUpdate::Saved { info_hash, data }).await;
+                }
+            });
+        }
+    }
+
+    /// Tells the trackers we stopped and saves everything before the
+    /// process exits.
+    async fn shutdown(&mut self) {
+        let (peer_id, port) = (self.peer_id, self.tracker_port());
+        let stops = self.torrents.values_mut().filter_map(|torrent| {
+            let (mut tiers, request) = torrent.stop_announce(peer_id, port)?;
+            Some(async move {
+                let _ = tiers.announce(&request).await;
+            })
+        });
+        if tokio::time::timeout(STOP_TIMEOUT, future::join_all(stops))
+            .await
+            .is_err()
+        {
+            tracing::debug!("Not every tracker heard we stopped");
+        }
+        let Some(store) = self.resume.clone() else {
+            return;
+        };
+        for torrent in self.torrents.values_mut() {
+            let (Some(storage), Some(have)) = (torrent.storage.clone(), torrent.have().cloned())
+            else {
+                continue;
+            };
+            if let Some(data) = torrent.resume_data() {
+                save_resume(&storage, &have, data, &store).await;
+            }
+        }
+        if let Err(e) = store.flush().await {
+            tracing::warn!("Failed to flush resume data: {}", e);
         }
     }
 
     pub(crate) async fn run(mut self) {
+        let mut tick = tokio::time::interval(TICK_INTERVAL);
         loop {
             tokio::select! {
                 event = self.swarm.select_next_some() => self.handle_event(event).await,
                 command = self.command_rx.next() => match command {
+                    Some(ClientCommand::Shutdown { tx }) => {
+                        self.shutdown().await;
+                        let _ = tx.send(());
+                        return;
+                    }
                     Some(command) => self.handle_command(command).await,
                     None => return,
+                },
+                update = self.updates_rx.select_next_some() => self.handle_update(update),
+                _ = tick.tick() => self.tick(),
+            }
+        }
+    }
+
+    /// Starts the background work that is due, e.g. tracker announces.
+    fn tick(&mut self) {
+        let now = Instant::now();
+        if now.duration_since(self.last_flush) >= FLUSH_INTERVAL {
+            self.last_flush = now;
+            self.save_resume_data();
+        }
+        // libp2p peers get no choke messages, the unchoked set only decides
+        // whose requests are served
+        let seeding = self.seeding();
+        self.choker.tick(now, seeding);
+        self.discover(now);
+        let port = self.tracker_port();
+        for (info_hash, torrent) in self.torrents.iter_mut() {
+            let announce = if torrent.is_active() {
+                torrent.start_announce(self.peer_id, port, now)
+            } else {
+                torrent.stop_announce(self.peer_id, port)
+            };
+            if let Some((mut tiers, request)) = announce {
+                let info_hash = *info_hash;
+                let mut updates = self.updates_tx.clone();
+                tokio::spawn(async move {
+                    let result = tiers.announce(&request).await;
+                    let _ = updates
+                        .send(SessionUpdate::Announced {
+                            info_hash,
+                            tiers,
+                            result,
+                        })
+                        .await;
+                });
+            }
+        }
+    }
+
+    // without a listener trackers get port 0, which hands us peers without
+    // listing us
+    fn tracker_port(&self) -> u16 {
+        self.bt_port.unwrap_or(0)
+    }
+
+    // the choker ranks peers by what we upload to them once there is nothing
+    // left to download
+    fn seeding(&self) -> bool {
+        self.torrents
+            .values()
+            .filter(|torrent| torrent.is_active())
+            .all(types::Torrent::is_complete)
+    }
+
+    fn handle_update(&mut self, update: SessionUpdate) {
+        match update {
+            SessionUpdate::Announced {
+                info_hash,
+                tiers,
+                result,
+            } => {
+                if let Some(torrent) = self.torrents.get_mut(&info_hash) {
+                    let added = torrent.finish_announce(tiers, result);
+                    tracing::debug!("{} new peers from trackers for {}", added, info_hash);
+                }
+            }
+            SessionUpdate::Checked { info_hash, result } => match result {
+                Ok(have) => {
+                    if let Some(torrent) = self.torrents.get_mut(&info_hash) {
+                        info!("Recheck of {} found {} pieces", info_hash, have.count());
+                        if torrent.set_have(have) {
+                            self.completed(info_hash);
+                        }
+                    }
+                }
+                Err(e) => tracing::warn!("Recheck of {} failed: {}", info_hash, e),
+            },
+            SessionUpdate::Saved { info_hash, data } => {
+                if let Some(torrent) = self.torrents.get_mut(&info_hash) {
+                    torrent.set_resume_data(data);
+                }
+            }
+            SessionUpdate::DhtPeers { info_hash, peers } => {
+                if let Some(torrent) = self.torrents.get_mut(&info_hash) {
+                    let added = torrent.add_dht_peers(peers);
+                    tracing::debug!("{} new peers from the DHT for {}", added, info_hash);
                 }
             }
         }
@@ -219,13 +562,25 @@ impl Session {
             )) => {}
             SwarmEvent::Behaviour(BehaviourEvent::Kademlia(_)) => {}
             SwarmEvent::Behaviour(BehaviourEvent::RequestResponse(
-                request_response::Event::Message { message, .. },
+                request_response::Event::Message { peer, message },
             )) => match message {
                 request_response::Message::Request {
                     request, channel, ..
                 } => {
+                    let key = peer_key(&peer);
+                    self.choker.set_interested(&key, true);
+                    if !self.choker.is_unchoked(&key) && self.choker.has_free_slot() {
+                        let seeding = self.seeding();
+                        self.choker.rechoke(Instant::now(), seeding);
+                    }
+                    if !self.choker.is_unchoked(&key) {
+                        // dropping the channel refuses the request
+                        tracing::debug!("Refusing request from choked peer {}", peer);
+                        return;
+                    }
                     self.event_tx
                         .send(Event::InboundRequest {
+                            peer,
                             request: request.0,
                             channel,
                         })
@@ -236,6 +591,15 @@ impl Session {
                     request_id,
                     response,
                 } => {
+                    self.choker
+                        .downloaded(&peer_key(&peer), response.0.len() as u64);
+                    if let Some(torrent) = self
+                        .request_torrent_map
+                        .remove(&request_id)
+                        .and_then(|info_hash| self.torrents.get_mut(&info_hash))
+                    {
+                        torrent.add_transferred(0, response.0.len() as u64);
+                    }
                     let _ = self
                         .request_file_map
                         .remove(&request_id)
@@ -248,6 +612,7 @@ impl Session {
                     request_id, error, ..
                 },
             )) => {
+                self.request_torrent_map.remove(&request_id);
                 let _ = self
                     .request_file_map
                     .remove(&request_id)
@@ -268,6 +633,7 @@ impl Session {
             SwarmEvent::ConnectionEstablished {
                 peer_id, endpoint, ..
             } => {
+                self.choker.add_peer(peer_key(&peer_id), Instant::now());
                 if endpoint.is_dialer() {
                     info!("Dialer {:?} connected at {:?}", peer_id, endpoint);
                     if let Some(sender) = self.pending_dial.remove(&peer_id) {
@@ -275,6 +641,16 @@ impl Session {
                     }
                 }
             }
+            SwarmEvent::ConnectionClosed {
+                peer_id,
+                num_established: 0,
+                ..
+            } => {
+                if self.choker.remove_peer(&peer_key(&peer_id)) {
+                    let seeding = self.seeding();
+                    self.choker.rechoke(Instant::now(), seeding);
+                }
+            }
             SwarmEvent::ConnectionClosed { .. } => {}
             SwarmEvent::OutgoingConnectionError { peer_id, error, .. } => {
                 if let Some(peer_id) = peer_id {
@@ -306,19 +682,24 @@ impl Session {
                 peer,
                 tx,
             } => {
+                let info_hash = torrent.parse::<types::InfoHash>().ok();
                 let request_id = self
                     .swarm
                     .behaviour_mut()
                     .request_response
                     .send_request(&peer, TorrentRequest(torrent));
                 self.request_file_map.insert(request_id, tx);
+                if let Some(info_hash) = info_hash {
+                    self.request_torrent_map.insert(request_id, info_hash);
+                }
             }
             ClientCommand::GetPeersCommand { torrent, tx } => {
-                let query_id = self
-                    .swarm
-                    .behaviour_mut()
-                    .kademlia
-                    .get_providers(torrent.into_bytes().into());
+                // torrents are provided under their info-hash, see ProvideTorrent
+                let key = match torrent.parse::<types::InfoHash>() {
+                    Ok(info_hash) => kad::RecordKey::new(info_hash.as_bytes()),
+                    Err(_) => torrent.into_bytes().into(),
+                };
+                let query_id = self.swarm.behaviour_mut().kademlia.get_providers(key);
                 self.query_peer_map.insert(query_id, tx);
             }
             ClientCommand::DialCommand {
@@ -350,18 +731,168 @@ impl Session {
                     Err(e) => tx.send(Err(Box::new(e))),
                 };
             }
-            ClientCommand::ProvideTorrent {
-                file: _,
-                channel: _,
+            ClientCommand::ProvideTorrent { file, channel } => {
+                let torrent = match types::Torrent::from_bytes(&file) {
+                    Ok(torrent) => torrent,
+                    Err(e) => {
+                        tracing::warn!("Not providing invalid torrent: {}", e);
+                        return;
+                    }
+                };
+                let info_hash = torrent.info_hash;
+                match self
+                    .swarm
+                    .behaviour_mut()
+                    .kademlia
+                    .start_providing(kad::RecordKey::new(info_hash.as_bytes()))
+                {
+                    Ok(query_id) => {
+                        info!("Providing {} ({})", torrent.name(), info_hash);
+                        self.provider_query_tx_map.insert(query_id, channel);
+                        self.torrents.entry(info_hash).or_insert(torrent);
+                    }
+                    Err(e) => tracing::warn!("Failed to provide {}: {:?}", info_hash, e),
+                }
+            }
+            ClientCommand::RespondFile {
+                peer,
+                torrent,
+                file,
+                channel,
+            } => {
+                let bytes = file.len() as u64;
+                if self
+                    .swarm
+                    .behaviour_mut()
+                    .request_response
+                    .send_response(channel, TorrentResponse(file))
+                    .is_err()
+                {
+                    tracing::debug!("{} left before its response was sent", peer);
+                    return;
+                }
+                self.choker.uploaded(&peer_key(&peer), bytes);
+                if let Some(torrent) = torrent
+                    .parse::<types::InfoHash>()
+                    .ok()
+                    .and_then(|info_hash| self.torrents.get_mut(&info_hash))
+                {
+                    torrent.add_transferred(bytes, 0);
+                }
+            }
+            ClientCommand::AddMagnet { magnet, tx } => {
+                let info_hash = magnet.swarm_hash();
+                info!("Adding magnet {:?} ({})", magnet.display_name, info_hash);
+                let state = self.states.get(&info_hash).copied();
+                self.torrents.entry(info_hash).or_insert_with(|| {
+                    let mut torrent = types::Torrent::from_magnet(magnet);
+                    torrent.set_active(state.is_none_or(types::FileStatus::is_active));
+                    torrent
+                });
+                let _ = tx.send(info_hash);
+            }
+            ClientCommand::AddTorrent {
+                file,
+                save_path,
+                tx,
             } => {
-                unimplemented!()
+                let mut torrent = match types::Torrent::from_bytes(&file) {
+                    Ok(torrent) => torrent,
+                    Err(e) => {
+                        tracing::warn!("Not adding invalid torrent: {}", e);
+                        return;
+                    }
+                };
+                let info_hash = torrent.info_hash;
+                info!("Adding {} ({})", torrent.name(), info_hash);
+                if !self.torrents.contains_key(&info_hash) {
+                    let state = self.states.get(&info_hash).copied();
+                    torrent.set_active(state.is_none_or(types::FileStatus::is_active));
+                    // the files may already be there, e.g. from another client
+                    if torrent
+                        .attach_storage(&save_path, StorageSettings::default())
+                        .is_some()
+                    {
+                        self.recheck(&torrent);
+                    }
+                    self.torrents.insert(info_hash, torrent);
+                }
+                let _ = tx.send(info_hash);
             }
+            ClientCommand::TrackerList { tx } => {
+                let trackers = self
+                    .torrents
+                    .values()
+                    .flat_map(|torrent| {
+                        torrent
+                            .trackers()
+                            .into_iter()
+                            .map(|tracker| (tracker, torrent.info_hash))
+                    })
+                    .collect();
+                let _ = tx.send(trackers);
+            }
+            ClientCommand::UpdateScrape { stats, tx } => {
+                for (info_hash, stats) in stats {
+                    if let Some(torrent) = self.torrents.get_mut(&info_hash) {
+                        torrent.update_scrape(stats);
+                    }
+                }
+                let mut health: Vec<types::SwarmHealth> =
+                    self.torrents.values().map(types::Torrent::health).collect();
+                health.sort_by(|a, b| a.name.cmp(&b.name));
+                let _ = tx.send(health);
+            }
+            ClientCommand::TrackerStatus { info_hash, tx } => {
+                let statuses = self
+                    .torrents
+                    .get(&info_hash)
+                    .map(|torrent| torrent.tiers.statuses());
+                let _ = tx.send(statuses);
+            }
+            ClientCommand::SetStates { states } => {
+                for (info_hash, state) in states {
+                    self.states.insert(info_hash, state);
+                    if let Some(torrent) = self.torrents.get_mut(&info_hash) {
+                        torrent.set_active(state.is_active());
+                    }
+                }
+            }
+            // handled in run, which stops the session afterwards
+            ClientCommand::Shutdown { .. } => {}
         }
     }
 
     // unimplemented!()
     // match command {}
 }
+/// The choker works on 20 byte peer ids, libp2p ids are longer.
+fn peer_key(peer: &PeerId) -> PeerKey {
+    Sha1::digest(peer.to_bytes()).into()
+}
+
+/// Flushes the storage, then records the pieces and file stamps. Returns
+/// the saved data.
+async fn save_resume(
+    storage: &Storage,
+    have: &types::Bitfield,
+    mut data: ResumeData,
+    store: &ResumeStore,
+) -> Option<ResumeData> {
+    if let Err(e) = storage.flush().await {
+        tracing::warn!("Not saving resume data of {}: {}", data.info_hash, e);
+        return None;
+    }
+    data.update(have, storage.layout());
+    match store.save(&data) {
+        Ok(()) => Some(data),
+        Err(e) => {
+            tracing::warn!("Failed to save resume data of {}: {}", data.info_hash, e);
+            None
+        }
+    }
+}
+
 async fn fetch_providers() -> Result<ProviderResult, NetworkError> {
     todo!()
 }
@@ -1,3 +1,6 @@
+use std::ops::Range;
+use thiserror::Error;
+
 pub fn chi_squared_test(observed: &Vec<f64>, expected: &Vec<f64>) -> f64 {
     let mut chi_squared = 0.0;
     for i in 0..observed.len() {
@@ -5,3 +8,107 @@ pub fn chi_squared_test(observed: &Vec<f64>, expected: &Vec<f64>) -> f64 {
     }
     chi_squared
 }
+
+#[derive(Debug, Error, PartialEq, Eq)]
+pub enum BencodeError {
+    #[error("Unexpected end of input at byte {0}")]
+    Eof(usize),
+    #[error("Unexpected byte {1:#04x} at byte {0}")]
+    Unexpected(usize, u8),
+    #[error("Expected a dictionary at byte {0}")]
+    NotADict(usize),
+    #[error("Missing key {0}")]
+    MissingKey(String),
+}
+
+/// Returns the end offset of the bencoded value starting at `pos`, without decoding it.
+pub fn skip_value(bytes: &[u8], pos: usize) -> Result<usize, BencodeError> {
+    match bytes.get(pos) {
+        None => Err(BencodeError::Eof(pos)),
+        Some(b'i') => {
+            let end = find(bytes, pos + 1, b'e')?;
+            Ok(end + 1)
+        }
+        Some(b'l') => {
+            let mut cursor = pos + 1;
+            while bytes.get(cursor) != Some(&b'e') {
+                cursor = skip_value(bytes, cursor)?;
+            }
+            Ok(cursor + 1)
+        }
+        Some(b'd') => {
+            let mut cursor = pos + 1;
+            while bytes.get(cursor) != Some(&b'e') {
+                cursor = skip_string(bytes, cursor)?.end;
+                cursor = skip_value(bytes, cursor)?;
+            }
+            Ok(cursor + 1)
+        }
+        Some(b'0'..=b'9') => Ok(skip_string(bytes, pos)?.end),
+        Some(byte) => Err(BencodeError::Unexpected(pos, *byte)),
+    }
+}
+
+/// Returns the byte range of the contents of the string starting at `pos`.
+fn skip_string(bytes: &[u8], pos: usize) -> Result<Range<usize>, BencodeError> {
+    let colon = find(bytes, pos, b':')?;
+    let len = std::str::from_utf8(&bytes[pos..colon])
+        .ok()
+        .and_then(|len| len.parse::<usize>().ok())
+        .ok_or(BencodeError::Unexpected(pos, bytes[pos]))?;
+    let start = colon + 1;
+    let end = start.checked_add(len).ok_or(BencodeError::Eof(start))?;
+    if end > bytes.len() {
+        return Err(BencodeError::Eof(bytes.len()));
+    }
+    Ok(start..end)
+}
+
+fn find(bytes: &[u8], from: usize, needle: u8) -> Result<usize, BencodeError> {
+    bytes
+        .get(from..)
+        .and_then(|rest| rest.iter().position(|byte| *byte == needle))
+        .map(|offset| from + offset)
+        .ok_or(BencodeError::Eof(bytes.len()))
+}
+
+/// Byte range of the raw value stored under `key` in the top level dictionary.
+pub fn dict_value_span(bytes: &[u8], key: &[u8]) -> Result<Range<usize>, BencodeError> {
+    if bytes.first() != Some(&b'd') {
+        return Err(BencodeError::NotADict(0));
+    }
+    let mut cursor = 1;
+    while bytes.get(cursor) != Some(&b'e') {
+        let name = skip_string(bytes, cursor)?;
+        let value_end = skip_value(bytes, name.end)?;
+        if &bytes[name.clone()] == key {
+            return Ok(name.end..value_end);
+        }
+        cursor = value_end;
+    }
+    Err(BencodeError::MissingKey(
+        String::from_utf8_lossy(key).into_owned(),
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_dict_value_span() {
+        let bytes = b"d3:fooi42e4:infod4:listl1:ai-1eee3:zzz0:e";
+        let span = dict_value_span(bytes, b"info").unwrap();
+        assert_eq!(&bytes[span], b"d4:listl1:ai-1eee");
+        assert_eq!(&bytes[dict_value_span(bytes, b"zzz").unwrap()], b"0:");
+        assert_eq!(
+            dict_value_span(bytes, b"missing"),
+            Err(BencodeError::MissingKey("missing".to_string()))
+        );
+        assert_eq!(
+            dict_value_span(b"l1:ae", b"info"),
+            Err(BencodeError::NotADict(0))
+        );
+        assert!(dict_value_span(b"d4:infod3:key99:shortee", b"info").is_err());
+    }
+}
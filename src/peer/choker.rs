@@ -0,0 +1,357 @@
+use crate::peer::connection::{PeerCommand, PeerKey};
+use hashbrown::HashMap;
+use rand::Rng;
+use std::time::{Duration, Instant};
+
+#[derive(Debug, Clone, Copy)]
+pub struct ChokerSettings {
+    /// Peers unchoked at once, the optimistic unchoke included.
+    pub upload_slots: usize,
+    /// How often the regular unchokes are recalculated.
+    pub interval: Duration,
+    /// How often the optimistic unchoke moves to another peer.
+    pub optimistic_interval: Duration,
+}
+
+impl Default for ChokerSettings {
+    fn default() -> Self {
+        Self {
+            upload_slots: 4,
+            interval: Duration::from_secs(10),
+            optimistic_interval: Duration::from_secs(30),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+struct ChokerPeer {
+    connected: Instant,
+    interested: bool,
+    unchoked: bool,
+    // bytes since the last round
+    downloaded: u64,
+    uploaded: u64,
+    // bytes per second over the last round
+    download_rate: f64,
+    upload_rate: f64,
+}
+
+/// Tit-for-tat upload slots for one torrent. Every `interval` the interested
+/// peers we download from fastest (upload to fastest when seeding) are
+/// unchoked, and every `optimistic_interval` one more peer is unchoked at
+/// random so new peers get a chance to prove themselves.
+#[derive(Debug, Clone)]
+pub struct Choker {
+    settings: ChokerSettings,
+    peers: HashMap<PeerKey, ChokerPeer>,
+    optimistic: Option<PeerKey>,
+    last_round: Option<Instant>,
+    last_optimistic: Option<Instant>,
+}
+
+impl Choker {
+    pub fn new(settings: ChokerSettings) -> Self {
+        Self {
+            settings,
+            peers: HashMap::new(),
+            optimistic: None,
+            last_round: None,
+            last_optimistic: None,
+        }
+    }
+
+    pub fn add_peer(&mut self, peer: PeerKey, now: Instant) {
+        self.peers.entry(peer).or_insert(ChokerPeer {
+            connected: now,
+            interested: false,
+            unchoked: false,
+            downloaded: 0,
+            uploaded: 0,
+            download_rate: 0.0,
+            upload_rate: 0.0,
+        });
+    }
+
+    /// Returns true if the peer held a slot, which is then free to hand out
+    /// with `rechoke`.
+    pub fn remove_peer(&mut self, peer: &PeerKey) -> bool {
+        if self.optimistic == Some(*peer) {
+            self.optimistic = None;
+        }
+        self.peers
+            .remove(peer)
+            .map(|state| state.unchoked)
+            .unwrap_or(false)
+    }
+
+    pub fn set_interested(&mut self, peer: &PeerKey, interested: bool) {
+        if let Some(state) = self.peers.get_mut(peer) {
+            state.interested = interested;
+        }
+    }
+
+    pub fn downloaded(&mut self, peer: &PeerKey, bytes: u64) {
+        if let Some(state) = self.peers.get_mut(peer) {
+            state.downloaded += bytes;
+        }
+    }
+
+    pub fn uploaded(&mut self, peer: &PeerKey, bytes: u64) {
+        if let Some(state) = self.peers.get_mut(peer) {
+            state.uploaded += bytes;
+        }
+    }
+
+    /// Requests from choked peers must not be served.
+    pub fn is_unchoked(&self, peer: &PeerKey) -> bool {
+        self.peers
+            .get(peer)
+            .map(|state| state.unchoked)
+            .unwrap_or(false)
+    }
+
+    /// Whether a `rechoke` would unchoke another interested peer.
+    pub fn has_free_slot(&self) -> bool {
+        self.peers.values().filter(|state| state.unchoked).count() < self.settings.upload_slots
+    }
+
+    pub fn optimistic(&self) -> Option<PeerKey> {
+        self.optimistic
+    }
+
+    /// Runs a choke round if `interval` has passed since the last one.
+    /// Returns the choke and unchoke commands to send.
+    pub fn tick(&mut self, now: Instant, seeding: bool) -> Vec<(PeerKey, PeerCommand)> {
+        let due = self
+            .last_round
+            .is_none_or(|last| now.duration_since(last) >= self.settings.interval);
+        if !due {
+            return Vec::new();
+        }
+        self.rechoke(now, seeding)
+    }
+
+    /// Runs a choke round now, e.g. after an unchoked peer left.
+    pub fn rechoke(&mut self, now: Instant, seeding: bool) -> Vec<(PeerKey, PeerCommand)> {
+        self.round(now, seeding, &mut rand::thread_rng())
+    }
+
+    fn round(
+        &mut self,
+        now: Instant,
+        seeding: bool,
+        rng: &mut impl Rng,
+    ) -> Vec<(PeerKey, PeerCommand)> {
+        let elapsed = self
+            .last_round
+            .map(|last| now.duration_since(last).as_secs_f64())
+            .filter(|secs| *secs > 0.0);
+        for state in self.peers.values_mut() {
+            if let Some(secs) = elapsed {
+                state.download_rate = state.downloaded as f64 / secs;
+                state.upload_rate = state.uploaded as f64 / secs;
+            }
+            state.downloaded = 0;
+            state.uploaded = 0;
+        }
+        self.last_round = Some(now);
+
+        let mut candidates: Vec<(PeerKey, f64)> = self
+            .peers
+            .iter()
+            .filter(|(_, state)| state.interested)
+            .map(|(peer, state)| {
+                let rate = if seeding {
+                    state.upload_rate
+                } else {
+                    state.download_rate
+                };
+                (*peer, rate)
+            })
+            .collect();
+        candidates.sort_by(|a, b| b.1.total_cmp(&a.1));
+        let regular_slots = self.settings.upload_slots.saturating_sub(1);
+        let mut unchoke: Vec<PeerKey> = candidates
+            .iter()
+            .take(regular_slots)
+            .map(|(peer, _)| *peer)
+            .collect();
+
+        let rotate = self
+            .last_optimistic
+            .is_none_or(|last| now.duration_since(last) >= self.settings.optimistic_interval);
+        let keep = self.optimistic.filter(|peer| {
+            !rotate
+                && !unchoke.contains(peer)
+                && self.peers.get(peer).is_some_and(|state| state.interested)
+        });
+        self.optimistic = match keep {
+            Some(peer) => Some(peer),
+            None => {
+                self.last_optimistic = Some(now);
+                self.pick_optimistic(&unchoke, now, rng)
+            }
+        };
+        if self.settings.upload_slots > 0 {
+            unchoke.extend(self.optimistic);
+        }
+
+        let mut commands = Vec::new();
+        for (peer, state) in self.peers.iter_mut() {
+            let unchoked = unchoke.contains(peer);
+            if unchoked != state.unchoked {
+                state.unchoked = unchoked;
+                let command = if unchoked {
+                    PeerCommand::Unchoke
+                } else {
+                    PeerCommand::Choke
+                };
+                commands.push((*peer, command));
+            }
+        }
+        commands
+    }
+
+    // a random interested peer outside the regular slots, with peers that
+    // connected recently three times as likely since they have nothing to
+    // offer yet
+    fn pick_optimistic(
+        &self,
+        regular: &[PeerKey],
+        now: Instant,
+        rng: &mut impl Rng,
+    ) -> Option<PeerKey> {
+        let new_peer_age = self.settings.optimistic_interval * 3;
+        let weighted: Vec<(PeerKey, u32)> = self
+            .peers
+            .iter()
+            .filter(|(peer, state)| state.interested && !regular.contains(peer))
+            .map(|(peer, state)| {
+                let weight = if now.duration_since(state.connected) < new_peer_age {
+                    3
+                } else {
+                    1
+                };
+                (*peer, weight)
+            })
+            .collect();
+        let total: u32 = weighted.iter().map(|(_, weight)| weight).sum();
+        if total == 0 {
+            return None;
+        }
+        let mut pick = rng.gen_range(0..total);
+        for (peer, weight) in weighted {
+            if pick < weight {
+                return Some(peer);
+            }
+            pick -= weight;
+        }
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::rngs::StdRng;
+    use rand::SeedableRng;
+
+    fn unchoked(choker: &Choker) -> Vec<u8> {
+        let mut peers: Vec<u8> = choker
+            .peers
+            .iter()
+            .filter(|(_, state)| state.unchoked)
+            .map(|(peer, _)| peer[0])
+            .collect();
+        peers.sort();
+        peers
+    }
+
+    #[test]
+    fn test_fastest_peers_unchoked() {
+        let mut rng = StdRng::seed_from_u64(7);
+        let mut choker = Choker::new(ChokerSettings {
+            upload_slots: 3,
+            ..Default::default()
+        });
+        let start = Instant::now();
+        for byte in 0..5 {
+            choker.add_peer([byte; 20], start);
+            choker.set_interested(&[byte; 20], byte != 4);
+        }
+        choker.round(start, false, &mut rng);
+        // no rates yet, but every slot is handed out
+        assert_eq!(unchoked(&choker).len(), 3);
+
+        for byte in 0..5 {
+            choker.downloaded(&[byte; 20], byte as u64 * 1000);
+        }
+        let now = start + Duration::from_secs(10);
+        choker.round(now, false, &mut rng);
+        let optimistic = choker.optimistic().unwrap()[0];
+        assert!(optimistic < 2);
+        let mut expected = vec![2, 3, optimistic];
+        expected.sort();
+        // peer 4 is fastest but doesn't want anything
+        assert_eq!(unchoked(&choker), expected);
+        assert!(choker.is_unchoked(&[3; 20]));
+        assert!(!choker.is_unchoked(&[4; 20]));
+
+        // seeding ranks by what we upload
+        choker.uploaded(&[0; 20], 5000);
+        choker.uploaded(&[1; 20], 4000);
+        choker.round(now + Duration::from_secs(10), true, &mut rng);
+        assert!(choker.is_unchoked(&[0; 20]));
+        assert!(choker.is_unchoked(&[1; 20]));
+        assert_eq!(unchoked(&choker).len(), 3);
+    }
+
+    #[test]
+    fn test_optimistic_rotation() {
+        let mut rng = StdRng::seed_from_u64(1);
+        let mut choker = Choker::new(ChokerSettings {
+            upload_slots: 1,
+            ..Default::default()
+        });
+        let start = Instant::now();
+        for byte in 0..8 {
+            choker.add_peer([byte; 20], start);
+            choker.set_interested(&[byte; 20], true);
+        }
+        let commands = choker.round(start, false, &mut rng);
+        let first = choker.optimistic().unwrap();
+        assert_eq!(commands, vec![(first, PeerCommand::Unchoke)]);
+
+        // kept for the whole optimistic interval
+        let mut now = start;
+        for _ in 0..2 {
+            now += Duration::from_secs(10);
+            assert!(choker.round(now, false, &mut rng).is_empty());
+            assert_eq!(choker.optimistic(), Some(first));
+        }
+        let mut seen = vec![first];
+        for _ in 0..10 {
+            now += Duration::from_secs(30);
+            choker.round(now, false, &mut rng);
+            seen.push(choker.optimistic().unwrap());
+        }
+        seen.dedup();
+        assert!(seen.len() > 1);
+
+        // losing interest ends the optimistic unchoke early
+        let current = choker.optimistic().unwrap();
+        choker.set_interested(&current, false);
+        let commands = choker.round(now + Duration::from_secs(10), false, &mut rng);
+        assert!(commands.contains(&(current, PeerCommand::Choke)));
+        assert_ne!(choker.optimistic(), Some(current));
+
+        let current = choker.optimistic().unwrap();
+        assert!(!choker.has_free_slot());
+        assert!(choker.remove_peer(&current));
+        assert_eq!(choker.optimistic(), None);
+        assert!(choker.has_free_slot());
+        // the freed slot waits for the next round
+        assert!(choker.tick(now + Duration::from_secs(15), false).is_empty());
+        assert!(!choker.tick(now + Duration::from_secs(20), false).is_empty());
+    }
+}
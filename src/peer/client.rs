@@ -1,10 +1,14 @@
 use crate::client::arguments::ClientCommand;
-use crate::peer::error::ClientError;
+use crate::create::{self, CreateOptions};
+use crate::magnet::MagnetLink;
+use crate::peer::error::{ClientError, WireError};
+use crate::peer::tracker;
 use crate::types;
 use crate::types::Node;
-use crate::types::Torrent;
+use crate::types::{Torrent, TorrentResponse};
 use ::futures::SinkExt;
 use libp2p::futures::channel::{mpsc, oneshot};
+use libp2p::request_response::ResponseChannel;
 use libp2p::Multiaddr;
 use libp2p::PeerId;
 use serde::{Deserialize, Serialize};
@@ -13,6 +17,25 @@ use serde_json as json;
 use std::path::PathBuf;
 use std::str::FromStr;
 use strum::{Display, VariantArray};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+// Azureus style client prefix for BitTorrent peer ids
+const PEER_ID_PREFIX: &[u8; 8] = b"-JJ0010-";
+
+/// BitTorrent peer id for trackers and the wire protocol; unrelated to the
+/// libp2p `PeerId`.
+pub fn generate_peer_id() -> [u8; 20] {
+    use rand::{distributions::Alphanumeric, Rng};
+    let mut peer_id = [0u8; 20];
+    peer_id[..8].copy_from_slice(PEER_ID_PREFIX);
+    for (byte, random) in peer_id[8..]
+        .iter_mut()
+        .zip(rand::thread_rng().sample_iter(Alphanumeric))
+    {
+        *byte = random;
+    }
+    peer_id
+}
+
 #[derive(Clone)]
 pub struct Client {
     pub tx: mpsc::Sender<ClientCommand>,
@@ -56,19 +79,137 @@ impl From<[u8; 20]> for TrackerInfoHash {
     }
 }
 
+pub const HANDSHAKE_LEN: usize = 68;
+const PROTOCOL: &[u8; 19] = b"BitTorrent protocol";
+// reserved bits as (byte, mask)
+const EXTENSION_PROTOCOL_BIT: (usize, u8) = (5, 0x10);
+const FAST_EXTENSION_BIT: (usize, u8) = (7, 0x04);
+const DHT_BIT: (usize, u8) = (7, 0x01);
+
 impl Handshake {
     pub fn new(info_hash: [u8; 20], peer_id: [u8; 20]) -> Self {
         Handshake {
             len: 19,
-            bittorent: *b"BitTorrent protocol",
+            bittorent: *PROTOCOL,
             reserved: [0; 8],
             info_hash,
             peer_id,
         }
     }
 
+    pub fn as_bytes(&self) -> &[u8] {
+        // SAFETY: repr(C) struct of u8 and u8 arrays, so 68 bytes without padding
+        unsafe { std::slice::from_raw_parts(self as *const Self as *const u8, HANDSHAKE_LEN) }
+    }
+
     pub fn as_bytes_mut(&mut self) -> &mut [u8] {
-        unimplemented!()
+        // SAFETY: as in `as_bytes`, and every byte pattern is a valid Handshake
+        unsafe { std::slice::from_raw_parts_mut(self as *mut Self as *mut u8, HANDSHAKE_LEN) }
+    }
+
+    /// Parses a handshake, checking only the length and protocol string.
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, WireError> {
+        if bytes.len() != HANDSHAKE_LEN {
+            return Err(WireError::InvalidLength(bytes.len()));
+        }
+        let mut handshake = Handshake::new([0; 20], [0; 20]);
+        handshake.as_bytes_mut().copy_from_slice(bytes);
+        if handshake.len != 19 || &handshake.bittorent != PROTOCOL {
+            return Err(WireError::InvalidProtocol);
+        }
+        Ok(handshake)
+    }
+
+    pub fn validate(&self, info_hash: &[u8; 20]) -> Result<(), WireError> {
+        if self.len != 19 || &self.bittorent != PROTOCOL {
+            return Err(WireError::InvalidProtocol);
+        }
+        if &self.info_hash != info_hash {
+            return Err(WireError::InfoHashMismatch);
+        }
+        Ok(())
+    }
+
+    fn reserved_bit(&self, (byte, mask): (usize, u8)) -> bool {
+        self.reserved[byte] & mask != 0
+    }
+
+    fn set_reserved_bit(&mut self, (byte, mask): (usize, u8), enabled: bool) {
+        if enabled {
+            self.reserved[byte] |= mask;
+        } else {
+            self.reserved[byte] &= !mask;
+        }
+    }
+
+    /// BEP 10 extension protocol.
+    pub fn supports_extensions(&self) -> bool {
+        self.reserved_bit(EXTENSION_PROTOCOL_BIT)
+    }
+
+    pub fn set_extensions(&mut self, enabled: bool) {
+        self.set_reserved_bit(EXTENSION_PROTOCOL_BIT, enabled)
+    }
+
+    /// BEP 6 fast extension.
+    pub fn supports_fast(&self) -> bool {
+        self.reserved_bit(FAST_EXTENSION_BIT)
+    }
+
+    pub fn set_fast(&mut self, enabled: bool) {
+        self.set_reserved_bit(FAST_EXTENSION_BIT, enabled)
+    }
+
+    /// BEP 5 DHT, the peer accepts `port` messages.
+    pub fn supports_dht(&self) -> bool {
+        self.reserved_bit(DHT_BIT)
+    }
+
+    pub fn set_dht(&mut self, enabled: bool) {
+        self.set_reserved_bit(DHT_BIT, enabled)
+    }
+
+    pub async fn read<R: AsyncRead + Unpin>(reader: &mut R) -> Result<Self, WireError> {
+        let mut handshake = Handshake::new([0; 20], [0; 20]);
+        // the protocol string is checked before waiting for the rest
+        reader
+            .read_exact(&mut handshake.as_bytes_mut()[..20])
+            .await?;
+        if handshake.len != 19 || &handshake.bittorent != PROTOCOL {
+            return Err(WireError::InvalidProtocol);
+        }
+        reader
+            .read_exact(&mut handshake.as_bytes_mut()[20..])
+            .await?;
+        Ok(handshake)
+    }
+
+    pub async fn write<W: AsyncWrite + Unpin>(&self, writer: &mut W) -> Result<(), WireError> {
+        writer.write_all(self.as_bytes()).await?;
+        writer.flush().await?;
+        Ok(())
+    }
+
+    /// Sends our handshake and reads the peer's, which must be for the same
+    /// torrent.
+    pub async fn exchange<S: AsyncRead + AsyncWrite + Unpin>(
+        &self,
+        stream: &mut S,
+    ) -> Result<Handshake, WireError> {
+        self.write(stream).await?;
+        let theirs = Handshake::read(stream).await?;
+        theirs.validate(&self.info_hash)?;
+        Ok(theirs)
+    }
+}
+
+impl std::fmt::Debug for Handshake {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.debug_struct("Handshake")
+            .field("reserved", &self.reserved)
+            .field("info_hash", &types::InfoHash::new(self.info_hash))
+            .field("peer_id", &String::from_utf8_lossy(&self.peer_id))
+            .finish()
     }
 }
 
@@ -136,6 +277,65 @@ impl Client {
                 )
                 .await
             }
+            Some("create") => {
+                let path = tx["params"]["path"]
+                    .as_str()
+                    .ok_or(ClientError::InvalidParams)?;
+                let output = tx["params"]["output"]
+                    .as_str()
+                    .map(str::to_string)
+                    .unwrap_or_else(|| format!("{}.torrent", path));
+                let options = Client::create_options(&tx["params"])?;
+                let bytes =
+                    Client::create_torrent(PathBuf::from(path), PathBuf::from(&output), options)
+                        .await?;
+                let torrent = Torrent::from_bytes(&bytes)
+                    .map_err(|e| ClientError::InvalidTorrent(e.to_string()))?;
+                Ok(json::json!({
+                    "result": {
+                        "info_hash": torrent.info_hash.to_string(),
+                        "output": output,
+                    },
+                }))
+            }
+            Some("add_magnet") => {
+                let uri = tx["params"]["uri"]
+                    .as_str()
+                    .ok_or(ClientError::InvalidParams)?;
+                Client::add_magnet(&mut self, uri).await
+            }
+            Some("add_torrent") => {
+                let file = tx["params"]["file"]
+                    .as_str()
+                    .ok_or(ClientError::InvalidParams)?;
+                let save_path = tx["params"]["save_path"]
+                    .as_str()
+                    .ok_or(ClientError::InvalidParams)?;
+                Client::add_torrent(&mut self, file, PathBuf::from(save_path)).await
+            }
+            Some("scrape") => {
+                let health = Client::scrape(&mut self).await?;
+                let torrents: Vec<json::Value> = health
+                    .iter()
+                    .map(|health| {
+                        json::json!({
+                            "info_hash": health.info_hash.to_string(),
+                            "name": health.name,
+                            "seeders": health.stats.map(|stats| stats.seeders),
+                            "leechers": health.stats.map(|stats| stats.leechers),
+                            "completed": health.stats.map(|stats| stats.completed),
+                        })
+                    })
+                    .collect();
+                Ok(json::json!({ "result": torrents }))
+            }
+            Some("trackers") => {
+                let info_hash = tx["params"]["info_hash"]
+                    .as_str()
+                    .and_then(|info_hash| info_hash.parse::<types::InfoHash>().ok())
+                    .ok_or(ClientError::InvalidParams)?;
+                Client::tracker_status(&mut self, info_hash).await
+            }
             Some("get_peers") => {
                 let file = tx["params"]["file"].as_str().unwrap();
                 Client::get_peers(&mut self, file.to_string()).await
@@ -150,7 +350,23 @@ impl Client {
         file: String,
     ) -> Result<json::Value, ClientError> {
         let (tx, _rx) = oneshot::channel();
-        let bytes = std::fs::read(file.clone()).expect("File not found");
+        // anything that isn't already a .torrent gets one built in memory, the
+        // user's directory is left alone
+        let bytes = if file.ends_with(".torrent") {
+            tokio::fs::read(&file)
+                .await
+                .map_err(|e| ClientError::InvalidTorrent(e.to_string()))?
+        } else {
+            let path = PathBuf::from(&file);
+            tokio::task::spawn_blocking(move || {
+                create::create_torrent(&path, &CreateOptions::default())?.to_bytes()
+            })
+            .await
+            .map_err(|e| ClientError::InvalidTorrent(e.to_string()))?
+            .map_err(|e| ClientError::InvalidTorrent(e.to_string()))?
+        };
+        let torrent =
+            Torrent::from_bytes(&bytes).map_err(|e| ClientError::InvalidTorrent(e.to_string()))?;
         self.tx
             .send(ClientCommand::ProvideTorrent {
                 file: bytes,
@@ -159,11 +375,191 @@ impl Client {
             .await
             .expect("Receiver not dropped yet...");
         let res = json::json!({
-        "result": "Providing {:?}",
-                    });
+            "result": torrent.info_hash.to_string(),
+        });
+        Ok(res)
+    }
+
+    pub(crate) async fn create_torrent(
+        path: PathBuf,
+        output: PathBuf,
+        options: CreateOptions,
+    ) -> Result<Vec<u8>, ClientError> {
+        tokio::task::spawn_blocking(move || create::write_torrent(&path, &output, &options))
+            .await
+            .map_err(|e| ClientError::InvalidTorrent(e.to_string()))?
+            .map_err(|e| ClientError::InvalidTorrent(e.to_string()))
+    }
+
+    fn create_options(params: &json::Value) -> Result<CreateOptions, ClientError> {
+        // trackers are given either as tiers or as a flat list with one tracker per tier
+        let trackers = match params["trackers"].as_array() {
+            Some(trackers) => trackers
+                .iter()
+                .map(|tier| match tier {
+                    json::Value::String(tracker) => Ok(vec![tracker.clone()]),
+                    json::Value::Array(tier) => tier
+                        .iter()
+                        .map(|tracker| tracker.as_str().map(str::to_string))
+                        .collect::<Option<Vec<_>>>()
+                        .ok_or(ClientError::InvalidParams),
+                    _ => Err(ClientError::InvalidParams),
+                })
+                .collect::<Result<Vec<_>, _>>()?,
+            None => vec![],
+        };
+        let web_seeds = params["web_seeds"]
+            .as_array()
+            .map(|seeds| {
+                seeds
+                    .iter()
+                    .filter_map(|seed| seed.as_str().map(str::to_string))
+                    .collect()
+            })
+            .unwrap_or_default();
+        Ok(CreateOptions {
+            piece_length: params["piece_length"].as_u64(),
+            trackers,
+            comment: params["comment"].as_str().map(str::to_string),
+            created_by: None,
+            private: params["private"].as_bool().unwrap_or(false),
+            web_seeds,
+        })
+    }
+
+    pub(crate) async fn add_magnet(&mut self, uri: &str) -> Result<json::Value, ClientError> {
+        let magnet = MagnetLink::parse(uri).map_err(|_| ClientError::InvalidParams)?;
+        let (tx, rx) = oneshot::channel();
+        self.tx
+            .send(ClientCommand::AddMagnet { magnet, tx })
+            .await
+            .expect("Receiver not dropped yet...");
+        let info_hash = rx.await.expect("Sender not dropped yet...");
+        let res = json::json!({
+            "result": info_hash.to_string(),
+        });
+        Ok(res)
+    }
+
+    pub(crate) async fn add_torrent(
+        &mut self,
+        file: &str,
+        save_path: PathBuf,
+    ) -> Result<json::Value, ClientError> {
+        let bytes = tokio::fs::read(file)
+            .await
+            .map_err(|e| ClientError::InvalidTorrent(e.to_string()))?;
+        Torrent::from_bytes(&bytes).map_err(|e| ClientError::InvalidTorrent(e.to_string()))?;
+        let (tx, rx) = oneshot::channel();
+        self.tx
+            .send(ClientCommand::AddTorrent {
+                file: bytes,
+                save_path,
+                tx,
+            })
+            .await
+            .expect("Receiver not dropped yet...");
+        let info_hash = rx.await.expect("Sender not dropped yet...");
+        let res = json::json!({
+            "result": info_hash.to_string(),
+        });
         Ok(res)
     }
 
+    /// Scrapes the trackers of every torrent in the session and stores the
+    /// results on the torrents.
+    pub(crate) async fn scrape(&mut self) -> Result<Vec<types::SwarmHealth>, ClientError> {
+        let (tx, rx) = oneshot::channel();
+        self.tx
+            .send(ClientCommand::TrackerList { tx })
+            .await
+            .expect("Receiver not dropped yet...");
+        let trackers = rx.await.expect("Sender not dropped yet...");
+        let stats = tracker::scrape_all(&trackers).await;
+        let (tx, rx) = oneshot::channel();
+        self.tx
+            .send(ClientCommand::UpdateScrape { stats, tx })
+            .await
+            .expect("Receiver not dropped yet...");
+        Ok(rx.await.expect("Sender not dropped yet..."))
+    }
+
+    pub(crate) async fn tracker_status(
+        &mut self,
+        info_hash: types::InfoHash,
+    ) -> Result<json::Value, ClientError> {
+        let (tx, rx) = oneshot::channel();
+        self.tx
+            .send(ClientCommand::TrackerStatus { info_hash, tx })
+            .await
+            .expect("Receiver not dropped yet...");
+        let statuses = rx
+            .await
+            .expect("Sender not dropped yet...")
+            .ok_or_else(|| ClientError::InvalidTorrent(info_hash.to_string()))?;
+        let now = std::time::Instant::now();
+        // instants are reported as seconds relative to now
+        let relative = |instant: Option<std::time::Instant>| {
+            instant.map(|instant| {
+                if instant > now {
+                    (instant - now).as_secs() as i64
+                } else {
+                    -((now - instant).as_secs() as i64)
+                }
+            })
+        };
+        let trackers: Vec<json::Value> = statuses
+            .iter()
+            .map(|status| {
+                json::json!({
+                    "url": status.url,
+                    "tier": status.tier,
+                    "last_announce": relative(status.last_announce),
+                    "next_announce": relative(status.next_announce),
+                    "last_error": status.last_error,
+                    "peers_received": status.peers_received,
+                })
+            })
+            .collect();
+        Ok(json::json!({ "result": trackers }))
+    }
+
+    /// Serves `file` to a peer that requested `torrent`.
+    pub(crate) async fn respond_file(
+        &mut self,
+        peer: PeerId,
+        torrent: String,
+        file: Vec<u8>,
+        channel: ResponseChannel<TorrentResponse>,
+    ) {
+        self.tx
+            .send(ClientCommand::RespondFile {
+                peer,
+                torrent,
+                file,
+                channel,
+            })
+            .await
+            .expect("Receiver not dropped yet...");
+    }
+
+    /// Tells the session which torrents the queue started or stopped.
+    pub(crate) async fn set_states(&mut self, states: Vec<(types::InfoHash, types::FileStatus)>) {
+        self.tx
+            .send(ClientCommand::SetStates { states })
+            .await
+            .expect("Receiver not dropped yet...");
+    }
+
+    /// Asks the session to save its resume data and stop, and waits until it
+    /// has.
+    pub async fn shutdown(&mut self) {
+        let (tx, rx) = oneshot::channel();
+        if self.tx.send(ClientCommand::Shutdown { tx }).await.is_ok() {
+            let _ = rx.await;
+        }
+    }
+
     pub fn decode_value(val: String) -> (json::Value, String) {
         let serialized = bencode::to_string(&val).unwrap();
         let res = json::Value::String(val.to_string());
@@ -234,8 +630,143 @@ mod tests {
         assert_eq!(val, json::Value::String("d4:spam3:egge".to_string()));
     }
 
+    #[test]
+    fn test_create_options() {
+        let params = json::json!({
+            "trackers": ["http://a.example/announce", ["udp://b.example:80", "udp://c.example:80"]],
+            "private": true,
+            "piece_length": 32768,
+            "web_seeds": ["http://seed.example/"],
+        });
+        let options = Client::create_options(&params).unwrap();
+        assert_eq!(options.trackers.len(), 2);
+        assert_eq!(options.trackers[1].len(), 2);
+        assert!(options.private);
+        assert_eq!(options.piece_length, Some(32768));
+        assert_eq!(options.web_seeds, vec!["http://seed.example/".to_string()]);
+        assert!(Client::create_options(&json::json!({ "trackers": [1] })).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_scrape_rpc() {
+        use ::futures::StreamExt;
+        let (tx, mut rx) = mpsc::channel(1);
+        let client = Client {
+            tx,
+            mode: ClientMode::Cmd,
+        };
+        // stands in for the session: no trackers, one known torrent
+        tokio::spawn(async move {
+            while let Some(command) = rx.next().await {
+                match command {
+                    ClientCommand::TrackerList { tx } => tx.send(vec![]).unwrap(),
+                    ClientCommand::UpdateScrape { stats, tx } => {
+                        assert!(stats.is_empty());
+                        let health = types::SwarmHealth {
+                            info_hash: types::InfoHash::new([1; 20]),
+                            name: "file.txt".to_string(),
+                            stats: Some(tracker::ScrapeStats {
+                                seeders: 3,
+                                completed: 9,
+                                leechers: 4,
+                            }),
+                        };
+                        tx.send(vec![health]).unwrap()
+                    }
+                    _ => unreachable!(),
+                }
+            }
+        });
+        let res = client
+            .execute_command(json::json!({ "method": "scrape" }))
+            .await
+            .unwrap();
+        assert_eq!(res["result"][0]["name"], "file.txt");
+        assert_eq!(res["result"][0]["seeders"], 3);
+        assert_eq!(res["result"][0]["leechers"], 4);
+        assert_eq!(res["result"][0]["completed"], 9);
+    }
+
+    #[test]
+    fn test_handshake_bytes() {
+        let mut handshake = Handshake::new([1; 20], *b"-JJ0010-abcdefghijkl");
+        handshake.set_extensions(true);
+        handshake.set_dht(true);
+        let bytes = handshake.as_bytes().to_vec();
+        assert_eq!(bytes.len(), HANDSHAKE_LEN);
+        assert_eq!(&bytes[..20], b"\x13BitTorrent protocol");
+        assert_eq!(&bytes[20..28], &[0, 0, 0, 0, 0, 0x10, 0, 0x01]);
+        assert_eq!(&bytes[28..48], &[1; 20]);
+        assert_eq!(&bytes[48..], b"-JJ0010-abcdefghijkl");
+
+        let parsed = Handshake::from_bytes(&bytes).unwrap();
+        assert!(parsed.supports_extensions());
+        assert!(parsed.supports_dht());
+        assert!(!parsed.supports_fast());
+        assert!(parsed.validate(&[1; 20]).is_ok());
+        assert!(matches!(
+            parsed.validate(&[2; 20]),
+            Err(WireError::InfoHashMismatch)
+        ));
+        handshake.set_dht(false);
+        assert!(!handshake.supports_dht());
+
+        let mut bad = bytes.clone();
+        bad[1] = b'b';
+        assert!(matches!(
+            Handshake::from_bytes(&bad),
+            Err(WireError::InvalidProtocol)
+        ));
+        assert!(matches!(
+            Handshake::from_bytes(&bytes[..67]),
+            Err(WireError::InvalidLength(67))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_handshake_exchange() {
+        let (mut ours, mut theirs) = tokio::io::duplex(256);
+        let peer = tokio::spawn(async move {
+            let received = Handshake::read(&mut theirs).await.unwrap();
+            let mut reply = Handshake::new(received.info_hash, [9; 20]);
+            reply.set_fast(true);
+            reply.write(&mut theirs).await.unwrap();
+        });
+        let handshake = Handshake::new([1; 20], generate_peer_id());
+        let reply = handshake.exchange(&mut ours).await.unwrap();
+        assert_eq!(reply.peer_id, [9; 20]);
+        assert!(reply.supports_fast());
+        peer.await.unwrap();
+
+        let (mut ours, mut theirs) = tokio::io::duplex(256);
+        tokio::spawn(async move {
+            Handshake::read(&mut theirs).await.unwrap();
+            Handshake::new([2; 20], [9; 20])
+                .write(&mut theirs)
+                .await
+                .unwrap();
+        });
+        assert!(matches!(
+            handshake.exchange(&mut ours).await,
+            Err(WireError::InfoHashMismatch)
+        ));
+
+        let (mut ours, mut theirs) = tokio::io::duplex(256);
+        theirs
+            .write_all(b"GET /announce HTTP/1.1\r\n\r\n")
+            .await
+            .unwrap();
+        assert!(matches!(
+            Handshake::read(&mut ours).await,
+            Err(WireError::InvalidProtocol)
+        ));
+    }
+
     #[tokio::test]
     async fn test_tracker() {
-        todo!()
+        let peer_id = generate_peer_id();
+        assert_eq!(&peer_id[..8], PEER_ID_PREFIX);
+        assert!(peer_id[8..].iter().all(u8::is_ascii_alphanumeric));
+        assert_ne!(peer_id, generate_peer_id());
     }
 }
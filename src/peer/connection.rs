@@ -0,0 +1,601 @@
+use crate::download::BLOCK_LEN;
+use crate::peer::error::WireError;
+use crate::peer::wire::{BlockInfo, Message, PeerCodec};
+use crate::types::Bitfield;
+use bytes::Bytes;
+use futures::channel::mpsc;
+use futures::{SinkExt, StreamExt};
+use hashbrown::HashMap;
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio_util::codec::Framed;
+
+/// BitTorrent peer id of the remote end, from its handshake.
+pub type PeerKey = [u8; 20];
+
+#[derive(Debug, Clone, Copy)]
+pub struct ConnectionSettings {
+    /// Send a keep-alive after this long without sending anything.
+    pub keep_alive: Duration,
+    /// Drop the peer after this long without receiving anything.
+    pub inactivity_timeout: Duration,
+    /// Give up on a requested block after this long.
+    pub request_timeout: Duration,
+    /// How often the timeouts above are checked.
+    pub tick: Duration,
+}
+
+impl Default for ConnectionSettings {
+    fn default() -> Self {
+        Self {
+            keep_alive: Duration::from_secs(90),
+            inactivity_timeout: Duration::from_secs(180),
+            request_timeout: Duration::from_secs(60),
+            tick: Duration::from_secs(1),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PeerState {
+    pub am_choking: bool,
+    pub am_interested: bool,
+    pub peer_choking: bool,
+    pub peer_interested: bool,
+}
+
+impl Default for PeerState {
+    // connections start out choked and not interested on both sides
+    fn default() -> Self {
+        Self {
+            am_choking: true,
+            am_interested: false,
+            peer_choking: true,
+            peer_interested: false,
+        }
+    }
+}
+
+/// What a connection reports back to its torrent.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PeerEvent {
+    Bitfield(Bitfield),
+    Have(u32),
+    Choked,
+    Unchoked,
+    Interested,
+    NotInterested,
+    /// A block we requested arrived.
+    Block {
+        index: u32,
+        begin: u32,
+        data: Bytes,
+    },
+    /// The peer wants a block from us.
+    Request(BlockInfo),
+    Cancel(BlockInfo),
+    /// A request went unanswered for `request_timeout` and was dropped.
+    RequestTimedOut(BlockInfo),
+    /// Outstanding requests the peer discarded by choking us.
+    RequestsRejected(Vec<BlockInfo>),
+    Port(u16),
+    /// A BEP 10 message, for `peer::extension::ExtensionRegistry`.
+    Extended {
+        id: u8,
+        payload: Bytes,
+    },
+    Disconnected(Option<String>),
+}
+
+/// What the torrent asks of a connection.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PeerCommand {
+    Choke,
+    Unchoke,
+    Interested,
+    NotInterested,
+    Have(u32),
+    Bitfield(Bitfield),
+    Request(BlockInfo),
+    Cancel(BlockInfo),
+    Piece { index: u32, begin: u32, data: Bytes },
+    Extended { id: u8, payload: Bytes },
+    Shutdown,
+}
+
+/// Owns a wire connection after the handshake and tracks the choke and
+/// interest state of both ends, the peer's pieces and our outstanding
+/// requests.
+pub struct PeerConnection<S> {
+    peer: PeerKey,
+    framed: Framed<S, PeerCodec>,
+    settings: ConnectionSettings,
+    state: PeerState,
+    bitfield: Bitfield,
+    outstanding: HashMap<BlockInfo, Instant>,
+    last_received: Instant,
+    last_sent: Instant,
+    events: mpsc::Sender<(PeerKey, PeerEvent)>,
+    commands: mpsc::Receiver<PeerCommand>,
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> PeerConnection<S> {
+    pub fn new(
+        peer: PeerKey,
+        framed: Framed<S, PeerCodec>,
+        piece_count: usize,
+        settings: ConnectionSettings,
+        events: mpsc::Sender<(PeerKey, PeerEvent)>,
+        commands: mpsc::Receiver<PeerCommand>,
+    ) -> Self {
+        let now = Instant::now();
+        Self {
+            peer,
+            framed,
+            settings,
+            state: PeerState::default(),
+            bitfield: Bitfield::new(piece_count),
+            outstanding: HashMap::new(),
+            last_received: now,
+            last_sent: now,
+            events,
+            commands,
+        }
+    }
+
+    pub fn state(&self) -> PeerState {
+        self.state
+    }
+
+    pub fn bitfield(&self) -> &Bitfield {
+        &self.bitfield
+    }
+
+    /// Runs until the peer disconnects, misbehaves or times out, or we shut
+    /// it down, then reports `PeerEvent::Disconnected`.
+    pub async fn run(mut self) {
+        let reason = match self.run_inner().await {
+            Ok(()) => None,
+            Err(e) => Some(e.to_string()),
+        };
+        tracing::debug!("Peer {:?} disconnected: {:?}", self.peer, reason);
+        let _ = self
+            .events
+            .send((self.peer, PeerEvent::Disconnected(reason)))
+            .await;
+    }
+
+    async fn run_inner(&mut self) -> Result<(), WireError> {
+        let mut ticker = tokio::time::interval(self.settings.tick);
+        let mut first_message = true;
+        loop {
+            tokio::select! {
+                message = self.framed.next() => match message {
+                    Some(message) => {
+                        self.last_received = Instant::now();
+                        let message = message?;
+                        // some clients send the extended handshake before the bitfield
+                        let extended = matches!(message, Message::Extended { .. });
+                        self.handle_message(message, first_message).await?;
+                        first_message &= extended;
+                    }
+                    None => return Ok(()),
+                },
+                command = self.commands.next() => match command {
+                    Some(PeerCommand::Shutdown) | None => return Ok(()),
+                    Some(command) => self.handle_command(command).await?,
+                },
+                _ = ticker.tick() => self.check_timeouts().await?,
+            }
+        }
+    }
+
+    async fn emit(&mut self, event: PeerEvent) -> Result<(), WireError> {
+        self.events
+            .send((self.peer, event))
+            .await
+            .map_err(|_| WireError::ChannelClosed)
+    }
+
+    async fn send(&mut self, message: Message) -> Result<(), WireError> {
+        self.last_sent = Instant::now();
+        self.framed.send(message).await
+    }
+
+    async fn handle_message(&mut self, message: Message, first: bool) -> Result<(), WireError> {
+        match message {
+            Message::KeepAlive => {}
+            Message::Choke => {
+                self.state.peer_choking = true;
+                self.emit(PeerEvent::Choked).await?;
+                // without the fast extension a choke discards every pending request
+                if !self.outstanding.is_empty() {
+                    let rejected = self.outstanding.drain().map(|(block, _)| block).collect();
+                    self.emit(PeerEvent::RequestsRejected(rejected)).await?;
+                }
+            }
+            Message::Unchoke => {
+                self.state.peer_choking = false;
+                self.emit(PeerEvent::Unchoked).await?;
+            }
+            Message::Interested => {
+                self.state.peer_interested = true;
+                self.emit(PeerEvent::Interested).await?;
+            }
+            Message::NotInterested => {
+                self.state.peer_interested = false;
+                self.emit(PeerEvent::NotInterested).await?;
+            }
+            Message::Have(index) => {
+                if !self.bitfield.set(index as usize) {
+                    return Err(WireError::InvalidMessage(format!(
+                        "have for piece {} of {}",
+                        index,
+                        self.bitfield.len()
+                    )));
+                }
+                self.emit(PeerEvent::Have(index)).await?;
+            }
+            Message::Bitfield(bytes) => {
+                if !first {
+                    return Err(WireError::InvalidMessage(
+                        "bitfield after the first message".to_string(),
+                    ));
+                }
+                self.bitfield = Bitfield::from_bytes(&bytes, self.bitfield.len())
+                    .map_err(|e| WireError::InvalidMessage(e.to_string()))?;
+                self.emit(PeerEvent::Bitfield(self.bitfield.clone()))
+                    .await?;
+            }
+            Message::Request(block) => {
+                if block.length > BLOCK_LEN {
+                    return Err(WireError::InvalidMessage(format!(
+                        "request for {} bytes",
+                        block.length
+                    )));
+                }
+                // requests while choked are dropped, the peer should know better
+                if !self.state.am_choking {
+                    self.emit(PeerEvent::Request(block)).await?;
+                }
+            }
+            Message::Cancel(block) => self.emit(PeerEvent::Cancel(block)).await?,
+            Message::Piece { index, begin, data } => {
+                let block = BlockInfo {
+                    index,
+                    begin,
+                    length: data.len() as u32,
+                };
+                // blocks we never asked for or already gave up on are dropped
+                if self.outstanding.remove(&block).is_some() {
+                    self.emit(PeerEvent::Block { index, begin, data }).await?;
+                }
+            }
+            Message::Port(port) => self.emit(PeerEvent::Port(port)).await?,
+            Message::Extended { id, payload } => {
+                self.emit(PeerEvent::Extended { id, payload }).await?
+            }
+        }
+        Ok(())
+    }
+
+    async fn handle_command(&mut self, command: PeerCommand) -> Result<(), WireError> {
+        match command {
+            PeerCommand::Choke if !self.state.am_choking => {
+                self.state.am_choking = true;
+                self.send(Message::Choke).await?;
+            }
+            PeerCommand::Unchoke if self.state.am_choking => {
+                self.state.am_choking = false;
+                self.send(Message::Unchoke).await?;
+            }
+            PeerCommand::Interested if !self.state.am_interested => {
+                self.state.am_interested = true;
+                self.send(Message::Interested).await?;
+            }
+            PeerCommand::NotInterested if self.state.am_interested => {
+                self.state.am_interested = false;
+                self.send(Message::NotInterested).await?;
+            }
+            PeerCommand::Choke
+            | PeerCommand::Unchoke
+            | PeerCommand::Interested
+            | PeerCommand::NotInterested => {}
+            PeerCommand::Have(index) => self.send(Message::Have(index)).await?,
+            PeerCommand::Bitfield(bitfield) => {
+                let bytes = Bytes::copy_from_slice(bitfield.as_bytes());
+                self.send(Message::Bitfield(bytes)).await?
+            }
+            PeerCommand::Request(block) => {
+                // handed back so the block goes to another peer
+                if self.state.peer_choking {
+                    self.emit(PeerEvent::RequestsRejected(vec![block])).await?;
+                    return Ok(());
+                }
+                if self.outstanding.contains_key(&block) {
+                    return Ok(());
+                }
+                self.outstanding.insert(block, Instant::now());
+                self.send(Message::Request(block)).await?;
+            }
+            PeerCommand::Cancel(block) => {
+                if self.outstanding.remove(&block).is_some() {
+                    self.send(Message::Cancel(block)).await?;
+                }
+            }
+            PeerCommand::Piece { index, begin, data } => {
+                if !self.state.am_choking {
+                    self.send(Message::Piece { index, begin, data }).await?;
+                }
+            }
+            PeerCommand::Extended { id, payload } => {
+                self.send(Message::Extended { id, payload }).await?
+            }
+            PeerCommand::Shutdown => {}
+        }
+        Ok(())
+    }
+
+    async fn check_timeouts(&mut self) -> Result<(), WireError> {
+        let now = Instant::now();
+        if now.duration_since(self.last_received) >= self.settings.inactivity_timeout {
+            return Err(WireError::Timeout(self.settings.inactivity_timeout));
+        }
+        let request_timeout = self.settings.request_timeout;
+        let expired: Vec<BlockInfo> = self
+            .outstanding
+            .iter()
+            .filter(|(_, requested)| now.duration_since(**requested) >= request_timeout)
+            .map(|(block, _)| *block)
+            .collect();
+        for block in expired {
+            self.outstanding.remove(&block);
+            self.emit(PeerEvent::RequestTimedOut(block)).await?;
+        }
+        if now.duration_since(self.last_sent) >= self.settings.keep_alive {
+            self.send(Message::KeepAlive).await?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::peer::wire::framed;
+    use tokio::io::DuplexStream;
+
+    const PEER: PeerKey = [7; 20];
+
+    struct Harness {
+        remote: Framed<DuplexStream, PeerCodec>,
+        events: mpsc::Receiver<(PeerKey, PeerEvent)>,
+        commands: mpsc::Sender<PeerCommand>,
+    }
+
+    impl Harness {
+        async fn event(&mut self) -> PeerEvent {
+            let (peer, event) = self.events.next().await.unwrap();
+            assert_eq!(peer, PEER);
+            event
+        }
+
+        async fn received(&mut self) -> Message {
+            self.remote.next().await.unwrap().unwrap()
+        }
+    }
+
+    fn start(settings: ConnectionSettings) -> Harness {
+        let (local, remote) = tokio::io::duplex(1 << 16);
+        let (events_tx, events) = mpsc::channel(16);
+        let (commands, commands_rx) = mpsc::channel(16);
+        let connection = PeerConnection::new(
+            PEER,
+            framed(local, PeerCodec::default()),
+            10,
+            settings,
+            events_tx,
+            commands_rx,
+        );
+        tokio::spawn(connection.run());
+        Harness {
+            remote: framed(remote, PeerCodec::default()),
+            events,
+            commands,
+        }
+    }
+
+    fn block(index: u32) -> BlockInfo {
+        BlockInfo {
+            index,
+            begin: 0,
+            length: 4,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_connection_states() {
+        let mut harness = start(ConnectionSettings::default());
+        // an extended handshake may come before the bitfield
+        let payload = Bytes::from_static(b"d1:md11:ut_metadatai1eee");
+        harness
+            .remote
+            .send(Message::Extended {
+                id: 0,
+                payload: payload.clone(),
+            })
+            .await
+            .unwrap();
+        assert_eq!(
+            harness.event().await,
+            PeerEvent::Extended {
+                id: 0,
+                payload: payload.clone()
+            }
+        );
+        harness
+            .commands
+            .send(PeerCommand::Extended {
+                id: 0,
+                payload: payload.clone(),
+            })
+            .await
+            .unwrap();
+        assert_eq!(
+            harness.received().await,
+            Message::Extended { id: 0, payload }
+        );
+        let bitfield = Bitfield::from_bytes(&[0b1000_0000, 0], 10).unwrap();
+        harness
+            .remote
+            .send(Message::Bitfield(Bytes::copy_from_slice(
+                bitfield.as_bytes(),
+            )))
+            .await
+            .unwrap();
+        assert_eq!(harness.event().await, PeerEvent::Bitfield(bitfield));
+        harness.remote.send(Message::Have(3)).await.unwrap();
+        assert_eq!(harness.event().await, PeerEvent::Have(3));
+
+        // requests are rejected until the peer unchokes us
+        harness
+            .commands
+            .send(PeerCommand::Request(block(0)))
+            .await
+            .unwrap();
+        assert_eq!(
+            harness.event().await,
+            PeerEvent::RequestsRejected(vec![block(0)])
+        );
+        harness
+            .commands
+            .send(PeerCommand::Interested)
+            .await
+            .unwrap();
+        assert_eq!(harness.received().await, Message::Interested);
+        harness.remote.send(Message::Unchoke).await.unwrap();
+        assert_eq!(harness.event().await, PeerEvent::Unchoked);
+        harness
+            .commands
+            .send(PeerCommand::Request(block(0)))
+            .await
+            .unwrap();
+        assert_eq!(harness.received().await, Message::Request(block(0)));
+
+        let data = Bytes::from_static(b"abcd");
+        harness
+            .remote
+            .send(Message::Piece {
+                index: 0,
+                begin: 0,
+                data: data.clone(),
+            })
+            .await
+            .unwrap();
+        assert_eq!(
+            harness.event().await,
+            PeerEvent::Block {
+                index: 0,
+                begin: 0,
+                data
+            }
+        );
+
+        // a request while we choke the peer is ignored
+        harness
+            .remote
+            .send(Message::Request(block(1)))
+            .await
+            .unwrap();
+        harness.remote.send(Message::Interested).await.unwrap();
+        assert_eq!(harness.event().await, PeerEvent::Interested);
+        harness.commands.send(PeerCommand::Unchoke).await.unwrap();
+        assert_eq!(harness.received().await, Message::Unchoke);
+        harness
+            .remote
+            .send(Message::Request(block(1)))
+            .await
+            .unwrap();
+        assert_eq!(harness.event().await, PeerEvent::Request(block(1)));
+
+        harness
+            .commands
+            .send(PeerCommand::Request(block(2)))
+            .await
+            .unwrap();
+        assert_eq!(harness.received().await, Message::Request(block(2)));
+        harness.remote.send(Message::Choke).await.unwrap();
+        assert_eq!(harness.event().await, PeerEvent::Choked);
+        assert_eq!(
+            harness.event().await,
+            PeerEvent::RequestsRejected(vec![block(2)])
+        );
+
+        harness.commands.send(PeerCommand::Shutdown).await.unwrap();
+        assert_eq!(harness.event().await, PeerEvent::Disconnected(None));
+    }
+
+    #[tokio::test]
+    async fn test_protocol_violations() {
+        let mut harness = start(ConnectionSettings::default());
+        harness.remote.send(Message::Have(10)).await.unwrap();
+        assert!(matches!(
+            harness.event().await,
+            PeerEvent::Disconnected(Some(_))
+        ));
+
+        let mut harness = start(ConnectionSettings::default());
+        harness.remote.send(Message::Unchoke).await.unwrap();
+        assert_eq!(harness.event().await, PeerEvent::Unchoked);
+        harness
+            .remote
+            .send(Message::Bitfield(Bytes::from_static(&[0, 0])))
+            .await
+            .unwrap();
+        assert!(matches!(
+            harness.event().await,
+            PeerEvent::Disconnected(Some(_))
+        ));
+
+        let mut harness = start(ConnectionSettings::default());
+        harness
+            .remote
+            .send(Message::Request(BlockInfo {
+                index: 0,
+                begin: 0,
+                length: BLOCK_LEN + 1,
+            }))
+            .await
+            .unwrap();
+        assert!(matches!(
+            harness.event().await,
+            PeerEvent::Disconnected(Some(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_connection_timeouts() {
+        let settings = ConnectionSettings {
+            keep_alive: Duration::from_millis(30),
+            inactivity_timeout: Duration::from_millis(200),
+            request_timeout: Duration::from_millis(60),
+            tick: Duration::from_millis(10),
+        };
+        let mut harness = start(settings);
+        harness.remote.send(Message::Unchoke).await.unwrap();
+        assert_eq!(harness.event().await, PeerEvent::Unchoked);
+        harness
+            .commands
+            .send(PeerCommand::Request(block(4)))
+            .await
+            .unwrap();
+        assert_eq!(harness.received().await, Message::Request(block(4)));
+        assert_eq!(harness.received().await, Message::KeepAlive);
+        assert_eq!(harness.event().await, PeerEvent::RequestTimedOut(block(4)));
+        // the peer stays silent and gets dropped
+        assert!(matches!(
+            harness.event().await,
+            PeerEvent::Disconnected(Some(reason)) if reason.starts_with("No message")
+        ));
+    }
+}
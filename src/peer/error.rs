@@ -6,6 +6,7 @@ pub enum ClientError {
     InvalidMethod,
     InvalidParams,
     ConnectionError,
+    InvalidTorrent(String),
 }
 
 impl std::fmt::Display for ClientError {
@@ -14,8 +15,48 @@ impl std::fmt::Display for ClientError {
             ClientError::InvalidMethod => write!(f, "Requested method does not exist"),
             ClientError::InvalidParams => write!(f, "Invalid params provided"),
             ClientError::ConnectionError => write!(f, "Connection error"),
+            ClientError::InvalidTorrent(e) => write!(f, "Invalid torrent: {}", e),
         }
     }
 }
 
 impl Error for ClientError {}
+
+#[derive(Debug)]
+pub enum WireError {
+    Io(std::io::Error),
+    InvalidLength(usize),
+    InvalidProtocol,
+    InfoHashMismatch,
+    MessageTooLong(usize),
+    UnknownMessage(u8),
+    InvalidMessage(String),
+    Timeout(std::time::Duration),
+    ChannelClosed,
+}
+
+impl std::fmt::Display for WireError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            WireError::Io(e) => write!(f, "Wire io error: {}", e),
+            WireError::InvalidLength(len) => write!(f, "Invalid handshake length {}", len),
+            WireError::InvalidProtocol => write!(f, "Peer does not speak BitTorrent protocol"),
+            WireError::InfoHashMismatch => write!(f, "Peer handshake is for another torrent"),
+            WireError::MessageTooLong(len) => {
+                write!(f, "Peer message of {} bytes is too long", len)
+            }
+            WireError::UnknownMessage(id) => write!(f, "Unknown peer message id {}", id),
+            WireError::InvalidMessage(e) => write!(f, "Invalid peer message: {}", e),
+            WireError::Timeout(after) => write!(f, "No message from peer for {:?}", after),
+            WireError::ChannelClosed => write!(f, "Peer event channel closed"),
+        }
+    }
+}
+
+impl Error for WireError {}
+
+impl From<std::io::Error> for WireError {
+    fn from(e: std::io::Error) -> Self {
+        WireError::Io(e)
+    }
+}
@@ -0,0 +1,534 @@
+pub mod metadata;
+pub mod pex;
+
+use crate::config::VERSION_STR;
+use crate::parser::BencodeError;
+use crate::peer::client::Handshake;
+use crate::peer::connection::{PeerCommand, PeerKey};
+use bytes::Bytes;
+use hashbrown::HashMap;
+use serde::{Deserialize, Serialize};
+use serde_bytes::ByteBuf;
+use std::any::Any;
+use std::collections::BTreeMap;
+use std::net::IpAddr;
+use std::time::Instant;
+use thiserror::Error;
+
+/// Extended message id of the handshake. Every other id is chosen by the
+/// side receiving the message and advertised in its handshake.
+pub const HANDSHAKE_ID: u8 = 0;
+/// Outstanding requests we accept from a peer, advertised as `reqq`.
+pub const DEFAULT_REQQ: u32 = 250;
+
+#[derive(Debug, Error)]
+pub enum ExtensionError {
+    #[error("Failed to decode extension message: {0}")]
+    Bencode(#[from] serde_bencode::Error),
+    #[error("Malformed bencode: {0}")]
+    Parse(#[from] BencodeError),
+    #[error("Extension message id {0} was never advertised")]
+    UnknownMessage(u8),
+    #[error("Invalid extension message: {0}")]
+    Invalid(String),
+}
+
+/// The BEP 10 handshake dictionary. Keys we don't know are ignored, and so
+/// are known keys with values out of range, e.g. a port above 65535.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(from = "RawHandshake")]
+pub struct ExtendedHandshake {
+    /// Extension names to the message ids the sender wants them sent with,
+    /// 0 disables an extension.
+    #[serde(default)]
+    pub m: BTreeMap<String, i64>,
+    /// Client name and version.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub v: Option<String>,
+    /// Port the sender listens on.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub p: Option<u16>,
+    /// The receiver's address as the sender sees it, 4 or 16 bytes.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub yourip: Option<ByteBuf>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reqq: Option<u32>,
+    /// Size of the info dictionary, from BEP 9.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub metadata_size: Option<u64>,
+}
+
+// the handshake as peers send it, before the values are checked
+#[derive(Deserialize)]
+struct RawHandshake {
+    #[serde(default)]
+    m: BTreeMap<String, i64>,
+    #[serde(default)]
+    v: Option<ByteBuf>,
+    #[serde(default)]
+    p: Option<i64>,
+    #[serde(default)]
+    yourip: Option<ByteBuf>,
+    #[serde(default)]
+    reqq: Option<i64>,
+    #[serde(default)]
+    metadata_size: Option<i64>,
+}
+
+impl From<RawHandshake> for ExtendedHandshake {
+    fn from(raw: RawHandshake) -> Self {
+        Self {
+            m: raw.m,
+            v: raw.v.and_then(|v| String::from_utf8(v.into_vec()).ok()),
+            p: raw.p.and_then(|p| u16::try_from(p).ok()),
+            yourip: raw.yourip,
+            reqq: raw.reqq.and_then(|reqq| u32::try_from(reqq).ok()),
+            metadata_size: raw.metadata_size.and_then(|size| u64::try_from(size).ok()),
+        }
+    }
+}
+
+impl ExtendedHandshake {
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, ExtensionError> {
+        Ok(serde_bencode::from_bytes(bytes)?)
+    }
+
+    pub fn to_bytes(&self) -> Result<Bytes, ExtensionError> {
+        Ok(serde_bencode::to_bytes(self)?.into())
+    }
+
+    /// The message id to send an extension with, `None` if the sender
+    /// doesn't support it.
+    pub fn id(&self, name: &str) -> Option<u8> {
+        self.m
+            .get(name)
+            .and_then(|id| u8::try_from(*id).ok())
+            .filter(|id| *id != HANDSHAKE_ID)
+    }
+
+    pub fn yourip(&self) -> Option<IpAddr> {
+        let bytes: &[u8] = self.yourip.as_deref()?;
+        match bytes.len() {
+            4 => Some(IpAddr::from(<[u8; 4]>::try_from(bytes).ok()?)),
+            16 => Some(IpAddr::from(<[u8; 16]>::try_from(bytes).ok()?)),
+            _ => None,
+        }
+    }
+
+    // a later handshake only changes what it mentions
+    fn merge(&mut self, update: ExtendedHandshake) {
+        self.m.extend(update.m);
+        self.v = update.v.or(self.v.take());
+        self.p = update.p.or(self.p);
+        self.yourip = update.yourip.or(self.yourip.take());
+        self.reqq = update.reqq.or(self.reqq);
+        self.metadata_size = update.metadata_size.or(self.metadata_size);
+    }
+}
+
+/// Messages an extension wants to send. Payloads are addressed by peer and
+/// get the id that peer picked for the extension.
+#[derive(Debug, Default)]
+pub struct Outbox {
+    messages: Vec<(PeerKey, Bytes)>,
+}
+
+impl Outbox {
+    pub fn send(&mut self, peer: PeerKey, payload: impl Into<Bytes>) {
+        self.messages.push((peer, payload.into()));
+    }
+}
+
+/// A protocol on top of BEP 10, such as ut_metadata or ut_pex. Extensions
+/// are registered per torrent with an [`ExtensionRegistry`] and only hear
+/// from peers that advertised the same name.
+pub trait Extension: Any + Send {
+    /// Key in the `m` dictionary.
+    fn name(&self) -> &'static str;
+
+    /// Adds fields such as `metadata_size` to our handshake.
+    fn extend_handshake(&self, _handshake: &mut ExtendedHandshake) {}
+
+    /// The peer advertised this extension.
+    fn on_peer(&mut self, _peer: PeerKey, _handshake: &ExtendedHandshake, _out: &mut Outbox) {}
+
+    fn on_message(
+        &mut self,
+        peer: PeerKey,
+        payload: &[u8],
+        out: &mut Outbox,
+    ) -> Result<(), ExtensionError>;
+
+    /// The peer disconnected or disabled this extension.
+    fn on_disconnect(&mut self, _peer: &PeerKey) {}
+
+    /// Called periodically for messages sent on a timer.
+    fn tick(&mut self, _now: Instant, _out: &mut Outbox) {}
+}
+
+/// The extensions of one torrent and the handshakes of its peers. Our
+/// message ids are the registration order, starting at 1.
+pub struct ExtensionRegistry {
+    extensions: Vec<Box<dyn Extension>>,
+    peers: HashMap<PeerKey, ExtendedHandshake>,
+    listen_port: Option<u16>,
+}
+
+impl ExtensionRegistry {
+    pub fn new(listen_port: Option<u16>) -> Self {
+        Self {
+            extensions: Vec::new(),
+            peers: HashMap::new(),
+            listen_port,
+        }
+    }
+
+    /// Returns the message id peers should use for the extension, or `None`
+    /// if the name is taken or every id is.
+    pub fn register(&mut self, extension: impl Extension) -> Option<u8> {
+        if self.local_id(extension.name()).is_some() {
+            return None;
+        }
+        let id = u8::try_from(self.extensions.len() + 1).ok()?;
+        self.extensions.push(Box::new(extension));
+        Some(id)
+    }
+
+    pub fn local_id(&self, name: &str) -> Option<u8> {
+        self.extensions
+            .iter()
+            .position(|extension| extension.name() == name)
+            .map(|index| index as u8 + 1)
+    }
+
+    pub fn get<E: Extension>(&self) -> Option<&E> {
+        self.extensions
+            .iter()
+            .find_map(|extension| (extension.as_ref() as &dyn Any).downcast_ref())
+    }
+
+    pub fn get_mut<E: Extension>(&mut self) -> Option<&mut E> {
+        self.extensions
+            .iter_mut()
+            .find_map(|extension| (extension.as_mut() as &mut dyn Any).downcast_mut())
+    }
+
+    /// Advertises BEP 10 in our BitTorrent handshake.
+    pub fn set_reserved(&self, handshake: &mut Handshake) {
+        handshake.set_extensions(true);
+    }
+
+    /// Our handshake for a peer at `addr`.
+    pub fn handshake(&self, addr: Option<IpAddr>) -> ExtendedHandshake {
+        let mut handshake = ExtendedHandshake {
+            m: self
+                .extensions
+                .iter()
+                .enumerate()
+                .map(|(index, extension)| (extension.name().to_string(), index as i64 + 1))
+                .collect(),
+            v: Some(format!("jubjub {}", VERSION_STR)),
+            p: self.listen_port,
+            yourip: addr.map(|addr| match addr {
+                IpAddr::V4(ip) => ByteBuf::from(ip.octets().to_vec()),
+                IpAddr::V6(ip) => ByteBuf::from(ip.octets().to_vec()),
+            }),
+            reqq: Some(DEFAULT_REQQ),
+            metadata_size: None,
+        };
+        for extension in &self.extensions {
+            extension.extend_handshake(&mut handshake);
+        }
+        handshake
+    }
+
+    /// Call after the BitTorrent handshake. Returns our extended handshake
+    /// if the peer supports BEP 10.
+    pub fn add_peer(
+        &self,
+        theirs: &Handshake,
+        addr: Option<IpAddr>,
+    ) -> Result<Option<PeerCommand>, ExtensionError> {
+        if !theirs.supports_extensions() {
+            return Ok(None);
+        }
+        Ok(Some(PeerCommand::Extended {
+            id: HANDSHAKE_ID,
+            payload: self.handshake(addr).to_bytes()?,
+        }))
+    }
+
+    pub fn peer_handshake(&self, peer: &PeerKey) -> Option<&ExtendedHandshake> {
+        self.peers.get(peer)
+    }
+
+    /// Handles `PeerEvent::Extended`. Returns the messages to send in reply.
+    pub fn on_message(
+        &mut self,
+        peer: PeerKey,
+        id: u8,
+        payload: &[u8],
+    ) -> Result<Vec<(PeerKey, PeerCommand)>, ExtensionError> {
+        if id == HANDSHAKE_ID {
+            return self.on_handshake(peer, ExtendedHandshake::from_bytes(payload)?);
+        }
+        if !self.peers.contains_key(&peer) {
+            return Err(ExtensionError::Invalid(
+                "extension message before the extended handshake".to_string(),
+            ));
+        }
+        let index = (id as usize) - 1;
+        let extension = self
+            .extensions
+            .get_mut(index)
+            .ok_or(ExtensionError::UnknownMessage(id))?;
+        let mut out = Outbox::default();
+        extension.on_message(peer, payload, &mut out)?;
+        Ok(self.route(index, out))
+    }
+
+    fn on_handshake(
+        &mut self,
+        peer: PeerKey,
+        update: ExtendedHandshake,
+    ) -> Result<Vec<(PeerKey, PeerCommand)>, ExtensionError> {
+        let known = self.peers.entry(peer).or_default();
+        let before: Vec<bool> = self
+            .extensions
+            .iter()
+            .map(|extension| known.id(extension.name()).is_some())
+            .collect();
+        known.merge(update);
+        let known = known.clone();
+        let mut commands = Vec::new();
+        for (index, supported) in before.into_iter().enumerate() {
+            let extension = &mut self.extensions[index];
+            let mut out = Outbox::default();
+            match (supported, known.id(extension.name()).is_some()) {
+                (false, true) => extension.on_peer(peer, &known, &mut out),
+                (true, false) => extension.on_disconnect(&peer),
+                _ => {}
+            }
+            commands.extend(self.route(index, out));
+        }
+        Ok(commands)
+    }
+
+    pub fn remove_peer(&mut self, peer: &PeerKey) {
+        let Some(handshake) = self.peers.remove(peer) else {
+            return;
+        };
+        for extension in &mut self.extensions {
+            if handshake.id(extension.name()).is_some() {
+                extension.on_disconnect(peer);
+            }
+        }
+    }
+
+    pub fn tick(&mut self, now: Instant) -> Vec<(PeerKey, PeerCommand)> {
+        let mut commands = Vec::new();
+        for index in 0..self.extensions.len() {
+            let mut out = Outbox::default();
+            self.extensions[index].tick(now, &mut out);
+            commands.extend(self.route(index, out));
+        }
+        commands
+    }
+
+    // messages to peers that don't support the extension are dropped
+    fn route(&self, index: usize, out: Outbox) -> Vec<(PeerKey, PeerCommand)> {
+        let name = self.extensions[index].name();
+        out.messages
+            .into_iter()
+            .filter_map(|(peer, payload)| {
+                let id = self.peers.get(&peer)?.id(name)?;
+                Some((peer, PeerCommand::Extended { id, payload }))
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::Ipv4Addr;
+
+    const PEER: PeerKey = [1; 20];
+
+    /// Replies to every message with the same payload.
+    #[derive(Default)]
+    struct Echo {
+        peers: Vec<PeerKey>,
+    }
+
+    impl Extension for Echo {
+        fn name(&self) -> &'static str {
+            "x_echo"
+        }
+
+        fn on_peer(&mut self, peer: PeerKey, _: &ExtendedHandshake, out: &mut Outbox) {
+            self.peers.push(peer);
+            out.send(peer, Bytes::from_static(b"hello"));
+        }
+
+        fn on_message(
+            &mut self,
+            peer: PeerKey,
+            payload: &[u8],
+            out: &mut Outbox,
+        ) -> Result<(), ExtensionError> {
+            out.send(peer, payload.to_vec());
+            out.send([9; 20], payload.to_vec());
+            Ok(())
+        }
+
+        fn on_disconnect(&mut self, peer: &PeerKey) {
+            self.peers.retain(|known| known != peer);
+        }
+    }
+
+    struct Silent;
+
+    impl Extension for Silent {
+        fn name(&self) -> &'static str {
+            "x_silent"
+        }
+
+        fn extend_handshake(&self, handshake: &mut ExtendedHandshake) {
+            handshake.metadata_size = Some(1234);
+        }
+
+        fn on_message(
+            &mut self,
+            _: PeerKey,
+            _: &[u8],
+            _: &mut Outbox,
+        ) -> Result<(), ExtensionError> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_handshake_encoding() {
+        let handshake = ExtendedHandshake {
+            m: [("ut_metadata".to_string(), 3), ("ut_pex".to_string(), 0)].into(),
+            p: Some(6881),
+            yourip: Some(ByteBuf::from(vec![10, 0, 0, 1])),
+            reqq: Some(250),
+            ..Default::default()
+        };
+        let bytes = handshake.to_bytes().unwrap();
+        assert_eq!(
+            &bytes[..],
+            b"d1:md11:ut_metadatai3e6:ut_pexi0ee1:pi6881e4:reqqi250e6:yourip4:\x0a\x00\x00\x01e"
+        );
+        let parsed = ExtendedHandshake::from_bytes(&bytes).unwrap();
+        assert_eq!(parsed, handshake);
+        assert_eq!(parsed.id("ut_metadata"), Some(3));
+        assert_eq!(parsed.id("ut_pex"), None);
+        assert_eq!(
+            parsed.yourip(),
+            Some(IpAddr::from(Ipv4Addr::new(10, 0, 0, 1)))
+        );
+
+        // unknown keys are skipped
+        let parsed =
+            ExtendedHandshake::from_bytes(b"d1:ei1e1:md6:ut_pexi300ee1:v6:client4:zzzzi1ee")
+                .unwrap();
+        assert_eq!(parsed.v.as_deref(), Some("client"));
+        assert_eq!(parsed.id("ut_pex"), None);
+        assert!(ExtendedHandshake::from_bytes(b"i1e").is_err());
+
+        // out of range values are dropped, the rest is kept
+        let parsed = ExtendedHandshake::from_bytes(
+            b"d1:md6:ut_pexi1ee13:metadata_sizei100e1:pi70000e4:reqqi-1e1:v2:\xff\xfee",
+        )
+        .unwrap();
+        assert_eq!(
+            (parsed.p, parsed.reqq, parsed.v.as_deref()),
+            (None, None, None)
+        );
+        assert_eq!(parsed.metadata_size, Some(100));
+        assert_eq!(parsed.id("ut_pex"), Some(1));
+    }
+
+    #[test]
+    fn test_registry_routing() {
+        let mut registry = ExtensionRegistry::new(Some(6881));
+        assert_eq!(registry.register(Silent), Some(1));
+        assert_eq!(registry.register(Echo::default()), Some(2));
+        assert_eq!(registry.register(Silent), None);
+
+        let mut bt_handshake = Handshake::new([0; 20], PEER);
+        assert!(registry.add_peer(&bt_handshake, None).unwrap().is_none());
+        registry.set_reserved(&mut bt_handshake);
+        let Some(PeerCommand::Extended { id, payload }) = registry
+            .add_peer(&bt_handshake, Some(IpAddr::from(Ipv4Addr::LOCALHOST)))
+            .unwrap()
+        else {
+            panic!("expected an extended handshake");
+        };
+        assert_eq!(id, HANDSHAKE_ID);
+        let ours = ExtendedHandshake::from_bytes(&payload).unwrap();
+        assert_eq!(ours.id("x_echo"), Some(2));
+        assert_eq!(ours.metadata_size, Some(1234));
+        assert_eq!(ours.p, Some(6881));
+        assert_eq!(ours.yourip(), Some(IpAddr::from(Ipv4Addr::LOCALHOST)));
+
+        assert!(matches!(
+            registry.on_message(PEER, 2, b"early"),
+            Err(ExtensionError::Invalid(_))
+        ));
+        let theirs = ExtendedHandshake {
+            m: [("x_echo".to_string(), 7)].into(),
+            ..Default::default()
+        };
+        let commands = registry
+            .on_message(PEER, HANDSHAKE_ID, &theirs.to_bytes().unwrap())
+            .unwrap();
+        assert_eq!(
+            commands,
+            vec![(
+                PEER,
+                PeerCommand::Extended {
+                    id: 7,
+                    payload: Bytes::from_static(b"hello")
+                }
+            )]
+        );
+        assert_eq!(registry.get::<Echo>().unwrap().peers, vec![PEER]);
+
+        // replies use the peer's id and skip peers without the extension
+        let commands = registry.on_message(PEER, 2, b"ping").unwrap();
+        assert_eq!(
+            commands,
+            vec![(
+                PEER,
+                PeerCommand::Extended {
+                    id: 7,
+                    payload: Bytes::from_static(b"ping")
+                }
+            )]
+        );
+        assert!(matches!(
+            registry.on_message(PEER, 5, b""),
+            Err(ExtensionError::UnknownMessage(5))
+        ));
+
+        // a second handshake can turn an extension off
+        let update = ExtendedHandshake {
+            m: [("x_echo".to_string(), 0)].into(),
+            ..Default::default()
+        };
+        registry
+            .on_message(PEER, HANDSHAKE_ID, &update.to_bytes().unwrap())
+            .unwrap();
+        assert!(registry.get::<Echo>().unwrap().peers.is_empty());
+        assert!(registry.on_message(PEER, 2, b"ping").unwrap().is_empty());
+        registry.get_mut::<Echo>().unwrap().peers.push([2; 20]);
+        registry.remove_peer(&PEER);
+        assert!(registry.peer_handshake(&PEER).is_none());
+        assert_eq!(registry.get::<Echo>().unwrap().peers, vec![[2; 20]]);
+        assert!(registry.tick(Instant::now()).is_empty());
+    }
+}
@@ -0,0 +1,582 @@
+use super::{ExtendedHandshake, Extension, ExtensionError, Outbox};
+use crate::metainfo::Info;
+use crate::parser;
+use crate::peer::connection::PeerKey;
+use crate::types::InfoHash;
+use bytes::Bytes;
+use hashbrown::HashMap;
+use serde::{Deserialize, Serialize};
+use std::time::{Duration, Instant};
+
+pub const NAME: &str = "ut_metadata";
+/// Metadata is exchanged in pieces of this size, only the last is shorter.
+pub const METADATA_PIECE_LEN: usize = 16 * 1024;
+/// Peers advertising a larger info dictionary are ignored so a malicious
+/// peer can't make us allocate without bound.
+pub const DEFAULT_MAX_METADATA_SIZE: u64 = 16 * 1024 * 1024;
+// pieces requested from one peer at a time
+const MAX_PEER_REQUESTS: usize = 2;
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(20);
+
+const REQUEST: u8 = 0;
+const DATA: u8 = 1;
+const REJECT: u8 = 2;
+
+#[derive(Debug, Serialize, Deserialize)]
+struct Header {
+    msg_type: u8,
+    piece: u32,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    total_size: Option<u64>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum MetadataMessage {
+    Request(u32),
+    Data {
+        piece: u32,
+        total_size: u64,
+        data: Bytes,
+    },
+    Reject(u32),
+}
+
+impl MetadataMessage {
+    /// A bencoded header, followed by the piece itself for data messages.
+    pub fn from_bytes(payload: &[u8]) -> Result<Self, ExtensionError> {
+        let end = parser::skip_value(payload, 0)?;
+        let header: Header = serde_bencode::from_bytes(&payload[..end])?;
+        match header.msg_type {
+            REQUEST => Ok(MetadataMessage::Request(header.piece)),
+            DATA => Ok(MetadataMessage::Data {
+                piece: header.piece,
+                total_size: header.total_size.ok_or_else(|| {
+                    ExtensionError::Invalid("metadata data without total_size".to_string())
+                })?,
+                data: Bytes::copy_from_slice(&payload[end..]),
+            }),
+            REJECT => Ok(MetadataMessage::Reject(header.piece)),
+            other => Err(ExtensionError::Invalid(format!(
+                "unknown metadata message type {}",
+                other
+            ))),
+        }
+    }
+
+    pub fn to_bytes(&self) -> Result<Bytes, ExtensionError> {
+        let (msg_type, piece, total_size, data) = match self {
+            MetadataMessage::Request(piece) => (REQUEST, *piece, None, None),
+            MetadataMessage::Data {
+                piece,
+                total_size,
+                data,
+            } => (DATA, *piece, Some(*total_size), Some(data)),
+            MetadataMessage::Reject(piece) => (REJECT, *piece, None, None),
+        };
+        let mut bytes = serde_bencode::to_bytes(&Header {
+            msg_type,
+            piece,
+            total_size,
+        })?;
+        if let Some(data) = data {
+            bytes.extend_from_slice(data);
+        }
+        Ok(bytes.into())
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+struct MetadataPeer {
+    size: Option<u64>,
+    /// Rejected a request or sent metadata that failed verification.
+    useless: bool,
+}
+
+/// BEP 9 metadata exchange. Downloads the info dictionary of a magnet link
+/// from peers and serves ours once it is known.
+#[derive(Debug)]
+pub struct UtMetadata {
+    info_hash: InfoHash,
+    max_size: u64,
+    metadata: Option<Bytes>,
+    size: Option<u64>,
+    pieces: Vec<Option<(Bytes, PeerKey)>>,
+    requested: HashMap<u32, (PeerKey, Instant)>,
+    peers: HashMap<PeerKey, MetadataPeer>,
+}
+
+impl UtMetadata {
+    /// For a magnet link, the metadata is downloaded.
+    pub fn new(info_hash: InfoHash) -> Self {
+        Self {
+            info_hash,
+            max_size: DEFAULT_MAX_METADATA_SIZE,
+            metadata: None,
+            size: None,
+            pieces: Vec::new(),
+            requested: HashMap::new(),
+            peers: HashMap::new(),
+        }
+    }
+
+    /// For a torrent whose raw info dictionary we have, which is served.
+    pub fn with_metadata(info_hash: InfoHash, metadata: Bytes) -> Self {
+        let mut extension = Self::new(info_hash);
+        extension.metadata = Some(metadata);
+        extension
+    }
+
+    pub fn set_max_size(&mut self, max_size: u64) {
+        self.max_size = max_size;
+    }
+
+    /// The verified info dictionary, once complete.
+    pub fn metadata(&self) -> Option<&Bytes> {
+        self.metadata.as_ref()
+    }
+
+    fn piece_count(size: u64) -> usize {
+        (size as usize).div_ceil(METADATA_PIECE_LEN)
+    }
+
+    fn piece_len(size: u64, piece: u32) -> Option<usize> {
+        let start = piece as usize * METADATA_PIECE_LEN;
+        let size = size as usize;
+        (start < size).then(|| METADATA_PIECE_LEN.min(size - start))
+    }
+
+    fn serve(&self, piece: u32) -> MetadataMessage {
+        let Some(metadata) = &self.metadata else {
+            return MetadataMessage::Reject(piece);
+        };
+        let size = metadata.len() as u64;
+        match Self::piece_len(size, piece) {
+            Some(len) => {
+                let start = piece as usize * METADATA_PIECE_LEN;
+                MetadataMessage::Data {
+                    piece,
+                    total_size: size,
+                    data: metadata.slice(start..start + len),
+                }
+            }
+            None => MetadataMessage::Reject(piece),
+        }
+    }
+
+    // the first acceptable size a peer advertises is the one we download
+    fn start_download(&mut self, size: u64) {
+        if self.metadata.is_some() || self.size.is_some() {
+            return;
+        }
+        if size == 0 || size > self.max_size {
+            return;
+        }
+        self.size = Some(size);
+        self.pieces = vec![None; Self::piece_count(size)];
+    }
+
+    fn request_more(&mut self, out: &mut Outbox) {
+        let Some(size) = self.size else {
+            return;
+        };
+        let now = Instant::now();
+        for piece in 0..self.pieces.len() as u32 {
+            if self.pieces[piece as usize].is_some() || self.requested.contains_key(&piece) {
+                continue;
+            }
+            let requested = &self.requested;
+            let peer = self
+                .peers
+                .iter()
+                .filter(|(_, state)| !state.useless && state.size == Some(size))
+                .map(|(peer, _)| {
+                    let load = requested.values().filter(|(by, _)| by == peer).count();
+                    (*peer, load)
+                })
+                .filter(|(_, load)| *load < MAX_PEER_REQUESTS)
+                .min_by_key(|(_, load)| *load)
+                .map(|(peer, _)| peer);
+            let Some(peer) = peer else {
+                return;
+            };
+            match MetadataMessage::Request(piece).to_bytes() {
+                Ok(payload) => out.send(peer, payload),
+                Err(e) => {
+                    tracing::warn!("Failed to encode metadata request: {}", e);
+                    return;
+                }
+            }
+            self.requested.insert(piece, (peer, now));
+        }
+    }
+
+    fn on_data(&mut self, peer: PeerKey, piece: u32, total_size: u64, data: Bytes) {
+        // unrequested pieces are dropped
+        match self.requested.get(&piece) {
+            Some((by, _)) if *by == peer => {}
+            _ => return,
+        }
+        self.requested.remove(&piece);
+        let Some(size) = self.size else {
+            return;
+        };
+        if total_size != size || Self::piece_len(size, piece) != Some(data.len()) {
+            self.mark_useless(&peer);
+            return;
+        }
+        self.pieces[piece as usize] = Some((data, peer));
+        if self.pieces.iter().all(Option::is_some) {
+            self.assemble();
+        }
+    }
+
+    fn assemble(&mut self) {
+        let pieces = std::mem::take(&mut self.pieces);
+        let mut metadata = Vec::with_capacity(self.size.unwrap_or_default() as usize);
+        let mut contributors = Vec::new();
+        for (data, peer) in pieces.into_iter().flatten() {
+            metadata.extend_from_slice(&data);
+            contributors.push(peer);
+        }
+        let valid = InfoHash::from_info_bytes(&metadata) == self.info_hash
+            && serde_bencode::from_bytes::<Info>(&metadata)
+                .is_ok_and(|info| info.validate().is_ok());
+        if valid {
+            self.metadata = Some(metadata.into());
+            self.size = None;
+            return;
+        }
+        // there is no telling which piece was bad, so none of the senders
+        // are asked again
+        tracing::warn!("Metadata for {} failed verification", self.info_hash);
+        self.pieces = vec![None; Self::piece_count(self.size.unwrap_or_default())];
+        for peer in contributors {
+            self.mark_useless(&peer);
+        }
+        self.restart_if_stuck();
+    }
+
+    fn mark_useless(&mut self, peer: &PeerKey) {
+        if let Some(state) = self.peers.get_mut(peer) {
+            state.useless = true;
+        }
+        self.requested.retain(|_, (by, _)| by != peer);
+        self.restart_if_stuck();
+    }
+
+    // the size we download may come from a lying peer, once no usable peer
+    // advertises it we start over with the size most of the others advertise
+    fn restart_if_stuck(&mut self) {
+        let Some(size) = self.size else {
+            return;
+        };
+        let usable = || self.peers.values().filter(|state| !state.useless);
+        if usable().any(|state| state.size == Some(size)) {
+            return;
+        }
+        let mut counts: HashMap<u64, usize> = HashMap::new();
+        for size in usable()
+            .filter_map(|state| state.size)
+            .filter(|size| *size > 0 && *size <= self.max_size)
+        {
+            *counts.entry(size).or_default() += 1;
+        }
+        self.size = None;
+        self.pieces.clear();
+        self.requested.clear();
+        if let Some((size, _)) = counts.into_iter().max_by_key(|(_, count)| *count) {
+            self.start_download(size);
+        }
+    }
+}
+
+impl Extension for UtMetadata {
+    fn name(&self) -> &'static str {
+        NAME
+    }
+
+    fn extend_handshake(&self, handshake: &mut ExtendedHandshake) {
+        if let Some(metadata) = &self.metadata {
+            handshake.metadata_size = Some(metadata.len() as u64);
+        }
+    }
+
+    fn on_peer(&mut self, peer: PeerKey, handshake: &ExtendedHandshake, out: &mut Outbox) {
+        self.peers.entry(peer).or_default().size = handshake.metadata_size;
+        if let Some(size) = handshake.metadata_size {
+            self.start_download(size);
+        }
+        self.request_more(out);
+    }
+
+    fn on_message(
+        &mut self,
+        peer: PeerKey,
+        payload: &[u8],
+        out: &mut Outbox,
+    ) -> Result<(), ExtensionError> {
+        match MetadataMessage::from_bytes(payload)? {
+            MetadataMessage::Request(piece) => out.send(peer, self.serve(piece).to_bytes()?),
+            MetadataMessage::Data {
+                piece,
+                total_size,
+                data,
+            } => self.on_data(peer, piece, total_size, data),
+            MetadataMessage::Reject(piece) => {
+                if matches!(self.requested.get(&piece), Some((by, _)) if *by == peer) {
+                    self.mark_useless(&peer);
+                }
+            }
+        }
+        self.request_more(out);
+        Ok(())
+    }
+
+    fn on_disconnect(&mut self, peer: &PeerKey) {
+        self.peers.remove(peer);
+        self.requested.retain(|_, (by, _)| by != peer);
+        self.restart_if_stuck();
+    }
+
+    fn tick(&mut self, now: Instant, out: &mut Outbox) {
+        let expired: Vec<PeerKey> = self
+            .requested
+            .values()
+            .filter(|(_, at)| now.duration_since(*at) >= REQUEST_TIMEOUT)
+            .map(|(peer, _)| *peer)
+            .collect();
+        for peer in expired {
+            self.mark_useless(&peer);
+        }
+        self.request_more(out);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_bytes::ByteBuf;
+
+    const SEEDER: PeerKey = [1; 20];
+    const LEECHER: PeerKey = [2; 20];
+    const LIAR: PeerKey = [3; 20];
+
+    fn info_bytes() -> Vec<u8> {
+        let info = Info {
+            name: "big".to_string(),
+            piece_length: 16384,
+            // long enough for the metadata to span three pieces
+            pieces: ByteBuf::from(vec![0xab; 20 * 2000]),
+            length: Some(16384 * 2000),
+            files: None,
+            private: None,
+        };
+        serde_bencode::to_bytes(&info).unwrap()
+    }
+
+    fn handshake(size: Option<u64>) -> ExtendedHandshake {
+        ExtendedHandshake {
+            metadata_size: size,
+            ..Default::default()
+        }
+    }
+
+    // delivers every message in `out` addressed to `to`, returning the rest
+    fn deliver(
+        out: Outbox,
+        from: PeerKey,
+        to: PeerKey,
+        extension: &mut UtMetadata,
+    ) -> Vec<(PeerKey, Bytes)> {
+        let mut replies = Outbox::default();
+        let mut rest = Vec::new();
+        for (peer, payload) in out.messages {
+            if peer == to {
+                extension.on_message(from, &payload, &mut replies).unwrap();
+            } else {
+                rest.push((peer, payload));
+            }
+        }
+        rest.extend(replies.messages);
+        rest
+    }
+
+    #[test]
+    fn test_message_encoding() {
+        let data = MetadataMessage::Data {
+            piece: 1,
+            total_size: 20000,
+            data: Bytes::from_static(b"d4:spam"),
+        };
+        let bytes = data.to_bytes().unwrap();
+        assert_eq!(
+            &bytes[..],
+            b"d8:msg_typei1e5:piecei1e10:total_sizei20000eed4:spam"
+        );
+        assert_eq!(MetadataMessage::from_bytes(&bytes).unwrap(), data);
+        let request = MetadataMessage::Request(3).to_bytes().unwrap();
+        assert_eq!(&request[..], b"d8:msg_typei0e5:piecei3ee");
+        assert_eq!(
+            MetadataMessage::from_bytes(b"d8:msg_typei2e5:piecei0ee").unwrap(),
+            MetadataMessage::Reject(0)
+        );
+        assert!(MetadataMessage::from_bytes(b"d8:msg_typei1e5:piecei0ee").is_err());
+        assert!(MetadataMessage::from_bytes(b"d8:msg_typei9e5:piecei0ee").is_err());
+        assert!(MetadataMessage::from_bytes(b"d8:msg_type").is_err());
+    }
+
+    #[test]
+    fn test_metadata_exchange() {
+        let info = info_bytes();
+        assert!(info.len() > 2 * METADATA_PIECE_LEN);
+        let info_hash = InfoHash::from_info_bytes(&info);
+        let mut seeder = UtMetadata::with_metadata(info_hash, info.clone().into());
+        let mut leecher = UtMetadata::new(info_hash);
+
+        let mut ours = handshake(None);
+        seeder.extend_handshake(&mut ours);
+        assert_eq!(ours.metadata_size, Some(info.len() as u64));
+
+        let mut out = Outbox::default();
+        leecher.on_peer(SEEDER, &ours, &mut out);
+        // only two requests to one peer at a time
+        assert_eq!(out.messages.len(), MAX_PEER_REQUESTS);
+        let mut pending = out.messages;
+        while !pending.is_empty() {
+            let mut to_seeder = Outbox::default();
+            let mut to_leecher = Outbox::default();
+            for (peer, payload) in pending {
+                if peer == SEEDER {
+                    seeder
+                        .on_message(LEECHER, &payload, &mut to_leecher)
+                        .unwrap();
+                } else {
+                    leecher
+                        .on_message(SEEDER, &payload, &mut to_seeder)
+                        .unwrap();
+                }
+            }
+            pending = to_seeder.messages;
+            pending.extend(to_leecher.messages);
+        }
+        assert_eq!(leecher.metadata().map(|data| &data[..]), Some(&info[..]));
+
+        // out of range pieces are rejected
+        let mut out = Outbox::default();
+        let request = MetadataMessage::Request(3).to_bytes().unwrap();
+        seeder.on_message(LEECHER, &request, &mut out).unwrap();
+        assert_eq!(
+            MetadataMessage::from_bytes(&out.messages[0].1).unwrap(),
+            MetadataMessage::Reject(3)
+        );
+    }
+
+    #[test]
+    fn test_bad_metadata() {
+        let info = info_bytes();
+        let info_hash = InfoHash::from_info_bytes(&info);
+        let mut leecher = UtMetadata::new(info_hash);
+        leecher.set_max_size(1 << 20);
+
+        // too large to even try
+        let mut out = Outbox::default();
+        leecher.on_peer(LIAR, &handshake(Some(1 << 30)), &mut out);
+        assert!(out.messages.is_empty());
+
+        // the right size, the wrong content
+        let mut forged = info.clone();
+        forged[10] ^= 1;
+        let mut liar = UtMetadata::with_metadata(info_hash, forged.into());
+        leecher.on_disconnect(&LIAR);
+        leecher.on_peer(LIAR, &handshake(Some(info.len() as u64)), &mut out);
+        let mut pending = std::mem::take(&mut out.messages);
+        while !pending.is_empty() {
+            let requests = Outbox { messages: pending };
+            let replies = deliver(requests, LEECHER, LIAR, &mut liar);
+            let answers = Outbox { messages: replies };
+            pending = deliver(answers, LIAR, LEECHER, &mut leecher);
+        }
+        assert!(leecher.metadata().is_none());
+        assert!(leecher.peers[&LIAR].useless);
+
+        // an honest peer finishes the job
+        let mut seeder = UtMetadata::with_metadata(info_hash, info.clone().into());
+        leecher.on_peer(SEEDER, &handshake(Some(info.len() as u64)), &mut out);
+        assert!(out.messages.iter().all(|(peer, _)| *peer == SEEDER));
+        let mut pending = out.messages;
+        while !pending.is_empty() {
+            let requests = Outbox { messages: pending };
+            let replies = deliver(requests, LEECHER, SEEDER, &mut seeder);
+            let answers = Outbox { messages: replies };
+            pending = deliver(answers, SEEDER, LEECHER, &mut leecher);
+        }
+        assert_eq!(leecher.metadata().map(|data| &data[..]), Some(&info[..]));
+    }
+
+    #[test]
+    fn test_lying_first_peer() {
+        let info = info_bytes();
+        let info_hash = InfoHash::from_info_bytes(&info);
+        let mut leecher = UtMetadata::new(info_hash);
+        // advertises a size, then rejects every request
+        let mut liar = UtMetadata::new(info_hash);
+        let mut out = Outbox::default();
+        leecher.on_peer(LIAR, &handshake(Some(info.len() as u64 + 100)), &mut out);
+        let mut seeder = UtMetadata::with_metadata(info_hash, info.clone().into());
+        leecher.on_peer(SEEDER, &handshake(Some(info.len() as u64)), &mut out);
+        assert!(out.messages.iter().all(|(peer, _)| *peer == LIAR));
+
+        let mut pending = out.messages;
+        while !pending.is_empty() {
+            let mut from_liar = Outbox::default();
+            let mut from_seeder = Outbox::default();
+            for (peer, payload) in pending {
+                match peer {
+                    LIAR => liar.on_message(LEECHER, &payload, &mut from_liar),
+                    _ => seeder.on_message(LEECHER, &payload, &mut from_seeder),
+                }
+                .unwrap();
+            }
+            let mut out = Outbox::default();
+            for (from, replies) in [(LIAR, from_liar), (SEEDER, from_seeder)] {
+                for (_, payload) in replies.messages {
+                    leecher.on_message(from, &payload, &mut out).unwrap();
+                }
+            }
+            pending = out.messages;
+        }
+        assert!(leecher.peers[&LIAR].useless);
+        assert_eq!(leecher.metadata().map(|data| &data[..]), Some(&info[..]));
+    }
+
+    #[test]
+    fn test_reject_and_timeout() {
+        let info = info_bytes();
+        let info_hash = InfoHash::from_info_bytes(&info);
+        let mut leecher = UtMetadata::new(info_hash);
+        let size = Some(info.len() as u64);
+        let mut out = Outbox::default();
+        leecher.on_peer(SEEDER, &handshake(size), &mut out);
+        leecher.on_peer(LIAR, &handshake(size), &mut out);
+        assert_eq!(leecher.requested.len(), 3);
+
+        // a reject moves its requests to the other peer
+        let rejecting = leecher.requested[&0].0;
+        let reject = MetadataMessage::Reject(0).to_bytes().unwrap();
+        let mut out = Outbox::default();
+        leecher.on_message(rejecting, &reject, &mut out).unwrap();
+        assert!(leecher.peers[&rejecting].useless);
+        assert!(!out.messages.is_empty());
+        assert!(leecher
+            .requested
+            .values()
+            .all(|(peer, _)| *peer != rejecting));
+
+        // the other peer times out as well
+        let later = Instant::now() + REQUEST_TIMEOUT;
+        let mut out = Outbox::default();
+        leecher.tick(later, &mut out);
+        assert!(leecher.requested.is_empty());
+        assert!(out.messages.is_empty());
+    }
+}
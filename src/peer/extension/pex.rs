@@ -0,0 +1,408 @@
+use super::{ExtendedHandshake, Extension, ExtensionError, Outbox};
+use crate::peer::connection::PeerKey;
+use crate::peer::tracker::{
+    encode_compact_peer, parse_compact_peers, parse_compact_peers6, TrackerError,
+};
+use hashbrown::{HashMap, HashSet};
+use serde::{Deserialize, Serialize};
+use serde_bytes::ByteBuf;
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+
+pub const NAME: &str = "ut_pex";
+/// BEP 11 allows one message a minute to each peer.
+pub const PEX_INTERVAL: Duration = Duration::from_secs(60);
+/// Messages closer together than this are ignored. Less than
+/// `PEX_INTERVAL` so peers whose timers run a little early are heard.
+pub const MIN_RECEIVE_INTERVAL: Duration = Duration::from_secs(45);
+/// Most added and most dropped peers in one message.
+pub const MAX_PEERS_PER_MESSAGE: usize = 50;
+/// Discovered peers kept until `take_discovered`, the rest are dropped.
+pub const MAX_DISCOVERED: usize = 500;
+
+/// The `added.f` flags of a peer.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+pub struct PexFlags(pub u8);
+
+impl PexFlags {
+    pub const ENCRYPTION: PexFlags = PexFlags(0x01);
+    pub const SEED: PexFlags = PexFlags(0x02);
+    pub const UTP: PexFlags = PexFlags(0x04);
+    pub const HOLEPUNCH: PexFlags = PexFlags(0x08);
+    /// We connected to the peer, so it accepts incoming connections.
+    pub const CONNECTABLE: PexFlags = PexFlags(0x10);
+
+    pub fn contains(self, other: PexFlags) -> bool {
+        self.0 & other.0 == other.0
+    }
+}
+
+impl std::ops::BitOr for PexFlags {
+    type Output = PexFlags;
+
+    fn bitor(self, other: PexFlags) -> PexFlags {
+        PexFlags(self.0 | other.0)
+    }
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct RawPex {
+    #[serde(default)]
+    added: ByteBuf,
+    #[serde(default, rename = "added.f")]
+    added_flags: ByteBuf,
+    #[serde(default)]
+    added6: ByteBuf,
+    #[serde(default, rename = "added6.f")]
+    added6_flags: ByteBuf,
+    #[serde(default)]
+    dropped: ByteBuf,
+    #[serde(default)]
+    dropped6: ByteBuf,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct PexMessage {
+    pub added: Vec<(SocketAddr, PexFlags)>,
+    pub dropped: Vec<SocketAddr>,
+}
+
+impl PexMessage {
+    pub fn is_empty(&self) -> bool {
+        self.added.is_empty() && self.dropped.is_empty()
+    }
+
+    pub fn from_bytes(payload: &[u8]) -> Result<Self, ExtensionError> {
+        let raw: RawPex = serde_bencode::from_bytes(payload)?;
+        let invalid = |e: TrackerError| ExtensionError::Invalid(e.to_string());
+        let mut added = Vec::new();
+        for (peers, flags) in [
+            (
+                parse_compact_peers(&raw.added).map_err(invalid)?,
+                &raw.added_flags,
+            ),
+            (
+                parse_compact_peers6(&raw.added6).map_err(invalid)?,
+                &raw.added6_flags,
+            ),
+        ] {
+            // flags are optional, missing ones count as none
+            added.extend(
+                peers
+                    .into_iter()
+                    .enumerate()
+                    .map(|(i, addr)| (addr, PexFlags(flags.get(i).copied().unwrap_or(0)))),
+            );
+        }
+        let mut dropped = parse_compact_peers(&raw.dropped).map_err(invalid)?;
+        dropped.extend(parse_compact_peers6(&raw.dropped6).map_err(invalid)?);
+        Ok(PexMessage { added, dropped })
+    }
+
+    pub fn to_bytes(&self) -> Result<Vec<u8>, ExtensionError> {
+        let mut raw = RawPex::default();
+        for (addr, flags) in &self.added {
+            let (peers, peer_flags) = if addr.is_ipv4() {
+                (&mut raw.added, &mut raw.added_flags)
+            } else {
+                (&mut raw.added6, &mut raw.added6_flags)
+            };
+            peers.extend(encode_compact_peer(addr));
+            peer_flags.push(flags.0);
+        }
+        for addr in &self.dropped {
+            let peers = if addr.is_ipv4() {
+                &mut raw.dropped
+            } else {
+                &mut raw.dropped6
+            };
+            peers.extend(encode_compact_peer(addr));
+        }
+        Ok(serde_bencode::to_bytes(&raw)?)
+    }
+}
+
+#[derive(Debug, Default)]
+struct PexPeer {
+    /// Listen address of the peer itself, never sent back to it.
+    addr: Option<SocketAddr>,
+    /// Whether it speaks ut_pex.
+    enabled: bool,
+    last_sent: Option<Instant>,
+    /// When the peer's last message was accepted.
+    last_received: Option<Instant>,
+    /// What the peer has heard from us so far.
+    sent: HashSet<SocketAddr>,
+}
+
+/// BEP 11 peer exchange. Tells every peer which peers we are connected to,
+/// at most once a minute, and collects the peers they tell us about. Must
+/// not be registered for private torrents.
+#[derive(Debug, Default)]
+pub struct UtPex {
+    connected: HashMap<SocketAddr, PexFlags>,
+    peers: HashMap<PeerKey, PexPeer>,
+    discovered: Vec<(SocketAddr, PexFlags)>,
+}
+
+impl UtPex {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// We connected to a peer listening at `addr`.
+    pub fn peer_connected(&mut self, peer: PeerKey, addr: SocketAddr, flags: PexFlags) {
+        self.connected.insert(addr, flags);
+        self.peers.entry(peer).or_default().addr = Some(addr);
+    }
+
+    pub fn peer_disconnected(&mut self, peer: &PeerKey) {
+        if let Some(state) = self.peers.remove(peer) {
+            if let Some(addr) = state.addr {
+                self.connected.remove(&addr);
+            }
+        }
+    }
+
+    /// Peers learned from other peers since the last call, to be added to
+    /// the torrent's peer list.
+    pub fn take_discovered(&mut self) -> Vec<(SocketAddr, PexFlags)> {
+        std::mem::take(&mut self.discovered)
+    }
+
+    fn message_for(&self, state: &PexPeer) -> PexMessage {
+        let added = self
+            .connected
+            .iter()
+            .filter(|(addr, _)| Some(**addr) != state.addr && !state.sent.contains(*addr))
+            .take(MAX_PEERS_PER_MESSAGE)
+            .map(|(addr, flags)| (*addr, *flags))
+            .collect();
+        let dropped = state
+            .sent
+            .iter()
+            .filter(|addr| !self.connected.contains_key(*addr))
+            .take(MAX_PEERS_PER_MESSAGE)
+            .copied()
+            .collect();
+        PexMessage { added, dropped }
+    }
+}
+
+impl Extension for UtPex {
+    fn name(&self) -> &'static str {
+        NAME
+    }
+
+    fn on_peer(&mut self, peer: PeerKey, _: &ExtendedHandshake, _: &mut Outbox) {
+        // the first message goes out with the next tick
+        self.peers.entry(peer).or_default().enabled = true;
+    }
+
+    fn on_message(
+        &mut self,
+        peer: PeerKey,
+        payload: &[u8],
+        _out: &mut Outbox,
+    ) -> Result<(), ExtensionError> {
+        let message = PexMessage::from_bytes(payload)?;
+        let now = Instant::now();
+        let state = self.peers.entry(peer).or_default();
+        // a peer flooding us faster than BEP 11 allows is not listened to
+        if state
+            .last_received
+            .is_some_and(|last| now.duration_since(last) < MIN_RECEIVE_INTERVAL)
+        {
+            tracing::debug!("Ignoring pex message sent too soon");
+            return Ok(());
+        }
+        state.last_received = Some(now);
+        // anything past the limits is ignored rather than trusted
+        let room = MAX_DISCOVERED.saturating_sub(self.discovered.len());
+        self.discovered.extend(
+            message
+                .added
+                .into_iter()
+                .filter(|(addr, _)| addr.port() != 0 && !self.connected.contains_key(addr))
+                .take(MAX_PEERS_PER_MESSAGE.min(room)),
+        );
+        Ok(())
+    }
+
+    fn on_disconnect(&mut self, peer: &PeerKey) {
+        self.peer_disconnected(peer);
+    }
+
+    fn tick(&mut self, now: Instant, out: &mut Outbox) {
+        let due: Vec<PeerKey> = self
+            .peers
+            .iter()
+            .filter(|(_, state)| {
+                state.enabled
+                    && state
+                        .last_sent
+                        .is_none_or(|last| now.duration_since(last) >= PEX_INTERVAL)
+            })
+            .map(|(peer, _)| *peer)
+            .collect();
+        for peer in due {
+            let message = self.message_for(&self.peers[&peer]);
+            if message.is_empty() {
+                continue;
+            }
+            match message.to_bytes() {
+                Ok(payload) => out.send(peer, payload),
+                Err(e) => {
+                    tracing::warn!("Failed to encode pex message: {}", e);
+                    continue;
+                }
+            }
+            let state = self.peers.get_mut(&peer).expect("due peers exist");
+            state.last_sent = Some(now);
+            for addr in &message.dropped {
+                state.sent.remove(addr);
+            }
+            state
+                .sent
+                .extend(message.added.iter().map(|(addr, _)| *addr));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const A: PeerKey = [1; 20];
+    const B: PeerKey = [2; 20];
+
+    fn addr(s: &str) -> SocketAddr {
+        s.parse().unwrap()
+    }
+
+    fn sent_to(out: &Outbox, peer: PeerKey) -> Option<PexMessage> {
+        out.messages
+            .iter()
+            .find(|(to, _)| *to == peer)
+            .map(|(_, payload)| PexMessage::from_bytes(payload).unwrap())
+    }
+
+    #[test]
+    fn test_pex_encoding() {
+        let message = PexMessage {
+            added: vec![
+                (addr("10.0.0.1:6881"), PexFlags::SEED | PexFlags::UTP),
+                (addr("[::1]:51413"), PexFlags::ENCRYPTION),
+            ],
+            dropped: vec![addr("10.0.0.2:6881")],
+        };
+        let bytes = message.to_bytes().unwrap();
+        assert!(bytes.starts_with(b"d5:added6:\x0a\x00\x00\x01\x1a\xe17:added.f1:\x06"));
+        let parsed = PexMessage::from_bytes(&bytes).unwrap();
+        assert_eq!(parsed, message);
+        assert!(parsed.added[0].1.contains(PexFlags::SEED));
+        assert!(!parsed.added[0].1.contains(PexFlags::CONNECTABLE));
+
+        // flags are optional
+        let parsed = PexMessage::from_bytes(b"d5:added6:\x0a\x00\x00\x01\x1a\xe1e").unwrap();
+        assert_eq!(parsed.added, vec![(addr("10.0.0.1:6881"), PexFlags(0))]);
+        assert!(PexMessage::from_bytes(b"d5:added5:abcdee").is_err());
+    }
+
+    #[test]
+    fn test_pex_once_a_minute() {
+        let mut pex = UtPex::new();
+        let mut out = Outbox::default();
+        pex.peer_connected(A, addr("10.0.0.1:6881"), PexFlags::CONNECTABLE);
+        pex.peer_connected(B, addr("10.0.0.2:6881"), PexFlags::SEED);
+        pex.on_peer(A, &ExtendedHandshake::default(), &mut out);
+        assert!(out.messages.is_empty());
+
+        let start = Instant::now();
+        pex.tick(start, &mut out);
+        // a peer doesn't hear about itself
+        let message = sent_to(&out, A).unwrap();
+        assert_eq!(message.added, vec![(addr("10.0.0.2:6881"), PexFlags::SEED)]);
+        assert!(sent_to(&out, B).is_none());
+
+        pex.peer_disconnected(&B);
+        pex.peer_connected([3; 20], addr("[::2]:7000"), PexFlags(0));
+        let mut out = Outbox::default();
+        pex.tick(start + Duration::from_secs(30), &mut out);
+        assert!(out.messages.is_empty());
+        pex.tick(start + PEX_INTERVAL, &mut out);
+        let message = sent_to(&out, A).unwrap();
+        assert_eq!(message.added, vec![(addr("[::2]:7000"), PexFlags(0))]);
+        assert_eq!(message.dropped, vec![addr("10.0.0.2:6881")]);
+
+        // nothing changed, nothing sent
+        let mut out = Outbox::default();
+        pex.tick(start + PEX_INTERVAL * 2, &mut out);
+        assert!(out.messages.is_empty());
+    }
+
+    #[test]
+    fn test_disconnect_drops_peer() {
+        let mut pex = UtPex::new();
+        let mut out = Outbox::default();
+        pex.peer_connected(A, addr("10.0.0.1:6881"), PexFlags(0));
+        pex.peer_connected(B, addr("10.0.0.2:6881"), PexFlags(0));
+        pex.on_peer(A, &ExtendedHandshake::default(), &mut out);
+        pex.on_peer(B, &ExtendedHandshake::default(), &mut out);
+        let start = Instant::now();
+        pex.tick(start, &mut out);
+
+        pex.on_disconnect(&B);
+        assert!(!pex.peers.contains_key(&B));
+        let mut out = Outbox::default();
+        pex.tick(start + PEX_INTERVAL, &mut out);
+        let message = sent_to(&out, A).unwrap();
+        assert_eq!(message.dropped, vec![addr("10.0.0.2:6881")]);
+        assert!(sent_to(&out, B).is_none());
+    }
+
+    #[test]
+    fn test_pex_discovery() {
+        let mut pex = UtPex::new();
+        let mut out = Outbox::default();
+        pex.peer_connected(A, addr("10.0.0.1:6881"), PexFlags(0));
+        let added = (0..60)
+            .map(|i| (addr(&format!("10.1.0.{}:6881", i)), PexFlags::SEED))
+            .chain([(addr("10.0.0.1:6881"), PexFlags(0))])
+            .collect();
+        let message = PexMessage {
+            added,
+            dropped: vec![],
+        };
+        pex.on_message(A, &message.to_bytes().unwrap(), &mut out)
+            .unwrap();
+        let discovered = pex.take_discovered();
+        assert_eq!(discovered.len(), MAX_PEERS_PER_MESSAGE);
+        assert!(discovered.iter().all(|(addr, _)| addr.port() == 6881));
+        assert!(!discovered.contains(&(addr("10.0.0.1:6881"), PexFlags(0))));
+        assert!(pex.take_discovered().is_empty());
+        assert!(pex.on_message(A, b"i1e", &mut out).is_err());
+
+        // a second message within the interval is ignored
+        pex.on_message(A, &message.to_bytes().unwrap(), &mut out)
+            .unwrap();
+        assert!(pex.take_discovered().is_empty());
+    }
+
+    #[test]
+    fn test_pex_discovered_is_bounded() {
+        let mut pex = UtPex::new();
+        let mut out = Outbox::default();
+        for i in 0..20u8 {
+            let message = PexMessage {
+                added: (0..MAX_PEERS_PER_MESSAGE)
+                    .map(|j| (addr(&format!("10.{}.0.{}:6881", i, j)), PexFlags(0)))
+                    .collect(),
+                dropped: vec![],
+            };
+            pex.on_message([i; 20], &message.to_bytes().unwrap(), &mut out)
+                .unwrap();
+        }
+        assert_eq!(pex.take_discovered().len(), MAX_DISCOVERED);
+    }
+}
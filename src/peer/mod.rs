@@ -1,3 +1,7 @@
+pub mod choker;
 pub mod client;
+pub mod connection;
 pub mod error;
+pub mod extension;
 pub mod tracker;
+pub mod wire;
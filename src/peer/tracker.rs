@@ -1,11 +1,215 @@
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, SocketAddrV6};
 use std::path::PathBuf;
+use std::time::Duration;
 
 use libp2p::{
     identity::Keypair, // mdns
 };
+use thiserror::Error;
+
+use crate::types::InfoHash;
+
+pub mod http;
+pub mod server;
+pub mod tiers;
+pub mod udp;
+
+use http::HttpTracker;
+use udp::UdpTracker;
 
 pub enum TrackerMode {}
 
+/// Upper bound for scraping one tracker, UDP retries included.
+pub const SCRAPE_TIMEOUT: Duration = Duration::from_secs(30);
+
+#[derive(Debug, Error)]
+pub enum TrackerError {
+    #[error("Tracker request failed: {0}")]
+    Http(#[from] reqwest::Error),
+    #[error("Tracker io error: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("Invalid tracker url: {0}")]
+    InvalidUrl(String),
+    #[error("Invalid tracker response: {0}")]
+    InvalidResponse(String),
+    #[error("Tracker failure: {0}")]
+    Failure(String),
+    #[error("Tracker timed out")]
+    Timeout,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AnnounceEvent {
+    None,
+    Started,
+    Stopped,
+    Completed,
+}
+
+impl AnnounceEvent {
+    pub fn as_str(&self) -> Option<&'static str> {
+        match self {
+            AnnounceEvent::None => None,
+            AnnounceEvent::Started => Some("started"),
+            AnnounceEvent::Stopped => Some("stopped"),
+            AnnounceEvent::Completed => Some("completed"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AnnounceRequest {
+    pub info_hash: InfoHash,
+    pub peer_id: [u8; 20],
+    pub port: u16,
+    pub uploaded: u64,
+    pub downloaded: u64,
+    pub left: u64,
+    pub event: AnnounceEvent,
+    pub num_want: Option<u32>,
+    pub key: u32,
+    pub tracker_id: Option<String>,
+}
+
+/// Announce result shared by the HTTP and UDP trackers.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AnnounceResponse {
+    pub interval: Duration,
+    pub min_interval: Option<Duration>,
+    pub tracker_id: Option<String>,
+    pub seeders: Option<u32>,
+    pub leechers: Option<u32>,
+    pub peers: Vec<SocketAddr>,
+    pub warning: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ScrapeStats {
+    pub seeders: u32,
+    pub completed: u32,
+    pub leechers: u32,
+}
+
+/// An HTTP or UDP tracker behind a single announce interface.
+#[derive(Debug)]
+pub enum Tracker {
+    Http(HttpTracker),
+    Udp(UdpTracker),
+}
+
+impl Tracker {
+    pub async fn new(url: &str) -> Result<Self, TrackerError> {
+        match url.split_once("://").map(|(scheme, _)| scheme) {
+            Some("http") | Some("https") => Ok(Tracker::Http(HttpTracker::new(url)?)),
+            Some("udp") => Ok(Tracker::Udp(UdpTracker::new(url).await?)),
+            _ => Err(TrackerError::InvalidUrl(url.to_string())),
+        }
+    }
+
+    pub async fn announce(
+        &mut self,
+        request: &AnnounceRequest,
+    ) -> Result<AnnounceResponse, TrackerError> {
+        match self {
+            Tracker::Http(tracker) => tracker.announce(request).await,
+            Tracker::Udp(tracker) => tracker.announce(request).await,
+        }
+    }
+
+    pub async fn scrape(
+        &mut self,
+        info_hashes: &[InfoHash],
+    ) -> Result<hashbrown::HashMap<InfoHash, ScrapeStats>, TrackerError> {
+        match self {
+            Tracker::Http(tracker) => tracker.scrape(info_hashes).await,
+            Tracker::Udp(tracker) => tracker.scrape(info_hashes).await,
+        }
+    }
+}
+
+/// Scrapes every `(tracker url, info-hash)` pair with one batched scrape per
+/// tracker. A torrent listed on several trackers keeps the stats with the most
+/// seeders; trackers that fail or take longer than `SCRAPE_TIMEOUT` are
+/// logged and skipped.
+pub async fn scrape_all(
+    torrents: &[(String, InfoHash)],
+) -> hashbrown::HashMap<InfoHash, ScrapeStats> {
+    let mut by_tracker: hashbrown::HashMap<&str, Vec<InfoHash>> = hashbrown::HashMap::new();
+    for (url, info_hash) in torrents {
+        by_tracker.entry(url.as_str()).or_default().push(*info_hash);
+    }
+    let scrapes = by_tracker.into_iter().map(|(url, info_hashes)| async move {
+        let scrape = async {
+            let mut tracker = Tracker::new(url).await?;
+            tracker.scrape(&info_hashes).await
+        };
+        let result = tokio::time::timeout(SCRAPE_TIMEOUT, scrape)
+            .await
+            .unwrap_or(Err(TrackerError::Timeout));
+        result.map_err(|e| tracing::warn!("Scrape of {} failed: {}", url, e))
+    });
+    let mut stats: hashbrown::HashMap<InfoHash, ScrapeStats> = hashbrown::HashMap::new();
+    for result in futures::future::join_all(scrapes)
+        .await
+        .into_iter()
+        .flatten()
+    {
+        for (info_hash, scraped) in result {
+            let best = stats.entry(info_hash).or_insert(scraped);
+            if scraped.seeders > best.seeders {
+                *best = scraped;
+            }
+        }
+    }
+    stats
+}
+
+/// Peers in the 6 byte compact format (BEP 23).
+pub fn parse_compact_peers(bytes: &[u8]) -> Result<Vec<SocketAddr>, TrackerError> {
+    if !bytes.len().is_multiple_of(6) {
+        return Err(TrackerError::InvalidResponse(format!(
+            "compact peers length {} is not a multiple of 6",
+            bytes.len()
+        )));
+    }
+    Ok(bytes
+        .chunks_exact(6)
+        .map(|chunk| {
+            let ip = Ipv4Addr::new(chunk[0], chunk[1], chunk[2], chunk[3]);
+            let port = u16::from_be_bytes([chunk[4], chunk[5]]);
+            SocketAddr::V4(SocketAddrV4::new(ip, port))
+        })
+        .collect())
+}
+
+/// Peers in the 18 byte compact IPv6 format (BEP 7).
+pub fn parse_compact_peers6(bytes: &[u8]) -> Result<Vec<SocketAddr>, TrackerError> {
+    if !bytes.len().is_multiple_of(18) {
+        return Err(TrackerError::InvalidResponse(format!(
+            "compact peers6 length {} is not a multiple of 18",
+            bytes.len()
+        )));
+    }
+    Ok(bytes
+        .chunks_exact(18)
+        .map(|chunk| {
+            let mut ip = [0u8; 16];
+            ip.copy_from_slice(&chunk[..16]);
+            let port = u16::from_be_bytes([chunk[16], chunk[17]]);
+            SocketAddr::V6(SocketAddrV6::new(Ipv6Addr::from(ip), port, 0, 0))
+        })
+        .collect())
+}
+
+pub fn encode_compact_peer(addr: &SocketAddr) -> Vec<u8> {
+    let mut bytes = match addr.ip() {
+        std::net::IpAddr::V4(ip) => ip.octets().to_vec(),
+        std::net::IpAddr::V6(ip) => ip.octets().to_vec(),
+    };
+    bytes.extend_from_slice(&addr.port().to_be_bytes());
+    bytes
+}
+
 use super::client::Client;
 
 async fn start_discovery(_id_key: Keypair, _client: Client, _file: PathBuf) {
@@ -17,6 +221,84 @@ async fn start_discovery(_id_key: Keypair, _client: Client, _file: PathBuf) {
 
 #[cfg(test)]
 mod tests {
+    use super::*;
+
+    #[test]
+    fn test_compact_peers() {
+        let v4: SocketAddr = "10.0.0.1:6881".parse().unwrap();
+        let v6: SocketAddr = "[::1]:51413".parse().unwrap();
+        let mut bytes = encode_compact_peer(&v4);
+        bytes.extend(encode_compact_peer(&v4));
+        assert_eq!(parse_compact_peers(&bytes).unwrap(), vec![v4, v4]);
+        assert_eq!(
+            parse_compact_peers6(&encode_compact_peer(&v6)).unwrap(),
+            vec![v6]
+        );
+        assert!(parse_compact_peers(&bytes[..5]).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_tracker_from_url() {
+        assert!(matches!(
+            Tracker::new("https://tracker.example/announce").await,
+            Ok(Tracker::Http(_))
+        ));
+        assert!(matches!(
+            Tracker::new("udp://127.0.0.1:6969/announce").await,
+            Ok(Tracker::Udp(_))
+        ));
+        assert!(matches!(
+            Tracker::new("wss://tracker.example").await,
+            Err(TrackerError::InvalidUrl(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_scrape_all() {
+        let tracker = server::TrackerServer::new(Default::default(), None);
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let router = tracker
+            .router()
+            .into_make_service_with_connect_info::<SocketAddr>();
+        tokio::spawn(async move { axum::serve(listener, router).await });
+
+        let url = format!("http://{}/announce", addr);
+        let mut request = AnnounceRequest {
+            info_hash: InfoHash::new([1; 20]),
+            peer_id: [1; 20],
+            port: 6881,
+            uploaded: 0,
+            downloaded: 0,
+            left: 0,
+            event: AnnounceEvent::Started,
+            num_want: None,
+            key: 0,
+            tracker_id: None,
+        };
+        let mut client = Tracker::new(&url).await.unwrap();
+        client.announce(&request).await.unwrap();
+        request.peer_id = [2; 20];
+        request.left = 10;
+        client.announce(&request).await.unwrap();
+
+        let torrents = vec![
+            (url.clone(), InfoHash::new([1; 20])),
+            (url, InfoHash::new([2; 20])),
+            ("udp://tracker.example".to_string(), InfoHash::new([3; 20])),
+        ];
+        let stats = scrape_all(&torrents).await;
+        assert_eq!(
+            stats[&InfoHash::new([1; 20])],
+            ScrapeStats {
+                seeders: 1,
+                completed: 0,
+                leechers: 1
+            }
+        );
+        assert_eq!(stats[&InfoHash::new([2; 20])], ScrapeStats::default());
+        assert!(!stats.contains_key(&InfoHash::new([3; 20])));
+    }
 
     #[tokio::test]
     async fn test_discovery_mode() {
@@ -0,0 +1,475 @@
+use super::{
+    parse_compact_peers, parse_compact_peers6, AnnounceRequest, AnnounceResponse, ScrapeStats,
+    TrackerError,
+};
+use crate::types::InfoHash;
+use percent_encoding::{percent_encode, AsciiSet, NON_ALPHANUMERIC};
+use reqwest::Url;
+use serde::Deserialize;
+use serde_bytes::ByteBuf;
+use std::net::{IpAddr, SocketAddr};
+use std::time::Duration;
+
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(30);
+const DEFAULT_INTERVAL: u64 = 1800;
+// keeps scrape urls well below common request line limits
+const MAX_SCRAPE_BATCH: usize = 50;
+// everything but the RFC 3986 unreserved characters, so a space is never a `+`
+const QUERY_VALUE: &AsciiSet = &NON_ALPHANUMERIC
+    .remove(b'-')
+    .remove(b'.')
+    .remove(b'_')
+    .remove(b'~');
+
+fn encode(bytes: &[u8]) -> String {
+    percent_encode(bytes, QUERY_VALUE).to_string()
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum RawPeers {
+    Compact(ByteBuf),
+    Dict(Vec<DictPeer>),
+}
+
+#[derive(Deserialize)]
+struct DictPeer {
+    ip: String,
+    port: u16,
+}
+
+#[derive(Deserialize)]
+struct RawAnnounceResponse {
+    #[serde(default, rename = "failure reason")]
+    failure_reason: Option<String>,
+    #[serde(default, rename = "warning message")]
+    warning_message: Option<String>,
+    #[serde(default)]
+    interval: Option<u64>,
+    #[serde(default, rename = "min interval")]
+    min_interval: Option<u64>,
+    #[serde(default, rename = "tracker id")]
+    tracker_id: Option<String>,
+    #[serde(default)]
+    complete: Option<u32>,
+    #[serde(default)]
+    incomplete: Option<u32>,
+    #[serde(default)]
+    peers: Option<RawPeers>,
+    #[serde(default)]
+    peers6: Option<ByteBuf>,
+}
+
+#[derive(Deserialize)]
+struct RawScrapeFile {
+    #[serde(default)]
+    complete: u32,
+    #[serde(default)]
+    downloaded: u32,
+    #[serde(default)]
+    incomplete: u32,
+}
+
+#[derive(Deserialize)]
+struct RawScrapeResponse {
+    #[serde(default, rename = "failure reason")]
+    failure_reason: Option<String>,
+    #[serde(default)]
+    files: std::collections::HashMap<ByteBuf, RawScrapeFile>,
+}
+
+/// Announce client for `http://` and `https://` trackers.
+#[derive(Debug, Clone)]
+pub struct HttpTracker {
+    url: Url,
+    client: reqwest::Client,
+}
+
+impl HttpTracker {
+    pub fn new(url: &str) -> Result<Self, TrackerError> {
+        let url = Url::parse(url).map_err(|e| TrackerError::InvalidUrl(e.to_string()))?;
+        if !matches!(url.scheme(), "http" | "https") {
+            return Err(TrackerError::InvalidUrl(format!(
+                "unsupported scheme {}",
+                url.scheme()
+            )));
+        }
+        let client = reqwest::Client::builder()
+            .timeout(REQUEST_TIMEOUT)
+            .build()?;
+        Ok(Self { url, client })
+    }
+
+    pub fn url(&self) -> &Url {
+        &self.url
+    }
+
+    /// Announce url with the binary `info_hash` and `peer_id` percent-encoded
+    /// byte by byte, keeping any query the tracker url already had (passkeys).
+    pub fn announce_url(&self, request: &AnnounceRequest) -> Url {
+        let mut query: Vec<String> = self
+            .url
+            .query()
+            .filter(|query| !query.is_empty())
+            .map(|query| vec![query.to_string()])
+            .unwrap_or_default();
+        query.push(format!(
+            "info_hash={}",
+            encode(request.info_hash.as_bytes())
+        ));
+        query.push(format!("peer_id={}", encode(&request.peer_id)));
+        query.push(format!("port={}", request.port));
+        query.push(format!("uploaded={}", request.uploaded));
+        query.push(format!("downloaded={}", request.downloaded));
+        query.push(format!("left={}", request.left));
+        query.push("compact=1".to_string());
+        if let Some(event) = request.event.as_str() {
+            query.push(format!("event={}", event));
+        }
+        if let Some(num_want) = request.num_want {
+            query.push(format!("numwant={}", num_want));
+        }
+        query.push(format!("key={:08x}", request.key));
+        if let Some(tracker_id) = &request.tracker_id {
+            query.push(format!("trackerid={}", encode(tracker_id.as_bytes())));
+        }
+        let mut url = self.url.clone();
+        url.set_query(Some(&query.join("&")));
+        url
+    }
+
+    pub async fn announce(
+        &self,
+        request: &AnnounceRequest,
+    ) -> Result<AnnounceResponse, TrackerError> {
+        let url = self.announce_url(request);
+        tracing::debug!("Announcing to {}", self.url);
+        let response = self.client.get(url).send().await?.error_for_status()?;
+        let body = response.bytes().await?;
+        parse_announce_response(&body)
+    }
+
+    /// Scrape url by the convention of replacing the `announce` in the last
+    /// path segment with `scrape`, `None` if the tracker does not follow it.
+    pub fn scrape_url(&self) -> Option<Url> {
+        let mut url = self.url.clone();
+        let last = url.path_segments()?.next_back()?.to_string();
+        let rest = last.strip_prefix("announce")?;
+        url.path_segments_mut()
+            .ok()?
+            .pop()
+            .push(&format!("scrape{}", rest));
+        Some(url)
+    }
+
+    /// Scrapes `info_hashes` in batches, one request per `MAX_SCRAPE_BATCH`.
+    /// A failed batch is skipped, it's only an error if every batch failed.
+    pub async fn scrape(
+        &self,
+        info_hashes: &[InfoHash],
+    ) -> Result<hashbrown::HashMap<InfoHash, ScrapeStats>, TrackerError> {
+        let scrape_url = self.scrape_url().ok_or_else(|| {
+            TrackerError::InvalidUrl(format!("{} does not support scrape", self.url))
+        })?;
+        let mut stats = hashbrown::HashMap::new();
+        let mut error = None;
+        for batch in info_hashes.chunks(MAX_SCRAPE_BATCH) {
+            match self.scrape_batch(&scrape_url, batch).await {
+                Ok(batch) => stats.extend(batch),
+                Err(e) => {
+                    tracing::debug!("Scrape batch from {} failed: {}", self.url, e);
+                    error = Some(e);
+                }
+            }
+        }
+        match error {
+            Some(e) if stats.is_empty() => Err(e),
+            _ => Ok(stats),
+        }
+    }
+
+    async fn scrape_batch(
+        &self,
+        scrape_url: &Url,
+        batch: &[InfoHash],
+    ) -> Result<hashbrown::HashMap<InfoHash, ScrapeStats>, TrackerError> {
+        let mut query: Vec<String> = scrape_url
+            .query()
+            .filter(|query| !query.is_empty())
+            .map(|query| vec![query.to_string()])
+            .unwrap_or_default();
+        query.extend(
+            batch
+                .iter()
+                .map(|info_hash| format!("info_hash={}", encode(info_hash.as_bytes()))),
+        );
+        let mut url = scrape_url.clone();
+        url.set_query(Some(&query.join("&")));
+        tracing::debug!("Scraping {} torrents from {}", batch.len(), self.url);
+        let response = self.client.get(url).send().await?.error_for_status()?;
+        let body = response.bytes().await?;
+        parse_scrape_response(&body)
+    }
+}
+
+pub fn parse_scrape_response(
+    body: &[u8],
+) -> Result<hashbrown::HashMap<InfoHash, ScrapeStats>, TrackerError> {
+    let raw: RawScrapeResponse = serde_bencode::from_bytes(body)
+        .map_err(|e| TrackerError::InvalidResponse(e.to_string()))?;
+    if let Some(reason) = raw.failure_reason {
+        return Err(TrackerError::Failure(reason));
+    }
+    raw.files
+        .into_iter()
+        .map(|(info_hash, file)| {
+            let info_hash: [u8; 20] = info_hash.as_slice().try_into().map_err(|_| {
+                TrackerError::InvalidResponse(format!(
+                    "scrape info-hash of {} bytes",
+                    info_hash.len()
+                ))
+            })?;
+            let stats = ScrapeStats {
+                seeders: file.complete,
+                completed: file.downloaded,
+                leechers: file.incomplete,
+            };
+            Ok((InfoHash::new(info_hash), stats))
+        })
+        .collect()
+}
+
+pub fn parse_announce_response(body: &[u8]) -> Result<AnnounceResponse, TrackerError> {
+    let raw: RawAnnounceResponse = serde_bencode::from_bytes(body)
+        .map_err(|e| TrackerError::InvalidResponse(e.to_string()))?;
+    if let Some(reason) = raw.failure_reason {
+        return Err(TrackerError::Failure(reason));
+    }
+    let mut peers = match raw.peers {
+        Some(RawPeers::Compact(bytes)) => parse_compact_peers(&bytes)?,
+        Some(RawPeers::Dict(peers)) => peers
+            .into_iter()
+            .filter_map(|peer| {
+                // hostnames are allowed by BEP 3 but we only dial addresses
+                let ip = peer.ip.parse::<IpAddr>().ok()?;
+                Some(SocketAddr::new(ip, peer.port))
+            })
+            .collect(),
+        None => Vec::new(),
+    };
+    if let Some(peers6) = raw.peers6 {
+        peers.extend(parse_compact_peers6(&peers6)?);
+    }
+    Ok(AnnounceResponse {
+        interval: Duration::from_secs(raw.interval.unwrap_or(DEFAULT_INTERVAL)),
+        min_interval: raw.min_interval.map(Duration::from_secs),
+        tracker_id: raw.tracker_id,
+        seeders: raw.complete,
+        leechers: raw.incomplete,
+        peers,
+        warning: raw.warning_message,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::peer::client::generate_peer_id;
+    use crate::peer::tracker::{encode_compact_peer, AnnounceEvent};
+    use axum::extract::RawQuery;
+    use axum::routing::get;
+    use axum::Router;
+
+    fn request() -> AnnounceRequest {
+        AnnounceRequest {
+            info_hash: InfoHash::new([0xab; 20]),
+            peer_id: *b"-JJ0010-abcdefghijkl",
+            port: 6881,
+            uploaded: 1,
+            downloaded: 2,
+            left: 3,
+            event: AnnounceEvent::Started,
+            num_want: Some(50),
+            key: 0xdeadbeef,
+            tracker_id: None,
+        }
+    }
+
+    #[test]
+    fn test_announce_url() {
+        let tracker = HttpTracker::new("http://tracker.example/announce?passkey=abc").unwrap();
+        let url = tracker.announce_url(&request());
+        let expected = format!(
+            "http://tracker.example/announce?passkey=abc&info_hash={}&peer_id=-JJ0010-abcdefghijkl&port=6881&uploaded=1&downloaded=2&left=3&compact=1&event=started&numwant=50&key=deadbeef",
+            "%AB".repeat(20)
+        );
+        assert_eq!(url.as_str(), expected);
+        let request = AnnounceRequest {
+            tracker_id: Some("a b+*~".to_string()),
+            ..request()
+        };
+        let url = tracker.announce_url(&request);
+        assert!(url.as_str().ends_with("&trackerid=a%20b%2B%2A~"));
+        assert!(HttpTracker::new("udp://tracker.example:80").is_err());
+    }
+
+    #[test]
+    fn test_parse_dict_peers() {
+        let body = b"d8:intervali900e5:peersld2:ip8:10.0.0.17:peer id20:aaaaaaaaaaaaaaaaaaaa4:porti6881eed2:ip11:example.org4:porti1eeee";
+        let response = parse_announce_response(body).unwrap();
+        assert_eq!(response.interval, Duration::from_secs(900));
+        assert_eq!(response.peers, vec!["10.0.0.1:6881".parse().unwrap()]);
+    }
+
+    #[test]
+    fn test_parse_failure() {
+        let body = b"d14:failure reason12:unregisterede";
+        assert!(matches!(
+            parse_announce_response(body),
+            Err(TrackerError::Failure(reason)) if reason == "unregistered"
+        ));
+        assert!(matches!(
+            parse_announce_response(b"garbage"),
+            Err(TrackerError::InvalidResponse(_))
+        ));
+    }
+
+    #[test]
+    fn test_scrape_url() {
+        let scrape_url = |url: &str| {
+            HttpTracker::new(url)
+                .unwrap()
+                .scrape_url()
+                .map(|url| url.to_string())
+        };
+        assert_eq!(
+            scrape_url("http://tracker.example/announce").as_deref(),
+            Some("http://tracker.example/scrape")
+        );
+        assert_eq!(
+            scrape_url("http://tracker.example/x/announce.php?passkey=abc").as_deref(),
+            Some("http://tracker.example/x/scrape.php?passkey=abc")
+        );
+        assert_eq!(scrape_url("http://tracker.example/a"), None);
+        assert_eq!(scrape_url("http://tracker.example/announce/x"), None);
+    }
+
+    #[test]
+    fn test_parse_scrape() {
+        let mut body = b"d5:filesd20:".to_vec();
+        body.extend_from_slice(&[0xab; 20]);
+        body.extend_from_slice(b"d8:completei5e10:downloadedi50e10:incompletei10eeee");
+        let stats = parse_scrape_response(&body).unwrap();
+        assert_eq!(
+            stats[&InfoHash::new([0xab; 20])],
+            ScrapeStats {
+                seeders: 5,
+                completed: 50,
+                leechers: 10
+            }
+        );
+        assert!(matches!(
+            parse_scrape_response(b"d14:failure reason8:disablede"),
+            Err(TrackerError::Failure(_))
+        ));
+    }
+
+    async fn stand_in_scrape(query: RawQuery) -> Vec<u8> {
+        let query = query.0.unwrap_or_default();
+        let hashes = query
+            .split('&')
+            .filter_map(|pair| pair.strip_prefix("info_hash="))
+            .count();
+        assert!(hashes <= MAX_SCRAPE_BATCH);
+        if query.contains(&"%C8".repeat(20)) {
+            return b"d14:failure reason7:blockede".to_vec();
+        }
+        let mut body = b"d5:filesd".to_vec();
+        for pair in query.split('&') {
+            if let Some(hash) = pair.strip_prefix("info_hash=") {
+                let hash: Vec<u8> = percent_encoding::percent_decode_str(hash).collect();
+                body.extend_from_slice(b"20:");
+                body.extend_from_slice(&hash);
+                body.extend_from_slice(
+                    format!("d8:completei{}e10:downloadedi0e10:incompletei1ee", hash[0]).as_bytes(),
+                );
+            }
+        }
+        body.extend_from_slice(b"ee");
+        body
+    }
+
+    #[tokio::test]
+    async fn test_scrape_batches() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let router = Router::new().route("/scrape", get(stand_in_scrape));
+        tokio::spawn(async move { axum::serve(listener, router).await });
+
+        let tracker = HttpTracker::new(&format!("http://{}/announce", addr)).unwrap();
+        // the stand-in echoes hashes back in key order, so keep them sorted
+        let hashes: Vec<InfoHash> = (0..120u8).map(|i| InfoHash::new([i; 20])).collect();
+        let stats = tracker.scrape(&hashes).await.unwrap();
+        assert_eq!(stats.len(), 120);
+        assert_eq!(stats[&InfoHash::new([99; 20])].seeders, 99);
+
+        // the second batch fails, the first is kept
+        let hashes: Vec<InfoHash> = (0..100u8)
+            .map(|i| InfoHash::new([if i == 60 { 200 } else { i }; 20]))
+            .collect();
+        let stats = tracker.scrape(&hashes).await.unwrap();
+        assert_eq!(stats.len(), MAX_SCRAPE_BATCH);
+        assert!(tracker.scrape(&[InfoHash::new([200; 20])]).await.is_err());
+    }
+
+    async fn stand_in_tracker(query: RawQuery) -> Vec<u8> {
+        let query = query.0.unwrap_or_default();
+        if !query.contains(&format!("info_hash={}", "%AB".repeat(20)))
+            || !query.contains("compact=1")
+        {
+            return b"d14:failure reason9:bad querye".to_vec();
+        }
+        let mut peers = encode_compact_peer(&"127.0.0.1:6881".parse().unwrap());
+        peers.extend(encode_compact_peer(&"127.0.0.2:6882".parse().unwrap()));
+        let mut body = format!(
+            "d8:completei5e10:incompletei7e8:intervali60e12:min intervali30e5:peers{}:",
+            peers.len()
+        )
+        .into_bytes();
+        body.extend(peers);
+        body.extend_from_slice(b"10:tracker id3:xyz15:warning message4:slowe");
+        body
+    }
+
+    #[tokio::test]
+    async fn test_announce_stand_in_tracker() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let router = Router::new().route("/announce", get(stand_in_tracker));
+        tokio::spawn(async move { axum::serve(listener, router).await });
+
+        let tracker = HttpTracker::new(&format!("http://{}/announce", addr)).unwrap();
+        let mut request = request();
+        request.peer_id = generate_peer_id();
+        let response = tracker.announce(&request).await.unwrap();
+        assert_eq!(response.interval, Duration::from_secs(60));
+        assert_eq!(response.min_interval, Some(Duration::from_secs(30)));
+        assert_eq!(response.seeders, Some(5));
+        assert_eq!(response.leechers, Some(7));
+        assert_eq!(response.tracker_id.as_deref(), Some("xyz"));
+        assert_eq!(response.warning.as_deref(), Some("slow"));
+        assert_eq!(
+            response.peers,
+            vec![
+                "127.0.0.1:6881".parse().unwrap(),
+                "127.0.0.2:6882".parse().unwrap()
+            ]
+        );
+
+        request.info_hash = InfoHash::new([0; 20]);
+        assert!(matches!(
+            tracker.announce(&request).await,
+            Err(TrackerError::Failure(_))
+        ));
+    }
+}
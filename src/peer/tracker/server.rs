@@ -0,0 +1,641 @@
+use super::encode_compact_peer;
+use crate::client::arguments::TrackerSettings;
+use crate::metrics::MetricServer;
+use crate::types::InfoHash;
+use axum::extract::{ConnectInfo, RawQuery, State};
+use axum::http::StatusCode;
+use axum::response::IntoResponse;
+use axum::routing::get;
+use axum::Router;
+use hashbrown::HashMap;
+use libp2p::metrics::Registry;
+use percent_encoding::percent_decode;
+use prometheus_client::encoding::EncodeLabelSet;
+use prometheus_client::metrics::counter::Counter;
+use prometheus_client::metrics::family::Family;
+use prometheus_client::metrics::gauge::Gauge;
+use rand::seq::IteratorRandom;
+use serde_bencode::value::Value;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, Instant};
+
+const DEFAULT_NUM_WANT: usize = 50;
+const MAX_NUM_WANT: usize = 200;
+
+#[derive(Clone, Debug, Hash, PartialEq, Eq, EncodeLabelSet)]
+struct SwarmLabels {
+    info_hash: String,
+}
+
+impl SwarmLabels {
+    fn new(info_hash: &InfoHash) -> Self {
+        Self {
+            info_hash: info_hash.to_hex(),
+        }
+    }
+}
+
+#[derive(Clone, Default)]
+struct TrackerMetrics {
+    seeders: Family<SwarmLabels, Gauge>,
+    leechers: Family<SwarmLabels, Gauge>,
+    completed: Family<SwarmLabels, Counter>,
+    announces: Counter,
+    scrapes: Counter,
+}
+
+impl TrackerMetrics {
+    fn register(&self, registry: &mut Registry) {
+        let registry = registry.sub_registry_with_prefix("tracker");
+        registry.register("seeders", "Seeders per swarm", self.seeders.clone());
+        registry.register("leechers", "Leechers per swarm", self.leechers.clone());
+        registry.register(
+            "completed",
+            "Completed downloads per swarm",
+            self.completed.clone(),
+        );
+        registry.register("announces", "Announce requests", self.announces.clone());
+        registry.register("scrapes", "Scrape requests", self.scrapes.clone());
+    }
+}
+
+#[derive(Debug, Clone)]
+struct PeerEntry {
+    addr: SocketAddr,
+    left: u64,
+    last_seen: Instant,
+}
+
+#[derive(Debug, Default)]
+struct Swarm {
+    peers: HashMap<[u8; 20], PeerEntry>,
+    completed: u32,
+}
+
+impl Swarm {
+    fn seeders(&self) -> u32 {
+        self.peers.values().filter(|peer| peer.left == 0).count() as u32
+    }
+
+    fn leechers(&self) -> u32 {
+        self.peers.len() as u32 - self.seeders()
+    }
+
+    fn expire(&mut self, expiry: Duration, now: Instant) {
+        self.peers
+            .retain(|_, peer| now.duration_since(peer.last_seen) < expiry);
+    }
+}
+
+/// Embedded HTTP tracker used in `Mode::TrackingMode`.
+#[derive(Clone)]
+pub struct TrackerServer {
+    swarms: Arc<RwLock<HashMap<InfoHash, Swarm>>>,
+    settings: TrackerSettings,
+    metrics: TrackerMetrics,
+}
+
+struct AnnounceParams {
+    info_hash: InfoHash,
+    peer_id: [u8; 20],
+    port: u16,
+    left: u64,
+    event: Option<String>,
+    compact: bool,
+    num_want: usize,
+}
+
+impl TrackerServer {
+    pub fn new(settings: TrackerSettings, registry: Option<&mut Registry>) -> Self {
+        let server = Self {
+            swarms: Default::default(),
+            settings,
+            metrics: TrackerMetrics::default(),
+        };
+        if let Some(registry) = registry {
+            server.metrics.register(registry);
+        }
+        server
+    }
+
+    pub fn router(&self) -> Router {
+        Router::new()
+            .route("/announce", get(announce_handler))
+            .route("/scrape", get(scrape_handler))
+            .with_state(self.clone())
+    }
+
+    fn allowed(&self, info_hash: &InfoHash) -> bool {
+        match &self.settings.whitelist {
+            Some(whitelist) => whitelist.contains(info_hash),
+            None => true,
+        }
+    }
+
+    fn announce(&self, query: &str, remote: IpAddr) -> Result<Vec<u8>, String> {
+        let params = parse_announce(query)?;
+        if !self.allowed(&params.info_hash) {
+            return Err("torrent not in whitelist".to_string());
+        }
+        self.metrics.announces.inc();
+        let now = Instant::now();
+        let mut swarms = self.swarms.write().unwrap();
+        let stopped = params.event.as_deref() == Some("stopped");
+        // a stopped peer never creates a swarm
+        let swarm = match swarms.entry(params.info_hash) {
+            hashbrown::hash_map::Entry::Occupied(entry) => Some(entry.into_mut()),
+            hashbrown::hash_map::Entry::Vacant(entry) if !stopped => {
+                Some(entry.insert(Swarm::default()))
+            }
+            hashbrown::hash_map::Entry::Vacant(_) => None,
+        };
+        let mut seeders = 0;
+        let mut leechers = 0;
+        let mut peers = Vec::new();
+        if let Some(swarm) = swarm {
+            swarm.expire(self.peer_expiry(), now);
+            if stopped {
+                swarm.peers.remove(&params.peer_id);
+            } else {
+                // repeats and peers that never leeched don't count
+                let was_leecher = swarm
+                    .peers
+                    .get(&params.peer_id)
+                    .is_some_and(|peer| peer.left > 0);
+                if params.event.as_deref() == Some("completed") && was_leecher {
+                    swarm.completed += 1;
+                    self.metrics
+                        .completed
+                        .get_or_create(&SwarmLabels::new(&params.info_hash))
+                        .inc();
+                }
+                swarm.peers.insert(
+                    params.peer_id,
+                    PeerEntry {
+                        addr: SocketAddr::new(remote, params.port),
+                        left: params.left,
+                        last_seen: now,
+                    },
+                );
+            }
+            (seeders, leechers) = (swarm.seeders(), swarm.leechers());
+            // a random sample, so every peer gets handed out, but not the
+            // ones announcing port 0, nobody can connect to them
+            peers = swarm
+                .peers
+                .iter()
+                .filter(|(peer_id, peer)| **peer_id != params.peer_id && peer.addr.port() != 0)
+                .map(|(peer_id, peer)| (*peer_id, peer.addr))
+                .choose_multiple(&mut rand::thread_rng(), params.num_want);
+        }
+        self.update_swarm(&mut swarms, &params.info_hash);
+        drop(swarms);
+
+        let mut response = std::collections::HashMap::new();
+        response.insert(
+            b"interval".to_vec(),
+            Value::Int(self.settings.interval as i64),
+        );
+        response.insert(
+            b"min interval".to_vec(),
+            Value::Int(self.settings.min_interval as i64),
+        );
+        response.insert(b"complete".to_vec(), Value::Int(seeders as i64));
+        response.insert(b"incomplete".to_vec(), Value::Int(leechers as i64));
+        if params.compact {
+            let (v4, v6): (Vec<_>, Vec<_>) = peers.iter().partition(|(_, addr)| addr.is_ipv4());
+            let encode = |peers: Vec<&([u8; 20], SocketAddr)>| {
+                peers
+                    .into_iter()
+                    .flat_map(|(_, addr)| encode_compact_peer(addr))
+                    .collect::<Vec<u8>>()
+            };
+            response.insert(b"peers".to_vec(), Value::Bytes(encode(v4)));
+            if !v6.is_empty() {
+                response.insert(b"peers6".to_vec(), Value::Bytes(encode(v6)));
+            }
+        } else {
+            let peers = peers
+                .into_iter()
+                .map(|(peer_id, addr)| {
+                    let mut dict = std::collections::HashMap::new();
+                    dict.insert(b"peer id".to_vec(), Value::Bytes(peer_id.to_vec()));
+                    dict.insert(
+                        b"ip".to_vec(),
+                        Value::Bytes(addr.ip().to_string().into_bytes()),
+                    );
+                    dict.insert(b"port".to_vec(), Value::Int(addr.port() as i64));
+                    Value::Dict(dict)
+                })
+                .collect();
+            response.insert(b"peers".to_vec(), Value::List(peers));
+        }
+        serde_bencode::to_bytes(&Value::Dict(response)).map_err(|e| e.to_string())
+    }
+
+    fn scrape(&self, query: &str) -> Result<Vec<u8>, String> {
+        self.metrics.scrapes.inc();
+        let requested = decode_query(query)
+            .into_iter()
+            .filter(|(key, _)| key == "info_hash")
+            .map(|(_, value)| to_info_hash(&value))
+            .collect::<Result<Vec<_>, _>>()?;
+        let swarms = self.swarms.read().unwrap();
+        let info_hashes: Vec<InfoHash> = if requested.is_empty() {
+            swarms.keys().copied().collect()
+        } else {
+            requested
+        };
+        let files = info_hashes
+            .into_iter()
+            .filter(|info_hash| self.allowed(info_hash))
+            .map(|info_hash| {
+                let (seeders, leechers, completed) = swarms
+                    .get(&info_hash)
+                    .map(|swarm| (swarm.seeders(), swarm.leechers(), swarm.completed))
+                    .unwrap_or_default();
+                let mut stats = std::collections::HashMap::new();
+                stats.insert(b"complete".to_vec(), Value::Int(seeders as i64));
+                stats.insert(b"incomplete".to_vec(), Value::Int(leechers as i64));
+                stats.insert(b"downloaded".to_vec(), Value::Int(completed as i64));
+                (info_hash.as_bytes().to_vec(), Value::Dict(stats))
+            })
+            .collect();
+        let mut response = std::collections::HashMap::new();
+        response.insert(b"files".to_vec(), Value::Dict(files));
+        serde_bencode::to_bytes(&Value::Dict(response)).map_err(|e| e.to_string())
+    }
+
+    fn peer_expiry(&self) -> Duration {
+        Duration::from_secs(self.settings.peer_expiry)
+    }
+
+    /// Refreshes the gauges of a swarm, or drops it and its label series
+    /// once the last peer is gone.
+    fn update_swarm(&self, swarms: &mut HashMap<InfoHash, Swarm>, info_hash: &InfoHash) {
+        let labels = SwarmLabels::new(info_hash);
+        match swarms.get(info_hash) {
+            Some(swarm) if !swarm.peers.is_empty() => {
+                self.metrics
+                    .seeders
+                    .get_or_create(&labels)
+                    .set(swarm.seeders() as i64);
+                self.metrics
+                    .leechers
+                    .get_or_create(&labels)
+                    .set(swarm.leechers() as i64);
+            }
+            _ => {
+                swarms.remove(info_hash);
+                self.metrics.seeders.remove(&labels);
+                self.metrics.leechers.remove(&labels);
+                self.metrics.completed.remove(&labels);
+            }
+        }
+    }
+
+    /// Drops peers that have not announced within the configured expiry.
+    pub fn prune(&self) {
+        let now = Instant::now();
+        let mut swarms = self.swarms.write().unwrap();
+        let info_hashes: Vec<InfoHash> = swarms.keys().copied().collect();
+        for info_hash in info_hashes {
+            if let Some(swarm) = swarms.get_mut(&info_hash) {
+                swarm.expire(self.peer_expiry(), now);
+            }
+            self.update_swarm(&mut swarms, &info_hash);
+        }
+    }
+}
+
+fn bencoded_failure(reason: String) -> Vec<u8> {
+    let mut response = std::collections::HashMap::new();
+    response.insert(
+        b"failure reason".to_vec(),
+        Value::Bytes(reason.into_bytes()),
+    );
+    serde_bencode::to_bytes(&Value::Dict(response)).unwrap_or_default()
+}
+
+async fn announce_handler(
+    State(server): State<TrackerServer>,
+    ConnectInfo(remote): ConnectInfo<SocketAddr>,
+    RawQuery(query): RawQuery,
+) -> impl IntoResponse {
+    let body = server
+        .announce(&query.unwrap_or_default(), remote.ip())
+        .unwrap_or_else(bencoded_failure);
+    (StatusCode::OK, body)
+}
+
+async fn scrape_handler(
+    State(server): State<TrackerServer>,
+    RawQuery(query): RawQuery,
+) -> impl IntoResponse {
+    let body = server
+        .scrape(&query.unwrap_or_default())
+        .unwrap_or_else(bencoded_failure);
+    (StatusCode::OK, body)
+}
+
+/// Decodes a query string keeping values as raw bytes, since `info_hash` and
+/// `peer_id` are binary.
+fn decode_query(query: &str) -> Vec<(String, Vec<u8>)> {
+    query
+        .split('&')
+        .filter(|pair| !pair.is_empty())
+        .map(|pair| {
+            let (key, value) = pair.split_once('=').unwrap_or((pair, ""));
+            let decode = |part: &str| {
+                let part = part.replace('+', " ");
+                percent_decode(part.as_bytes()).collect::<Vec<u8>>()
+            };
+            (
+                String::from_utf8_lossy(&decode(key)).into_owned(),
+                decode(value),
+            )
+        })
+        .collect()
+}
+
+fn to_info_hash(bytes: &[u8]) -> Result<InfoHash, String> {
+    let hash: [u8; 20] = bytes
+        .try_into()
+        .map_err(|_| format!("invalid info_hash length {}", bytes.len()))?;
+    Ok(InfoHash::new(hash))
+}
+
+fn parse_announce(query: &str) -> Result<AnnounceParams, String> {
+    let params: HashMap<String, Vec<u8>> = decode_query(query).into_iter().collect();
+    let text = |key: &str| {
+        params
+            .get(key)
+            .map(|value| String::from_utf8_lossy(value).into_owned())
+    };
+    let number = |key: &str| -> Result<Option<u64>, String> {
+        text(key)
+            .map(|value| value.parse::<u64>().map_err(|_| format!("invalid {}", key)))
+            .transpose()
+    };
+    let info_hash = to_info_hash(params.get("info_hash").ok_or("missing info_hash")?)?;
+    let peer_id: [u8; 20] = params
+        .get("peer_id")
+        .ok_or("missing peer_id")?
+        .as_slice()
+        .try_into()
+        .map_err(|_| "invalid peer_id".to_string())?;
+    let port = number("port")?
+        .ok_or("missing port")?
+        .try_into()
+        .map_err(|_| "invalid port".to_string())?;
+    Ok(AnnounceParams {
+        info_hash,
+        peer_id,
+        port,
+        left: number("left")?.unwrap_or(0),
+        event: text("event").filter(|event| !event.is_empty()),
+        compact: text("compact").as_deref() != Some("0"),
+        num_want: number("numwant")?
+            .map(|num_want| (num_want as usize).min(MAX_NUM_WANT))
+            .unwrap_or(DEFAULT_NUM_WANT),
+    })
+}
+
+pub async fn serve(
+    settings: TrackerSettings,
+    metrics: Option<MetricServer>,
+) -> Result<(), std::io::Error> {
+    use tokio::net::TcpListener;
+    let addr = settings.socket_addr;
+    let prune_interval = Duration::from_secs(settings.min_interval.max(1));
+    let server = match metrics {
+        Some(metrics) => {
+            let registry = metrics.get_registry();
+            let mut registry = registry.write().unwrap();
+            TrackerServer::new(settings, Some(&mut registry))
+        }
+        None => TrackerServer::new(settings, None),
+    };
+    let pruner = server.clone();
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(prune_interval);
+        loop {
+            interval.tick().await;
+            pruner.prune();
+        }
+    });
+    let listener = TcpListener::bind(addr).await?;
+    tracing::info!(tracker=%format!("http://{}/announce", listener.local_addr()?));
+    axum::serve(
+        listener,
+        server
+            .router()
+            .into_make_service_with_connect_info::<SocketAddr>(),
+    )
+    .await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::peer::tracker::http::{parse_announce_response, HttpTracker};
+    use crate::peer::tracker::{AnnounceEvent, AnnounceRequest, TrackerError};
+
+    fn announce_query(peer: u8, left: u64, event: &str) -> String {
+        let tracker = HttpTracker::new("http://127.0.0.1/announce").unwrap();
+        let request = AnnounceRequest {
+            info_hash: InfoHash::new([7; 20]),
+            peer_id: [peer; 20],
+            port: 6880 + peer as u16,
+            uploaded: 0,
+            downloaded: 0,
+            left,
+            event: match event {
+                "started" => AnnounceEvent::Started,
+                "stopped" => AnnounceEvent::Stopped,
+                "completed" => AnnounceEvent::Completed,
+                _ => AnnounceEvent::None,
+            },
+            num_want: None,
+            key: 0,
+            tracker_id: None,
+        };
+        tracker.announce_url(&request).query().unwrap().to_string()
+    }
+
+    fn settings() -> TrackerSettings {
+        TrackerSettings {
+            interval: 60,
+            min_interval: 30,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_announce_swarm() {
+        let server = TrackerServer::new(settings(), None);
+        let remote: IpAddr = "10.0.0.1".parse().unwrap();
+        let first = server
+            .announce(&announce_query(1, 100, "started"), remote)
+            .unwrap();
+        let first = parse_announce_response(&first).unwrap();
+        assert_eq!(first.interval, Duration::from_secs(60));
+        assert_eq!(first.min_interval, Some(Duration::from_secs(30)));
+        assert!(first.peers.is_empty());
+
+        let second = server
+            .announce(
+                &announce_query(2, 0, "started"),
+                "10.0.0.2".parse().unwrap(),
+            )
+            .unwrap();
+        let second = parse_announce_response(&second).unwrap();
+        assert_eq!(second.peers, vec!["10.0.0.1:6881".parse().unwrap()]);
+        assert_eq!(second.seeders, Some(1));
+        assert_eq!(second.leechers, Some(1));
+
+        for _ in 0..2 {
+            server
+                .announce(&announce_query(1, 0, "completed"), remote)
+                .unwrap();
+        }
+        // a seed from the start never completed a download here
+        server
+            .announce(&announce_query(2, 0, "completed"), remote)
+            .unwrap();
+        server
+            .announce(&announce_query(2, 0, "stopped"), remote)
+            .unwrap();
+        let scrape = server
+            .scrape(&format!("info_hash={}", "%07".repeat(20)))
+            .unwrap();
+        let mut expected = b"d5:filesd20:".to_vec();
+        expected.extend_from_slice(&[7; 20]);
+        expected.extend_from_slice(b"d8:completei1e10:downloadedi1e10:incompletei0eeee");
+        assert_eq!(scrape, expected);
+    }
+
+    #[test]
+    fn test_port_zero_not_listed() {
+        let server = TrackerServer::new(settings(), None);
+        let remote: IpAddr = "10.0.0.1".parse().unwrap();
+        let query = announce_query(1, 100, "started").replace("port=6881", "port=0");
+        server.announce(&query, remote).unwrap();
+        let response = server
+            .announce(&announce_query(2, 100, "started"), remote)
+            .unwrap();
+        let response = parse_announce_response(&response).unwrap();
+        assert!(response.peers.is_empty());
+        assert_eq!(response.leechers, Some(2));
+    }
+
+    #[test]
+    fn test_whitelist_and_expiry() {
+        let server = TrackerServer::new(
+            TrackerSettings {
+                whitelist: Some(vec![InfoHash::new([1; 20])]),
+                ..settings()
+            },
+            None,
+        );
+        let response = server
+            .announce(&announce_query(1, 0, ""), "10.0.0.1".parse().unwrap())
+            .unwrap_err();
+        assert_eq!(response, "torrent not in whitelist");
+
+        let server = TrackerServer::new(
+            TrackerSettings {
+                peer_expiry: 0,
+                ..settings()
+            },
+            None,
+        );
+        server
+            .announce(&announce_query(1, 0, ""), "10.0.0.1".parse().unwrap())
+            .unwrap();
+        server.prune();
+        assert!(server.swarms.read().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_empty_swarms_are_dropped() {
+        let mut registry = Registry::default();
+        let server = TrackerServer::new(settings(), Some(&mut registry));
+        let remote: IpAddr = "10.0.0.1".parse().unwrap();
+        server
+            .announce(&announce_query(1, 0, "stopped"), remote)
+            .unwrap();
+        assert!(server.swarms.read().unwrap().is_empty());
+
+        for peer in 1..=5 {
+            server
+                .announce(&announce_query(peer, 10, "started"), remote)
+                .unwrap();
+        }
+        let query = format!("{}&numwant=2", announce_query(6, 10, "started"));
+        let response = parse_announce_response(&server.announce(&query, remote).unwrap()).unwrap();
+        assert_eq!(response.peers.len(), 2);
+        assert!(!response.peers.contains(&"10.0.0.1:6886".parse().unwrap()));
+
+        for peer in 1..=6 {
+            server
+                .announce(&announce_query(peer, 10, "stopped"), remote)
+                .unwrap();
+        }
+        assert!(server.swarms.read().unwrap().is_empty());
+        let mut buffer = String::new();
+        prometheus_client::encoding::text::encode(&mut buffer, &registry).unwrap();
+        assert!(!buffer.contains(&InfoHash::new([7; 20]).to_hex()));
+    }
+
+    #[tokio::test]
+    async fn test_tracker_server() {
+        let mut registry = Registry::default();
+        let server = TrackerServer::new(settings(), Some(&mut registry));
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let router = server
+            .router()
+            .into_make_service_with_connect_info::<SocketAddr>();
+        tokio::spawn(async move { axum::serve(listener, router).await });
+
+        let tracker = HttpTracker::new(&format!("http://{}/announce", addr)).unwrap();
+        let mut request = AnnounceRequest {
+            info_hash: InfoHash::new([7; 20]),
+            peer_id: [1; 20],
+            port: 7000,
+            uploaded: 0,
+            downloaded: 0,
+            left: 10,
+            event: AnnounceEvent::Started,
+            num_want: Some(10),
+            key: 0,
+            tracker_id: None,
+        };
+        tracker.announce(&request).await.unwrap();
+        request.peer_id = [2; 20];
+        let response = tracker.announce(&request).await.unwrap();
+        assert_eq!(response.peers, vec!["127.0.0.1:7000".parse().unwrap()]);
+        assert_eq!(response.leechers, Some(2));
+
+        let mut buffer = String::new();
+        prometheus_client::encoding::text::encode(&mut buffer, &registry).unwrap();
+        assert!(buffer.contains(&format!(
+            "tracker_leechers{{info_hash=\"{}\"}} 2",
+            InfoHash::new([7; 20]).to_hex()
+        )));
+
+        // a malformed announce is answered with a bencoded failure reason
+        let body = reqwest::get(format!("http://{}/announce?info_hash=short", addr))
+            .await
+            .unwrap()
+            .bytes()
+            .await
+            .unwrap();
+        assert!(matches!(
+            parse_announce_response(&body),
+            Err(TrackerError::Failure(_))
+        ));
+    }
+}
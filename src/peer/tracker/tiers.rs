@@ -0,0 +1,429 @@
+use super::{AnnounceRequest, AnnounceResponse, Tracker, TrackerError};
+use rand::seq::SliceRandom;
+use std::time::{Duration, Instant};
+
+// retry a failed tracker no sooner than this
+const RETRY_INTERVAL: Duration = Duration::from_secs(60);
+// a tracker that takes longer is skipped for the next one
+const ANNOUNCE_TIMEOUT: Duration = Duration::from_secs(20);
+// one quick retry instead of the full BEP 15 backoff, which takes hours
+const UDP_TIMEOUT_BASE: Duration = Duration::from_secs(5);
+const UDP_MAX_RETRIES: u32 = 1;
+// announce no more often than this, whatever the tracker says
+const MIN_ANNOUNCE_INTERVAL: Duration = Duration::from_secs(60);
+
+/// The tracker's interval, but never below its own `min interval` or
+/// `MIN_ANNOUNCE_INTERVAL`.
+fn announce_interval(response: &AnnounceResponse) -> Duration {
+    response
+        .interval
+        .max(response.min_interval.unwrap_or_default())
+        .max(MIN_ANNOUNCE_INTERVAL)
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TrackerStatus {
+    pub url: String,
+    pub tier: usize,
+    pub last_announce: Option<Instant>,
+    pub next_announce: Option<Instant>,
+    pub last_error: Option<String>,
+    pub peers_received: usize,
+    /// Sent back on every announce once the tracker handed one out.
+    pub tracker_id: Option<String>,
+}
+
+impl TrackerStatus {
+    fn new(url: String, tier: usize) -> Self {
+        Self {
+            url,
+            tier,
+            last_announce: None,
+            next_announce: None,
+            last_error: None,
+            peers_received: 0,
+            tracker_id: None,
+        }
+    }
+}
+
+#[derive(Debug)]
+struct TrackerEntry {
+    status: TrackerStatus,
+    // kept between announces so UDP connection ids are reused
+    tracker: Option<Tracker>,
+}
+
+/// Announce-list tiers (BEP 12). Trackers are shuffled within their tier,
+/// tried tier by tier, and a tracker that answers moves to the front of its
+/// tier.
+#[derive(Debug, Default)]
+pub struct TrackerTiers {
+    tiers: Vec<Vec<TrackerEntry>>,
+    next_announce: Option<Instant>,
+}
+
+impl TrackerTiers {
+    pub fn new(mut tiers: Vec<Vec<String>>) -> Self {
+        let mut rng = rand::thread_rng();
+        for tier in tiers.iter_mut() {
+            tier.shuffle(&mut rng);
+        }
+        Self::restore(tiers)
+    }
+
+    /// Tiers in their current order, without shuffling, e.g. from resume
+    /// data.
+    pub fn restore(tiers: Vec<Vec<String>>) -> Self {
+        let tiers = tiers
+            .into_iter()
+            .filter(|tier| !tier.is_empty())
+            .enumerate()
+            .map(|(index, tier)| {
+                tier.into_iter()
+                    .map(|url| TrackerEntry {
+                        status: TrackerStatus::new(url, index),
+                        tracker: None,
+                    })
+                    .collect()
+            })
+            .collect();
+        Self {
+            tiers,
+            next_announce: None,
+        }
+    }
+
+    /// The same trackers and statuses without their connections, to stand in
+    /// while the tiers are away announcing.
+    pub fn snapshot(&self) -> Self {
+        let tiers = self
+            .tiers
+            .iter()
+            .map(|tier| {
+                tier.iter()
+                    .map(|entry| TrackerEntry {
+                        status: entry.status.clone(),
+                        tracker: None,
+                    })
+                    .collect()
+            })
+            .collect();
+        Self {
+            tiers,
+            next_announce: self.next_announce,
+        }
+    }
+
+    /// `(url, tracker id)` of every tracker that handed out an id, for resume
+    /// data.
+    pub fn tracker_ids(&self) -> Vec<(String, String)> {
+        self.tiers
+            .iter()
+            .flatten()
+            .filter_map(|entry| {
+                let id = entry.status.tracker_id.clone()?;
+                Some((entry.status.url.clone(), id))
+            })
+            .collect()
+    }
+
+    pub fn set_tracker_id(&mut self, url: &str, tracker_id: String) {
+        for entry in self.tiers.iter_mut().flatten() {
+            if entry.status.url == url {
+                entry.status.tracker_id = Some(tracker_id.clone());
+            }
+        }
+    }
+
+    /// Urls per tier in the order they are tried, responsive trackers first.
+    pub fn urls(&self) -> Vec<Vec<String>> {
+        self.tiers
+            .iter()
+            .map(|tier| tier.iter().map(|entry| entry.status.url.clone()).collect())
+            .collect()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.tiers.is_empty()
+    }
+
+    /// Status of every tracker in the order they would be tried.
+    pub fn statuses(&self) -> Vec<TrackerStatus> {
+        self.tiers
+            .iter()
+            .flatten()
+            .map(|entry| entry.status.clone())
+            .collect()
+    }
+
+    /// When to announce again: after the interval of the tracker that last
+    /// answered, or `RETRY_INTERVAL` after every tracker failed. `None`
+    /// before the first announce.
+    pub fn next_announce(&self) -> Option<Instant> {
+        self.next_announce
+    }
+
+    /// Makes the tiers due right away, e.g. when a stopped torrent starts
+    /// again.
+    pub fn announce_now(&mut self) {
+        self.next_announce = None;
+    }
+
+    pub fn is_due(&self, now: Instant) -> bool {
+        !self.is_empty() && self.next_announce.is_none_or(|next| next <= now)
+    }
+
+    /// Announces to the first tracker that answers, falling through the tiers
+    /// on failure. Returns the last error when every tracker failed.
+    pub async fn announce(
+        &mut self,
+        request: &AnnounceRequest,
+    ) -> Result<AnnounceResponse, TrackerError> {
+        let result = self.announce_tiers(request).await;
+        let interval = match &result {
+            Ok(response) => announce_interval(response),
+            Err(_) => RETRY_INTERVAL,
+        };
+        self.next_announce = Some(Instant::now() + interval);
+        result
+    }
+
+    async fn announce_tiers(
+        &mut self,
+        request: &AnnounceRequest,
+    ) -> Result<AnnounceResponse, TrackerError> {
+        let mut last_error = TrackerError::Failure("no trackers".to_string());
+        for tier in self.tiers.iter_mut() {
+            for index in 0..tier.len() {
+                let entry = &mut tier[index];
+                let result = tokio::time::timeout(ANNOUNCE_TIMEOUT, announce_entry(entry, request))
+                    .await
+                    .unwrap_or_else(|_| {
+                        entry.status.last_error = Some(TrackerError::Timeout.to_string());
+                        Err(TrackerError::Timeout)
+                    });
+                match result {
+                    Ok(response) => {
+                        let entry = tier.remove(index);
+                        tier.insert(0, entry);
+                        return Ok(response);
+                    }
+                    Err(e) => {
+                        tracing::debug!("Announce to {} failed: {}", entry.status.url, e);
+                        last_error = e;
+                    }
+                }
+            }
+        }
+        Err(last_error)
+    }
+}
+
+async fn announce_entry(
+    entry: &mut TrackerEntry,
+    request: &AnnounceRequest,
+) -> Result<AnnounceResponse, TrackerError> {
+    let now = Instant::now();
+    entry.status.last_announce = Some(now);
+    entry.status.next_announce = Some(now + RETRY_INTERVAL);
+    let request = AnnounceRequest {
+        tracker_id: entry.status.tracker_id.clone(),
+        ..request.clone()
+    };
+    let result = match entry.tracker.as_mut() {
+        Some(tracker) => tracker.announce(&request).await,
+        None => match Tracker::new(&entry.status.url).await {
+            Ok(Tracker::Udp(tracker)) => {
+                let tracker = tracker.with_backoff(UDP_TIMEOUT_BASE, UDP_MAX_RETRIES);
+                entry
+                    .tracker
+                    .insert(Tracker::Udp(tracker))
+                    .announce(&request)
+                    .await
+            }
+            Ok(tracker) => entry.tracker.insert(tracker).announce(&request).await,
+            Err(e) => Err(e),
+        },
+    };
+    match &result {
+        Ok(response) => {
+            entry.status.last_error = None;
+            entry.status.peers_received += response.peers.len();
+            entry.status.next_announce = Some(now + announce_interval(response));
+            if response.tracker_id.is_some() {
+                entry.status.tracker_id = response.tracker_id.clone();
+            }
+        }
+        Err(e) => {
+            entry.status.last_error = Some(e.to_string());
+            entry.status.next_announce = Some(now + RETRY_INTERVAL);
+        }
+    }
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::peer::tracker::server::TrackerServer;
+    use crate::peer::tracker::AnnounceEvent;
+    use crate::types::InfoHash;
+    use std::net::SocketAddr;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    fn request() -> AnnounceRequest {
+        AnnounceRequest {
+            info_hash: InfoHash::new([5; 20]),
+            peer_id: [1; 20],
+            port: 6881,
+            uploaded: 0,
+            downloaded: 0,
+            left: 10,
+            event: AnnounceEvent::Started,
+            num_want: None,
+            key: 0,
+            tracker_id: None,
+        }
+    }
+
+    async fn live_tracker() -> String {
+        let server = TrackerServer::new(Default::default(), None);
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let router = server
+            .router()
+            .into_make_service_with_connect_info::<SocketAddr>();
+        tokio::spawn(async move { axum::serve(listener, router).await });
+        format!("http://{}/announce", addr)
+    }
+
+    async fn dead_tracker() -> String {
+        // bound then dropped, so connections are refused
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        format!("http://{}/announce", listener.local_addr().unwrap())
+    }
+
+    #[test]
+    fn test_tiers_shuffle_within_tier() {
+        let first: Vec<String> = (0..20).map(|i| format!("http://{}.example/a", i)).collect();
+        let tiers = TrackerTiers::new(vec![
+            first.clone(),
+            vec![],
+            vec!["udp://last.example:80".to_string()],
+        ]);
+        let statuses = tiers.statuses();
+        assert_eq!(statuses.len(), 21);
+        assert!(statuses[..20]
+            .iter()
+            .all(|status| status.tier == 0 && first.contains(&status.url)));
+        assert_eq!(statuses[20].tier, 1);
+        assert_eq!(statuses[20].url, "udp://last.example:80");
+        assert_eq!(tiers.next_announce(), None);
+    }
+
+    #[tokio::test]
+    async fn test_tiers_fall_through() {
+        let live = live_tracker().await;
+        let dead = dead_tracker().await;
+        let mut tiers = TrackerTiers::new(vec![
+            vec![dead.clone(), "wss://unsupported.example".to_string()],
+            vec![live.clone()],
+        ]);
+        tiers.announce(&request()).await.unwrap();
+        let statuses = tiers.statuses();
+        assert!(statuses[..2]
+            .iter()
+            .all(|status| status.last_error.is_some() && status.next_announce.is_some()));
+        assert_eq!(statuses[2].url, live);
+        assert_eq!(statuses[2].last_error, None);
+        assert!(statuses[2].next_announce.unwrap() > Instant::now() + RETRY_INTERVAL);
+
+        let mut other = request();
+        other.peer_id = [2; 20];
+        tiers.announce(&other).await.unwrap();
+        assert_eq!(tiers.statuses()[2].peers_received, 1);
+    }
+
+    #[tokio::test]
+    async fn test_tiers_promote_responding_tracker() {
+        let live = live_tracker().await;
+        let dead = dead_tracker().await;
+        let mut tiers = TrackerTiers::new(vec![vec![dead.clone(), live.clone()]]);
+        // whatever the shuffle, the live tracker ends up first
+        tiers.announce(&request()).await.unwrap();
+        assert_eq!(tiers.statuses()[0].url, live);
+        assert_eq!(tiers.statuses()[1].url, dead);
+        // the order survives a restart
+        let restored = TrackerTiers::restore(tiers.urls());
+        assert_eq!(restored.urls(), vec![vec![live.clone(), dead.clone()]]);
+
+        let mut dead_only = TrackerTiers::new(vec![vec![dead]]);
+        assert!(dead_only.announce(&request()).await.is_err());
+        assert!(TrackerTiers::new(vec![])
+            .announce(&request())
+            .await
+            .is_err());
+    }
+
+    // hands out a tracker id and counts the announces that sent it back
+    async fn id_tracker(
+        axum::extract::State(echoed): axum::extract::State<std::sync::Arc<AtomicUsize>>,
+        axum::extract::RawQuery(query): axum::extract::RawQuery,
+    ) -> Vec<u8> {
+        if query.unwrap_or_default().contains("trackerid=abc") {
+            echoed.fetch_add(1, Ordering::SeqCst);
+        }
+        b"d8:intervali900e5:peers0:10:tracker id3:abce".to_vec()
+    }
+
+    #[tokio::test]
+    async fn test_tiers_schedule_and_tracker_id() {
+        let echoed = std::sync::Arc::new(AtomicUsize::new(0));
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/announce", listener.local_addr().unwrap());
+        let router = axum::Router::new()
+            .route("/announce", axum::routing::get(id_tracker))
+            .with_state(echoed.clone());
+        tokio::spawn(async move { axum::serve(listener, router).await });
+
+        let mut tiers = TrackerTiers::new(vec![vec![url.clone()]]);
+        let now = Instant::now();
+        assert!(tiers.is_due(now));
+        tiers.announce(&request()).await.unwrap();
+        assert_eq!(tiers.tracker_ids(), vec![(url.clone(), "abc".to_string())]);
+        assert_eq!(echoed.load(Ordering::SeqCst), 0);
+        // the tracker's interval decides the next announce
+        assert!(!tiers.is_due(Instant::now()));
+        assert!(tiers.is_due(now + Duration::from_secs(901)));
+
+        let mut restored = TrackerTiers::restore(tiers.urls());
+        restored.set_tracker_id(&url, "abc".to_string());
+        restored.announce(&request()).await.unwrap();
+        assert_eq!(echoed.load(Ordering::SeqCst), 1);
+        assert_eq!(restored.snapshot().statuses(), restored.statuses());
+
+        let mut dead = TrackerTiers::new(vec![vec![dead_tracker().await]]);
+        assert!(dead.announce(&request()).await.is_err());
+        assert!(!dead.is_due(Instant::now()));
+        assert!(!TrackerTiers::new(vec![]).is_due(Instant::now()));
+    }
+
+    #[test]
+    fn test_announce_interval_floor() {
+        let mut response = AnnounceResponse {
+            interval: Duration::ZERO,
+            min_interval: None,
+            tracker_id: None,
+            seeders: None,
+            leechers: None,
+            peers: vec![],
+            warning: None,
+        };
+        assert_eq!(announce_interval(&response), MIN_ANNOUNCE_INTERVAL);
+        response.min_interval = Some(Duration::from_secs(300));
+        assert_eq!(announce_interval(&response), Duration::from_secs(300));
+        response.interval = Duration::from_secs(1800);
+        assert_eq!(announce_interval(&response), Duration::from_secs(1800));
+    }
+}
@@ -0,0 +1,457 @@
+use super::{
+    parse_compact_peers, parse_compact_peers6, AnnounceEvent, AnnounceRequest, AnnounceResponse,
+    ScrapeStats, TrackerError,
+};
+use crate::types::InfoHash;
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+use tokio::net::UdpSocket;
+use url::Url;
+
+const PROTOCOL_ID: u64 = 0x41727101980;
+const ACTION_CONNECT: u32 = 0;
+const ACTION_ANNOUNCE: u32 = 1;
+const ACTION_SCRAPE: u32 = 2;
+const ACTION_ERROR: u32 = 3;
+const CONNECTION_ID_TTL: Duration = Duration::from_secs(60);
+// BEP 15 retransmission: wait 15 * 2^n seconds, n = 0..=8
+const TIMEOUT_BASE: Duration = Duration::from_secs(15);
+const MAX_RETRIES: u32 = 8;
+const MAX_PACKET_SIZE: usize = 2048;
+// BEP 15: up to about 74 torrents fit in one scrape
+pub const MAX_SCRAPE_BATCH: usize = 74;
+
+/// Announce and scrape client for `udp://` trackers (BEP 15).
+#[derive(Debug)]
+pub struct UdpTracker {
+    socket: UdpSocket,
+    addr: SocketAddr,
+    connection: Option<(u64, Instant)>,
+    timeout_base: Duration,
+    max_retries: u32,
+}
+
+impl UdpTracker {
+    pub async fn new(url: &str) -> Result<Self, TrackerError> {
+        let url = Url::parse(url).map_err(|e| TrackerError::InvalidUrl(e.to_string()))?;
+        if url.scheme() != "udp" {
+            return Err(TrackerError::InvalidUrl(format!(
+                "unsupported scheme {}",
+                url.scheme()
+            )));
+        }
+        let host = url
+            .host_str()
+            .ok_or_else(|| TrackerError::InvalidUrl("missing host".to_string()))?;
+        let port = url
+            .port()
+            .ok_or_else(|| TrackerError::InvalidUrl("missing port".to_string()))?;
+        let addr = tokio::net::lookup_host((host.trim_matches(['[', ']']), port))
+            .await?
+            .next()
+            .ok_or_else(|| TrackerError::InvalidUrl(format!("could not resolve {}", host)))?;
+        Self::with_addr(addr).await
+    }
+
+    pub async fn with_addr(addr: SocketAddr) -> Result<Self, TrackerError> {
+        let bind: SocketAddr = if addr.is_ipv6() {
+            "[::]:0".parse().unwrap()
+        } else {
+            "0.0.0.0:0".parse().unwrap()
+        };
+        let socket = UdpSocket::bind(bind).await?;
+        socket.connect(addr).await?;
+        Ok(Self {
+            socket,
+            addr,
+            connection: None,
+            timeout_base: TIMEOUT_BASE,
+            max_retries: MAX_RETRIES,
+        })
+    }
+
+    /// Overrides the BEP 15 backoff schedule of `base * 2^n` for `n` up to `max_retries`.
+    pub fn with_backoff(mut self, base: Duration, max_retries: u32) -> Self {
+        self.timeout_base = base;
+        self.max_retries = max_retries;
+        self
+    }
+
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    pub async fn announce(
+        &mut self,
+        request: &AnnounceRequest,
+    ) -> Result<AnnounceResponse, TrackerError> {
+        let event: u32 = match request.event {
+            AnnounceEvent::None => 0,
+            AnnounceEvent::Completed => 1,
+            AnnounceEvent::Started => 2,
+            AnnounceEvent::Stopped => 3,
+        };
+        let mut body = Vec::with_capacity(82);
+        body.extend_from_slice(request.info_hash.as_bytes());
+        body.extend_from_slice(&request.peer_id);
+        body.extend_from_slice(&request.downloaded.to_be_bytes());
+        body.extend_from_slice(&request.left.to_be_bytes());
+        body.extend_from_slice(&request.uploaded.to_be_bytes());
+        body.extend_from_slice(&event.to_be_bytes());
+        // ip address, 0 means the sender's address
+        body.extend_from_slice(&0u32.to_be_bytes());
+        body.extend_from_slice(&request.key.to_be_bytes());
+        let num_want = request.num_want.map(|n| n as i32).unwrap_or(-1);
+        body.extend_from_slice(&num_want.to_be_bytes());
+        body.extend_from_slice(&request.port.to_be_bytes());
+
+        let response = self.request(ACTION_ANNOUNCE, &body).await?;
+        if response.len() < 12 {
+            return Err(TrackerError::InvalidResponse(format!(
+                "announce response of {} bytes",
+                response.len()
+            )));
+        }
+        let interval = read_u32(&response, 0);
+        let leechers = read_u32(&response, 4);
+        let seeders = read_u32(&response, 8);
+        let peers = if self.addr.is_ipv6() {
+            parse_compact_peers6(&response[12..])?
+        } else {
+            parse_compact_peers(&response[12..])?
+        };
+        Ok(AnnounceResponse {
+            interval: Duration::from_secs(interval as u64),
+            min_interval: None,
+            tracker_id: None,
+            seeders: Some(seeders),
+            leechers: Some(leechers),
+            peers,
+            warning: None,
+        })
+    }
+
+    /// Scrapes `info_hashes`, split into packets of `MAX_SCRAPE_BATCH`. Failed
+    /// packets are skipped unless all of them failed.
+    pub async fn scrape(
+        &mut self,
+        info_hashes: &[InfoHash],
+    ) -> Result<hashbrown::HashMap<InfoHash, ScrapeStats>, TrackerError> {
+        let mut stats = hashbrown::HashMap::new();
+        let mut error = None;
+        for batch in info_hashes.chunks(MAX_SCRAPE_BATCH) {
+            match self.scrape_batch(batch).await {
+                Ok(batch) => stats.extend(batch),
+                Err(e) => error = Some(e),
+            }
+        }
+        match error {
+            Some(e) if stats.is_empty() => Err(e),
+            _ => Ok(stats),
+        }
+    }
+
+    async fn scrape_batch(
+        &mut self,
+        info_hashes: &[InfoHash],
+    ) -> Result<Vec<(InfoHash, ScrapeStats)>, TrackerError> {
+        let body: Vec<u8> = info_hashes
+            .iter()
+            .flat_map(|info_hash| info_hash.as_bytes().to_vec())
+            .collect();
+        let response = self.request(ACTION_SCRAPE, &body).await?;
+        if response.len() < info_hashes.len() * 12 {
+            return Err(TrackerError::InvalidResponse(format!(
+                "scrape response of {} bytes for {} torrents",
+                response.len(),
+                info_hashes.len()
+            )));
+        }
+        Ok(info_hashes
+            .iter()
+            .zip(response.chunks_exact(12))
+            .map(|(info_hash, chunk)| {
+                let stats = ScrapeStats {
+                    seeders: read_u32(chunk, 0),
+                    completed: read_u32(chunk, 4),
+                    leechers: read_u32(chunk, 8),
+                };
+                (*info_hash, stats)
+            })
+            .collect())
+    }
+
+    async fn connection_id(&mut self) -> Result<u64, TrackerError> {
+        if let Some((connection_id, obtained)) = self.connection {
+            if obtained.elapsed() < CONNECTION_ID_TTL {
+                return Ok(connection_id);
+            }
+        }
+        let transaction_id: u32 = rand::random();
+        let packet = header(PROTOCOL_ID, ACTION_CONNECT, transaction_id);
+        for attempt in 0..=self.max_retries {
+            if let Some(response) = self
+                .attempt(&packet, transaction_id, ACTION_CONNECT, attempt)
+                .await?
+            {
+                if response.len() < 8 {
+                    return Err(TrackerError::InvalidResponse(
+                        "short connect response".to_string(),
+                    ));
+                }
+                let connection_id = u64::from_be_bytes(response[..8].try_into().unwrap());
+                self.connection = Some((connection_id, Instant::now()));
+                return Ok(connection_id);
+            }
+        }
+        Err(TrackerError::Timeout)
+    }
+
+    /// Sends `action` with a fresh connection id on every retransmission, so
+    /// an id that expires while backing off gets renewed.
+    async fn request(&mut self, action: u32, body: &[u8]) -> Result<Vec<u8>, TrackerError> {
+        for attempt in 0..=self.max_retries {
+            let connection_id = self.connection_id().await?;
+            let transaction_id: u32 = rand::random();
+            let mut packet = header(connection_id, action, transaction_id);
+            packet.extend_from_slice(body);
+            if let Some(response) = self
+                .attempt(&packet, transaction_id, action, attempt)
+                .await?
+            {
+                return Ok(response);
+            }
+        }
+        Err(TrackerError::Timeout)
+    }
+
+    /// One send and wait; `None` when the attempt timed out.
+    async fn attempt(
+        &self,
+        packet: &[u8],
+        transaction_id: u32,
+        action: u32,
+        attempt: u32,
+    ) -> Result<Option<Vec<u8>>, TrackerError> {
+        self.socket.send(packet).await?;
+        let factor = 2u32.checked_pow(attempt).unwrap_or(u32::MAX);
+        let timeout = self.timeout_base.saturating_mul(factor);
+        match tokio::time::timeout(timeout, self.receive(transaction_id, action)).await {
+            Ok(response) => response.map(Some),
+            Err(_) => {
+                tracing::debug!(
+                    "UDP tracker {} timed out after {:?}, attempt {}",
+                    self.addr,
+                    timeout,
+                    attempt
+                );
+                Ok(None)
+            }
+        }
+    }
+
+    async fn receive(&self, transaction_id: u32, action: u32) -> Result<Vec<u8>, TrackerError> {
+        let mut buf = [0u8; MAX_PACKET_SIZE];
+        loop {
+            let len = self.socket.recv(&mut buf).await?;
+            // stale responses to earlier retransmissions carry other transaction ids
+            if len < 8 || read_u32(&buf, 4) != transaction_id {
+                continue;
+            }
+            let payload = buf[8..len].to_vec();
+            return match read_u32(&buf, 0) {
+                received if received == action => Ok(payload),
+                ACTION_ERROR => Err(TrackerError::Failure(
+                    String::from_utf8_lossy(&payload).into_owned(),
+                )),
+                received => Err(TrackerError::InvalidResponse(format!(
+                    "expected action {}, got {}",
+                    action, received
+                ))),
+            };
+        }
+    }
+}
+
+fn header(connection_id: u64, action: u32, transaction_id: u32) -> Vec<u8> {
+    let mut packet = Vec::with_capacity(16);
+    packet.extend_from_slice(&connection_id.to_be_bytes());
+    packet.extend_from_slice(&action.to_be_bytes());
+    packet.extend_from_slice(&transaction_id.to_be_bytes());
+    packet
+}
+
+fn read_u32(bytes: &[u8], offset: usize) -> u32 {
+    u32::from_be_bytes(bytes[offset..offset + 4].try_into().unwrap())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::peer::tracker::encode_compact_peer;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    const CONNECTION_ID: u64 = 0x1122334455667788;
+
+    #[derive(Default)]
+    struct Counters {
+        connects: AtomicUsize,
+        announces: AtomicUsize,
+    }
+
+    /// Minimal BEP 15 tracker that drops the first `drop_announces` announces.
+    async fn stand_in_tracker(drop_announces: usize) -> (SocketAddr, Arc<Counters>) {
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let addr = socket.local_addr().unwrap();
+        let counters = Arc::new(Counters::default());
+        let state = counters.clone();
+        tokio::spawn(async move {
+            let mut buf = [0u8; MAX_PACKET_SIZE];
+            loop {
+                let (len, from) = socket.recv_from(&mut buf).await.unwrap();
+                let connection_id = u64::from_be_bytes(buf[..8].try_into().unwrap());
+                let action = read_u32(&buf, 8);
+                let transaction_id = &buf[12..16];
+                let mut response = Vec::new();
+                match action {
+                    ACTION_CONNECT => {
+                        assert_eq!(connection_id, PROTOCOL_ID);
+                        state.connects.fetch_add(1, Ordering::SeqCst);
+                        response.extend_from_slice(&ACTION_CONNECT.to_be_bytes());
+                        response.extend_from_slice(transaction_id);
+                        response.extend_from_slice(&CONNECTION_ID.to_be_bytes());
+                    }
+                    ACTION_ANNOUNCE => {
+                        assert_eq!(connection_id, CONNECTION_ID);
+                        assert_eq!(len, 98);
+                        if state.announces.fetch_add(1, Ordering::SeqCst) < drop_announces {
+                            continue;
+                        }
+                        if buf[16..36] == [0xee; 20] {
+                            response.extend_from_slice(&ACTION_ERROR.to_be_bytes());
+                            response.extend_from_slice(transaction_id);
+                            response.extend_from_slice(b"torrent not registered");
+                        } else {
+                            response.extend_from_slice(&ACTION_ANNOUNCE.to_be_bytes());
+                            response.extend_from_slice(transaction_id);
+                            response.extend_from_slice(&900u32.to_be_bytes());
+                            response.extend_from_slice(&3u32.to_be_bytes());
+                            response.extend_from_slice(&4u32.to_be_bytes());
+                            response.extend(encode_compact_peer(&"10.0.0.1:6881".parse().unwrap()));
+                        }
+                    }
+                    ACTION_SCRAPE => {
+                        assert!((len - 16) / 20 <= MAX_SCRAPE_BATCH);
+                        response.extend_from_slice(&ACTION_SCRAPE.to_be_bytes());
+                        response.extend_from_slice(transaction_id);
+                        for (index, _) in buf[16..len].chunks_exact(20).enumerate() {
+                            for value in [index as u32 + 1, 10, 20] {
+                                response.extend_from_slice(&value.to_be_bytes());
+                            }
+                        }
+                    }
+                    _ => unreachable!(),
+                }
+                socket.send_to(&response, from).await.unwrap();
+            }
+        });
+        (addr, counters)
+    }
+
+    fn request(info_hash: [u8; 20]) -> AnnounceRequest {
+        AnnounceRequest {
+            info_hash: InfoHash::new(info_hash),
+            peer_id: *b"-JJ0010-abcdefghijkl",
+            port: 6881,
+            uploaded: 0,
+            downloaded: 0,
+            left: 100,
+            event: AnnounceEvent::Started,
+            num_want: None,
+            key: 1,
+            tracker_id: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_udp_announce_and_scrape() {
+        let (addr, counters) = stand_in_tracker(0).await;
+        let url = format!("udp://{}/announce", addr);
+        let mut tracker = UdpTracker::new(&url).await.unwrap();
+        let response = tracker.announce(&request([1; 20])).await.unwrap();
+        assert_eq!(response.interval, Duration::from_secs(900));
+        assert_eq!(response.leechers, Some(3));
+        assert_eq!(response.seeders, Some(4));
+        assert_eq!(response.peers, vec!["10.0.0.1:6881".parse().unwrap()]);
+
+        let hashes = [InfoHash::new([1; 20]), InfoHash::new([2; 20])];
+        let stats = tracker.scrape(&hashes).await.unwrap();
+        assert_eq!(stats[&hashes[1]].seeders, 2);
+        assert_eq!(stats[&hashes[1]].completed, 10);
+        assert_eq!(stats[&hashes[1]].leechers, 20);
+        // the connection id is cached for both requests
+        assert_eq!(counters.connects.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn test_udp_scrape_batches() {
+        let (addr, _) = stand_in_tracker(0).await;
+        let mut tracker = UdpTracker::with_addr(addr).await.unwrap();
+        let hashes: Vec<InfoHash> = (0..100u8).map(|i| InfoHash::new([i; 20])).collect();
+        let stats = tracker.scrape(&hashes).await.unwrap();
+        assert_eq!(stats.len(), 100);
+        // seeders are the position within the packet, so the second batch restarts at 1
+        assert_eq!(stats[&hashes[MAX_SCRAPE_BATCH - 1]].seeders, 74);
+        assert_eq!(stats[&hashes[MAX_SCRAPE_BATCH]].seeders, 1);
+    }
+
+    #[tokio::test]
+    async fn test_udp_connection_id_expiry() {
+        let (addr, counters) = stand_in_tracker(0).await;
+        let mut tracker = UdpTracker::with_addr(addr).await.unwrap();
+        tracker.announce(&request([1; 20])).await.unwrap();
+        let (connection_id, _) = tracker.connection.unwrap();
+        let expired = Instant::now()
+            .checked_sub(CONNECTION_ID_TTL + Duration::from_secs(1))
+            .unwrap();
+        tracker.connection = Some((connection_id, expired));
+        tracker.announce(&request([1; 20])).await.unwrap();
+        assert_eq!(counters.connects.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn test_udp_retransmission() {
+        let (addr, counters) = stand_in_tracker(2).await;
+        let mut tracker = UdpTracker::with_addr(addr)
+            .await
+            .unwrap()
+            .with_backoff(Duration::from_millis(20), 3);
+        tracker.announce(&request([1; 20])).await.unwrap();
+        assert_eq!(counters.announces.load(Ordering::SeqCst), 3);
+    }
+
+    #[tokio::test]
+    async fn test_udp_errors() {
+        let (addr, _) = stand_in_tracker(0).await;
+        let mut tracker = UdpTracker::with_addr(addr).await.unwrap();
+        assert!(matches!(
+            tracker.announce(&request([0xee; 20])).await,
+            Err(TrackerError::Failure(message)) if message == "torrent not registered"
+        ));
+
+        // nothing listens on this socket
+        let silent = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let mut tracker = UdpTracker::with_addr(silent.local_addr().unwrap())
+            .await
+            .unwrap()
+            .with_backoff(Duration::from_millis(5), 1);
+        assert!(matches!(
+            tracker.announce(&request([1; 20])).await,
+            Err(TrackerError::Timeout)
+        ));
+        assert!(UdpTracker::new("http://tracker.example/announce")
+            .await
+            .is_err());
+    }
+}
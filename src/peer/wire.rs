@@ -0,0 +1,382 @@
+use crate::peer::error::WireError;
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio_util::codec::{Decoder, Encoder, Framed};
+use tokio_util::compat::{Compat, FuturesAsyncReadCompatExt};
+
+/// Large enough for a 16 KiB block and the bitfield of a torrent with two
+/// million pieces.
+pub const DEFAULT_MAX_MESSAGE_LEN: usize = 1 << 18;
+
+const CHOKE: u8 = 0;
+const UNCHOKE: u8 = 1;
+const INTERESTED: u8 = 2;
+const NOT_INTERESTED: u8 = 3;
+const HAVE: u8 = 4;
+const BITFIELD: u8 = 5;
+const REQUEST: u8 = 6;
+const PIECE: u8 = 7;
+const CANCEL: u8 = 8;
+const PORT: u8 = 9;
+const EXTENDED: u8 = 20;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct BlockInfo {
+    pub index: u32,
+    pub begin: u32,
+    pub length: u32,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Message {
+    KeepAlive,
+    Choke,
+    Unchoke,
+    Interested,
+    NotInterested,
+    Have(u32),
+    Bitfield(Bytes),
+    Request(BlockInfo),
+    Piece {
+        index: u32,
+        begin: u32,
+        data: Bytes,
+    },
+    Cancel(BlockInfo),
+    Port(u16),
+    /// BEP 10 extension message, `id` 0 is the extended handshake.
+    Extended {
+        id: u8,
+        payload: Bytes,
+    },
+}
+
+impl Message {
+    fn payload_len(&self) -> usize {
+        match self {
+            Message::KeepAlive => 0,
+            Message::Choke | Message::Unchoke | Message::Interested | Message::NotInterested => 1,
+            Message::Have(_) => 5,
+            Message::Bitfield(bitfield) => 1 + bitfield.len(),
+            Message::Request(_) | Message::Cancel(_) => 13,
+            Message::Piece { data, .. } => 9 + data.len(),
+            Message::Port(_) => 3,
+            Message::Extended { payload, .. } => 2 + payload.len(),
+        }
+    }
+}
+
+/// Length-prefixed peer message codec. Works on any tokio stream through
+/// [`framed`] and on libp2p streams through [`framed_compat`].
+#[derive(Debug, Clone, Copy)]
+pub struct PeerCodec {
+    max_message_len: usize,
+}
+
+impl Default for PeerCodec {
+    fn default() -> Self {
+        Self::new(DEFAULT_MAX_MESSAGE_LEN)
+    }
+}
+
+impl PeerCodec {
+    pub fn new(max_message_len: usize) -> Self {
+        Self { max_message_len }
+    }
+}
+
+impl Decoder for PeerCodec {
+    type Item = Message;
+    type Error = WireError;
+
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Message>, WireError> {
+        if src.len() < 4 {
+            return Ok(None);
+        }
+        let len = u32::from_be_bytes(src[..4].try_into().unwrap()) as usize;
+        if len > self.max_message_len {
+            return Err(WireError::MessageTooLong(len));
+        }
+        if src.len() < 4 + len {
+            src.reserve(4 + len - src.len());
+            return Ok(None);
+        }
+        src.advance(4);
+        let mut payload = src.split_to(len);
+        if len == 0 {
+            return Ok(Some(Message::KeepAlive));
+        }
+        let id = payload.get_u8();
+        let expect = |payload: &BytesMut, expected: usize| {
+            if payload.len() == expected {
+                Ok(())
+            } else {
+                Err(WireError::InvalidMessage(format!(
+                    "message {} with {} byte payload",
+                    id,
+                    payload.len()
+                )))
+            }
+        };
+        let message = match id {
+            CHOKE | UNCHOKE | INTERESTED | NOT_INTERESTED => {
+                expect(&payload, 0)?;
+                match id {
+                    CHOKE => Message::Choke,
+                    UNCHOKE => Message::Unchoke,
+                    INTERESTED => Message::Interested,
+                    _ => Message::NotInterested,
+                }
+            }
+            HAVE => {
+                expect(&payload, 4)?;
+                Message::Have(payload.get_u32())
+            }
+            BITFIELD => Message::Bitfield(payload.freeze()),
+            REQUEST | CANCEL => {
+                expect(&payload, 12)?;
+                let block = BlockInfo {
+                    index: payload.get_u32(),
+                    begin: payload.get_u32(),
+                    length: payload.get_u32(),
+                };
+                if id == REQUEST {
+                    Message::Request(block)
+                } else {
+                    Message::Cancel(block)
+                }
+            }
+            PIECE => {
+                if payload.len() < 8 {
+                    return Err(WireError::InvalidMessage(format!(
+                        "piece with {} byte payload",
+                        payload.len()
+                    )));
+                }
+                Message::Piece {
+                    index: payload.get_u32(),
+                    begin: payload.get_u32(),
+                    data: payload.freeze(),
+                }
+            }
+            PORT => {
+                expect(&payload, 2)?;
+                Message::Port(payload.get_u16())
+            }
+            EXTENDED => {
+                if payload.is_empty() {
+                    return Err(WireError::InvalidMessage(
+                        "extended message without an id".to_string(),
+                    ));
+                }
+                Message::Extended {
+                    id: payload.get_u8(),
+                    payload: payload.freeze(),
+                }
+            }
+            id => return Err(WireError::UnknownMessage(id)),
+        };
+        Ok(Some(message))
+    }
+}
+
+impl Encoder<Message> for PeerCodec {
+    type Error = WireError;
+
+    fn encode(&mut self, message: Message, dst: &mut BytesMut) -> Result<(), WireError> {
+        let len = message.payload_len();
+        if len > self.max_message_len {
+            return Err(WireError::MessageTooLong(len));
+        }
+        dst.reserve(4 + len);
+        dst.put_u32(len as u32);
+        match message {
+            Message::KeepAlive => {}
+            Message::Choke => dst.put_u8(CHOKE),
+            Message::Unchoke => dst.put_u8(UNCHOKE),
+            Message::Interested => dst.put_u8(INTERESTED),
+            Message::NotInterested => dst.put_u8(NOT_INTERESTED),
+            Message::Have(index) => {
+                dst.put_u8(HAVE);
+                dst.put_u32(index);
+            }
+            Message::Bitfield(bitfield) => {
+                dst.put_u8(BITFIELD);
+                dst.extend_from_slice(&bitfield);
+            }
+            Message::Request(block) => put_block(dst, REQUEST, block),
+            Message::Cancel(block) => put_block(dst, CANCEL, block),
+            Message::Piece { index, begin, data } => {
+                dst.put_u8(PIECE);
+                dst.put_u32(index);
+                dst.put_u32(begin);
+                dst.extend_from_slice(&data);
+            }
+            Message::Port(port) => {
+                dst.put_u8(PORT);
+                dst.put_u16(port);
+            }
+            Message::Extended { id, payload } => {
+                dst.put_u8(EXTENDED);
+                dst.put_u8(id);
+                dst.extend_from_slice(&payload);
+            }
+        }
+        Ok(())
+    }
+}
+
+fn put_block(dst: &mut BytesMut, id: u8, block: BlockInfo) {
+    dst.put_u8(id);
+    dst.put_u32(block.index);
+    dst.put_u32(block.begin);
+    dst.put_u32(block.length);
+}
+
+/// Peer messages over a raw TCP connection or any other tokio stream, after
+/// the handshake.
+pub fn framed<S: AsyncRead + AsyncWrite>(stream: S, codec: PeerCodec) -> Framed<S, PeerCodec> {
+    Framed::new(stream, codec)
+}
+
+/// Peer messages over a `futures` io stream such as a libp2p stream opened by
+/// `network::Session`.
+pub fn framed_compat<S>(stream: S, codec: PeerCodec) -> Framed<Compat<S>, PeerCodec>
+where
+    S: futures::AsyncRead + futures::AsyncWrite,
+{
+    Framed::new(stream.compat(), codec)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::{SinkExt, StreamExt};
+    use tokio_util::compat::TokioAsyncReadCompatExt;
+
+    fn messages() -> Vec<Message> {
+        let block = BlockInfo {
+            index: 1,
+            begin: 16384,
+            length: 16384,
+        };
+        vec![
+            Message::KeepAlive,
+            Message::Choke,
+            Message::Unchoke,
+            Message::Interested,
+            Message::NotInterested,
+            Message::Have(7),
+            Message::Bitfield(Bytes::from_static(&[0b1010_0000, 0x01])),
+            Message::Request(block),
+            Message::Piece {
+                index: 1,
+                begin: 0,
+                data: Bytes::from(vec![0xab; 100]),
+            },
+            Message::Cancel(block),
+            Message::Port(6881),
+            Message::Extended {
+                id: 0,
+                payload: Bytes::from_static(b"d1:md11:ut_metadatai1eee"),
+            },
+        ]
+    }
+
+    #[test]
+    fn test_encode_layout() {
+        let mut buf = BytesMut::new();
+        let mut codec = PeerCodec::default();
+        codec.encode(Message::KeepAlive, &mut buf).unwrap();
+        codec.encode(Message::Have(7), &mut buf).unwrap();
+        codec
+            .encode(
+                Message::Request(BlockInfo {
+                    index: 1,
+                    begin: 2,
+                    length: 3,
+                }),
+                &mut buf,
+            )
+            .unwrap();
+        assert_eq!(
+            &buf[..],
+            &[
+                0, 0, 0, 0, // keep-alive
+                0, 0, 0, 5, 4, 0, 0, 0, 7, // have
+                0, 0, 0, 13, 6, 0, 0, 0, 1, 0, 0, 0, 2, 0, 0, 0, 3, // request
+            ]
+        );
+    }
+
+    #[test]
+    fn test_roundtrip_partial_frames() {
+        let mut codec = PeerCodec::default();
+        let mut encoded = BytesMut::new();
+        for message in messages() {
+            codec.encode(message, &mut encoded).unwrap();
+        }
+        // feed one byte at a time, frames must only complete once whole
+        let mut buf = BytesMut::new();
+        let mut decoded = Vec::new();
+        for byte in encoded {
+            buf.put_u8(byte);
+            while let Some(message) = codec.decode(&mut buf).unwrap() {
+                decoded.push(message);
+            }
+        }
+        assert_eq!(decoded, messages());
+        assert!(buf.is_empty());
+    }
+
+    #[test]
+    fn test_decode_errors() {
+        let mut codec = PeerCodec::new(16);
+        let mut buf = BytesMut::from(&[0, 0, 0, 17][..]);
+        assert!(matches!(
+            codec.decode(&mut buf),
+            Err(WireError::MessageTooLong(17))
+        ));
+        let mut buf = BytesMut::from(&[0, 0, 0, 1, 42][..]);
+        assert!(matches!(
+            codec.decode(&mut buf),
+            Err(WireError::UnknownMessage(42))
+        ));
+        let mut buf = BytesMut::from(&[0, 0, 0, 2, HAVE, 1][..]);
+        assert!(matches!(
+            codec.decode(&mut buf),
+            Err(WireError::InvalidMessage(_))
+        ));
+        let mut buf = BytesMut::from(&[0, 0, 0, 1, EXTENDED][..]);
+        assert!(matches!(
+            codec.decode(&mut buf),
+            Err(WireError::InvalidMessage(_))
+        ));
+        let mut buf = BytesMut::new();
+        let piece = Message::Piece {
+            index: 0,
+            begin: 0,
+            data: Bytes::from(vec![0; 16]),
+        };
+        assert!(matches!(
+            codec.encode(piece, &mut buf),
+            Err(WireError::MessageTooLong(25))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_framed_streams() {
+        let (ours, theirs) = tokio::io::duplex(1024);
+        let mut ours = framed(ours, PeerCodec::default());
+        // the peer side goes through the futures io adapters used for libp2p streams
+        let mut theirs = framed_compat(theirs.compat(), PeerCodec::default());
+        for message in messages() {
+            ours.send(message).await.unwrap();
+        }
+        for message in messages() {
+            assert_eq!(theirs.next().await.unwrap().unwrap(), message);
+        }
+        theirs.send(Message::Unchoke).await.unwrap();
+        assert_eq!(ours.next().await.unwrap().unwrap(), Message::Unchoke);
+    }
+}
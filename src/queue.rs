@@ -0,0 +1,391 @@
+use crate::client::arguments::QueueSettings;
+use crate::db::TorrentRecord;
+use crate::types::{FileStatus, InfoHash};
+
+/// Where to move a torrent in the queue.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum QueueMove {
+    Top,
+    Up,
+    Down,
+    Bottom,
+}
+
+#[derive(Debug, Clone)]
+struct QueueEntry {
+    info_hash: InfoHash,
+    status: FileStatus,
+    paused: bool,
+    /// Always active and not counted against the limits.
+    forced: bool,
+    complete: bool,
+}
+
+impl QueueEntry {
+    fn is_active(&self) -> bool {
+        self.status.is_active()
+    }
+}
+
+/// Decides which torrents run. Torrents earlier in the queue are started
+/// first, up to the download, seed and total limits; the rest wait in
+/// `DownloadQueued` or `SeedQueued`.
+#[derive(Debug, Clone)]
+pub struct QueueManager {
+    settings: QueueSettings,
+    entries: Vec<QueueEntry>,
+}
+
+impl QueueManager {
+    pub fn new(settings: QueueSettings) -> Self {
+        Self {
+            settings,
+            entries: Vec::new(),
+        }
+    }
+
+    /// Builds the queue from the library, which is already in queue order.
+    pub fn with_torrents(settings: QueueSettings, torrents: &[TorrentRecord]) -> Self {
+        let mut queue = Self::new(settings);
+        for record in torrents {
+            let complete = record.completed.is_some()
+                || matches!(record.state, FileStatus::Seeding | FileStatus::SeedQueued);
+            queue.entries.push(QueueEntry {
+                info_hash: record.info_hash,
+                status: record.state,
+                paused: record.state == FileStatus::Paused,
+                forced: record.forced,
+                complete,
+            });
+        }
+        queue
+    }
+
+    pub fn settings(&self) -> QueueSettings {
+        self.settings
+    }
+
+    /// Takes effect on the next `update`.
+    pub fn set_settings(&mut self, settings: QueueSettings) {
+        self.settings = settings;
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    fn index(&self, info_hash: &InfoHash) -> Option<usize> {
+        self.entries
+            .iter()
+            .position(|entry| entry.info_hash == *info_hash)
+    }
+
+    fn entry_mut(&mut self, info_hash: &InfoHash) -> Option<&mut QueueEntry> {
+        self.entries
+            .iter_mut()
+            .find(|entry| entry.info_hash == *info_hash)
+    }
+
+    /// Adds a torrent at the bottom of the queue. Returns false if it is
+    /// already queued.
+    pub fn add(&mut self, info_hash: InfoHash, complete: bool) -> bool {
+        if self.index(&info_hash).is_some() {
+            return false;
+        }
+        let status = if complete {
+            FileStatus::SeedQueued
+        } else {
+            FileStatus::DownloadQueued
+        };
+        self.entries.push(QueueEntry {
+            info_hash,
+            status,
+            paused: false,
+            forced: false,
+            complete,
+        });
+        true
+    }
+
+    pub fn remove(&mut self, info_hash: &InfoHash) -> bool {
+        match self.index(info_hash) {
+            Some(index) => {
+                self.entries.remove(index);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Zero-based, the top of the queue is 0.
+    pub fn position(&self, info_hash: &InfoHash) -> Option<usize> {
+        self.index(info_hash)
+    }
+
+    pub fn status(&self, info_hash: &InfoHash) -> Option<FileStatus> {
+        self.index(info_hash)
+            .map(|index| self.entries[index].status)
+    }
+
+    pub fn is_forced(&self, info_hash: &InfoHash) -> bool {
+        self.index(info_hash)
+            .map(|index| self.entries[index].forced)
+            .unwrap_or(false)
+    }
+
+    /// Like the other changes, takes effect on the next `update`.
+    pub fn pause(&mut self, info_hash: &InfoHash) -> bool {
+        match self.entry_mut(info_hash) {
+            Some(entry) => {
+                entry.paused = true;
+                entry.forced = false;
+                true
+            }
+            None => false,
+        }
+    }
+
+    pub fn resume(&mut self, info_hash: &InfoHash) -> bool {
+        match self.entry_mut(info_hash) {
+            Some(entry) => {
+                entry.paused = false;
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// A forced torrent runs regardless of the limits, resuming it if paused.
+    pub fn set_forced(&mut self, info_hash: &InfoHash, forced: bool) -> bool {
+        if forced {
+            self.resume(info_hash);
+        }
+        match self.entry_mut(info_hash) {
+            Some(entry) => {
+                entry.forced = forced;
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// The torrent finished downloading and moves on to seeding.
+    pub fn completed(&mut self, info_hash: &InfoHash) -> bool {
+        match self.entry_mut(info_hash) {
+            Some(entry) => {
+                entry.complete = true;
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Returns false if the torrent isn't queued or is already at the
+    /// requested end.
+    pub fn move_to(&mut self, info_hash: &InfoHash, to: QueueMove) -> bool {
+        let Some(index) = self.index(info_hash) else {
+            return false;
+        };
+        let last = self.entries.len() - 1;
+        let target = match to {
+            QueueMove::Top => 0,
+            QueueMove::Up => index.saturating_sub(1),
+            QueueMove::Down => (index + 1).min(last),
+            QueueMove::Bottom => last,
+        };
+        if target == index {
+            return false;
+        }
+        let entry = self.entries.remove(index);
+        self.entries.insert(target, entry);
+        true
+    }
+
+    /// Starts and queues torrents in queue order to fit the limits. Returns
+    /// the torrents whose status changed, with their new status.
+    pub fn update(&mut self) -> Vec<(InfoHash, FileStatus)> {
+        let settings = self.settings;
+        let mut downloads = 0;
+        let mut seeds = 0;
+        let mut changes = Vec::new();
+        for entry in &mut self.entries {
+            let status = if entry.paused {
+                FileStatus::Paused
+            } else if entry.complete {
+                if entry.forced
+                    || (seeds < settings.max_active_seeds
+                        && downloads + seeds < settings.max_active)
+                {
+                    if !entry.forced {
+                        seeds += 1;
+                    }
+                    FileStatus::Seeding
+                } else {
+                    FileStatus::SeedQueued
+                }
+            } else if entry.forced
+                || (downloads < settings.max_active_downloads
+                    && downloads + seeds < settings.max_active)
+            {
+                if !entry.forced {
+                    downloads += 1;
+                }
+                FileStatus::Downloading
+            } else {
+                FileStatus::DownloadQueued
+            };
+            if status != entry.status {
+                entry.status = status;
+                changes.push((entry.info_hash, status));
+            }
+        }
+        changes
+    }
+
+    pub fn active(&self) -> impl Iterator<Item = InfoHash> + '_ {
+        self.entries
+            .iter()
+            .filter(|entry| entry.is_active())
+            .map(|entry| entry.info_hash)
+    }
+
+    /// Library priorities that keep the current queue order, top first.
+    pub fn priorities(&self) -> Vec<(InfoHash, u32)> {
+        let count = self.entries.len() as u32;
+        self.entries
+            .iter()
+            .enumerate()
+            .map(|(index, entry)| (entry.info_hash, count - index as u32))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hash(byte: u8) -> InfoHash {
+        InfoHash::new([byte; 20])
+    }
+
+    fn settings() -> QueueSettings {
+        QueueSettings {
+            max_active_downloads: 2,
+            max_active_seeds: 1,
+            max_active: 3,
+        }
+    }
+
+    #[test]
+    fn test_limits() {
+        let mut queue = QueueManager::new(settings());
+        for byte in 0..4 {
+            assert!(queue.add(hash(byte), false));
+        }
+        assert!(!queue.add(hash(0), false));
+        queue.add(hash(10), true);
+        queue.add(hash(11), true);
+
+        let changes = queue.update();
+        assert_eq!(
+            changes,
+            vec![
+                (hash(0), FileStatus::Downloading),
+                (hash(1), FileStatus::Downloading),
+                (hash(10), FileStatus::Seeding),
+            ]
+        );
+        assert_eq!(queue.status(&hash(2)), Some(FileStatus::DownloadQueued));
+        assert_eq!(queue.status(&hash(11)), Some(FileStatus::SeedQueued));
+        assert!(queue.update().is_empty());
+
+        // a finished download frees a slot but takes the only seed slot
+        queue.completed(&hash(0));
+        let changes = queue.update();
+        assert!(changes.contains(&(hash(0), FileStatus::Seeding)));
+        assert!(changes.contains(&(hash(2), FileStatus::Downloading)));
+        assert!(changes.contains(&(hash(10), FileStatus::SeedQueued)));
+
+        // paused torrents don't count
+        queue.pause(&hash(1));
+        assert_eq!(
+            queue.update(),
+            vec![
+                (hash(1), FileStatus::Paused),
+                (hash(3), FileStatus::Downloading)
+            ]
+        );
+        queue.resume(&hash(1));
+        queue.update();
+        assert_eq!(queue.status(&hash(1)), Some(FileStatus::Downloading));
+        assert_eq!(queue.status(&hash(3)), Some(FileStatus::DownloadQueued));
+        assert_eq!(queue.active().count(), 3);
+    }
+
+    #[test]
+    fn test_forced_ignores_limits() {
+        let mut queue = QueueManager::new(settings());
+        for byte in 0..4 {
+            queue.add(hash(byte), false);
+        }
+        queue.pause(&hash(3));
+        assert!(queue.set_forced(&hash(3), true));
+        queue.update();
+        assert_eq!(queue.status(&hash(3)), Some(FileStatus::Downloading));
+        assert_eq!(queue.active().count(), 3);
+        assert_eq!(queue.status(&hash(2)), Some(FileStatus::DownloadQueued));
+
+        queue.set_forced(&hash(3), false);
+        queue.update();
+        assert_eq!(queue.status(&hash(3)), Some(FileStatus::DownloadQueued));
+    }
+
+    #[test]
+    fn test_forced_is_restored() {
+        let magnet = crate::magnet::MagnetLink::parse(
+            "magnet:?xt=urn:btih:0123456789abcdef0123456789abcdef01234567",
+        )
+        .unwrap();
+        let records: Vec<_> = (0..4)
+            .map(|byte| {
+                let mut record = TorrentRecord::from_magnet(&magnet, "/downloads".into());
+                record.info_hash = hash(byte);
+                record.forced = byte == 3;
+                record
+            })
+            .collect();
+        let mut queue = QueueManager::with_torrents(settings(), &records);
+        assert!(queue.is_forced(&hash(3)));
+        queue.update();
+        assert_eq!(queue.status(&hash(3)), Some(FileStatus::Downloading));
+        assert_eq!(queue.active().count(), 3);
+    }
+
+    #[test]
+    fn test_reorder() {
+        let mut queue = QueueManager::new(settings());
+        for byte in 0..4 {
+            queue.add(hash(byte), false);
+        }
+        queue.update();
+        assert!(queue.move_to(&hash(3), QueueMove::Top));
+        assert!(!queue.move_to(&hash(3), QueueMove::Up));
+        assert!(queue.move_to(&hash(0), QueueMove::Bottom));
+        assert!(queue.move_to(&hash(2), QueueMove::Up));
+        assert!(!queue.move_to(&hash(9), QueueMove::Down));
+        let order: Vec<_> = queue.priorities().into_iter().map(|(h, _)| h).collect();
+        assert_eq!(order, vec![hash(3), hash(2), hash(1), hash(0)]);
+        assert_eq!(queue.priorities()[0].1, 4);
+
+        let changes = queue.update();
+        assert!(changes.contains(&(hash(3), FileStatus::Downloading)));
+        assert!(changes.contains(&(hash(0), FileStatus::DownloadQueued)));
+        assert_eq!(queue.position(&hash(1)), Some(2));
+        assert!(queue.remove(&hash(1)));
+        assert_eq!(queue.len(), 3);
+    }
+}
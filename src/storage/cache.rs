@@ -0,0 +1,253 @@
+use bytes::Bytes;
+use hashbrown::HashMap;
+use std::collections::BTreeMap;
+
+/// Verified data waiting to be written, keyed by offset in the torrent byte
+/// stream. Adjacent and overlapping writes are merged into one run, so a
+/// flush issues one write per run and file instead of one per block.
+#[derive(Debug, Default)]
+pub struct WriteCache {
+    // runs never overlap or touch
+    runs: BTreeMap<u64, Vec<u8>>,
+    len: usize,
+}
+
+impl WriteCache {
+    /// Bytes waiting to be written.
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.runs.is_empty()
+    }
+
+    pub fn run_count(&self) -> usize {
+        self.runs.len()
+    }
+
+    pub fn insert(&mut self, offset: u64, data: &[u8]) {
+        self.merge(offset, data, true);
+    }
+
+    /// Puts runs from a failed flush back. Anything written to the cache
+    /// since the flush took them is newer and wins where they overlap.
+    pub fn restore(&mut self, runs: Vec<(u64, Vec<u8>)>) {
+        for (offset, data) in runs {
+            self.merge(offset, &data, false);
+        }
+    }
+
+    fn merge(&mut self, offset: u64, data: &[u8], newest: bool) {
+        let end = offset + data.len() as u64;
+        let touching: Vec<u64> = self
+            .runs
+            .range(..=end)
+            .rev()
+            .take_while(|(start, run)| **start + run.len() as u64 >= offset)
+            .map(|(start, _)| *start)
+            .collect();
+        let mut start = offset;
+        let mut merged_end = end;
+        let mut parts = Vec::with_capacity(touching.len());
+        for run_start in touching {
+            let run = self.runs.remove(&run_start).expect("run just seen");
+            self.len -= run.len();
+            start = start.min(run_start);
+            merged_end = merged_end.max(run_start + run.len() as u64);
+            parts.push((run_start, run));
+        }
+        let mut buffer = vec![0; (merged_end - start) as usize];
+        let at = (offset - start) as usize;
+        if !newest {
+            buffer[at..at + data.len()].copy_from_slice(data);
+        }
+        for (run_start, run) in parts {
+            let run_at = (run_start - start) as usize;
+            buffer[run_at..run_at + run.len()].copy_from_slice(&run);
+        }
+        // the newest data wins where it overlaps older runs
+        if newest {
+            buffer[at..at + data.len()].copy_from_slice(data);
+        }
+        self.len += buffer.len();
+        self.runs.insert(start, buffer);
+    }
+
+    /// Pending data for `offset..offset + len`, if a single run covers it.
+    pub fn read(&self, offset: u64, len: u64) -> Option<Vec<u8>> {
+        let (start, run) = self.runs.range(..=offset).next_back()?;
+        let at = (offset - start) as usize;
+        run.get(at..at + len as usize).map(<[u8]>::to_vec)
+    }
+
+    /// Copies the pending data overlapping `offset..offset + buffer.len()`
+    /// over `buffer`, e.g. a piece read from disk. Returns true if any did.
+    pub fn overlay(&self, offset: u64, buffer: &mut [u8]) -> bool {
+        let end = offset + buffer.len() as u64;
+        let mut overlaid = false;
+        for (start, run) in self.runs.range(..end).rev() {
+            let run_end = start + run.len() as u64;
+            if run_end <= offset {
+                break;
+            }
+            let from = offset.max(*start);
+            let to = end.min(run_end);
+            buffer[(from - offset) as usize..(to - offset) as usize]
+                .copy_from_slice(&run[(from - start) as usize..(to - start) as usize]);
+            overlaid = true;
+        }
+        overlaid
+    }
+
+    /// Empties the cache for a flush.
+    pub fn take(&mut self) -> Vec<(u64, Vec<u8>)> {
+        self.len = 0;
+        std::mem::take(&mut self.runs).into_iter().collect()
+    }
+}
+
+#[derive(Debug)]
+struct CachedPiece {
+    data: Bytes,
+    hits: u64,
+}
+
+/// Whole pieces read from disk to serve uploads. When full, the piece with
+/// the fewest reads is evicted so popular pieces stay in memory.
+#[derive(Debug)]
+pub struct ReadCache {
+    capacity: usize,
+    pieces: HashMap<u32, CachedPiece>,
+    len: usize,
+}
+
+impl ReadCache {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            pieces: HashMap::new(),
+            len: 0,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.pieces.is_empty()
+    }
+
+    pub fn get(&mut self, index: u32) -> Option<Bytes> {
+        let piece = self.pieces.get_mut(&index)?;
+        piece.hits += 1;
+        Some(piece.data.clone())
+    }
+
+    pub fn insert(&mut self, index: u32, data: Bytes) {
+        if data.len() > self.capacity {
+            return;
+        }
+        self.invalidate(index);
+        while self.len + data.len() > self.capacity {
+            let Some(coldest) = self
+                .pieces
+                .iter()
+                .min_by_key(|(_, piece)| piece.hits)
+                .map(|(index, _)| *index)
+            else {
+                break;
+            };
+            self.invalidate(coldest);
+        }
+        self.len += data.len();
+        self.pieces.insert(index, CachedPiece { data, hits: 1 });
+    }
+
+    pub fn invalidate(&mut self, index: u32) {
+        if let Some(piece) = self.pieces.remove(&index) {
+            self.len -= piece.data.len();
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.pieces.clear();
+        self.len = 0;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_write_cache_coalesces() {
+        let mut cache = WriteCache::default();
+        cache.insert(10, &[1; 5]);
+        cache.insert(20, &[3; 5]);
+        assert_eq!(cache.run_count(), 2);
+        // fills the gap, all three become one run
+        cache.insert(15, &[2; 5]);
+        assert_eq!(cache.run_count(), 1);
+        assert_eq!(cache.len(), 15);
+        // overlapping data replaces what was there
+        cache.insert(12, &[9; 2]);
+        cache.insert(40, &[4; 1]);
+        assert_eq!(cache.read(11, 4), Some(vec![1, 9, 9, 1]));
+        assert_eq!(cache.read(20, 10), None);
+        assert_eq!(cache.len(), 16);
+
+        let runs = cache.take();
+        assert!(cache.is_empty());
+        assert_eq!(runs.len(), 2);
+        let mut expected = vec![1; 5];
+        expected[2..4].copy_from_slice(&[9, 9]);
+        expected.extend_from_slice(&[2; 5]);
+        expected.extend_from_slice(&[3; 5]);
+        assert_eq!(runs[0], (10, expected));
+        assert_eq!(runs[1], (40, vec![4]));
+    }
+
+    #[test]
+    fn test_write_cache_overlay() {
+        let mut cache = WriteCache::default();
+        cache.insert(2, &[1; 2]);
+        cache.insert(8, &[2; 4]);
+        let mut buffer = [0; 10];
+        assert!(cache.overlay(0, &mut buffer));
+        assert_eq!(buffer, [0, 0, 1, 1, 0, 0, 0, 0, 2, 2]);
+        assert!(!cache.overlay(4, &mut [0; 4]));
+    }
+
+    #[test]
+    fn test_write_cache_restore_keeps_newer_data() {
+        let mut cache = WriteCache::default();
+        cache.insert(0, &[1; 4]);
+        let runs = cache.take();
+        cache.insert(2, &[2; 4]);
+        cache.restore(runs);
+        assert_eq!(cache.run_count(), 1);
+        assert_eq!(cache.read(0, 6), Some(vec![1, 1, 2, 2, 2, 2]));
+        assert_eq!(cache.len(), 6);
+    }
+
+    #[test]
+    fn test_read_cache_keeps_popular_pieces() {
+        let mut cache = ReadCache::new(10);
+        cache.insert(0, Bytes::from(vec![0; 4]));
+        cache.insert(1, Bytes::from(vec![1; 4]));
+        cache.get(0);
+        // piece 1 was read least and makes room
+        cache.insert(2, Bytes::from(vec![2; 4]));
+        assert!(cache.get(1).is_none());
+        assert_eq!(cache.get(0), Some(Bytes::from(vec![0; 4])));
+        assert_eq!(cache.len(), 8);
+        cache.insert(3, Bytes::from(vec![3; 11]));
+        assert!(cache.get(3).is_none());
+        cache.invalidate(0);
+        assert_eq!(cache.len(), 4);
+        cache.clear();
+        assert!(cache.is_empty());
+    }
+}
@@ -0,0 +1,495 @@
+use super::cache::{ReadCache, WriteCache};
+use super::layout::FileLayout;
+use crate::download::BLOCK_LEN;
+use crate::metainfo::Info;
+use crate::types::Bitfield;
+use bytes::Bytes;
+use sha1::{Digest, Sha1};
+use std::fs::OpenOptions;
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use thiserror::Error;
+use tokio::sync::{RwLock, Semaphore};
+
+#[derive(Debug, Error)]
+pub enum StorageError {
+    #[error("Disk error on {path}: {source}")]
+    Io {
+        path: PathBuf,
+        #[source]
+        source: io::Error,
+    },
+    #[error("Block {index}:{begin} of {len} bytes is outside the torrent")]
+    OutOfRange { index: u32, begin: u32, len: u64 },
+    #[error("Block request of {0} bytes is over the {BLOCK_LEN} byte limit")]
+    BlockTooLong(u32),
+    #[error("Disk worker failed: {0}")]
+    Worker(String),
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct StorageSettings {
+    /// Blocking threads doing disk I/O at the same time.
+    pub workers: usize,
+    /// Bytes buffered before the write cache is flushed.
+    pub write_cache_size: usize,
+    pub read_cache_size: usize,
+}
+
+impl Default for StorageSettings {
+    fn default() -> Self {
+        Self {
+            workers: 4,
+            write_cache_size: 4 << 20,
+            read_cache_size: 16 << 20,
+        }
+    }
+}
+
+struct Inner {
+    layout: FileLayout,
+    settings: StorageSettings,
+    workers: Semaphore,
+    write_cache: Mutex<WriteCache>,
+    read_cache: Mutex<ReadCache>,
+    // held exclusively while a flush is writing, so reads never see data
+    // that left the write cache but isn't on disk yet
+    disk: RwLock<()>,
+    error: Mutex<Option<String>>,
+}
+
+/// Reads and writes the files of one torrent. Disk I/O runs on tokio's
+/// blocking pool, never on the event loop.
+#[derive(Clone)]
+pub struct Storage {
+    inner: Arc<Inner>,
+}
+
+impl std::fmt::Debug for Storage {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Storage")
+            .field("root", &self.inner.layout.root)
+            .finish()
+    }
+}
+
+impl Storage {
+    pub fn new(layout: FileLayout, settings: StorageSettings) -> Self {
+        Self {
+            inner: Arc::new(Inner {
+                layout,
+                settings,
+                workers: Semaphore::new(settings.workers.max(1)),
+                write_cache: Mutex::new(WriteCache::default()),
+                read_cache: Mutex::new(ReadCache::new(settings.read_cache_size)),
+                disk: RwLock::new(()),
+                error: Mutex::new(None),
+            }),
+        }
+    }
+
+    pub fn layout(&self) -> &FileLayout {
+        &self.inner.layout
+    }
+
+    /// The first disk error. The torrent can't make progress after one and
+    /// should be stopped with it.
+    pub fn error(&self) -> Option<String> {
+        self.inner.error.lock().unwrap().clone()
+    }
+
+    fn offset(&self, index: u32, begin: u32, len: u64) -> Result<u64, StorageError> {
+        let range = self.inner.layout.piece_range(index as usize);
+        match (range, (begin as u64).checked_add(len)) {
+            (Some(range), Some(end)) if range.start + end <= range.end => {
+                Ok(range.start + begin as u64)
+            }
+            _ => Err(StorageError::OutOfRange { index, begin, len }),
+        }
+    }
+
+    async fn blocking<T, F>(&self, job: F) -> Result<T, StorageError>
+    where
+        T: Send + 'static,
+        F: FnOnce(&FileLayout) -> Result<T, StorageError> + Send + 'static,
+    {
+        let _permit = self
+            .inner
+            .workers
+            .acquire()
+            .await
+            .map_err(|e| StorageError::Worker(e.to_string()))?;
+        let inner = self.inner.clone();
+        let result = tokio::task::spawn_blocking(move || job(&inner.layout))
+            .await
+            .map_err(|e| StorageError::Worker(e.to_string()))?;
+        result.map_err(|e| self.record(e))
+    }
+
+    fn record(&self, e: StorageError) -> StorageError {
+        if let StorageError::Io { .. } = &e {
+            tracing::error!("{}", e);
+            self.inner
+                .error
+                .lock()
+                .unwrap()
+                .get_or_insert_with(|| e.to_string());
+        }
+        e
+    }
+
+    /// Queues a verified block for writing, flushing once the write cache is
+    /// full.
+    pub async fn write_block(
+        &self,
+        index: u32,
+        begin: u32,
+        data: &[u8],
+    ) -> Result<(), StorageError> {
+        let offset = self.offset(index, begin, data.len() as u64)?;
+        let full = {
+            let mut cache = self.inner.write_cache.lock().unwrap();
+            cache.insert(offset, data);
+            cache.len() >= self.inner.settings.write_cache_size
+        };
+        self.inner.read_cache.lock().unwrap().invalidate(index);
+        if full {
+            self.flush().await?;
+        }
+        Ok(())
+    }
+
+    pub async fn write_piece(&self, index: u32, data: &[u8]) -> Result<(), StorageError> {
+        if self.inner.layout.piece_len(index as usize) != Some(data.len() as u64) {
+            return Err(StorageError::OutOfRange {
+                index,
+                begin: 0,
+                len: data.len() as u64,
+            });
+        }
+        self.write_block(index, 0, data).await
+    }
+
+    /// Writes out everything in the write cache.
+    pub async fn flush(&self) -> Result<(), StorageError> {
+        let _guard = self.inner.disk.write().await;
+        let runs = self.inner.write_cache.lock().unwrap().take();
+        if runs.is_empty() {
+            return Ok(());
+        }
+        let (runs, result) = self
+            .blocking(move |layout| {
+                let result = runs
+                    .iter()
+                    .try_for_each(|(offset, data)| write_run(layout, *offset, data));
+                Ok((runs, result))
+            })
+            .await?;
+        if let Err(e) = result {
+            // keep the data so a later flush can retry it
+            self.inner.write_cache.lock().unwrap().restore(runs);
+            return Err(self.record(e));
+        }
+        Ok(())
+    }
+
+    /// Reads a block for an upload, from the caches when possible.
+    pub async fn read_block(
+        &self,
+        index: u32,
+        begin: u32,
+        len: u32,
+    ) -> Result<Bytes, StorageError> {
+        if len > BLOCK_LEN {
+            return Err(StorageError::BlockTooLong(len));
+        }
+        let offset = self.offset(index, begin, len as u64)?;
+        if let Some(data) = self
+            .inner
+            .write_cache
+            .lock()
+            .unwrap()
+            .read(offset, len as u64)
+        {
+            return Ok(Bytes::from(data));
+        }
+        let slice = |piece: &Bytes| piece.slice(begin as usize..(begin + len) as usize);
+        if let Some(piece) = self.inner.read_cache.lock().unwrap().get(index) {
+            return Ok(slice(&piece));
+        }
+
+        let _guard = self.inner.disk.read().await;
+        // a flush may have run while we waited
+        if let Some(data) = self
+            .inner
+            .write_cache
+            .lock()
+            .unwrap()
+            .read(offset, len as u64)
+        {
+            return Ok(Bytes::from(data));
+        }
+        let range = self
+            .inner
+            .layout
+            .piece_range(index as usize)
+            .expect("offset checked the piece");
+        let mut piece = self
+            .blocking(move |layout| read_range(layout, range.start, range.end - range.start))
+            .await?;
+        // blocks still waiting for a flush are newer than the disk, and the
+        // piece is only cached once none are; holding the write cache lock
+        // orders the insert before the invalidation of any later write
+        let write_cache = self.inner.write_cache.lock().unwrap();
+        let pending = write_cache.overlay(range.start, &mut piece);
+        let piece = Bytes::from(piece);
+        if !pending {
+            self.inner
+                .read_cache
+                .lock()
+                .unwrap()
+                .insert(index, piece.clone());
+        }
+        Ok(slice(&piece))
+    }
+
+    /// Hashes every piece on disk against `info` and returns the pieces that
+    /// are complete. Missing or short files just mean missing pieces.
+    pub async fn recheck(&self, info: &Info) -> Result<Bitfield, StorageError> {
+        self.flush().await?;
+        let _guard = self.inner.disk.read().await;
+        // what was cached may not be what is on disk now
+        self.inner.read_cache.lock().unwrap().clear();
+        let info = info.clone();
+        self.blocking(move |layout| {
+            let mut have = Bitfield::new(layout.piece_count());
+            for index in 0..layout.piece_count() {
+                let range = layout.piece_range(index).expect("index below piece count");
+                let Ok(data) = read_range(layout, range.start, range.end - range.start) else {
+                    continue;
+                };
+                if info.piece_hash(index) == Some(&Sha1::digest(&data)[..]) {
+                    have.set(index);
+                }
+            }
+            Ok(have)
+        })
+        .await
+    }
+}
+
+fn io_error(path: &std::path::Path) -> impl FnOnce(io::Error) -> StorageError + '_ {
+    move |source| StorageError::Io {
+        path: path.to_path_buf(),
+        source,
+    }
+}
+
+fn write_run(layout: &FileLayout, offset: u64, data: &[u8]) -> Result<(), StorageError> {
+    let spans = layout
+        .spans(offset, data.len() as u64)
+        .ok_or(StorageError::OutOfRange {
+            index: (offset / layout.piece_length) as u32,
+            begin: (offset % layout.piece_length) as u32,
+            len: data.len() as u64,
+        })?;
+    let mut written = 0;
+    for span in spans {
+        let path = &layout.files[span.file_index].path;
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent).map_err(io_error(parent))?;
+        }
+        let mut file = OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(path)
+            .map_err(io_error(path))?;
+        file.seek(SeekFrom::Start(span.file_offset))
+            .and_then(|_| file.write_all(&data[written..written + span.len as usize]))
+            .map_err(io_error(path))?;
+        written += span.len as usize;
+    }
+    Ok(())
+}
+
+fn read_range(layout: &FileLayout, offset: u64, len: u64) -> Result<Vec<u8>, StorageError> {
+    let spans = layout.spans(offset, len).ok_or(StorageError::OutOfRange {
+        index: (offset / layout.piece_length) as u32,
+        begin: (offset % layout.piece_length) as u32,
+        len,
+    })?;
+    let mut data = vec![0; len as usize];
+    let mut read = 0;
+    for span in spans {
+        let path = &layout.files[span.file_index].path;
+        let mut file = std::fs::File::open(path).map_err(io_error(path))?;
+        file.seek(SeekFrom::Start(span.file_offset))
+            .and_then(|_| file.read_exact(&mut data[read..read + span.len as usize]))
+            .map_err(io_error(path))?;
+        read += span.len as usize;
+    }
+    Ok(data)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::metainfo::{FileEntry, Info};
+    use serde_bytes::ByteBuf;
+    use std::path::Path;
+
+    // two files of 10 and 25 bytes with 16 byte pieces
+    fn info() -> Info {
+        Info {
+            name: "pack".to_string(),
+            piece_length: 16,
+            pieces: ByteBuf::from(vec![0u8; 60]),
+            length: None,
+            files: Some(vec![
+                FileEntry {
+                    length: 10,
+                    path: vec!["a".to_string()],
+                },
+                FileEntry {
+                    length: 25,
+                    path: vec!["dir".to_string(), "b".to_string()],
+                },
+            ]),
+            private: None,
+        }
+    }
+
+    fn layout(dir: &Path) -> FileLayout {
+        FileLayout::new(&info(), dir).unwrap()
+    }
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("jubjub_storage_{}", name));
+        let _ = std::fs::remove_dir_all(&dir);
+        dir
+    }
+
+    #[tokio::test]
+    async fn test_write_and_read() {
+        let dir = temp_dir("roundtrip");
+        let data: Vec<u8> = (0..35).collect();
+        let storage = Storage::new(layout(&dir), StorageSettings::default());
+        storage.write_piece(1, &data[16..32]).await.unwrap();
+        storage.write_block(0, 0, &data[..8]).await.unwrap();
+        storage.write_block(0, 8, &data[8..16]).await.unwrap();
+        storage.write_piece(2, &data[32..]).await.unwrap();
+        // not flushed yet, served from the write cache
+        assert_eq!(
+            storage.read_block(0, 4, 8).await.unwrap(),
+            Bytes::copy_from_slice(&data[4..12])
+        );
+        assert!(!dir.join("pack/a").exists());
+
+        storage.flush().await.unwrap();
+        assert_eq!(std::fs::read(dir.join("pack/a")).unwrap(), &data[..10]);
+        assert_eq!(std::fs::read(dir.join("pack/dir/b")).unwrap(), &data[10..]);
+        assert_eq!(
+            storage.read_block(1, 0, 16).await.unwrap(),
+            Bytes::copy_from_slice(&data[16..32])
+        );
+        // the second read comes from the read cache
+        std::fs::remove_file(dir.join("pack/dir/b")).unwrap();
+        assert_eq!(
+            storage.read_block(1, 2, 3).await.unwrap(),
+            Bytes::copy_from_slice(&data[18..21])
+        );
+        assert!(storage.error().is_none());
+
+        assert!(matches!(
+            storage.write_block(2, 2, &[0; 2]).await,
+            Err(StorageError::OutOfRange { .. })
+        ));
+        assert!(storage.write_piece(0, &[0; 4]).await.is_err());
+        assert!(matches!(
+            storage.read_block(0, 0, BLOCK_LEN + 1).await,
+            Err(StorageError::BlockTooLong(_))
+        ));
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[tokio::test]
+    async fn test_read_with_pending_writes() {
+        let dir = temp_dir("pending");
+        let data: Vec<u8> = (0..35).collect();
+        let storage = Storage::new(layout(&dir), StorageSettings::default());
+        storage.write_piece(0, &data[..16]).await.unwrap();
+        storage.flush().await.unwrap();
+        // the block is only partly in the write cache
+        storage.write_block(0, 4, &[99; 4]).await.unwrap();
+        let mut expected = data[..8].to_vec();
+        expected[4..].copy_from_slice(&[99; 4]);
+        assert_eq!(storage.read_block(0, 0, 8).await.unwrap(), expected);
+        // nor was the stale piece cached meanwhile
+        storage.flush().await.unwrap();
+        assert_eq!(storage.read_block(0, 0, 8).await.unwrap(), expected);
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[tokio::test]
+    async fn test_recheck() {
+        let dir = temp_dir("recheck");
+        let data: Vec<u8> = (0..35).collect();
+        let mut info = info();
+        info.pieces = ByteBuf::from(
+            data.chunks(16)
+                .flat_map(|piece| Sha1::digest(piece).to_vec())
+                .collect::<Vec<u8>>(),
+        );
+        let storage = Storage::new(
+            FileLayout::new(&info, &dir).unwrap(),
+            StorageSettings::default(),
+        );
+        assert_eq!(storage.recheck(&info).await.unwrap().count(), 0);
+        storage.write_piece(0, &data[..16]).await.unwrap();
+        storage.write_piece(2, &data[32..]).await.unwrap();
+        let have = storage.recheck(&info).await.unwrap();
+        assert_eq!(have.iter_set().collect::<Vec<_>>(), vec![0, 2]);
+
+        // corrupt a byte of piece 2 on disk, after it was read once
+        storage.read_block(2, 0, 3).await.unwrap();
+        let path = dir.join("pack/dir/b");
+        let mut file = std::fs::read(&path).unwrap();
+        file[23] ^= 0xff;
+        std::fs::write(&path, file).unwrap();
+        let have = storage.recheck(&info).await.unwrap();
+        assert_eq!(have.iter_set().collect::<Vec<_>>(), vec![0]);
+        assert_ne!(storage.read_block(2, 0, 3).await.unwrap(), data[32..]);
+        assert!(storage.error().is_none());
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[tokio::test]
+    async fn test_disk_errors_are_reported() {
+        let dir = temp_dir("errors");
+        std::fs::create_dir_all(&dir).unwrap();
+        // a file where the torrent wants its directory
+        std::fs::write(dir.join("pack"), b"").unwrap();
+        let settings = StorageSettings {
+            write_cache_size: 1,
+            ..Default::default()
+        };
+        let storage = Storage::new(layout(&dir), settings);
+        assert!(matches!(
+            storage.write_block(0, 0, &[1; 16]).await,
+            Err(StorageError::Io { .. })
+        ));
+        assert!(storage.error().is_some());
+        assert!(matches!(
+            storage.read_block(2, 0, 3).await,
+            Err(StorageError::Io { .. })
+        ));
+        // the failed flush kept the block, it's written once the disk is fixed
+        assert_eq!(storage.read_block(0, 4, 4).await.unwrap(), vec![1; 4]);
+        std::fs::remove_file(dir.join("pack")).unwrap();
+        storage.flush().await.unwrap();
+        assert_eq!(std::fs::read(dir.join("pack/a")).unwrap(), vec![1; 10]);
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+}
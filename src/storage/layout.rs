@@ -0,0 +1,263 @@
+use crate::client::arguments::Settings;
+use crate::metainfo::Info;
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+
+/// A file of the torrent and where it sits in the torrent's contiguous byte stream.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FileSlot {
+    pub path: PathBuf,
+    pub length: u64,
+    pub offset: u64,
+}
+
+impl FileSlot {
+    pub fn end(&self) -> u64 {
+        self.offset + self.length
+    }
+}
+
+/// The part of a torrent byte range that falls inside a single file.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FileSpan {
+    pub file_index: usize,
+    pub file_offset: u64,
+    pub len: u64,
+}
+
+/// Maps pieces and byte ranges of a torrent onto the files on disk.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FileLayout {
+    pub root: PathBuf,
+    pub files: Vec<FileSlot>,
+    pub piece_length: u64,
+    pub total_length: u64,
+}
+
+impl FileLayout {
+    /// `None` if the file lengths overflow, which `Info::validate` rejects.
+    pub fn new(info: &Info, download_dir: &Path) -> Option<Self> {
+        let root = expand_home(download_dir);
+        let mut offset = 0u64;
+        let mut files = Vec::new();
+        for (path, length) in info.file_paths() {
+            files.push(FileSlot {
+                path: root.join(path),
+                length,
+                offset,
+            });
+            offset = offset.checked_add(length)?;
+        }
+        Some(Self {
+            root,
+            files,
+            piece_length: info.piece_length,
+            total_length: offset,
+        })
+    }
+
+    pub fn from_settings(info: &Info, settings: &Settings) -> Option<Self> {
+        Self::new(info, &settings.download_dir)
+    }
+
+    pub fn piece_count(&self) -> usize {
+        self.total_length.div_ceil(self.piece_length) as usize
+    }
+
+    /// Byte range of piece `index` in the torrent byte stream.
+    pub fn piece_range(&self, index: usize) -> Option<Range<u64>> {
+        if index >= self.piece_count() {
+            return None;
+        }
+        let start = index as u64 * self.piece_length;
+        let end = (start + self.piece_length).min(self.total_length);
+        Some(start..end)
+    }
+
+    pub fn piece_len(&self, index: usize) -> Option<u64> {
+        self.piece_range(index).map(|range| range.end - range.start)
+    }
+
+    /// Splits `len` bytes starting at torrent offset `offset` into per-file spans.
+    /// Returns `None` if the range runs past the end of the torrent.
+    pub fn spans(&self, offset: u64, len: u64) -> Option<Vec<FileSpan>> {
+        let end = offset.checked_add(len)?;
+        if end > self.total_length {
+            return None;
+        }
+        let mut spans = Vec::new();
+        let first = self.files.partition_point(|file| file.end() <= offset);
+        let mut cursor = offset;
+        for (file_index, file) in self.files.iter().enumerate().skip(first) {
+            if cursor >= end {
+                break;
+            }
+            if file.length == 0 {
+                continue;
+            }
+            let span_end = file.end().min(end);
+            spans.push(FileSpan {
+                file_index,
+                file_offset: cursor - file.offset,
+                len: span_end - cursor,
+            });
+            cursor = span_end;
+        }
+        Some(spans)
+    }
+
+    pub fn piece_spans(&self, index: usize) -> Option<Vec<FileSpan>> {
+        let range = self.piece_range(index)?;
+        self.spans(range.start, range.end - range.start)
+    }
+
+    /// Spans of the block at `begin..begin + len` within piece `index`.
+    pub fn block_spans(&self, index: usize, begin: u64, len: u64) -> Option<Vec<FileSpan>> {
+        let range = self.piece_range(index)?;
+        if begin.checked_add(len)? > range.end - range.start {
+            return None;
+        }
+        self.spans(range.start + begin, len)
+    }
+
+    /// Pieces that hold at least one byte of file `file_index`.
+    pub fn file_pieces(&self, file_index: usize) -> Option<Range<usize>> {
+        let file = self.files.get(file_index)?;
+        if file.length == 0 {
+            return Some(0..0);
+        }
+        let first = file.offset / self.piece_length;
+        let last = (file.end() - 1) / self.piece_length;
+        Some(first as usize..last as usize + 1)
+    }
+}
+
+pub(crate) fn expand_home(path: &Path) -> PathBuf {
+    match (path.strip_prefix("~"), std::env::var_os("HOME")) {
+        (Ok(rest), Some(home)) => PathBuf::from(home).join(rest),
+        _ => path.to_path_buf(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::metainfo::FileEntry;
+    use serde_bytes::ByteBuf;
+
+    fn multi_file_info() -> Info {
+        // 3 files of 10, 0 and 25 bytes with 16 byte pieces: 3 pieces, the
+        // first two straddle file boundaries
+        Info {
+            name: "pack".to_string(),
+            piece_length: 16,
+            pieces: ByteBuf::from(vec![0u8; 60]),
+            length: None,
+            files: Some(vec![
+                FileEntry {
+                    length: 10,
+                    path: vec!["a".to_string()],
+                },
+                FileEntry {
+                    length: 0,
+                    path: vec!["empty".to_string()],
+                },
+                FileEntry {
+                    length: 25,
+                    path: vec!["dir".to_string(), "b".to_string()],
+                },
+            ]),
+            private: None,
+        }
+    }
+
+    #[test]
+    fn test_layout_paths() {
+        let layout = FileLayout::new(&multi_file_info(), Path::new("/downloads")).unwrap();
+        assert_eq!(layout.total_length, 35);
+        assert_eq!(layout.piece_count(), 3);
+        assert_eq!(layout.files[2].path, PathBuf::from("/downloads/pack/dir/b"));
+        assert_eq!(layout.files[2].offset, 10);
+        assert_eq!(layout.piece_len(2), Some(3));
+        assert_eq!(layout.piece_range(3), None);
+    }
+
+    #[test]
+    fn test_piece_spans() {
+        let layout = FileLayout::new(&multi_file_info(), Path::new("/downloads")).unwrap();
+        assert_eq!(
+            layout.piece_spans(0).unwrap(),
+            vec![
+                FileSpan {
+                    file_index: 0,
+                    file_offset: 0,
+                    len: 10
+                },
+                FileSpan {
+                    file_index: 2,
+                    file_offset: 0,
+                    len: 6
+                },
+            ]
+        );
+        assert_eq!(
+            layout.piece_spans(2).unwrap(),
+            vec![FileSpan {
+                file_index: 2,
+                file_offset: 22,
+                len: 3
+            }]
+        );
+        assert_eq!(
+            layout.block_spans(0, 8, 4).unwrap(),
+            vec![
+                FileSpan {
+                    file_index: 0,
+                    file_offset: 8,
+                    len: 2
+                },
+                FileSpan {
+                    file_index: 2,
+                    file_offset: 0,
+                    len: 2
+                },
+            ]
+        );
+        assert_eq!(layout.block_spans(2, 0, 4), None);
+        assert_eq!(layout.spans(30, 10), None);
+    }
+
+    #[test]
+    fn test_file_pieces() {
+        let layout = FileLayout::new(&multi_file_info(), Path::new("/downloads")).unwrap();
+        assert_eq!(layout.file_pieces(0), Some(0..1));
+        assert_eq!(layout.file_pieces(1), Some(0..0));
+        assert_eq!(layout.file_pieces(2), Some(0..3));
+        assert_eq!(layout.file_pieces(3), None);
+    }
+
+    #[test]
+    fn test_single_file_layout() {
+        let info = Info {
+            name: "file.txt".to_string(),
+            piece_length: 16,
+            pieces: ByteBuf::from(vec![0u8; 40]),
+            length: Some(20),
+            files: None,
+            private: None,
+        };
+        let layout = FileLayout::new(&info, Path::new("/downloads")).unwrap();
+        assert_eq!(layout.files.len(), 1);
+        assert_eq!(layout.files[0].path, PathBuf::from("/downloads/file.txt"));
+        assert_eq!(layout.piece_spans(1).unwrap()[0].file_offset, 16);
+    }
+
+    #[test]
+    fn test_layout_overflow() {
+        let mut info = multi_file_info();
+        for file in info.files.as_mut().unwrap() {
+            file.length = i64::MAX as u64;
+        }
+        assert!(FileLayout::new(&info, Path::new("/downloads")).is_none());
+    }
+}
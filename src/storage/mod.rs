@@ -0,0 +1,6 @@
+pub mod cache;
+pub mod disk;
+pub mod layout;
+
+pub use disk::{Storage, StorageError, StorageSettings};
+pub use layout::{FileLayout, FileSlot, FileSpan};
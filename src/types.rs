@@ -1,13 +1,32 @@
 use cratetorrent::prelude::*;
+use data_encoding::{BASE32, HEXLOWER, HEXLOWER_PERMISSIVE};
 // use url::{Url, ParseError};
 use libp2p::{request_response::ResponseChannel, PeerId};
 
 use reqwest::Url;
 use serde::{Deserialize, Serialize};
-use std::{collections::HashSet, error::Error, path::PathBuf};
+use sha1::{Digest, Sha1};
+use std::{collections::HashSet, error::Error, net::SocketAddr, path::PathBuf};
 use strum::Display;
+use thiserror::Error;
 
 use crate::client::arguments::ClientCommand;
+use crate::db::ResumeData;
+use crate::download::{PiecePicker, Priority};
+use crate::magnet::MagnetLink;
+use crate::metainfo::{Metainfo, MetainfoError};
+use crate::peer::extension::metadata::UtMetadata;
+use crate::peer::extension::pex::UtPex;
+use crate::peer::extension::ExtensionRegistry;
+use crate::peer::tracker::http::HttpTracker;
+use crate::peer::tracker::tiers::TrackerTiers;
+use crate::peer::tracker::{
+    AnnounceEvent, AnnounceRequest, AnnounceResponse, ScrapeStats, TrackerError,
+};
+use crate::storage::{FileLayout, Storage, StorageSettings};
+
+/// Addresses kept per torrent, new ones are dropped past this.
+pub const MAX_KNOWN_PEERS: usize = 2000;
 
 pub trait Node {
     fn get_peer_id(&self) -> u32;
@@ -15,65 +34,507 @@ pub trait Node {
 #[derive(Debug)]
 pub(crate) enum Event {
     InboundRequest {
+        peer: PeerId,
         request: String,
         channel: ResponseChannel<TorrentResponse>,
     },
+    /// Every piece of the torrent is verified.
+    Completed(InfoHash),
 }
 
 pub type SessionId = u32;
 
 pub type PeerMap = hashbrown::HashMap<PeerId, SessionId>;
 
+/// Where we learned a peer's address from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum PeerSource {
+    Tracker,
+    Pex,
+    Dht,
+    Magnet,
+}
+
 #[derive(Debug)]
 pub struct Torrent {
-    announce: Option<String>,
+    /// `None` until the info dictionary is known, e.g. for magnet links.
+    pub metainfo: Option<Metainfo>,
+    pub magnet: Option<MagnetLink>,
     pub info_hash: InfoHash,
+    /// Swarm health from the last successful scrape.
+    pub scrape: Option<ScrapeStats>,
+    pub tiers: TrackerTiers,
     peers: PeerMap,
+    /// Addresses of swarm members we could connect to.
+    known_peers: hashbrown::HashMap<SocketAddr, PeerSource>,
     cmd_rx: futures::channel::mpsc::Receiver<ClientCommand>,
     listen_addr: libp2p::Multiaddr,
     started: Option<std::time::Instant>,
     completed: Option<Vec<usize>>,
+    // the event of the announce job the tiers are out with
+    announcing: Option<AnnounceEvent>,
+    announce_key: u32,
+    // sent with the next announce, however soon
+    pending_event: Option<AnnounceEvent>,
+    // paused and queued torrents leave their swarms
+    active: bool,
+    /// Bytes sent to and received from peers, resume data included.
+    uploaded: u64,
+    downloaded: u64,
+    // per file, empty while every file is downloaded normally
+    file_priorities: Vec<Priority>,
+    /// The files on disk, once the metadata is known.
+    pub storage: Option<Storage>,
+    /// Verified pieces, `None` until resume data or a recheck told us.
+    have: Option<Bitfield>,
+    resume: Option<ResumeData>,
 }
 
 impl Torrent {
-    pub fn new(announce: Option<String>, info_hash: Vec<u8>) -> Self {
+    pub fn new(metainfo: Option<Metainfo>, info_hash: InfoHash) -> Self {
         let (_cmd_tx, cmd_rx) = futures::channel::mpsc::channel(10);
-        let listen_addr = "/ip4/".parse().unwrap();
+        let listen_addr = "/ip4/0.0.0.0".parse().unwrap();
+        let tiers = TrackerTiers::new(
+            metainfo
+                .as_ref()
+                .map(Metainfo::trackers)
+                .unwrap_or_default(),
+        );
         Self {
-            announce,
-            info_hash: InfoHash {
-                hash: info_hash.try_into().unwrap(),
-            },
+            metainfo,
+            magnet: None,
+            info_hash,
+            scrape: None,
+            tiers,
             peers: hashbrown::HashMap::new(),
+            known_peers: hashbrown::HashMap::new(),
             cmd_rx,
             listen_addr,
             started: None,
             completed: None,
+            announcing: None,
+            announce_key: rand::random(),
+            pending_event: None,
+            active: true,
+            uploaded: 0,
+            downloaded: 0,
+            file_priorities: Vec::new(),
+            storage: None,
+            have: None,
+            resume: None,
+        }
+    }
+
+    pub(crate) async fn open(file: &str) -> Result<Self, MetainfoError> {
+        let torrent = tokio::fs::read(file).await?;
+        Torrent::from_bytes(&torrent)
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, MetainfoError> {
+        let metainfo = Metainfo::from_bytes(bytes)?;
+        let info_hash = InfoHash::from_info_bytes(Metainfo::raw_info(bytes)?);
+        Ok(Torrent::new(Some(metainfo), info_hash))
+    }
+
+    pub fn from_magnet(magnet: MagnetLink) -> Self {
+        let mut torrent = Torrent::new(None, magnet.swarm_hash());
+        torrent.tiers = TrackerTiers::new(magnet_tiers(&magnet));
+        let peers: Vec<SocketAddr> = magnet
+            .peers
+            .iter()
+            .filter_map(|peer| peer.parse().ok())
+            .collect();
+        torrent.add_peers(peers, PeerSource::Magnet);
+        torrent.magnet = Some(magnet);
+        torrent
+    }
+
+    /// Returns how many of the addresses were new.
+    pub fn add_peers(
+        &mut self,
+        addrs: impl IntoIterator<Item = SocketAddr>,
+        source: PeerSource,
+    ) -> usize {
+        let before = self.known_peers.len();
+        for addr in addrs {
+            if self.known_peers.len() >= MAX_KNOWN_PEERS {
+                break;
+            }
+            self.known_peers.entry(addr).or_insert(source);
+        }
+        self.known_peers.len() - before
+    }
+
+    /// The BEP 10 extensions for this torrent's peers: ut_metadata while the
+    /// info dictionary is missing, and ut_pex unless the torrent is private.
+    pub fn extensions(&self, listen_port: Option<u16>) -> ExtensionRegistry {
+        let mut registry = ExtensionRegistry::new(listen_port);
+        if !self.has_metadata() {
+            registry.register(UtMetadata::new(self.info_hash));
+        }
+        if !self.is_private() {
+            registry.register(UtPex::new());
+        }
+        registry
+    }
+
+    /// Adds peers found on the DHT. Returns how many were new.
+    pub fn add_dht_peers(&mut self, peers: Vec<SocketAddr>) -> usize {
+        // a magnet link may only turn out to be private once the metadata is in
+        if self.is_private() {
+            return 0;
+        }
+        self.add_peers(peers, PeerSource::Dht)
+    }
+
+    /// Adds the peers `extensions` learned through peer exchange. Returns how
+    /// many were new.
+    pub fn add_pex_peers(&mut self, extensions: &mut ExtensionRegistry) -> usize {
+        let Some(pex) = extensions.get_mut::<UtPex>() else {
+            return 0;
+        };
+        let discovered = pex.take_discovered();
+        // a magnet link may only turn out to be private once the metadata is in
+        if self.is_private() {
+            return 0;
+        }
+        self.add_peers(
+            discovered.into_iter().map(|(addr, _)| addr),
+            PeerSource::Pex,
+        )
+    }
+
+    pub fn known_peers(&self) -> impl Iterator<Item = (&SocketAddr, &PeerSource)> {
+        self.known_peers.iter()
+    }
+
+    /// Hands the tracker tiers to an announce job once they are due, leaving
+    /// a snapshot of their statuses behind. `finish_announce` takes them back.
+    pub fn start_announce(
+        &mut self,
+        peer_id: [u8; 20],
+        port: u16,
+        now: std::time::Instant,
+    ) -> Option<(TrackerTiers, AnnounceRequest)> {
+        if !self.active || self.announcing.is_some() || self.tiers.is_empty() {
+            return None;
+        }
+        let event = match (self.started, self.pending_event) {
+            (None, _) => AnnounceEvent::Started,
+            (Some(_), Some(event)) => event,
+            (Some(_), None) => AnnounceEvent::None,
+        };
+        if self.pending_event.is_none() && !self.tiers.is_due(now) {
+            return None;
+        }
+        self.pending_event = None;
+        Some(self.take_tiers(peer_id, port, event))
+    }
+
+    pub fn is_active(&self) -> bool {
+        self.active
+    }
+
+    /// Set from the queue. A torrent that stops running sends `stopped` on
+    /// its next announce instead.
+    pub fn set_active(&mut self, active: bool) {
+        self.active = active;
+    }
+
+    /// Hands out the tiers for a `stopped` announce if the trackers know us,
+    /// e.g. on shutdown or once the torrent was paused or queued.
+    pub fn stop_announce(
+        &mut self,
+        peer_id: [u8; 20],
+        port: u16,
+    ) -> Option<(TrackerTiers, AnnounceRequest)> {
+        if self.announcing.is_some() || self.started.is_none() {
+            return None;
+        }
+        Some(self.take_tiers(peer_id, port, AnnounceEvent::Stopped))
+    }
+
+    fn take_tiers(
+        &mut self,
+        peer_id: [u8; 20],
+        port: u16,
+        event: AnnounceEvent,
+    ) -> (TrackerTiers, AnnounceRequest) {
+        self.announcing = Some(event);
+        let request = AnnounceRequest {
+            info_hash: self.info_hash,
+            peer_id,
+            port,
+            uploaded: self.uploaded,
+            downloaded: self.downloaded,
+            left: self.left(),
+            event,
+            num_want: None,
+            key: self.announce_key,
+            tracker_id: None,
+        };
+        let snapshot = self.tiers.snapshot();
+        let tiers = std::mem::replace(&mut self.tiers, snapshot);
+        (tiers, request)
+    }
+
+    /// Bytes still missing, all of them while the pieces are unknown.
+    pub fn left(&self) -> u64 {
+        let Some(metainfo) = &self.metainfo else {
+            return 0;
+        };
+        let info = &metainfo.info;
+        let have: u64 = self.have.as_ref().map_or(0, |have| {
+            have.iter_set()
+                .filter_map(|index| info.piece_size(index))
+                .sum()
+        });
+        info.total_length().saturating_sub(have)
+    }
+
+    pub fn is_complete(&self) -> bool {
+        self.have.as_ref().is_some_and(Bitfield::is_complete)
+    }
+
+    pub fn add_transferred(&mut self, uploaded: u64, downloaded: u64) {
+        self.uploaded = self.uploaded.saturating_add(uploaded);
+        self.downloaded = self.downloaded.saturating_add(downloaded);
+    }
+
+    /// Returns how many of the announced peers were new.
+    pub fn finish_announce(
+        &mut self,
+        tiers: TrackerTiers,
+        result: Result<AnnounceResponse, TrackerError>,
+    ) -> usize {
+        let event = self.announcing.take();
+        self.tiers = tiers;
+        if event == Some(AnnounceEvent::Stopped) {
+            // the trackers forgot us, whether they answered or not
+            self.started = None;
+            self.tiers.announce_now();
+            return 0;
+        }
+        match result {
+            Ok(response) => {
+                self.started.get_or_insert_with(std::time::Instant::now);
+                self.add_peers(response.peers, PeerSource::Tracker)
+            }
+            Err(e) => {
+                tracing::debug!("Announce of {} failed: {}", self.info_hash, e);
+                if event == Some(AnnounceEvent::Completed) {
+                    self.pending_event = event;
+                }
+                0
+            }
+        }
+    }
+
+    /// Opens the files under `save_path`. Which pieces they hold is known
+    /// once `set_have` is called, from resume data or a recheck.
+    pub fn attach_storage(
+        &mut self,
+        save_path: &std::path::Path,
+        settings: StorageSettings,
+    ) -> Option<&Storage> {
+        let layout = self.layout(save_path)?;
+        Some(self.storage.insert(Storage::new(layout, settings)))
+    }
+
+    /// Takes over the trackers of saved resume data. Returns the saved pieces
+    /// if the files are unchanged since, otherwise the torrent needs a
+    /// recheck.
+    pub fn restore(&mut self, data: ResumeData) -> Option<Bitfield> {
+        if !data.trackers.is_empty() {
+            self.tiers = TrackerTiers::restore(data.trackers.clone());
+        }
+        for (url, tracker_id) in &data.tracker_ids {
+            self.tiers.set_tracker_id(url, tracker_id.clone());
+        }
+        self.file_priorities = data.file_priorities.clone();
+        self.uploaded = data.uploaded;
+        self.downloaded = data.downloaded;
+        let have = data.verified_have(self.storage.as_ref()?.layout());
+        self.resume = Some(data);
+        have
+    }
+
+    pub fn have(&self) -> Option<&Bitfield> {
+        self.have.as_ref()
+    }
+
+    /// Returns true if the torrent became complete with these pieces.
+    /// Finishing a download while the trackers know us is announced.
+    pub fn set_have(&mut self, have: Bitfield) -> bool {
+        let was_complete = self.have.as_ref().is_some_and(Bitfield::is_complete);
+        let became_complete = have.is_complete() && !was_complete;
+        if became_complete && self.have.is_some() && self.started.is_some() {
+            self.pending_event = Some(AnnounceEvent::Completed);
+        }
+        self.have = Some(have);
+        became_complete
+    }
+
+    /// Resume data with the current trackers, to be updated with the pieces
+    /// and saved once the storage is flushed.
+    pub fn resume_data(&mut self) -> Option<ResumeData> {
+        let layout = self.storage.as_ref()?.layout();
+        let data = self
+            .resume
+            .get_or_insert_with(|| ResumeData::new(self.info_hash, layout));
+        data.trackers = self.tiers.urls();
+        data.tracker_ids = self.tiers.tracker_ids();
+        data.file_priorities = self.file_priorities.clone();
+        data.uploaded = self.uploaded;
+        data.downloaded = self.downloaded;
+        Some(data.clone())
+    }
+
+    pub fn file_priorities(&self) -> &[Priority] {
+        &self.file_priorities
+    }
+
+    /// Returns false if the torrent has no such file.
+    pub fn set_file_priority(&mut self, file_index: usize, priority: Priority) -> bool {
+        let Some(metainfo) = &self.metainfo else {
+            return false;
+        };
+        let file_count = metainfo.info.file_paths().len();
+        if file_index >= file_count {
+            return false;
+        }
+        self.file_priorities.resize(file_count, Priority::Normal);
+        self.file_priorities[file_index] = priority;
+        true
+    }
+
+    /// A picker for the pieces still missing, honouring the file priorities.
+    pub fn picker(&self) -> Option<PiecePicker> {
+        let layout = self.storage.as_ref()?.layout();
+        let mut picker = PiecePicker::with_have(self.have.clone()?);
+        for (file_index, priority) in self.file_priorities.iter().enumerate() {
+            picker.set_file_priority(layout, file_index, *priority);
+        }
+        Some(picker)
+    }
+
+    pub fn set_resume_data(&mut self, data: ResumeData) {
+        self.resume = Some(data);
+    }
+
+    pub fn has_metadata(&self) -> bool {
+        self.metainfo.is_some()
+    }
+
+    /// Private torrents (BEP 27) must only get peers from their trackers.
+    pub fn is_private(&self) -> bool {
+        self.metainfo
+            .as_ref()
+            .is_some_and(|metainfo| metainfo.info.is_private())
+    }
+
+    pub fn announce(&self) -> Option<&str> {
+        match (&self.metainfo, &self.magnet) {
+            (Some(metainfo), _) => metainfo.announce.as_deref(),
+            (None, Some(magnet)) => magnet.trackers.first().map(String::as_str),
+            (None, None) => None,
+        }
+    }
+
+    /// Every tracker url of the torrent, in announce-list order.
+    pub fn trackers(&self) -> Vec<String> {
+        self.tracker_tiers().into_iter().flatten().collect()
+    }
+
+    pub fn tracker_tiers(&self) -> Vec<Vec<String>> {
+        match (&self.metainfo, &self.magnet) {
+            (Some(metainfo), _) => metainfo.trackers(),
+            (None, Some(magnet)) => magnet_tiers(magnet),
+            (None, None) => Vec::new(),
+        }
+    }
+
+    pub fn update_scrape(&mut self, stats: ScrapeStats) {
+        self.scrape = Some(stats);
+    }
+
+    pub fn health(&self) -> SwarmHealth {
+        SwarmHealth {
+            info_hash: self.info_hash,
+            name: self.name(),
+            stats: self.scrape,
         }
     }
 
-    pub(crate) async fn open(file: &str) -> Result<Self, Box<dyn Error>> {
-        let torrent = std::fs::read(file).unwrap();
+    pub fn name(&self) -> String {
+        match (&self.metainfo, &self.magnet) {
+            (Some(metainfo), _) => metainfo.info.name.clone(),
+            (
+                None,
+                Some(MagnetLink {
+                    display_name: Some(name),
+                    ..
+                }),
+            ) => name.clone(),
+            _ => self.info_hash.to_hex(),
+        }
+    }
 
-        let torrent = Torrent::from_bytes(&torrent).unwrap();
-        Ok(torrent)
+    /// Completes a magnet link with the info dictionary fetched from peers.
+    /// Returns the equivalent .torrent file, which is also written to
+    /// `save_dir` if given.
+    pub fn set_metadata(
+        &mut self,
+        info: &[u8],
+        save_dir: Option<&std::path::Path>,
+    ) -> Result<Vec<u8>, MetainfoError> {
+        if InfoHash::from_info_bytes(info) != self.info_hash {
+            return Err(MetainfoError::Invalid(
+                "info dictionary does not match the info-hash".to_string(),
+            ));
+        }
+        let (metainfo, bytes) = Metainfo::from_raw_info(info, &self.tracker_tiers())?;
+        if let Some(dir) = save_dir {
+            std::fs::write(
+                dir.join(format!("{}.torrent", self.info_hash.to_hex())),
+                &bytes,
+            )?;
+        }
+        self.metainfo = Some(metainfo);
+        Ok(bytes)
     }
-    pub fn from_bytes(bytes: &[u8]) -> Result<Self, Box<dyn Error>> {
-        let announce = serde_bencode::from_bytes::<String>(bytes)?;
-        let info_hash = serde_bencode::from_bytes::<Vec<u8>>(bytes)?;
-        let torrent = Torrent::new(Some(announce), info_hash);
-        Ok(torrent)
+
+    pub fn layout(&self, download_dir: &std::path::Path) -> Option<FileLayout> {
+        let metainfo = self.metainfo.as_ref()?;
+        FileLayout::new(&metainfo.info, download_dir)
     }
 }
 
+// magnet trackers have no tiers, try them one after another
+fn magnet_tiers(magnet: &MagnetLink) -> Vec<Vec<String>> {
+    magnet
+        .trackers
+        .iter()
+        .map(|tracker| vec![tracker.clone()])
+        .collect()
+}
+
 fn decode_torrent(torrent: &Torrent) {}
 
+/// Scrape results of a torrent as reported to the RPC and GUI.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SwarmHealth {
+    pub info_hash: InfoHash,
+    pub name: String,
+    pub stats: Option<ScrapeStats>,
+}
+
 #[derive(Deserialize, Serialize)]
 pub struct RequestHeader {
     request: Request,
 }
 
-#[derive(Debug, Clone, PartialEq)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
 pub enum FileStatus {
     Paused = 0,
     Downloading = 1,
@@ -82,6 +543,13 @@ pub enum FileStatus {
     SeedQueued = 4,
 }
 
+impl FileStatus {
+    /// Whether the torrent runs, i.e. talks to trackers and peers.
+    pub fn is_active(self) -> bool {
+        matches!(self, FileStatus::Downloading | FileStatus::Seeding)
+    }
+}
+
 #[derive(Deserialize, Serialize)]
 pub struct File {
     pub header: RequestHeader,
@@ -89,6 +557,8 @@ pub struct File {
     pub seeders: u32,
     pub leechers: u32,
     pub announce: Option<String>,
+    #[serde(default)]
+    pub announce_list: Vec<Vec<String>>,
     pub info_hash: InfoHash,
     pub piece_hash: Vec<u8>,
     pub piece_length: u64,
@@ -100,6 +570,7 @@ pub struct File {
 impl File {
     pub fn new(
         announce: Option<String>,
+        announce_list: Vec<Vec<String>>,
         id: String,
         seeders: u32,
         leechers: u32,
@@ -117,6 +588,7 @@ impl File {
             seeders,
             leechers,
             announce,
+            announce_list,
             info_hash,
             piece_hash,
             piece_length,
@@ -129,29 +601,40 @@ impl File {
     async fn from_bytes(_bytes: &[u8]) -> Self {
         unimplemented!()
     }
-    async fn build_tracker_URL(&self, peer_id: PeerId, port: u16) -> Result<Url, Box<dyn Error>> {
-        // let mut opt_info_hash = None;
-        // let mut opt_peer_id = None;
-        // let mut opt_port = None;
-        // let mut opt_bytes_left = None;
-
-        let mut url = Url::parse(self.announce.as_ref().unwrap().as_str())?;
-        // let params = [
-        //     ("info_hash", self.info_hash),
-        //     ("peer_id", peer_id.to_bytes()),
-        //     ("port", port.to_string().into_bytes()),
-        //     ("uploaded", 0.to_string().into_bytes()),
-        //     ("downloaded", 0.to_string().into_bytes()),
-        //     ("left", self.length.to_string().into_bytes()),
-        // ];
-        // url.query_pairs_mut().append_pair("info_hash", &self.info_hash);
-        url.query_pairs_mut()
-            .append_pair("peer_id", peer_id.to_string().as_str())
-            .append_pair("port", port.to_string().as_str())
-            .append_pair("uploaded", 0.to_string().as_str())
-            .append_pair("downloaded", 0.to_string().as_str())
-            .append_pair("left", self.length.to_string().as_str());
-        Ok(url)
+
+    /// BEP 12 tiers, or the single `announce` when there is no announce-list.
+    pub fn tracker_tiers(&self) -> Vec<Vec<String>> {
+        if self.announce_list.iter().any(|tier| !tier.is_empty()) {
+            return self.announce_list.clone();
+        }
+        self.announce.iter().map(|url| vec![url.clone()]).collect()
+    }
+
+    pub fn update_scrape(&mut self, stats: &ScrapeStats) {
+        self.seeders = stats.seeders;
+        self.leechers = stats.leechers;
+    }
+    async fn build_tracker_url(&self, peer_id: [u8; 20], port: u16) -> Result<Url, Box<dyn Error>> {
+        let tiers = self.tracker_tiers();
+        let announce = tiers
+            .iter()
+            .flatten()
+            .find(|url| url.starts_with("http"))
+            .ok_or("missing announce url")?;
+        let tracker = HttpTracker::new(announce)?;
+        let request = AnnounceRequest {
+            info_hash: self.info_hash,
+            peer_id,
+            port,
+            uploaded: 0,
+            downloaded: 0,
+            left: self.length as u64,
+            event: AnnounceEvent::Started,
+            num_want: None,
+            key: 0,
+            tracker_id: None,
+        };
+        Ok(tracker.announce_url(&request))
     }
 
     // async fn request_peers(&self, peer_id: PeerId, port: u16, peer_addr: Multiaddr) -> Result<(), Box<dyn Error>> {
@@ -164,9 +647,21 @@ impl File {
     //     unimplemented!()
     // }
 
-    async fn check_piece(&self, index: u64) -> Result<bool, Box<dyn Error>> {
-        let piece = self.piece_hash.get(index as usize).unwrap();
-        Ok(true)
+    /// Whether `data` is piece `index`, by length and SHA-1 against the
+    /// `pieces` string.
+    pub fn check_piece(&self, index: u64, data: &[u8]) -> Result<bool, Box<dyn Error>> {
+        let start = (index as usize)
+            .checked_mul(20)
+            .ok_or("piece index out of range")?;
+        let expected = self
+            .piece_hash
+            .get(start..start + 20)
+            .ok_or_else(|| format!("no hash for piece {}", index))?;
+        let offset = index * self.piece_length;
+        let len = self
+            .piece_length
+            .min((self.length as u64).saturating_sub(offset));
+        Ok(data.len() as u64 == len && Sha1::digest(data)[..] == *expected)
     }
 }
 #[derive(Deserialize, Serialize, Debug)]
@@ -203,15 +698,187 @@ pub struct ListRequest {
     port: u16,
 }
 
-#[derive(Ord, PartialOrd, Debug, PartialEq, Eq, Clone, Deserialize, Serialize)]
+#[derive(Ord, PartialOrd, Debug, PartialEq, Eq, Hash, Clone, Copy, Deserialize, Serialize)]
 pub struct InfoHash {
     hash: [u8; 20],
 }
 
+#[derive(Debug, Error, PartialEq, Eq)]
+pub enum InfoHashError {
+    #[error("Info-hash must be 40 hex or 32 base32 characters, got {0}")]
+    Length(usize),
+    #[error("Invalid info-hash encoding: {0}")]
+    Encoding(String),
+}
+
+impl InfoHash {
+    pub fn new(hash: [u8; 20]) -> Self {
+        Self { hash }
+    }
+
+    /// v1 info-hash: SHA-1 over the bencoded `info` dictionary.
+    pub fn from_info_bytes(info: &[u8]) -> Self {
+        Self {
+            hash: Sha1::digest(info).into(),
+        }
+    }
+
+    pub fn as_bytes(&self) -> &[u8; 20] {
+        &self.hash
+    }
+
+    pub fn to_hex(&self) -> String {
+        HEXLOWER.encode(&self.hash)
+    }
+
+    pub fn to_base32(&self) -> String {
+        BASE32.encode(&self.hash)
+    }
+
+    pub fn from_hex(hex: &str) -> Result<Self, InfoHashError> {
+        Self::decode(&HEXLOWER_PERMISSIVE, hex, 40)
+    }
+
+    pub fn from_base32(base32: &str) -> Result<Self, InfoHashError> {
+        Self::decode(&BASE32, &base32.to_ascii_uppercase(), 32)
+    }
+
+    fn decode(
+        encoding: &data_encoding::Encoding,
+        input: &str,
+        len: usize,
+    ) -> Result<Self, InfoHashError> {
+        if input.len() != len {
+            return Err(InfoHashError::Length(input.len()));
+        }
+        let bytes = encoding
+            .decode(input.as_bytes())
+            .map_err(|e| InfoHashError::Encoding(e.to_string()))?;
+        let hash = bytes
+            .try_into()
+            .map_err(|_| InfoHashError::Length(input.len()))?;
+        Ok(Self { hash })
+    }
+}
+
+impl From<[u8; 20]> for InfoHash {
+    fn from(hash: [u8; 20]) -> Self {
+        Self { hash }
+    }
+}
+
+impl std::str::FromStr for InfoHash {
+    type Err = InfoHashError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.len() {
+            40 => Self::from_hex(s),
+            32 => Self::from_base32(s),
+            len => Err(InfoHashError::Length(len)),
+        }
+    }
+}
+
 impl std::fmt::Display for InfoHash {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        let rx: [u8; 20] = serde_bencode::from_bytes(&self.hash).expect("failed to serialize");
-        write!(f, "{:?}", std::str::from_utf8(&rx).unwrap())
+        write!(f, "{}", self.to_hex())
+    }
+}
+
+/// Pieces a peer has, most significant bit of the first byte is piece 0.
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct Bitfield {
+    bits: Vec<u8>,
+    len: usize,
+}
+
+#[derive(Debug, Error, PartialEq, Eq)]
+pub enum BitfieldError {
+    #[error("Bitfield of {actual} bytes for {len} pieces")]
+    Length { len: usize, actual: usize },
+    #[error("Bitfield has spare bits set")]
+    SpareBits,
+}
+
+impl Bitfield {
+    pub fn new(len: usize) -> Self {
+        Self {
+            bits: vec![0; len.div_ceil(8)],
+            len,
+        }
+    }
+
+    pub fn full(len: usize) -> Self {
+        let mut bitfield = Bitfield::new(len);
+        for index in 0..len {
+            bitfield.set(index);
+        }
+        bitfield
+    }
+
+    /// Parses a peer's bitfield message for a torrent of `len` pieces.
+    pub fn from_bytes(bytes: &[u8], len: usize) -> Result<Self, BitfieldError> {
+        if bytes.len() != len.div_ceil(8) {
+            return Err(BitfieldError::Length {
+                len,
+                actual: bytes.len(),
+            });
+        }
+        let bitfield = Self {
+            bits: bytes.to_vec(),
+            len,
+        };
+        let spare = bitfield.bits.len() * 8 - len;
+        if spare > 0 && bitfield.bits[bitfield.bits.len() - 1] & ((1 << spare) - 1) != 0 {
+            return Err(BitfieldError::SpareBits);
+        }
+        Ok(bitfield)
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn has(&self, index: usize) -> bool {
+        index < self.len && self.bits[index / 8] & (0x80 >> (index % 8)) != 0
+    }
+
+    /// Marks `index`, returns false when it is out of range.
+    pub fn set(&mut self, index: usize) -> bool {
+        if index >= self.len {
+            return false;
+        }
+        self.bits[index / 8] |= 0x80 >> (index % 8);
+        true
+    }
+
+    pub fn unset(&mut self, index: usize) {
+        if index < self.len {
+            self.bits[index / 8] &= !(0x80 >> (index % 8));
+        }
+    }
+
+    pub fn count(&self) -> usize {
+        self.bits
+            .iter()
+            .map(|byte| byte.count_ones() as usize)
+            .sum()
+    }
+
+    pub fn is_complete(&self) -> bool {
+        self.count() == self.len
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.bits
+    }
+
+    pub fn iter_set(&self) -> impl Iterator<Item = usize> + '_ {
+        (0..self.len).filter(|index| self.has(*index))
     }
 }
 
@@ -234,7 +901,72 @@ pub enum Sources {}
 #[derive(Debug)]
 pub enum ChannelRequest {}
 
+#[cfg(test)]
 mod tests {
+    use super::*;
+    use crate::metainfo;
+
+    #[test]
+    fn test_bitfield() {
+        let mut bitfield = Bitfield::new(10);
+        assert_eq!(bitfield.as_bytes(), &[0, 0]);
+        assert!(bitfield.set(0));
+        assert!(bitfield.set(9));
+        assert!(!bitfield.set(10));
+        assert_eq!(bitfield.as_bytes(), &[0x80, 0x40]);
+        assert!(bitfield.has(9) && !bitfield.has(8) && !bitfield.has(10));
+        assert_eq!(bitfield.iter_set().collect::<Vec<_>>(), vec![0, 9]);
+        bitfield.unset(0);
+        assert_eq!(bitfield.count(), 1);
+        assert!(Bitfield::full(10).is_complete());
+        assert_eq!(Bitfield::full(10).as_bytes(), &[0xff, 0xc0]);
+
+        assert_eq!(
+            Bitfield::from_bytes(&[0xff, 0xc0], 10).unwrap(),
+            Bitfield::full(10)
+        );
+        assert_eq!(
+            Bitfield::from_bytes(&[0xff, 0xe0], 10),
+            Err(BitfieldError::SpareBits)
+        );
+        assert_eq!(
+            Bitfield::from_bytes(&[0xff], 10),
+            Err(BitfieldError::Length { len: 10, actual: 1 })
+        );
+        assert!(Bitfield::from_bytes(&[0xff], 8).unwrap().is_complete());
+    }
+
+    #[test]
+    fn test_check_piece() {
+        let data = [7u8; 25];
+        let mut piece_hash = Sha1::digest(&data[..10]).to_vec();
+        piece_hash.extend_from_slice(&Sha1::digest(&data[10..20]));
+        piece_hash.extend_from_slice(&Sha1::digest(&data[20..]));
+        let file = File::new(
+            None,
+            vec![],
+            "test".to_string(),
+            0,
+            0,
+            InfoHash::new([0; 20]),
+            piece_hash,
+            10,
+            data.len(),
+            "test".to_string(),
+            RequestHeader {
+                request: Request::Torrent(TorrentRequest("test".to_string())),
+            },
+            None,
+        );
+        assert!(file.check_piece(0, &data[..10]).unwrap());
+        assert!(file.check_piece(2, &data[20..]).unwrap());
+        // the short last piece must not be padded
+        assert!(!file.check_piece(2, &data[15..]).unwrap());
+        let mut corrupt = data[10..20].to_vec();
+        corrupt[3] ^= 1;
+        assert!(!file.check_piece(1, &corrupt).unwrap());
+        assert!(file.check_piece(3, &data[..10]).is_err());
+    }
 
     #[tokio::test]
     async fn test_bincode_serialize() {
@@ -243,6 +975,365 @@ mod tests {
 
         // assert!(pack(&infohash).is_ok());
     }
+
+    #[test]
+    fn test_torrent_from_bytes() {
+        let bytes = metainfo::tests::single_file_bytes();
+        let torrent = Torrent::from_bytes(&bytes).unwrap();
+        assert_eq!(torrent.announce(), Some("http://tracker.example/announce"));
+        assert_eq!(torrent.name(), "file.txt");
+        assert_eq!(torrent.metainfo.unwrap().info.piece_count(), 2);
+        assert!(Torrent::from_bytes(b"4:spam").is_err());
+    }
+
+    #[test]
+    fn test_set_metadata() {
+        let bytes = metainfo::tests::multi_file_bytes();
+        let info = Metainfo::raw_info(&bytes).unwrap();
+        let info_hash = InfoHash::from_info_bytes(info);
+        let magnet = MagnetLink::parse(&format!(
+            "magnet:?xt=urn:btih:{}&tr=http://a.example/ann",
+            info_hash.to_hex()
+        ))
+        .unwrap();
+        let mut torrent = Torrent::from_magnet(magnet);
+        assert!(!torrent.has_metadata());
+        assert!(torrent.set_metadata(b"d4:name1:ae", None).is_err());
+
+        let dir = std::env::temp_dir().join("jubjub_set_metadata");
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let file = torrent.set_metadata(info, Some(&dir)).unwrap();
+        assert_eq!(torrent.name(), "pack");
+        assert_eq!(torrent.trackers(), vec!["http://a.example/ann".to_string()]);
+        let saved = std::fs::read(dir.join(format!("{}.torrent", info_hash.to_hex()))).unwrap();
+        assert_eq!(saved, file);
+        assert_eq!(Torrent::from_bytes(&saved).unwrap().info_hash, info_hash);
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_set_metadata_rejects_escaping_name() {
+        let mut info =
+            b"d6:lengthi20e4:name13:../../.bashrc12:piece lengthi32e6:pieces20:".to_vec();
+        info.extend_from_slice(&[0u8; 20]);
+        info.push(b'e');
+        let info_hash = InfoHash::from_info_bytes(&info);
+        let magnet =
+            MagnetLink::parse(&format!("magnet:?xt=urn:btih:{}", info_hash.to_hex())).unwrap();
+        let mut torrent = Torrent::from_magnet(magnet);
+        let dir = std::env::temp_dir().join("jubjub_set_metadata_escape");
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        // the info-hash matches, but the name would leave the save dir
+        assert!(matches!(
+            torrent.set_metadata(&info, Some(&dir)),
+            Err(MetainfoError::Invalid(_))
+        ));
+        assert!(!torrent.has_metadata());
+        assert_eq!(std::fs::read_dir(&dir).unwrap().count(), 0);
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_known_peers() {
+        let magnet = MagnetLink::parse(
+            "magnet:?xt=urn:btih:0123456789abcdef0123456789abcdef01234567&x.pe=10.0.0.1:6881&x.pe=bad",
+        )
+        .unwrap();
+        let mut torrent = Torrent::from_magnet(magnet);
+        let first: SocketAddr = "10.0.0.1:6881".parse().unwrap();
+        let second: SocketAddr = "[::1]:6881".parse().unwrap();
+        assert_eq!(torrent.add_peers([first, second], PeerSource::Pex), 1);
+        let mut known: Vec<_> = torrent.known_peers().collect();
+        known.sort_by_key(|(addr, _)| **addr);
+        assert_eq!(
+            known,
+            vec![(&first, &PeerSource::Magnet), (&second, &PeerSource::Pex)]
+        );
+
+        let added = torrent.add_peers(
+            (0..=MAX_KNOWN_PEERS as u32).map(|i| SocketAddr::from((i.to_be_bytes(), 6881))),
+            PeerSource::Dht,
+        );
+        assert_eq!(added, MAX_KNOWN_PEERS - 2);
+        assert_eq!(torrent.known_peers().count(), MAX_KNOWN_PEERS);
+    }
+
+    #[test]
+    fn test_pex_peers() {
+        use crate::peer::extension::pex::{PexFlags, PexMessage};
+        use crate::peer::extension::ExtendedHandshake;
+
+        let bytes = metainfo::tests::multi_file_bytes();
+        let mut torrent = Torrent::from_bytes(&bytes).unwrap();
+        // private torrents get no peer exchange
+        assert!(torrent.is_private());
+        assert!(torrent
+            .extensions(None)
+            .local_id(crate::peer::extension::pex::NAME)
+            .is_none());
+
+        torrent.metainfo.as_mut().unwrap().info.private = None;
+        let mut extensions = torrent.extensions(Some(6881));
+        assert_eq!(
+            extensions.local_id(crate::peer::extension::pex::NAME),
+            Some(1)
+        );
+        assert!(extensions
+            .local_id(crate::peer::extension::metadata::NAME)
+            .is_none());
+
+        let peer = [1; 20];
+        let mut handshake = ExtendedHandshake::default();
+        handshake
+            .m
+            .insert(crate::peer::extension::pex::NAME.to_string(), 3);
+        extensions
+            .on_message(peer, 0, &handshake.to_bytes().unwrap())
+            .unwrap();
+        let message = PexMessage {
+            added: vec![("10.0.0.9:6881".parse().unwrap(), PexFlags(0))],
+            dropped: vec![],
+        };
+        extensions
+            .on_message(peer, 1, &message.to_bytes().unwrap())
+            .unwrap();
+        assert_eq!(torrent.add_pex_peers(&mut extensions), 1);
+        assert_eq!(
+            torrent.known_peers().next(),
+            Some((&"10.0.0.9:6881".parse().unwrap(), &PeerSource::Pex))
+        );
+    }
+
+    #[test]
+    fn test_announce_schedule() {
+        let mut torrent = Torrent::from_bytes(&metainfo::tests::multi_file_bytes()).unwrap();
+        let now = std::time::Instant::now();
+        let (tiers, request) = torrent.start_announce([1; 20], 6881, now).unwrap();
+        assert_eq!(request.event, AnnounceEvent::Started);
+        assert_eq!(
+            request.left,
+            torrent.metainfo.as_ref().unwrap().info.total_length()
+        );
+        // one announce at a time, the statuses stay visible meanwhile
+        assert!(torrent.start_announce([1; 20], 6881, now).is_none());
+        assert_eq!(torrent.tiers.statuses().len(), 3);
+
+        let response = AnnounceResponse {
+            interval: std::time::Duration::from_secs(60),
+            min_interval: None,
+            tracker_id: None,
+            seeders: None,
+            leechers: None,
+            peers: vec!["10.0.0.1:6881".parse().unwrap()],
+            warning: None,
+        };
+        assert_eq!(torrent.finish_announce(tiers, Ok(response)), 1);
+        assert_eq!(torrent.known_peers().count(), 1);
+        let (tiers, request) = torrent.start_announce([1; 20], 6881, now).unwrap();
+        assert_eq!(request.event, AnnounceEvent::None);
+        torrent.finish_announce(tiers, Err(TrackerError::Timeout));
+    }
+
+    #[test]
+    fn test_announce_events() {
+        let mut torrent = Torrent::from_bytes(&metainfo::tests::multi_file_bytes()).unwrap();
+        let pieces = torrent.metainfo.as_ref().unwrap().info.piece_count();
+        let total = torrent.metainfo.as_ref().unwrap().info.total_length();
+        let mut have = Bitfield::new(pieces);
+        have.set(0);
+        torrent.set_have(have);
+        torrent.add_transferred(10, 20);
+        let now = std::time::Instant::now();
+        let (tiers, request) = torrent.start_announce([1; 20], 6881, now).unwrap();
+        assert_eq!(request.event, AnnounceEvent::Started);
+        assert!(request.left < total);
+        assert_eq!((request.uploaded, request.downloaded), (10, 20));
+        torrent.finish_announce(tiers, Err(TrackerError::Timeout));
+        assert!(torrent.started.is_none());
+        let (tiers, _) = torrent.start_announce([1; 20], 6881, now).unwrap();
+        let response = AnnounceResponse {
+            interval: std::time::Duration::from_secs(60),
+            min_interval: None,
+            tracker_id: None,
+            seeders: None,
+            leechers: None,
+            peers: vec![],
+            warning: None,
+        };
+        torrent.finish_announce(tiers, Ok(response));
+
+        // finishing is announced until a tracker hears of it
+        assert!(torrent.set_have(Bitfield::full(pieces)));
+        let (tiers, request) = torrent.start_announce([1; 20], 6881, now).unwrap();
+        assert_eq!(request.event, AnnounceEvent::Completed);
+        assert_eq!(request.left, 0);
+        torrent.finish_announce(tiers, Err(TrackerError::Timeout));
+        let (tiers, request) = torrent.start_announce([1; 20], 6881, now).unwrap();
+        assert_eq!(request.event, AnnounceEvent::Completed);
+        torrent.finish_announce(tiers, Err(TrackerError::Timeout));
+
+        let (tiers, request) = torrent.stop_announce([1; 20], 6881).unwrap();
+        assert_eq!(request.event, AnnounceEvent::Stopped);
+        torrent.finish_announce(tiers, Err(TrackerError::Timeout));
+        assert!(torrent.stop_announce([1; 20], 6881).is_none());
+        let (_, request) = torrent.start_announce([1; 20], 6881, now).unwrap();
+        assert_eq!(request.event, AnnounceEvent::Started);
+    }
+
+    #[test]
+    fn test_queued_torrent_does_not_announce() {
+        let mut torrent = Torrent::from_bytes(&metainfo::tests::multi_file_bytes()).unwrap();
+        let now = std::time::Instant::now();
+        torrent.set_active(FileStatus::DownloadQueued.is_active());
+        assert!(torrent.start_announce([1; 20], 6881, now).is_none());
+        // nothing to stop before the trackers know us
+        assert!(torrent.stop_announce([1; 20], 6881).is_none());
+
+        torrent.set_active(FileStatus::Downloading.is_active());
+        let (tiers, request) = torrent.start_announce([1; 20], 6881, now).unwrap();
+        assert_eq!(request.event, AnnounceEvent::Started);
+        let response = AnnounceResponse {
+            interval: std::time::Duration::from_secs(60),
+            min_interval: None,
+            tracker_id: None,
+            seeders: None,
+            leechers: None,
+            peers: vec![],
+            warning: None,
+        };
+        torrent.finish_announce(tiers, Ok(response));
+
+        // queueing a running torrent takes it out of the swarm
+        torrent.set_active(FileStatus::SeedQueued.is_active());
+        assert!(torrent.start_announce([1; 20], 6881, now).is_none());
+        let (tiers, request) = torrent.stop_announce([1; 20], 6881).unwrap();
+        assert_eq!(request.event, AnnounceEvent::Stopped);
+        torrent.finish_announce(tiers, Err(TrackerError::Timeout));
+        assert!(torrent.start_announce([1; 20], 6881, now).is_none());
+    }
+
+    #[test]
+    fn test_resume_round_trip() {
+        let root = std::env::temp_dir().join("jubjub_test_resume_round_trip");
+        let _ = std::fs::remove_dir_all(&root);
+        let bytes = metainfo::tests::multi_file_bytes();
+        let mut torrent = Torrent::from_bytes(&bytes).unwrap();
+        assert!(torrent.resume_data().is_none());
+        let layout = torrent
+            .attach_storage(&root, StorageSettings::default())
+            .unwrap()
+            .layout()
+            .clone();
+        torrent
+            .tiers
+            .set_tracker_id("http://b.example/ann", "abc".to_string());
+        let mut have = Bitfield::new(layout.piece_count());
+        have.set(0);
+        assert!(torrent.set_file_priority(1, Priority::Skip));
+        assert!(!torrent.set_file_priority(layout.files.len(), Priority::High));
+        torrent.add_transferred(100, 200);
+        let mut data = torrent.resume_data().unwrap();
+        data.update(&have, &layout);
+
+        let mut restored = Torrent::from_bytes(&bytes).unwrap();
+        restored.attach_storage(&root, StorageSettings::default());
+        assert_eq!(restored.restore(data.clone()), Some(have.clone()));
+        assert_eq!(restored.tiers.tracker_ids(), data.tracker_ids);
+        assert_eq!(restored.file_priorities(), torrent.file_priorities());
+        assert_eq!((restored.uploaded, restored.downloaded), (100, 200));
+        restored.set_have(have);
+        let picker = restored.picker().unwrap();
+        // piece 0 is shared with the first file
+        assert_eq!(picker.priority(0), Priority::Normal);
+        assert_eq!(picker.priority(2), Priority::Skip);
+
+        // a file that changed since the save needs a recheck
+        let path = &layout.files[0].path;
+        std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+        std::fs::write(path, b"x").unwrap();
+        assert_eq!(restored.restore(data), None);
+        std::fs::remove_dir_all(root).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_torrent_open() {
+        let path = std::env::temp_dir().join("jubjub_test_torrent_open.torrent");
+        std::fs::write(&path, metainfo::tests::multi_file_bytes()).unwrap();
+        let torrent = Torrent::open(path.to_str().unwrap()).await.unwrap();
+        assert_eq!(torrent.name(), "pack");
+        assert_eq!(
+            torrent.trackers(),
+            vec![
+                "http://a.example/ann".to_string(),
+                "http://b.example/ann".to_string(),
+                "http://c.example/ann".to_string()
+            ]
+        );
+        assert_eq!(torrent.health().stats, None);
+        assert_eq!(torrent.tiers.statuses().len(), 3);
+        assert!(matches!(
+            Torrent::open("/nonexistent/jubjub.torrent").await,
+            Err(MetainfoError::Io(_))
+        ));
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_info_hash_raw_span() {
+        // keys out of canonical order: re-encoding the info dict would change the hash
+        let bytes =
+            b"d4:infod4:name1:a6:lengthi1e12:piece lengthi1e6:pieces20:aaaaaaaaaaaaaaaaaaaaee";
+        let torrent = Torrent::from_bytes(bytes).unwrap();
+        let raw = &bytes[7..bytes.len() - 1];
+        assert_eq!(torrent.info_hash, InfoHash::from_info_bytes(raw));
+        let reencoded = serde_bencode::to_bytes(&torrent.metainfo.unwrap().info).unwrap();
+        assert_ne!(torrent.info_hash, InfoHash::from_info_bytes(&reencoded));
+    }
+
+    #[test]
+    fn test_info_hash_encodings() {
+        let hash = InfoHash::from_info_bytes(b"abc");
+        assert_eq!(hash.to_hex(), "a9993e364706816aba3e25717850c26c9cd0d89d");
+        assert_eq!(hash.to_string(), hash.to_hex());
+        assert_eq!(hash.to_base32(), "VGMT4NSHA2AWVOR6EVYXQUGCNSONBWE5");
+        assert_eq!(
+            "A9993E364706816ABA3E25717850C26C9CD0D89D"
+                .parse::<InfoHash>()
+                .unwrap(),
+            hash
+        );
+        assert_eq!(
+            "vgmt4nsha2awvor6evyxqugcnsonbwe5"
+                .parse::<InfoHash>()
+                .unwrap(),
+            hash
+        );
+        assert_eq!("abc".parse::<InfoHash>(), Err(InfoHashError::Length(3)));
+        assert!(matches!(
+            "zz993e364706816aba3e25717850c26c9cd0d89d".parse::<InfoHash>(),
+            Err(InfoHashError::Encoding(_))
+        ));
+    }
+
+    #[test]
+    fn test_torrent_from_magnet() {
+        let magnet = MagnetLink::parse(
+            "magnet:?xt=urn:btih:a9993e364706816aba3e25717850c26c9cd0d89d&dn=abc&tr=http://t.example/a",
+        )
+        .unwrap();
+        let torrent = Torrent::from_magnet(magnet);
+        assert!(!torrent.has_metadata());
+        assert_eq!(torrent.name(), "abc");
+        assert_eq!(torrent.announce(), Some("http://t.example/a"));
+        assert_eq!(
+            torrent.info_hash.to_hex(),
+            "a9993e364706816aba3e25717850c26c9cd0d89d"
+        );
+        assert!(torrent.layout(std::path::Path::new("/tmp")).is_none());
+    }
+
     async fn test_file_new() {
         // let destination_dir = Some("/Downloads".parse::<PathBuf>().unwrap());
         // let file = File::new(